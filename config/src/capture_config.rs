@@ -0,0 +1,42 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Dump the plaintext of tcp connections to some destinations as pcap files, for debugging
+/// broken sites. The data is captured before being encrypted for the proxy, so it's only
+/// plaintext if the application doesn't use tls.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaptureConfig {
+    /// Domains, including their subdomains, or ips.
+    pub destinations: Vec<String>,
+    /// A `<id>-<destination>.pcap` file is written here for each captured connection.
+    pub dir: PathBuf,
+}
+
+impl CaptureConfig {
+    /// Whether connections to `destination`, a domain or ip, are captured.
+    pub fn matches(&self, destination: &str) -> bool {
+        self.destinations.iter().any(|d| {
+            destination == d
+                || (destination.ends_with(d.as_str())
+                    && destination[..destination.len() - d.len()].ends_with('.'))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let config = CaptureConfig {
+            destinations: vec!["example.com".to_string(), "1.2.3.4".to_string()],
+            dir: PathBuf::from("/tmp"),
+        };
+        assert!(config.matches("example.com"));
+        assert!(config.matches("www.example.com"));
+        assert!(!config.matches("badexample.com"));
+        assert!(config.matches("1.2.3.4"));
+        assert!(!config.matches("11.2.3.4"));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{include, Config, ShadowsocksServerConfig};
+use serde_yaml::Value;
+use std::path::Path;
+
+/// Problems found in a config file by `check_config_file`.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// The config can't be used.
+    pub errors: Vec<String>,
+    /// The config can be used but probably doesn't do what is intended.
+    pub warnings: Vec<String>,
+}
+
+/// Validate the config file and its includes without downloading anything. Unknown fields
+/// are errors, with the path of the field, eg. `shadowsocks_servers[0].pasword`.
+/// Servers are parsed from `serde_yaml::Value`s so their fields are checked one by one.
+pub fn check_config_file(path: &str) -> CheckReport {
+    let mut report = CheckReport::default();
+    let value = match include::load_file(Path::new(path), &mut vec![]) {
+        Ok(value) => value,
+        Err(e) => {
+            report.errors.push(e.to_string());
+            return report;
+        }
+    };
+
+    let mut unknown_fields = server_unknown_fields(&value);
+    let mut callback = |field: serde_ignored::Path| unknown_fields.push(field.to_string());
+    let deserializer = serde_ignored::Deserializer::new(value, &mut callback);
+    let config: Result<Config, _> = serde_path_to_error::deserialize(deserializer);
+    report.errors.extend(
+        unknown_fields
+            .into_iter()
+            .map(|field| format!("unknown field: {}", field)),
+    );
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            report.errors.push(format!("{}: {}", e.path(), e.inner()));
+            return report;
+        }
+    };
+    for (rule, shadowed_by) in config.rules.unreachable_rules() {
+        report.warnings.push(format!(
+            "rule {:?} is unreachable, shadowed by {:?}",
+            rule, shadowed_by
+        ));
+    }
+    if let Err(e) = config.validate() {
+        report.errors.push(e.to_string());
+    }
+    report
+}
+
+fn server_unknown_fields(value: &Value) -> Vec<String> {
+    let servers = match value.get("shadowsocks_servers") {
+        Some(Value::Sequence(servers)) => servers,
+        _ => return vec![],
+    };
+    let mut unknown_fields = vec![];
+    for (i, server) in servers.iter().enumerate() {
+        if !server.is_mapping() {
+            continue;
+        }
+        let mut callback = |field: serde_ignored::Path| {
+            unknown_fields.push(format!("shadowsocks_servers[{}].{}", i, field))
+        };
+        let deserializer = serde_ignored::Deserializer::new(server.clone(), &mut callback);
+        // Type errors are reported when deserializing the whole config.
+        let _: Result<ShadowsocksServerConfig, _> = serde::Deserialize::deserialize(deserializer);
+    }
+    unknown_fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_check_config_file() {
+        let path = std::env::temp_dir().join(format!("seeker-check-{}.yml", std::process::id()));
+        let config = r#"
+dns_start_ip: 11.0.0.10
+dns_servers: [223.5.5.5:53]
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 0.0.0.0:53
+dns_timout: 1s
+max_connect_errors: 2
+shadowsocks_servers:
+  - name: server1
+    addr: 1.2.3.4:8388
+    method: aes-256-gcm
+    pasword: password
+    password: password
+rules:
+  - 'DOMAIN-SUFFIX,google.com,PROXY'
+  - 'DOMAIN,www.google.com,DIRECT'
+  - 'MATCH,DIRECT'
+"#;
+        fs::write(&path, config).unwrap();
+        let report = check_config_file(path.to_str().unwrap());
+        assert_eq!(
+            report.errors,
+            vec![
+                "unknown field: shadowsocks_servers[0].pasword",
+                "unknown field: dns_timout"
+            ]
+        );
+        assert_eq!(report.warnings.len(), 1);
+
+        fs::write(&path, config.replace("11.0.0.0/16", "11.0.0.0/33")).unwrap();
+        let report = check_config_file(path.to_str().unwrap());
+        assert!(report
+            .errors
+            .last()
+            .unwrap()
+            .starts_with("tun_cidr: invalid cidr"));
+
+        fs::write(&path, config.replace("    pasword: password\n", "")).unwrap();
+        let report = check_config_file(path.to_str().unwrap());
+        assert_eq!(report.errors, vec!["unknown field: dns_timout"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,36 @@
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Http api for inspecting and controlling a running seeker, used by the `seeker` subcommands.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ControllerConfig {
+    /// Listen address, eg. `127.0.0.1:9000`, or `unix:<path>` for a unix socket only accessible
+    /// by the owner.
+    pub addr: String,
+    /// Required as `Authorization: Bearer <token>` by all requests if set.
+    pub token: Option<String>,
+    /// Serve https instead of http, not for unix sockets.
+    pub tls: Option<ControllerTlsConfig>,
+}
+
+impl ControllerConfig {
+    /// Path of the unix socket if `addr` is `unix:<path>`.
+    pub fn unix_socket(&self) -> Option<&str> {
+        if self.addr.starts_with("unix:") {
+            Some(&self.addr[5..])
+        } else {
+            None
+        }
+    }
+}
+
+/// Certificate of the controller.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ControllerTlsConfig {
+    /// PKCS#12 file of the certificate chain and private key, eg. by
+    /// `openssl pkcs12 -export -in cert.pem -inkey key.pem -out controller.p12`.
+    pub pkcs12: PathBuf,
+    /// Password of `pkcs12`.
+    #[serde(default)]
+    pub password: String,
+}
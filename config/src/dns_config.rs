@@ -0,0 +1,318 @@
+use crate::parse_cidr6;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr, Ipv6Cidr};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Upstream dns server.
+///
+/// * `223.5.5.5` or `223.5.5.5:53`: plain udp
+/// * `https://1.1.1.1/dns-query`: DNS over HTTPS
+/// * `tls://1.1.1.1` or `tls://1.1.1.1:853#cloudflare-dns.com`: DNS over TLS, the name after
+///   `#` is used to verify the certificate, defaults to the IP.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum DnsServerAddr {
+    Udp(SocketAddr),
+    Https(String),
+    Tls(SocketAddr, String),
+}
+
+impl FromStr for DnsServerAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid dns server: {}", s);
+        if s.starts_with("https://") {
+            return Ok(DnsServerAddr::Https(s.to_string()));
+        }
+        if s.starts_with("tls://") {
+            let mut segments = s[6..].splitn(2, '#');
+            let addr = segments.next().unwrap_or_default();
+            let addr = match addr.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(_) => SocketAddr::new(addr.parse().map_err(|_| err())?, 853),
+            };
+            let name = match segments.next() {
+                Some(name) => name.to_string(),
+                None => addr.ip().to_string(),
+            };
+            return Ok(DnsServerAddr::Tls(addr, name));
+        }
+        let addr = if s.starts_with("udp://") { &s[6..] } else { s };
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => Ok(DnsServerAddr::Udp(addr)),
+            Err(_) => {
+                let ip: IpAddr = addr.parse().map_err(|_| err())?;
+                Ok(DnsServerAddr::Udp(SocketAddr::new(ip, 53)))
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DnsServerAddr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(Error::custom)
+    }
+}
+
+impl fmt::Display for DnsServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsServerAddr::Udp(addr) => write!(f, "{}", addr),
+            DnsServerAddr::Https(url) => write!(f, "{}", url),
+            DnsServerAddr::Tls(addr, name) => write!(f, "tls://{}#{}", addr, name),
+        }
+    }
+}
+
+/// Verification of the certificates of DNS over TLS and HTTPS servers, on top of the system
+/// roots.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DnsTlsConfig {
+    /// PEM file of extra trusted CA certificates, eg. of a self-hosted resolver.
+    pub ca_file: Option<PathBuf>,
+    /// base64 SHA-256 hashes of SubjectPublicKeyInfo, connections to DoT servers whose
+    /// certificate has none of them are closed. DoH servers can't be used with pins.
+    #[serde(deserialize_with = "deserialize_pins")]
+    pub pinned_spki: Vec<Vec<u8>>,
+}
+
+fn deserialize_pins<'de, D>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|pin| match base64::decode(pin) {
+            Ok(hash) if hash.len() == 32 => Ok(hash),
+            _ => Err(Error::custom(format!(
+                "invalid pin: {}, expected base64 of sha256",
+                pin
+            ))),
+        })
+        .collect()
+}
+
+/// How AAAA queries of domains not resolved directly are answered.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AaaaStrategy {
+    /// Answer without addresses, so dual-stack clients fall back to IPv4.
+    Drop,
+    /// Answer with the real addresses, IPv6 traffic won't go through seeker.
+    Passthrough,
+    /// Answer with fake IPv6 addresses allocated from `dns_start_ipv6`.
+    Fake,
+}
+
+impl Default for AaaaStrategy {
+    fn default() -> Self {
+        AaaaStrategy::Drop
+    }
+}
+
+/// EDNS Client Subnet (RFC 7871) attached to upstream queries, eg. `1.2.3.0/24`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ClientSubnet {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl FromStr for ClientSubnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid client subnet: {}", s);
+        let mut segments = s.splitn(2, '/');
+        let addr: IpAddr = segments
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| err())?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match segments.next() {
+            Some(prefix) => prefix.parse().map_err(|_| err())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(err());
+        }
+        Ok(ClientSubnet { addr, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientSubnet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(Error::custom)
+    }
+}
+
+/// Responses of upstream dns servers containing these IPs are treated as poisoned and
+/// dropped, eg. `127.0.0.0/8` or `::1/128`.
+#[derive(Debug, Clone, Default)]
+pub struct IpBlacklist {
+    v4: Vec<Ipv4Cidr>,
+    v6: Vec<Ipv6Cidr>,
+}
+
+impl IpBlacklist {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self.v4.iter().any(|c| c.contains_addr(&ip.into())),
+            IpAddr::V6(ip) => self.v6.iter().any(|c| c.contains_addr(&ip.into())),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.is_empty() && self.v6.is_empty()
+    }
+}
+
+impl FromStr for IpBlacklist {
+    type Err = String;
+
+    /// Parse comma separated CIDRs.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut blacklist = IpBlacklist::default();
+        for cidr in s.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            if let Some(cidr) = parse_cidr6(cidr) {
+                blacklist.v6.push(cidr);
+                continue;
+            }
+            let mut segments = cidr.splitn(2, '/');
+            let addr = segments.next().and_then(|a| a.parse::<Ipv4Addr>().ok());
+            let prefix = segments.next().and_then(|p| p.parse::<u8>().ok());
+            match (addr, prefix) {
+                (Some(addr), Some(prefix)) if prefix <= 32 => blacklist
+                    .v4
+                    .push(Ipv4Cidr::new(Ipv4Address::from(addr), prefix)),
+                _ => return Err(format!("invalid cidr: {}", cidr)),
+            }
+        }
+        Ok(blacklist)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpBlacklist {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let cidrs = Vec::<String>::deserialize(deserializer)?;
+        cidrs.join(",").parse().map_err(Error::custom)
+    }
+}
+
+/// Cache for answers of upstream dns servers.
+///
+/// Answers are cached for their TTL clamped to `[min_ttl, max_ttl]`, NXDOMAIN and empty
+/// answers are cached for `negative_ttl`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct DnsCacheConfig {
+    /// Max number of cached queries, 0 disables the cache.
+    pub size: usize,
+    #[serde(with = "crate::duration")]
+    pub min_ttl: Duration,
+    #[serde(with = "crate::duration")]
+    pub max_ttl: Duration,
+    #[serde(with = "crate::duration")]
+    pub negative_ttl: Duration,
+}
+
+impl Default for DnsCacheConfig {
+    fn default() -> Self {
+        DnsCacheConfig {
+            size: 4096,
+            min_ttl: Duration::from_secs(0),
+            max_ttl: Duration::from_secs(3600),
+            negative_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dns_server_addr() {
+        let udp = DnsServerAddr::Udp("223.5.5.5:53".parse().unwrap());
+        assert_eq!("223.5.5.5:53".parse(), Ok(udp.clone()));
+        assert_eq!("223.5.5.5".parse(), Ok(udp.clone()));
+        assert_eq!("udp://223.5.5.5:53".parse(), Ok(udp));
+        assert_eq!(
+            "https://1.1.1.1/dns-query".parse(),
+            Ok(DnsServerAddr::Https(
+                "https://1.1.1.1/dns-query".to_string()
+            ))
+        );
+        let addr = "1.1.1.1:853".parse().unwrap();
+        assert_eq!(
+            "tls://1.1.1.1".parse(),
+            Ok(DnsServerAddr::Tls(addr, "1.1.1.1".to_string()))
+        );
+        assert_eq!(
+            "tls://1.1.1.1:853#cloudflare-dns.com".parse(),
+            Ok(DnsServerAddr::Tls(addr, "cloudflare-dns.com".to_string()))
+        );
+        assert!("dns.google".parse::<DnsServerAddr>().is_err());
+        assert!("tls://dns.google".parse::<DnsServerAddr>().is_err());
+    }
+
+    #[test]
+    fn test_deserialize_dns_tls() {
+        let tls: DnsTlsConfig = serde_yaml::from_str(
+            "{ca_file: /etc/seeker/ca.pem, pinned_spki: ['47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=']}",
+        )
+        .unwrap();
+        assert_eq!(tls.ca_file, Some(PathBuf::from("/etc/seeker/ca.pem")));
+        assert_eq!(tls.pinned_spki[0][..4], [0xe3, 0xb0, 0xc4, 0x42]);
+        assert!(serde_yaml::from_str::<DnsTlsConfig>("{pinned_spki: [abcd]}").is_err());
+    }
+
+    #[test]
+    fn test_ip_blacklist() {
+        let blacklist: IpBlacklist = "127.0.0.0/8, 243.185.187.39/32, ::1/128".parse().unwrap();
+        assert!(blacklist.contains("127.0.0.1".parse().unwrap()));
+        assert!(blacklist.contains("243.185.187.39".parse().unwrap()));
+        assert!(blacklist.contains("::1".parse().unwrap()));
+        assert!(!blacklist.contains("1.1.1.1".parse().unwrap()));
+        assert!(!blacklist.contains("::2".parse().unwrap()));
+        assert!("1.1.1.1".parse::<IpBlacklist>().is_err());
+        assert!("1.1.1.1/33".parse::<IpBlacklist>().is_err());
+    }
+
+    #[test]
+    fn test_parse_client_subnet() {
+        assert_eq!(
+            "1.2.3.0/24".parse(),
+            Ok(ClientSubnet {
+                addr: "1.2.3.0".parse().unwrap(),
+                prefix: 24
+            })
+        );
+        assert_eq!(
+            "2001:db8::1".parse(),
+            Ok(ClientSubnet {
+                addr: "2001:db8::1".parse().unwrap(),
+                prefix: 128
+            })
+        );
+        assert!("1.2.3.0/33".parse::<ClientSubnet>().is_err());
+        assert!("1.2.3/24".parse::<ClientSubnet>().is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use serde_yaml::Value;
+use std::env;
+
+/// Replace `${NAME}` in the string values of a parsed config with the environment variable
+/// `NAME`, `$${` is an escaped `${`. Values are replaced after parsing, so the variables can hold
+/// any characters, and commented out settings don't need them. Keys and numbers are kept as is.
+pub fn interpolate_env(value: Value) -> Result<Value, String> {
+    interpolate_value(value, &|name| env::var(name).ok())
+}
+
+fn interpolate_value(
+    value: Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<Value, String> {
+    Ok(match value {
+        Value::String(s) => Value::String(interpolate(&s, lookup)?),
+        Value::Sequence(seq) => Value::Sequence(
+            seq.into_iter()
+                .map(|v| interpolate_value(v, lookup))
+                .collect::<Result<_, _>>()?,
+        ),
+        Value::Mapping(map) => Value::Mapping(
+            map.into_iter()
+                .map(|(k, v)| Ok((k, interpolate_value(v, lookup)?)))
+                .collect::<Result<_, String>>()?,
+        ),
+        value => value,
+    })
+}
+
+fn interpolate(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find("${") {
+        if rest[..pos].ends_with('$') {
+            // the first `$` escapes the second one
+            result.push_str(&rest[..pos]);
+            result.push('{');
+            rest = &rest[pos + 2..];
+            continue;
+        }
+        result.push_str(&rest[..pos]);
+        let end = rest[pos..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed ${{ in config: {}", &rest[pos..]))?;
+        let name = &rest[pos + 2..pos + end];
+        let value =
+            lookup(name).ok_or_else(|| format!("environment variable {} is not set", name))?;
+        result.push_str(&value);
+        rest = &rest[pos + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::include::parse_value;
+    use crate::ConfigFormat;
+
+    #[test]
+    fn test_interpolate() {
+        let lookup = |name: &str| match name {
+            "HOST" => Some("example.com".to_string()),
+            _ => None,
+        };
+        assert_eq!(
+            interpolate("${HOST}:8388", lookup),
+            Ok("example.com:8388".to_string())
+        );
+        assert_eq!(
+            interpolate("price: $5, literal: $${HOST}", lookup),
+            Ok("price: $5, literal: ${HOST}".to_string())
+        );
+        assert!(interpolate("${MISSING}", lookup).is_err());
+        assert!(interpolate("${HOST", lookup).is_err());
+    }
+
+    #[test]
+    fn test_interpolate_parsed() {
+        let tricky = "a#b: \"c' ]}\n[d]";
+        env::set_var("SEEKER_TEST_TRICKY", tricky);
+        let yaml = "
+# password: ${SEEKER_TEST_MISSING}
+password: ${SEEKER_TEST_TRICKY}
+servers: ['${SEEKER_TEST_TRICKY}', {name: 'x${SEEKER_TEST_TRICKY}'}]
+port: 8388
+";
+        let toml = r#"
+# password = "${SEEKER_TEST_MISSING}"
+password = "${SEEKER_TEST_TRICKY}"
+servers = ["${SEEKER_TEST_TRICKY}", { name = "x${SEEKER_TEST_TRICKY}" }]
+port = 8388
+"#;
+        let json = r#"{
+            "password": "${SEEKER_TEST_TRICKY}",
+            "servers": ["${SEEKER_TEST_TRICKY}", {"name": "x${SEEKER_TEST_TRICKY}"}],
+            "port": 8388
+        }"#;
+        for (content, format) in vec![
+            (yaml, ConfigFormat::Yaml),
+            (toml, ConfigFormat::Toml),
+            (json, ConfigFormat::Json),
+        ] {
+            let value = parse_value(content, format).unwrap();
+            assert_eq!(value["password"].as_str(), Some(tricky));
+            assert_eq!(value["servers"][0].as_str(), Some(tricky));
+            assert_eq!(
+                value["servers"][1]["name"].as_str(),
+                Some(format!("x{}", tricky).as_str())
+            );
+            assert_eq!(value["port"].as_u64(), Some(8388));
+        }
+        assert!(parse_value("password: ${SEEKER_TEST_MISSING}", ConfigFormat::Yaml).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use crate::rule::Action;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use socks5_client::Address;
+use std::str::FromStr;
+
+/// A local port forwarded to a remote destination, like `ssh -L`, eg. for databases behind the
+/// proxy.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForwardConfig {
+    /// Listen address, eg. `127.0.0.1:5432`.
+    pub listen: String,
+    /// Destination of the forwarded connections, `host:port`.
+    #[serde(deserialize_with = "deserialize_target")]
+    pub target: Address,
+    /// `PROXY`, `DIRECT` or the name of a server group. Decided by the rules if not set.
+    #[serde(default, deserialize_with = "deserialize_via")]
+    pub via: Option<Action>,
+}
+
+fn deserialize_target<'de, D>(deserializer: D) -> Result<Address, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match Address::from_str(&s) {
+        Ok(addr) if s.contains(':') => Ok(addr),
+        _ => Err(Error::custom(format!(
+            "invalid target: {}, expected host:port",
+            s
+        ))),
+    }
+}
+
+fn deserialize_via<'de, D>(deserializer: D) -> Result<Option<Action>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match Action::from_str(&s) {
+        Ok(action @ Action::Proxy)
+        | Ok(action @ Action::Direct)
+        | Ok(action @ Action::Group(_)) => Ok(Some(action)),
+        _ => Err(Error::custom(format!(
+            "invalid via: {}, expected PROXY, DIRECT or a server group",
+            s
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize() {
+        let forward: ForwardConfig =
+            serde_yaml::from_str("{listen: '127.0.0.1:5432', target: 'db.internal:5432'}").unwrap();
+        assert_eq!(
+            forward.target,
+            Address::DomainNameAddress("db.internal".to_string(), 5432)
+        );
+        assert_eq!(forward.via, None);
+
+        let forward: ForwardConfig = serde_yaml::from_str(
+            "{listen: '127.0.0.1:3389', target: '10.0.0.2:3389', via: STREAMING}",
+        )
+        .unwrap();
+        assert_eq!(forward.via, Some(Action::Group("STREAMING".to_string())));
+
+        assert!(serde_yaml::from_str::<ForwardConfig>(
+            "{listen: '127.0.0.1:5432', target: 'db.internal'}"
+        )
+        .is_err());
+        assert!(serde_yaml::from_str::<ForwardConfig>(
+            "{listen: '127.0.0.1:5432', target: 'db.internal:5432', via: REJECT}"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use parking_lot::RwLock;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Static domain to IP mappings from the `hosts` section.
+///
+/// `*.example.com` matches all subdomains of `example.com` but not `example.com` itself.
+/// Exact domains take precedence over wildcards, and longer wildcards over shorter ones.
+#[derive(Debug, Clone, Default)]
+pub struct Hosts {
+    mappings: Arc<RwLock<Mappings>>,
+}
+
+#[derive(Debug, Default)]
+struct Mappings {
+    exact: HashMap<String, IpAddr>,
+    wildcards: HashMap<String, IpAddr>,
+}
+
+impl Hosts {
+    pub fn new(hosts: HashMap<String, IpAddr>) -> Self {
+        let mut exact = HashMap::new();
+        let mut wildcards = HashMap::new();
+        for (domain, ip) in hosts {
+            let domain = normalize(&domain);
+            if domain.starts_with("*.") {
+                wildcards.insert(domain[2..].to_string(), ip);
+            } else {
+                exact.insert(domain, ip);
+            }
+        }
+        Hosts {
+            mappings: Arc::new(RwLock::new(Mappings { exact, wildcards })),
+        }
+    }
+
+    /// Replace the mappings with the ones of `other`. All clones of this `Hosts` see the new
+    /// mappings.
+    pub fn replace(&self, other: &Hosts) {
+        if Arc::ptr_eq(&self.mappings, &other.mappings) {
+            return;
+        }
+        let other = other.mappings.read();
+        let mut mappings = self.mappings.write();
+        mappings.exact = other.exact.clone();
+        mappings.wildcards = other.wildcards.clone();
+    }
+
+    pub fn get(&self, domain: &str) -> Option<IpAddr> {
+        let domain = normalize(domain);
+        let mappings = self.mappings.read();
+        if let Some(ip) = mappings.exact.get(&domain) {
+            return Some(*ip);
+        }
+        let mut suffix = domain.as_str();
+        while let Some(pos) = suffix.find('.') {
+            suffix = &suffix[pos + 1..];
+            if let Some(ip) = mappings.wildcards.get(suffix) {
+                return Some(*ip);
+            }
+        }
+        None
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+impl<'de> Deserialize<'de> for Hosts {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let hosts = HashMap::<String, IpAddr>::deserialize(deserializer)?;
+        Ok(Hosts::new(hosts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let hosts: Hosts = serde_yaml::from_str(
+            r#"
+gitlab.internal.corp: 10.0.0.2
+"*.internal.corp": 10.0.0.1
+"*.dev.internal.corp": 10.0.0.3
+v6.example.com: "::1"
+"#,
+        )
+        .unwrap();
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        assert_eq!(hosts.get("gitlab.internal.corp"), ip("10.0.0.2"));
+        assert_eq!(hosts.get("GitLab.internal.corp."), ip("10.0.0.2"));
+        assert_eq!(hosts.get("wiki.internal.corp"), ip("10.0.0.1"));
+        assert_eq!(hosts.get("a.b.internal.corp"), ip("10.0.0.1"));
+        assert_eq!(hosts.get("a.dev.internal.corp"), ip("10.0.0.3"));
+        assert_eq!(hosts.get("v6.example.com"), ip("::1"));
+        assert_eq!(hosts.get("internal.corp"), None);
+        assert_eq!(hosts.get("example.com"), None);
+
+        let shared = hosts.clone();
+        let mut mappings = HashMap::new();
+        mappings.insert("example.com".to_string(), "1.1.1.1".parse().unwrap());
+        hosts.replace(&Hosts::new(mappings));
+        assert_eq!(shared.get("example.com"), ip("1.1.1.1"));
+        assert_eq!(shared.get("gitlab.internal.corp"), None);
+    }
+}
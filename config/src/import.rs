@@ -0,0 +1,509 @@
+//! Conversion of the config of other proxy tools to seeker's format.
+//!
+//! The converted config only contains servers, server groups, rules and rule providers. It can
+//! be included by a seeker config with `include:`.
+
+use crate::rule::Rule;
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::str::FromStr;
+
+/// Converted config with what couldn't be converted.
+#[derive(Debug)]
+pub struct ImportedConfig {
+    pub config: Value,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct ClashConfig {
+    #[serde(default)]
+    proxies: Vec<ClashProxy>,
+    #[serde(default, rename = "proxy-groups")]
+    proxy_groups: Vec<ClashProxyGroup>,
+    #[serde(default)]
+    rules: Vec<String>,
+    #[serde(default, rename = "rule-providers")]
+    rule_providers: HashMap<String, ClashRuleProvider>,
+}
+
+#[derive(Deserialize)]
+struct ClashProxy {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    server: String,
+    #[serde(default)]
+    port: u16,
+    #[serde(default)]
+    cipher: String,
+    #[serde(default)]
+    password: String,
+    plugin: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClashProxyGroup {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    proxies: Vec<String>,
+    url: Option<String>,
+    /// Seconds
+    interval: Option<u64>,
+    /// Milliseconds
+    tolerance: Option<u64>,
+    strategy: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClashRuleProvider {
+    #[serde(rename = "type")]
+    kind: String,
+    behavior: String,
+    url: Option<String>,
+    path: Option<String>,
+    /// Seconds
+    interval: Option<u64>,
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn mapping(entries: Vec<(&str, Value)>) -> Value {
+    let mut mapping = Mapping::new();
+    for (key, value) in entries {
+        mapping.insert(string(key), value);
+    }
+    Value::Mapping(mapping)
+}
+
+/// Server names of `name` with nested groups expanded, `DIRECT` and `REJECT` are left out.
+fn group_servers(
+    name: &str,
+    servers: &HashSet<&str>,
+    groups: &HashMap<&str, &ClashProxyGroup>,
+    visiting: &mut Vec<String>,
+    result: &mut Vec<String>,
+) {
+    if servers.contains(name) {
+        if !result.iter().any(|server| server == name) {
+            result.push(name.to_string());
+        }
+        return;
+    }
+    let group = match groups.get(name) {
+        Some(group) if !visiting.iter().any(|n| n == name) => group,
+        _ => return,
+    };
+    visiting.push(name.to_string());
+    for proxy in &group.proxies {
+        group_servers(proxy, servers, groups, visiting, result);
+    }
+    visiting.pop();
+}
+
+/// Convert the `proxies`, `proxy-groups`, `rules` and `rule-providers` of a clash config.
+pub fn import_clash(content: &str) -> io::Result<ImportedConfig> {
+    let clash: ClashConfig = serde_yaml::from_str(content)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    Ok(convert(clash, vec![]))
+}
+
+/// Convert the `[Proxy]`, `[Proxy Group]` and `[Rule]` sections of a surge config.
+pub fn import_surge(content: &str) -> io::Result<ImportedConfig> {
+    let mut clash = ClashConfig::default();
+    let mut warnings = vec![];
+    let mut section = "";
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = &line[1..line.len() - 1];
+            continue;
+        }
+        match section {
+            "Proxy" => match parse_surge_proxy(line) {
+                Some(proxy) => clash.proxies.push(proxy),
+                None => warnings.push(format!("skip proxy {}: invalid proxy", line)),
+            },
+            "Proxy Group" => match parse_surge_group(line) {
+                Some(group) => clash.proxy_groups.push(group),
+                None => warnings.push(format!("skip proxy group {}: invalid group", line)),
+            },
+            // Remote rule sets of surge are plain lists, not clash rule providers.
+            "Rule" if line.starts_with("RULE-SET,") || line.starts_with("DOMAIN-SET,") => {
+                warnings.push(format!("skip rule {}: not supported", line))
+            }
+            "Rule" => clash.rules.push(surge_rule(line)),
+            _ => {}
+        }
+    }
+    if clash.proxies.is_empty() && clash.rules.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no [Proxy] or [Rule] section",
+        ));
+    }
+    Ok(convert(clash, warnings))
+}
+
+/// Split `name = value, value, key=value` into the name, values and key value pairs.
+fn parse_surge_line(line: &str) -> Option<(&str, Vec<&str>, HashMap<&str, &str>)> {
+    let pos = line.find('=')?;
+    let name = line[..pos].trim();
+    let mut values = vec![];
+    let mut params = HashMap::new();
+    for item in line[pos + 1..].split(',').map(str::trim) {
+        match item.find('=') {
+            Some(pos) => {
+                params.insert(item[..pos].trim(), item[pos + 1..].trim());
+            }
+            None => values.push(item),
+        }
+    }
+    if name.is_empty() || values.is_empty() {
+        return None;
+    }
+    Some((name, values, params))
+}
+
+/// `hk = ss, hk.example.com, 8388, encrypt-method=aes-256-gcm, password=test`
+fn parse_surge_proxy(line: &str) -> Option<ClashProxy> {
+    let (name, values, params) = parse_surge_line(line)?;
+    let server = values.get(1).map(|s| s.to_string()).unwrap_or_default();
+    let port = match values.get(2) {
+        Some(port) => port.parse().ok()?,
+        None => 0,
+    };
+    Some(ClashProxy {
+        name: name.to_string(),
+        kind: values[0].to_string(),
+        server,
+        port,
+        cipher: params.get("encrypt-method").unwrap_or(&"").to_string(),
+        password: params.get("password").unwrap_or(&"").to_string(),
+        plugin: params.get("obfs").map(|_| "obfs".to_string()),
+    })
+}
+
+/// `auto = url-test, hk, jp, url=http://www.gstatic.com/generate_204, interval=600`
+fn parse_surge_group(line: &str) -> Option<ClashProxyGroup> {
+    let (name, values, params) = parse_surge_line(line)?;
+    Some(ClashProxyGroup {
+        name: name.to_string(),
+        kind: values[0].to_string(),
+        proxies: values[1..].iter().map(|s| s.to_string()).collect(),
+        url: params.get("url").map(|s| s.to_string()),
+        interval: params.get("interval").and_then(|s| s.parse().ok()),
+        tolerance: params.get("tolerance").and_then(|s| s.parse().ok()),
+        strategy: None,
+    })
+}
+
+/// Remove the options which clash doesn't have, eg. `FINAL,Proxy,dns-failed`.
+fn surge_rule(line: &str) -> String {
+    let mut rule = line;
+    for option in &[",dns-failed", ",extended-matching"] {
+        rule = rule.trim_end_matches(option);
+    }
+    if rule.ends_with(",REJECT-TINYGIF") {
+        return format!("{},REJECT", &rule[..rule.len() - ",REJECT-TINYGIF".len()]);
+    }
+    rule.to_string()
+}
+
+/// Only shadowsocks proxies without plugins are supported. Nested groups are flattened into
+/// the servers they contain, rules targeting a single proxy use a group with only that proxy.
+fn convert(clash: ClashConfig, mut warnings: Vec<String>) -> ImportedConfig {
+    let mut servers = vec![];
+    let mut server_names = HashSet::new();
+    for proxy in &clash.proxies {
+        if proxy.kind != "ss" {
+            warnings.push(format!(
+                "skip proxy {}: type {} is not supported",
+                proxy.name, proxy.kind
+            ));
+            continue;
+        }
+        if let Some(plugin) = &proxy.plugin {
+            warnings.push(format!(
+                "skip proxy {}: plugin {} is not supported",
+                proxy.name, plugin
+            ));
+            continue;
+        }
+        server_names.insert(proxy.name.as_str());
+        servers.push(mapping(vec![
+            ("name", string(&proxy.name)),
+            ("addr", string(&format!("{}:{}", proxy.server, proxy.port))),
+            ("method", string(&proxy.cipher)),
+            ("password", string(&proxy.password)),
+        ]));
+    }
+
+    let groups: HashMap<&str, &ClashProxyGroup> = clash
+        .proxy_groups
+        .iter()
+        .map(|group| (group.name.as_str(), group))
+        .collect();
+    let mut server_groups = vec![];
+    // Rule targets which are converted to seeker actions.
+    let mut targets: HashMap<String, String> = HashMap::new();
+    for action in &["DIRECT", "REJECT"] {
+        targets.insert(action.to_string(), action.to_string());
+    }
+    for group in &clash.proxy_groups {
+        let mode = match group.kind.as_str() {
+            "select" => "sticky",
+            "url-test" => "auto",
+            "fallback" => "fallback",
+            "load-balance" => "load-balance",
+            kind => {
+                warnings.push(format!(
+                    "skip proxy group {}: type {} is not supported",
+                    group.name, kind
+                ));
+                continue;
+            }
+        };
+        let mut members = vec![];
+        group_servers(
+            &group.name,
+            &server_names,
+            &groups,
+            &mut vec![],
+            &mut members,
+        );
+        if members.is_empty() {
+            // eg. a group choosing between DIRECT and REJECT, use the first choice.
+            match group
+                .proxies
+                .first()
+                .and_then(|proxy| targets.get(proxy))
+                .cloned()
+            {
+                Some(action) => {
+                    targets.insert(group.name.clone(), action);
+                }
+                None => warnings.push(format!("skip proxy group {}: no servers", group.name)),
+            }
+            continue;
+        }
+
+        let mut entries = vec![
+            ("name", string(&group.name)),
+            (
+                "servers",
+                Value::Sequence(members.iter().map(|name| string(name)).collect()),
+            ),
+            ("mode", string(mode)),
+        ];
+        if mode == "load-balance" {
+            // Clash defaults to consistent hashing.
+            let strategy = match group.strategy.as_ref().map(String::as_str) {
+                Some("round-robin") => "round-robin",
+                _ => "consistent-hashing",
+            };
+            entries.push(("strategy", string(strategy)));
+        }
+        match &group.url {
+            Some(url) if url.starts_with("http://") => entries.push(("url", string(url))),
+            Some(url) => warnings.push(format!(
+                "proxy group {}: probe url {} is not supported, use the default",
+                group.name, url
+            )),
+            None => {}
+        }
+        if let Some(interval) = group.interval {
+            entries.push(("interval", string(&format!("{}s", interval))));
+        }
+        if let Some(tolerance) = group.tolerance {
+            entries.push(("tolerance", string(&format!("{}ms", tolerance))));
+        }
+        server_groups.push(mapping(entries));
+        targets.insert(group.name.clone(), group.name.clone());
+    }
+
+    let mut rules = vec![];
+    for rule in &clash.rules {
+        let rule = rule.trim();
+        let without_options = rule.trim_end_matches(",no-resolve");
+        let (criteria, target) = match without_options.rfind(',') {
+            Some(pos) => (&without_options[..pos], &without_options[pos + 1..]),
+            None => {
+                warnings.push(format!("skip rule {}: invalid rule", rule));
+                continue;
+            }
+        };
+        let action = match targets.get(target) {
+            Some(action) => action.clone(),
+            None if server_names.contains(target) => {
+                server_groups.push(mapping(vec![
+                    ("name", string(target)),
+                    ("servers", Value::Sequence(vec![string(target)])),
+                ]));
+                targets.insert(target.to_string(), target.to_string());
+                target.to_string()
+            }
+            None => {
+                warnings.push(format!("skip rule {}: unknown proxy {}", rule, target));
+                continue;
+            }
+        };
+        let converted = format!("{},{}", criteria, action);
+        if Rule::from_str(&converted).is_err() {
+            warnings.push(format!("skip rule {}: not supported", rule));
+            continue;
+        }
+        rules.push(string(&converted));
+    }
+
+    let mut names: Vec<&String> = clash.rule_providers.keys().collect();
+    names.sort();
+    let mut rule_providers = Mapping::new();
+    for name in names {
+        let provider = &clash.rule_providers[name];
+        let url = match (provider.kind.as_str(), &provider.url) {
+            ("http", Some(url)) => url,
+            _ => {
+                warnings.push(format!(
+                    "skip rule provider {}: type {} is not supported",
+                    name, provider.kind
+                ));
+                continue;
+            }
+        };
+        let mut entries = vec![
+            ("behavior", string(&provider.behavior)),
+            ("url", string(url)),
+        ];
+        if let Some(path) = &provider.path {
+            entries.push(("path", string(path)));
+        }
+        if let Some(interval) = provider.interval {
+            entries.push(("interval", string(&format!("{}s", interval))));
+        }
+        rule_providers.insert(string(name), mapping(entries));
+    }
+
+    let mut config = vec![("shadowsocks_servers", Value::Sequence(servers))];
+    if !server_groups.is_empty() {
+        config.push(("server_groups", Value::Sequence(server_groups)));
+    }
+    config.push(("rules", Value::Sequence(rules)));
+    if !rule_providers.is_empty() {
+        config.push(("rule_providers", Value::Mapping(rule_providers)));
+    }
+    ImportedConfig {
+        config: mapping(config),
+        warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_clash() {
+        let clash = r#"
+proxies:
+  - {name: hk, type: ss, server: hk.example.com, port: 8388, cipher: aes-256-gcm, password: test}
+  - {name: jp, type: ss, server: 1.2.3.4, port: 443, cipher: chacha20-ietf-poly1305, password: test}
+  - {name: obfs, type: ss, server: 1.2.3.4, port: 443, cipher: aes-256-gcm, password: test, plugin: obfs}
+  - {name: vmess, type: vmess, server: 1.2.3.4, port: 443, uuid: test}
+proxy-groups:
+  - {name: auto, type: url-test, proxies: [hk, jp, vmess], url: 'http://www.gstatic.com/generate_204', interval: 300}
+  - {name: Proxy, type: select, proxies: [auto, hk, DIRECT]}
+  - {name: AdBlock, type: select, proxies: [REJECT, DIRECT]}
+rule-providers:
+  reject:
+    type: http
+    behavior: domain
+    url: https://example.com/reject.txt
+    path: ./ruleset/reject.yaml
+    interval: 86400
+rules:
+  - DOMAIN-SUFFIX,ad.com,AdBlock
+  - DOMAIN-SUFFIX,jp.example.com,jp
+  - RULE-SET,reject,REJECT
+  - IP-CIDR,192.168.0.0/16,DIRECT,no-resolve
+  - GEOIP,CN,DIRECT
+  - MATCH,Proxy
+"#;
+        let imported = import_clash(clash).unwrap();
+        let expected: Value = serde_yaml::from_str(
+            r#"
+shadowsocks_servers:
+  - {name: hk, addr: 'hk.example.com:8388', method: aes-256-gcm, password: test}
+  - {name: jp, addr: '1.2.3.4:443', method: chacha20-ietf-poly1305, password: test}
+server_groups:
+  - {name: auto, servers: [hk, jp], mode: auto, url: 'http://www.gstatic.com/generate_204', interval: 300s}
+  - {name: Proxy, servers: [hk, jp], mode: sticky}
+  - {name: jp, servers: [jp]}
+rules:
+  - 'DOMAIN-SUFFIX,ad.com,REJECT'
+  - 'DOMAIN-SUFFIX,jp.example.com,jp'
+  - 'RULE-SET,reject,REJECT'
+  - 'IP-CIDR,192.168.0.0/16,DIRECT'
+  - 'MATCH,Proxy'
+rule_providers:
+  reject: {behavior: domain, url: 'https://example.com/reject.txt', path: ./ruleset/reject.yaml, interval: 86400s}
+"#,
+        )
+        .unwrap();
+        assert_eq!(imported.config, expected);
+        assert_eq!(imported.warnings.len(), 3);
+        assert!(imported.warnings[2].starts_with("skip rule GEOIP"));
+    }
+
+    #[test]
+    fn test_import_surge() {
+        let surge = r#"
+[General]
+loglevel = notify
+
+[Proxy]
+On = direct
+hk = ss, hk.example.com, 8388, encrypt-method=aes-256-gcm, password=test, udp-relay=true
+jp = ss, 1.2.3.4, 443, encrypt-method=aes-256-gcm, password=test, obfs=http, obfs-host=example.com
+
+[Proxy Group]
+Proxy = select, auto, hk, DIRECT
+auto = url-test, hk, url=http://www.gstatic.com/generate_204, interval=600, tolerance=100
+
+[Rule]
+# comment
+DOMAIN-SET,https://example.com/reject.txt,REJECT
+DOMAIN-SUFFIX,ad.com,REJECT-TINYGIF
+IP-CIDR,192.168.0.0/16,DIRECT,no-resolve
+FINAL,Proxy,dns-failed
+"#;
+        let imported = import_surge(surge).unwrap();
+        let expected: Value = serde_yaml::from_str(
+            r#"
+shadowsocks_servers:
+  - {name: hk, addr: 'hk.example.com:8388', method: aes-256-gcm, password: test}
+server_groups:
+  - {name: Proxy, servers: [hk], mode: sticky}
+  - {name: auto, servers: [hk], mode: auto, url: 'http://www.gstatic.com/generate_204', interval: 600s, tolerance: 100ms}
+rules:
+  - 'DOMAIN-SUFFIX,ad.com,REJECT'
+  - 'IP-CIDR,192.168.0.0/16,DIRECT'
+  - 'FINAL,Proxy'
+"#,
+        )
+        .unwrap();
+        assert_eq!(imported.config, expected);
+        assert_eq!(imported.warnings.len(), 3);
+        assert!(import_surge("loglevel = notify").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! `include:` lists files merged into the config which includes them.
+//!
+//! Values of the including file take precedence, then the values of the included files in the
+//! listed order. Lists are concatenated with the items of the included files first in the listed
+//! order, then the items of the including file, so a trailing `MATCH` rule of the including file
+//! doesn't shadow the included rules. Maps are merged key by key.
+
+use crate::{env, ConfigFormat};
+use serde_yaml::Value;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+fn invalid(e: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+/// Parse the config and replace environment variables in its string values.
+pub(crate) fn parse_value(content: &str, format: ConfigFormat) -> io::Result<Value> {
+    let value = match format {
+        ConfigFormat::Yaml => serde_yaml::from_str(content).map_err(|e| invalid(e.to_string()))?,
+        ConfigFormat::Toml => toml::from_str(content).map_err(|e| invalid(e.to_string()))?,
+        ConfigFormat::Json => serde_json::from_str(content).map_err(|e| invalid(e.to_string()))?,
+    };
+    env::interpolate_env(value).map_err(invalid)
+}
+
+/// Load the config file at `path` with its includes.
+pub(crate) fn load_file(path: &Path, including: &mut Vec<PathBuf>) -> io::Result<Value> {
+    let canonical = path.canonicalize()?;
+    if including.contains(&canonical) {
+        return Err(invalid(format!("{} includes itself", path.display())));
+    }
+    let content = fs::read_to_string(path)?;
+    let value = parse_value(&content, ConfigFormat::from_path(path))?;
+    including.push(canonical);
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let value = resolve_includes(value, dir, including);
+    including.pop();
+    value
+}
+
+/// Merge the files listed in `include` of `value`, relative paths are relative to `dir`.
+pub(crate) fn resolve_includes(
+    value: Value,
+    dir: &Path,
+    including: &mut Vec<PathBuf>,
+) -> io::Result<Value> {
+    let mut mapping = match value {
+        Value::Mapping(mapping) => mapping,
+        value => return Ok(value),
+    };
+    let paths = match mapping.remove(&Value::String("include".to_string())) {
+        Some(Value::String(path)) => vec![path],
+        Some(Value::Sequence(paths)) => paths
+            .into_iter()
+            .map(|path| match path {
+                Value::String(path) => Ok(path),
+                path => Err(invalid(format!("invalid include: {:?}", path))),
+            })
+            .collect::<io::Result<_>>()?,
+        Some(Value::Null) | None => vec![],
+        Some(value) => return Err(invalid(format!("invalid include: {:?}", value))),
+    };
+    let mut included = Value::Null;
+    for path in paths {
+        let value = load_file(&dir.join(&path), including)
+            .map_err(|e| io::Error::new(e.kind(), format!("include {}: {}", path, e)))?;
+        included = merge(included, value, false);
+    }
+    Ok(merge(Value::Mapping(mapping), included, true))
+}
+
+/// Merge `other` into `base`, `base` wins. Lists are concatenated with the items of `other`
+/// first if `other_first`.
+fn merge(base: Value, other: Value, other_first: bool) -> Value {
+    match (base, other) {
+        (Value::Mapping(mut base), Value::Mapping(other)) => {
+            for (key, other_value) in other {
+                match base.get_mut(&key) {
+                    Some(value) => {
+                        let base_value = std::mem::replace(value, Value::Null);
+                        *value = merge(base_value, other_value, other_first);
+                    }
+                    None => {
+                        base.insert(key, other_value);
+                    }
+                }
+            }
+            Value::Mapping(base)
+        }
+        (Value::Sequence(base), Value::Sequence(mut other)) if other_first => {
+            other.extend(base);
+            Value::Sequence(other)
+        }
+        (Value::Sequence(mut base), Value::Sequence(other)) => {
+            base.extend(other);
+            Value::Sequence(base)
+        }
+        (Value::Null, other) => other,
+        (base, _) => base,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let base: Value =
+            serde_yaml::from_str("dns_listen: 0.0.0.0:53\nrules: [a]\nhosts: {a.com: 1.1.1.1}")
+                .unwrap();
+        let other: Value = serde_yaml::from_str(
+            "dns_listen: 127.0.0.1:53\nrules: [b]\nhosts: {b.com: 2.2.2.2}\ntun_name: utun4",
+        )
+        .unwrap();
+        let expected: Value = serde_yaml::from_str(
+            "dns_listen: 0.0.0.0:53\nrules: [a, b]\nhosts: {a.com: 1.1.1.1, b.com: 2.2.2.2}\ntun_name: utun4",
+        )
+        .unwrap();
+        assert_eq!(merge(base.clone(), other.clone(), false), expected);
+        let expected: Value = serde_yaml::from_str(
+            "dns_listen: 0.0.0.0:53\nrules: [b, a]\nhosts: {a.com: 1.1.1.1, b.com: 2.2.2.2}\ntun_name: utun4",
+        )
+        .unwrap();
+        assert_eq!(merge(base, other, true), expected);
+        assert_eq!(
+            merge(Value::Null, Value::Bool(true), false),
+            Value::Bool(true)
+        );
+    }
+
+    #[test]
+    fn test_include_files() {
+        let dir = std::env::temp_dir().join(format!("seeker-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("rules")).unwrap();
+        fs::write(
+            dir.join("config.yml"),
+            "include: [rules/proxy.yml, rules/direct.yml, servers.json]\nrules: ['MATCH,DIRECT']",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("rules/proxy.yml"),
+            "rules: ['DOMAIN-SUFFIX,google.com,PROXY']",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("rules/direct.yml"),
+            "rules: ['DOMAIN-SUFFIX,cn,DIRECT']",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("servers.json"),
+            r#"{"shadowsocks_servers": [], "include": "config.yml"}"#,
+        )
+        .unwrap();
+        // servers.json includes config.yml again
+        assert!(load_file(&dir.join("config.yml"), &mut vec![]).is_err());
+
+        fs::write(dir.join("servers.json"), r#"{"shadowsocks_servers": []}"#).unwrap();
+        let value = load_file(&dir.join("config.yml"), &mut vec![]).unwrap();
+        let expected: Value = serde_yaml::from_str(
+            "rules: ['DOMAIN-SUFFIX,google.com,PROXY', 'DOMAIN-SUFFIX,cn,DIRECT', 'MATCH,DIRECT']\nshadowsocks_servers: []",
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
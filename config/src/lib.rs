@@ -1,37 +1,163 @@
+mod capture_config;
+mod check;
+mod controller_config;
+mod dns_config;
+mod env;
+mod forward_config;
+mod hosts;
+mod import;
+mod include;
+mod log_config;
+mod redir_config;
 pub mod rule;
+mod rule_provider;
+mod script;
+mod secret;
 mod server_config;
-pub use server_config::{ServerAddr, ShadowsocksServerConfig};
+mod server_group;
+mod socks5_inbound_config;
+mod subscription;
+pub use capture_config::CaptureConfig;
+pub use check::{check_config_file, CheckReport};
+pub use controller_config::{ControllerConfig, ControllerTlsConfig};
+pub use dns_config::{
+    AaaaStrategy, ClientSubnet, DnsCacheConfig, DnsServerAddr, DnsTlsConfig, IpBlacklist,
+};
+pub use forward_config::ForwardConfig;
+pub use hosts::Hosts;
+pub use import::{import_clash, import_surge, ImportedConfig};
+pub use log_config::{LogConfig, LogFormat, LogRotation, OtlpConfig};
+pub use redir_config::{RedirConfig, RedirFirewall};
+pub use rule_provider::{RuleProviderConfig, RuleSetBehavior};
+pub use script::RuleScript;
+pub use server_config::{
+    ConnectionLimitConfig, MuxConfig, PluginConfig, RetryConfig, ServerAddr,
+    ShadowsocksServerConfig,
+};
+pub use server_group::{
+    BalanceStrategy, GroupMode, NamedServerGroup, ProbeMethod, ProbeUrl, ServerGroupConfig,
+};
 pub use socks5_client::Address;
+pub use socks5_inbound_config::{Socks5InboundConfig, Socks5User};
+pub use subscription::SubscriptionConfig;
 
 use crate::server_config::ProxyServerConfig;
 use rule::ProxyRules;
 use serde::Deserialize;
-use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
-use std::fs::File;
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::{ErrorKind, Read};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
+    /// Servers as fields or `ss://` urls.
+    #[serde(default, with = "shadowsocks_servers")]
     pub shadowsocks_servers: Option<Arc<Vec<ShadowsocksServerConfig>>>,
+    /// Allow servers with the legacy stream ciphers. Without integrity checks, their traffic can
+    /// be tampered with undetected.
+    #[serde(default)]
+    pub allow_insecure_ciphers: bool,
+    /// Remote server lists merged into `shadowsocks_servers`.
+    #[serde(default)]
+    pub subscriptions: HashMap<String, SubscriptionConfig>,
+    #[serde(default)]
+    pub server_group: ServerGroupConfig,
+    /// Groups of `shadowsocks_servers` used by rules with the group name as the action.
+    #[serde(default)]
+    pub server_groups: Vec<NamedServerGroup>,
     pub socks5_server: Option<ProxyServerConfig>,
     pub http_proxy_server: Option<ProxyServerConfig>,
     pub dns_start_ip: Ipv4Addr,
-    pub dns_servers: Vec<SocketAddr>,
+    /// Start of the fake IPv6 pool, used when `dns_aaaa` is `fake`.
+    #[serde(default = "default_dns_start_ipv6")]
+    pub dns_start_ipv6: Ipv6Addr,
+    #[serde(default)]
+    pub dns_aaaa: AaaaStrategy,
+    pub dns_servers: Vec<DnsServerAddr>,
+    /// Dns servers for domain suffixes, eg. `corp.example.com: [10.0.0.2]`.
+    #[serde(default)]
+    pub dns_domain_servers: HashMap<String, Vec<DnsServerAddr>>,
+    #[serde(default)]
+    pub dns_cache: DnsCacheConfig,
+    /// EDNS Client Subnet sent to upstream dns servers.
+    pub dns_client_subnet: Option<ClientSubnet>,
+    /// Query all dns servers concurrently and use the first valid answer.
+    #[serde(default)]
+    pub dns_race: bool,
+    #[serde(default)]
+    pub dns_ip_blacklist: IpBlacklist,
+    #[serde(default)]
+    pub dns_tls: DnsTlsConfig,
+    /// Static domain to IP mappings answered by the dns server, supports `*.example.com`.
+    #[serde(default)]
+    pub hosts: Hosts,
     pub tun_name: String,
+    /// `tun_name` is a persistent device set up beforehand, seeker attaches to it without
+    /// changing its addresses and routes.
+    #[serde(default)]
+    pub tun_persistent: bool,
+    /// An opened tun device inherited from the parent process, used instead of `tun_name`.
+    pub tun_fd: Option<i32>,
     pub tun_ip: Ipv4Addr,
     #[serde(default)]
     pub verbose: bool,
     #[serde(with = "ipv4_cidr")]
     pub tun_cidr: Ipv4Cidr,
+    /// IPv6 of the tun device, IPv6 packets are dropped if not set.
+    pub tun_ipv6: Option<TunIpv6Config>,
+    /// MTU of the tun device, the MSS of tcp connections through it is clamped to fit. The system
+    /// default if not set.
+    pub tun_mtu: Option<u16>,
+    /// Connections tracked by the nat of the tun device, the least recently used are evicted
+    /// beyond it.
+    #[serde(default = "default_tun_max_sessions")]
+    pub tun_max_sessions: usize,
+    /// Queues of the tun device read in parallel, linux only.
+    #[serde(default = "default_tun_queues")]
+    pub tun_queues: usize,
+    /// Let the kernel pass coalesced tcp segments through the tun device, linux only.
+    #[serde(default)]
+    pub tun_offload: bool,
+    /// Accept connections redirected by iptables/nftables instead of running the tun device.
+    pub redir: Option<RedirConfig>,
     #[serde(with = "rules")]
     pub rules: ProxyRules,
+    #[serde(default)]
+    pub rule_providers: HashMap<String, RuleProviderConfig>,
+    /// Script deciding the action for rules with the `SCRIPT` action.
+    pub script: Option<PathBuf>,
     pub dns_listen: String,
+    /// Answer the dns queries reaching the tun device by the dns server at `dns_listen`, whatever
+    /// resolver they were sent to, eg. those of apps with hard-coded resolvers.
+    #[serde(default)]
+    pub dns_hijack: bool,
+    /// Address of the local http proxy, for apps and hosts of the LAN preferring an explicit
+    /// proxy. Not served if not set.
+    pub http_listen: Option<String>,
+    /// Local socks5 proxy, not served if not set.
+    pub socks5_inbound: Option<Socks5InboundConfig>,
+    /// Address of a local proxy serving both socks5 and http clients, told apart by the first
+    /// byte. Not served if not set.
+    pub mixed_listen: Option<String>,
+    /// Local ports forwarded to remote destinations.
+    #[serde(default)]
+    pub forwards: Vec<ForwardConfig>,
+    /// Fake IPs not used for this long are released.
+    #[serde(with = "duration", default = "default_fake_ip_max_age")]
+    pub fake_ip_max_age: Duration,
     #[serde(default)]
     pub gateway_mode: bool,
+    /// Switch to this user, a name or an id, once the tun device and the system settings are set
+    /// up, linux only.
+    pub user: Option<String>,
+    /// Group to switch to with `user`, the primary group of `user` if not set.
+    pub group: Option<String>,
     #[serde(with = "duration", default = "default_connect_timeout")]
     pub ping_timeout: Duration,
     #[serde(with = "duration", default = "default_connect_timeout")]
@@ -44,7 +170,30 @@ pub struct Config {
     pub read_timeout: Duration,
     #[serde(with = "duration", default = "default_write_timeout")]
     pub write_timeout: Duration,
+    /// Connections to shadowsocks servers fail if the server sends no salt for this long after
+    /// connecting, which it only sends with the first response of the target. 0, the default,
+    /// for never, so long-polls and other slow first responses aren't cut.
+    #[serde(with = "duration", default)]
+    pub handshake_timeout: Duration,
     pub max_connect_errors: usize,
+    /// Cap on connections open to shadowsocks servers, unlimited if not set.
+    pub server_connection_limit: Option<ConnectionLimitConfig>,
+    pub controller: Option<ControllerConfig>,
+    /// Log to a rotated file instead of stdout, overridden by `--log`.
+    pub log: Option<LogConfig>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    pub otlp: Option<OtlpConfig>,
+    pub capture: Option<CaptureConfig>,
+}
+
+/// The IPv6 address of the tun device and the network routed to it, which should contain
+/// `dns_start_ipv6` for the `fake` `dns_aaaa`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TunIpv6Config {
+    pub ip: Ipv6Addr,
+    #[serde(with = "ipv6_cidr")]
+    pub cidr: Ipv6Cidr,
 }
 
 fn default_read_timeout() -> Duration {
@@ -56,12 +205,25 @@ fn default_write_timeout() -> Duration {
 fn default_connect_timeout() -> Duration {
     Duration::from_millis(100)
 }
+fn default_tun_max_sessions() -> usize {
+    10000
+}
+fn default_tun_queues() -> usize {
+    1
+}
+fn default_dns_start_ipv6() -> Ipv6Addr {
+    Ipv6Addr::new(0xfd00, 0x5ee, 0, 0, 0, 0, 0, 1)
+}
+fn default_fake_ip_max_age() -> Duration {
+    Duration::from_secs(7 * 24 * 3600)
+}
 fn default_ping_timeout() -> Duration {
     Duration::from_secs(3)
 }
 
 mod ipv4_cidr {
     use crate::parse_cidr;
+    use serde::de::Error;
     use serde::{Deserialize, Deserializer};
     use smoltcp::wire::Ipv4Cidr;
 
@@ -70,7 +232,22 @@ mod ipv4_cidr {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Ok(parse_cidr(s))
+        parse_cidr(&s).ok_or_else(|| Error::custom(format!("invalid cidr: {}", s)))
+    }
+}
+
+mod ipv6_cidr {
+    use crate::parse_cidr6;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer};
+    use smoltcp::wire::Ipv6Cidr;
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Ipv6Cidr, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        parse_cidr6(&s).ok_or_else(|| Error::custom(format!("invalid cidr: {}", s)))
     }
 }
 
@@ -108,6 +285,7 @@ mod duration {
 
 mod rules {
     use crate::rule::{ProxyRules, Rule};
+    use serde::de::Error;
     use serde::{Deserialize, Deserializer};
     use std::str::FromStr;
 
@@ -116,50 +294,401 @@ mod rules {
         D: Deserializer<'de>,
     {
         let rules: Vec<String> = Vec::deserialize(deserializer)?;
-        let rs: Vec<Rule> = rules
+        let rs = rules
             .into_iter()
-            .map(|s| Rule::from_str(&s).unwrap())
-            .collect();
+            .map(|s| Rule::from_str(&s).map_err(|_| Error::custom(format!("invalid rule: {}", s))))
+            .collect::<Result<Vec<Rule>, _>>()?;
         Ok(ProxyRules::new(rs))
     }
 }
 
-fn parse_cidr(s: String) -> Ipv4Cidr {
-    let segments = s.splitn(2, '/').collect::<Vec<&str>>();
-    let addr = segments[0];
-    let len = segments[1];
-    let addr: Ipv4Addr = addr.parse().unwrap();
-    let prefix = len.parse().unwrap();
-    Ipv4Cidr::new(Ipv4Address::from(addr), prefix)
+mod shadowsocks_servers {
+    use crate::ShadowsocksServerConfig;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer};
+    use std::sync::Arc;
+
+    pub fn deserialize<'de, D>(
+        deserializer: D,
+    ) -> Result<Option<Arc<Vec<ShadowsocksServerConfig>>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let values: Option<Vec<serde_yaml::Value>> = Option::deserialize(deserializer)?;
+        let servers = match values {
+            Some(values) => values,
+            None => return Ok(None),
+        };
+        let servers = servers
+            .into_iter()
+            .map(|value| match value {
+                serde_yaml::Value::String(url) => url.parse().map_err(Error::custom),
+                value => serde_yaml::from_value(value).map_err(Error::custom),
+            })
+            .collect::<Result<Vec<ShadowsocksServerConfig>, D::Error>>()?;
+        Ok(Some(Arc::new(servers)))
+    }
+}
+
+fn parse_cidr(s: &str) -> Option<Ipv4Cidr> {
+    let mut segments = s.splitn(2, '/');
+    let addr: Ipv4Addr = segments.next()?.parse().ok()?;
+    let prefix: u8 = segments.next()?.parse().ok()?;
+    if prefix > 32 {
+        return None;
+    }
+    Some(Ipv4Cidr::new(Ipv4Address::from(addr), prefix))
+}
+
+fn parse_cidr6(s: &str) -> Option<Ipv6Cidr> {
+    let mut segments = s.splitn(2, '/');
+    let addr: Ipv6Addr = segments.next()?.parse().ok()?;
+    let prefix: u8 = segments.next()?.parse().ok()?;
+    if prefix > 128 {
+        return None;
+    }
+    Some(Ipv6Cidr::new(Ipv6Address::from(addr), prefix))
+}
+
+/// Format of the config file, the same fields are used in all formats.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Detect the format by the extension, defaults to yaml.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("toml") => ConfigFormat::Toml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Yaml,
+        }
+    }
 }
 
 impl Config {
+    /// Files in `include` are merged into the config, see `include` for the precedence.
     pub fn from_config_file(path: &str) -> io::Result<Self> {
-        let file = File::open(&path).unwrap();
-        Config::from_reader(file)
+        let value = include::load_file(Path::new(path), &mut vec![])?;
+        Config::from_value(value)
     }
 
     pub fn from_reader<R: Read>(reader: R) -> io::Result<Self> {
-        let conf: Config = serde_yaml::from_reader(reader).expect("serde yaml deserialize error");
-        if let (None, None, None) = (
+        Config::from_reader_with_format(reader, ConfigFormat::Yaml)
+    }
+
+    /// `${NAME}` in string values is replaced with the environment variable `NAME`. Relative
+    /// paths in `include` are relative to the current directory.
+    pub fn from_reader_with_format<R: Read>(
+        mut reader: R,
+        format: ConfigFormat,
+    ) -> io::Result<Self> {
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+        let value = include::parse_value(&content, format)?;
+        let value = include::resolve_includes(value, Path::new("."), &mut vec![])?;
+        Config::from_value(value)
+    }
+
+    /// Fake IPs handed out by the DNS server, from `dns_start_ip` to the broadcast address of
+    /// `tun_cidr`.
+    pub fn fake_ips(&self) -> Range<Ipv4Addr> {
+        let host_bits = u32::MAX
+            .checked_shr(u32::from(self.tun_cidr.prefix_len()))
+            .unwrap_or(0);
+        let broadcast = u32::from(Ipv4Addr::from(self.tun_cidr.address().0)) | host_bits;
+        self.dns_start_ip..Ipv4Addr::from(broadcast)
+    }
+
+    fn from_value(value: serde_yaml::Value) -> io::Result<Self> {
+        let conf: Config = serde_yaml::from_value(value)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        conf.validate()
+    }
+
+    fn validate(mut self) -> io::Result<Self> {
+        let conf = &mut self;
+        if let (None, None, None, true) = (
             &conf.shadowsocks_servers,
             &conf.socks5_server,
             &conf.http_proxy_server,
+            conf.subscriptions.is_empty(),
         ) {
             return Err(io::Error::new(
                 ErrorKind::InvalidData,
-                "shadowsocks_servers, socks5_server and http_proxy_server should be set one at least.",
+                "shadowsocks_servers, subscriptions, socks5_server and http_proxy_server should be set one at least.",
             ));
         };
-        Ok(conf)
+        if let Some(servers) = &mut conf.shadowsocks_servers {
+            for server in Arc::make_mut(servers) {
+                server.resolve_password().map_err(|e| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("password of server {}: {}", server.name(), e),
+                    )
+                })?;
+            }
+        }
+        if conf.user.is_some() && !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "user is only supported on linux",
+            ));
+        }
+        if conf.group.is_some() && conf.user.is_none() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "group is only used with user",
+            ));
+        }
+        if conf.redir.is_some() && !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "redir is only supported on linux",
+            ));
+        }
+        if let Some(redir) = conf.redir.as_ref().filter(|r| r.firewall.is_some()) {
+            for listen in Some(&redir.tcp_listen).into_iter().chain(&redir.udp_listen) {
+                if listen.parse::<SocketAddr>().is_err() {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid redir listen address for firewall: {}", listen),
+                    ));
+                }
+            }
+        }
+        if conf.dns_hijack && conf.dns_listen.parse::<SocketAddr>().is_err() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid dns_listen for dns_hijack: {}", conf.dns_listen),
+            ));
+        }
+        if let Some(controller) = &conf.controller {
+            if controller.unix_socket().is_some() && !cfg!(unix) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "controller on unix sockets is only supported on unix",
+                ));
+            }
+            if controller.unix_socket().is_some() && controller.tls.is_some() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "controller tls is not supported on unix sockets",
+                ));
+            }
+        }
+        if let Some(ca_file) = conf.dns_tls.ca_file.as_ref().filter(|f| !f.is_file()) {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("dns_tls ca_file not found: {}", ca_file.display()),
+            ));
+        }
+        let mut dns_servers = conf
+            .dns_servers
+            .iter()
+            .chain(conf.dns_domain_servers.values().flatten());
+        if !conf.dns_tls.pinned_spki.is_empty()
+            && dns_servers.any(|server| matches!(server, DnsServerAddr::Https(_)))
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "dns_tls pinned_spki is not supported by DNS over HTTPS servers",
+            ));
+        }
+        if let Some(socks5_inbound) = &mut conf.socks5_inbound {
+            socks5_inbound
+                .resolve_passwords()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+        let mut names = HashSet::new();
+        for server in conf
+            .shadowsocks_servers
+            .iter()
+            .flat_map(|servers| servers.iter())
+        {
+            if !names.insert(server.name()) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("duplicated server name {}", server.name()),
+                ));
+            }
+            if server.has_insecure_cipher() && !conf.allow_insecure_ciphers {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "server {} uses the insecure cipher {}, use an AEAD cipher or set allow_insecure_ciphers",
+                        server.name(),
+                        server.method()
+                    ),
+                ));
+            }
+            if let Some(plugin) = server.plugin() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "plugin {} of server {} is not supported",
+                        plugin.name,
+                        server.name()
+                    ),
+                ));
+            }
+        }
+        match &conf.script {
+            Some(path) => {
+                let script = RuleScript::from_file(path)?;
+                conf.rules.set_script(script);
+            }
+            None if conf.rules.has_script_rules() => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "script should be set to use the SCRIPT action.",
+                ));
+            }
+            None => {}
+        }
+        conf.rules
+            .set_groups(conf.server_groups.iter().map(|group| group.name.clone()));
+        let unknown_groups = conf.rules.unknown_groups();
+        if !unknown_groups.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "unknown actions or server groups in rules: {}",
+                    unknown_groups.join(", ")
+                ),
+            ));
+        }
+        let unknown_rule_sets = conf
+            .rules
+            .unknown_rule_sets(|name| conf.rule_providers.contains_key(name));
+        if !unknown_rule_sets.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "unknown rule providers in rules: {}",
+                    unknown_rule_sets.join(", ")
+                ),
+            ));
+        }
+        for forward in &conf.forwards {
+            if let Some(rule::Action::Group(name)) = &forward.via {
+                if !conf.server_groups.iter().any(|group| group.name == *name) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "unknown server group {} of forward {}",
+                            name, forward.listen
+                        ),
+                    ));
+                }
+            }
+        }
+        // servers of subscriptions are only known after downloading
+        let groups = if conf.subscriptions.is_empty() {
+            &conf.server_groups[..]
+        } else {
+            &[]
+        };
+        for group in groups {
+            for name in &group.servers {
+                let exists = conf
+                    .shadowsocks_servers
+                    .iter()
+                    .flat_map(|servers| servers.iter())
+                    .any(|server| server.name() == name);
+                if !exists {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unknown server {} in server group {}", name, group.name),
+                    ));
+                }
+            }
+        }
+        Ok(self)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::duration::parse_duration;
+    use super::*;
     use std::time::Duration;
 
+    #[test]
+    fn test_config_formats() {
+        assert_eq!(ConfigFormat::from_path("a/config.TOML"), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::from_path("config.json"), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path("config.yml"), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_path("config"), ConfigFormat::Yaml);
+
+        let toml = r#"
+dns_start_ip = "11.0.0.10"
+dns_servers = ["223.5.5.5:53"]
+tun_name = "utun4"
+tun_ip = "11.0.0.1"
+tun_cidr = "11.0.0.0/16"
+dns_listen = "0.0.0.0:53"
+max_connect_errors = 2
+rules = ["DOMAIN-SUFFIX,google.com,PROXY", "MATCH,DIRECT"]
+
+[[shadowsocks_servers]]
+name = "server1"
+addr = "1.2.3.4:8388"
+method = "aes-256-gcm"
+password = "password"
+"#;
+        let json = r#"{
+            "dns_start_ip": "11.0.0.10",
+            "dns_servers": ["223.5.5.5:53"],
+            "tun_name": "utun4",
+            "tun_ip": "11.0.0.1",
+            "tun_cidr": "11.0.0.0/16",
+            "dns_listen": "0.0.0.0:53",
+            "max_connect_errors": 2,
+            "rules": ["DOMAIN-SUFFIX,google.com,PROXY", "MATCH,DIRECT"],
+            "shadowsocks_servers": ["ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ@1.2.3.4:8388#server1"]
+        }"#;
+        for (content, format) in vec![(toml, ConfigFormat::Toml), (json, ConfigFormat::Json)] {
+            let config = Config::from_reader_with_format(content.as_bytes(), format).unwrap();
+            let servers = config.shadowsocks_servers.unwrap();
+            assert_eq!(servers[0].name(), "server1");
+            assert_eq!(servers[0].password(), "password");
+            assert_eq!(
+                config.fake_ips(),
+                Ipv4Addr::new(11, 0, 0, 10)..Ipv4Addr::new(11, 0, 255, 255)
+            );
+            assert_eq!(
+                config.rules.action_for_domain("www.google.com"),
+                Some(rule::Action::Proxy)
+            );
+        }
+        assert!(Config::from_reader_with_format("{".as_bytes(), ConfigFormat::Json).is_err());
+
+        let insecure = toml.replace("aes-256-gcm", "aes-256-cfb");
+        assert!(Config::from_reader_with_format(insecure.as_bytes(), ConfigFormat::Toml).is_err());
+        let allowed = format!("allow_insecure_ciphers = true\n{}", insecure);
+        assert!(Config::from_reader_with_format(allowed.as_bytes(), ConfigFormat::Toml).is_ok());
+        let rule_set = toml.replace("MATCH,DIRECT", "OR,((RULE-SET,ads),(DST-PORT,25)),REJECT");
+        assert!(Config::from_reader_with_format(rule_set.as_bytes(), ConfigFormat::Toml).is_err());
+        let pinned = format!(
+            "{}\n[dns_tls]\npinned_spki = ['47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=']",
+            toml
+        );
+        assert!(Config::from_reader_with_format(pinned.as_bytes(), ConfigFormat::Toml).is_ok());
+        let doh = pinned.replace("223.5.5.5:53", "https://1.1.1.1/dns-query");
+        assert!(Config::from_reader_with_format(doh.as_bytes(), ConfigFormat::Toml).is_err());
+        let plain = toml.replace("aes-256-gcm", "plain");
+        assert!(Config::from_reader_with_format(plain.as_bytes(), ConfigFormat::Toml).is_err());
+    }
+
     #[test]
     fn test_parse_duration() {
         assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
@@ -0,0 +1,140 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::path::PathBuf;
+
+/// When the log file is rotated regardless of its size.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    /// At the start of every hour, UTC.
+    Hourly,
+    /// At 00:00 UTC.
+    Daily,
+}
+
+impl Default for LogRotation {
+    fn default() -> Self {
+        LogRotation::Never
+    }
+}
+
+/// Format of the log lines, `json` writes one json object per event for log collectors.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+/// Log to a file instead of stdout. The rotated files are `<path>.1` (the newest) to
+/// `<path>.<keep>`, older ones are deleted.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogConfig {
+    pub path: PathBuf,
+    /// Rotate when the file would grow larger than this, eg. `10MB`. `0` disables size based
+    /// rotation.
+    #[serde(default = "default_max_size", deserialize_with = "size")]
+    pub max_size: u64,
+    #[serde(default)]
+    pub rotation: LogRotation,
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+impl LogConfig {
+    /// Log to `path` with the default rotation.
+    pub fn new(path: PathBuf) -> Self {
+        LogConfig {
+            path,
+            max_size: default_max_size(),
+            rotation: LogRotation::default(),
+            keep: default_keep(),
+        }
+    }
+}
+
+/// Export tracing spans to an opentelemetry collector over otlp, needs the `otlp` feature.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OtlpConfig {
+    /// Grpc endpoint of the collector, eg. `localhost:4317`.
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_service_name() -> String {
+    "seeker".to_string()
+}
+
+fn default_max_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep() -> usize {
+    20
+}
+
+/// Parse a size with an optional `KB`, `MB` or `GB` suffix, eg. `512KB`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => (&s[..pos], s[pos..].trim()),
+        None => (s, ""),
+    };
+    let multiplier = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" | "K" => 1024,
+        "MB" | "M" => 1024 * 1024,
+        "GB" | "G" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size: {}, expected eg. 10MB", s)),
+    };
+    let num: u64 = num
+        .parse()
+        .map_err(|_| format!("invalid size: {}, expected eg. 10MB", s))?;
+    Ok(num * multiplier)
+}
+
+fn size<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Text(s) => parse_size(&s).map_err(Error::custom),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_config() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("512KB"), Ok(512 * 1024));
+        assert_eq!(parse_size("10 mb"), Ok(10 * 1024 * 1024));
+        assert!(parse_size("10TB").is_err());
+        assert!(parse_size("MB").is_err());
+
+        let config: LogConfig =
+            serde_yaml::from_str("path: seeker.log\nmax_size: 1GB\nrotation: daily").unwrap();
+        assert_eq!(config.max_size, 1024 * 1024 * 1024);
+        assert_eq!(config.rotation, LogRotation::Daily);
+        assert_eq!(config.keep, 20);
+        let config: LogConfig = serde_yaml::from_str("path: seeker.log\nmax_size: 0").unwrap();
+        assert_eq!(config.max_size, 0);
+    }
+}
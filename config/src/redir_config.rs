@@ -0,0 +1,23 @@
+use serde::Deserialize;
+
+/// Transparent proxy for connections redirected by iptables/nftables, used instead of the tun
+/// device, linux only.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedirConfig {
+    /// Listen address of tcp connections redirected by `REDIRECT`, eg. `0.0.0.0:1300`.
+    pub tcp_listen: String,
+    /// Listen address of udp packets redirected by `TPROXY`, udp isn't proxied if not set.
+    pub udp_listen: Option<String>,
+    /// Set up the rules redirecting connections to fake ips by this firewall, the rules are left
+    /// to the user if not set.
+    pub firewall: Option<RedirFirewall>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedirFirewall {
+    /// nftables if available, otherwise iptables.
+    Auto,
+    Nftables,
+    Iptables,
+}
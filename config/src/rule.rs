@@ -1,68 +1,487 @@
-use crate::parse_cidr;
+use crate::rule_provider::RuleSetBehavior;
+use crate::script::RuleScript;
+use crate::{parse_cidr, parse_cidr6};
+use parking_lot::RwLock;
+use regex::Regex;
 use serde::export::Formatter;
-use smoltcp::wire::Ipv4Cidr;
+use smoltcp::wire::{Ipv4Cidr, Ipv6Cidr};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::net::Ipv4Addr;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 use std::sync::Arc;
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Rule {
     Domain(String, Action),
     DomainSuffix(String, Action),
     DomainKeyword(String, Action),
+    DomainRegex(Regex, Action),
     IpCidr(Ipv4Cidr, Action),
+    IpCidr6(Ipv6Cidr, Action),
+    /// Single port `25` or port range `6881-6889`
+    DstPort(RangeInclusive<u16>, Action),
+    SrcPort(RangeInclusive<u16>, Action),
+    ProcessName(String, Action),
+    RuleSet(String, Action),
+    /// Logical rules, eg. `AND,((DST-PORT,443),(DOMAIN-SUFFIX,google.com)),PROXY`.
+    /// Actions of the sub rules are ignored.
+    And(Vec<Rule>, Action),
+    Or(Vec<Rule>, Action),
+    Not(Box<Rule>, Action),
     Match(Action),
 }
 
-#[derive(Eq, PartialEq, Copy, Clone, Debug, Hash)]
+/// Information about a connection which rules are matched against.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionMeta {
+    pub domain: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub src_port: Option<u16>,
+    pub dst_port: Option<u16>,
+    pub process_name: Option<String>,
+}
+
+impl Rule {
+    pub fn action(&self) -> Action {
+        self.action_ref().clone()
+    }
+
+    fn action_ref(&self) -> &Action {
+        match self {
+            Rule::Domain(_, action)
+            | Rule::DomainSuffix(_, action)
+            | Rule::DomainKeyword(_, action)
+            | Rule::DomainRegex(_, action)
+            | Rule::IpCidr(_, action)
+            | Rule::IpCidr6(_, action)
+            | Rule::DstPort(_, action)
+            | Rule::SrcPort(_, action)
+            | Rule::ProcessName(_, action)
+            | Rule::RuleSet(_, action)
+            | Rule::And(_, action)
+            | Rule::Or(_, action)
+            | Rule::Not(_, action)
+            | Rule::Match(action) => action,
+        }
+    }
+
+    /// Whether the criteria of the rule match `meta`. `RULE-SET` and `MATCH` are matched
+    /// by `ProxyRules`.
+    fn matches(&self, meta: &ConnectionMeta) -> bool {
+        let domain = meta.domain.as_deref();
+        match self {
+            Rule::Domain(d, _) => domain == Some(d.as_str()),
+            Rule::DomainSuffix(d, _) => domain.map_or(false, |domain| domain.ends_with(d.as_str())),
+            Rule::DomainKeyword(d, _) => domain.map_or(false, |domain| domain.contains(d.as_str())),
+            Rule::DomainRegex(re, _) => domain.map_or(false, |domain| re.is_match(domain)),
+            Rule::IpCidr(cidr, _) => match meta.ip {
+                Some(IpAddr::V4(ip)) => cidr.contains_addr(&ip.into()),
+                _ => false,
+            },
+            Rule::IpCidr6(cidr, _) => match meta.ip {
+                Some(IpAddr::V6(ip)) => cidr.contains_addr(&ip.into()),
+                _ => false,
+            },
+            Rule::DstPort(ports, _) => meta.dst_port.map_or(false, |p| ports.contains(&p)),
+            Rule::SrcPort(ports, _) => meta.src_port.map_or(false, |p| ports.contains(&p)),
+            Rule::ProcessName(name, _) => meta.process_name.as_deref() == Some(name.as_str()),
+            Rule::And(rules, _) => rules.iter().all(|rule| rule.matches(meta)),
+            Rule::Or(rules, _) => rules.iter().any(|rule| rule.matches(meta)),
+            Rule::Not(rule, _) => !rule.matches(meta),
+            Rule::RuleSet(..) | Rule::Match(_) => false,
+        }
+    }
+
+    /// Write the rule without the action, eg. `DOMAIN-SUFFIX,google.com`.
+    fn fmt_criteria(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Domain(d, _) => write!(f, "DOMAIN,{}", d),
+            Rule::DomainSuffix(d, _) => write!(f, "DOMAIN-SUFFIX,{}", d),
+            Rule::DomainKeyword(d, _) => write!(f, "DOMAIN-KEYWORD,{}", d),
+            Rule::DomainRegex(re, _) => write!(f, "DOMAIN-REGEX,{}", re.as_str()),
+            Rule::IpCidr(cidr, _) => write!(f, "IP-CIDR,{}", cidr),
+            Rule::IpCidr6(cidr, _) => write!(f, "IP-CIDR6,{}", cidr),
+            Rule::DstPort(ports, _) => {
+                write!(f, "DST-PORT,")?;
+                fmt_ports(f, ports)
+            }
+            Rule::SrcPort(ports, _) => {
+                write!(f, "SRC-PORT,")?;
+                fmt_ports(f, ports)
+            }
+            Rule::ProcessName(name, _) => write!(f, "PROCESS-NAME,{}", name),
+            Rule::RuleSet(name, _) => write!(f, "RULE-SET,{}", name),
+            Rule::And(rules, _) => {
+                write!(f, "AND,")?;
+                fmt_sub_rules(f, &rules.iter().collect::<Vec<_>>())
+            }
+            Rule::Or(rules, _) => {
+                write!(f, "OR,")?;
+                fmt_sub_rules(f, &rules.iter().collect::<Vec<_>>())
+            }
+            Rule::Not(rule, _) => {
+                write!(f, "NOT,")?;
+                fmt_sub_rules(f, &[rule.as_ref()])
+            }
+            Rule::Match(_) => write!(f, "MATCH"),
+        }
+    }
+
+    fn has_process_rule(&self) -> bool {
+        match self {
+            Rule::ProcessName(..) => true,
+            Rule::And(rules, _) | Rule::Or(rules, _) => rules.iter().any(Rule::has_process_rule),
+            Rule::Not(rule, _) => rule.has_process_rule(),
+            _ => false,
+        }
+    }
+
+    fn collect_rule_sets<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match self {
+            Rule::RuleSet(name, _) => names.push(name),
+            Rule::And(rules, _) | Rule::Or(rules, _) => {
+                rules.iter().for_each(|rule| rule.collect_rule_sets(names))
+            }
+            Rule::Not(rule, _) => rule.collect_rule_sets(names),
+            _ => {}
+        }
+    }
+
+    /// Whether the rule can match connections to IPs, which are matched without `MATCH`.
+    fn matches_ips(&self) -> bool {
+        match self {
+            Rule::IpCidr(..)
+            | Rule::IpCidr6(..)
+            | Rule::DstPort(..)
+            | Rule::SrcPort(..)
+            | Rule::RuleSet(..)
+            | Rule::Not(..) => true,
+            Rule::And(rules, _) => rules.iter().all(Rule::matches_ips),
+            Rule::Or(rules, _) => rules.iter().any(Rule::matches_ips),
+            _ => false,
+        }
+    }
+
+    /// Whether every connection matched by `later` is already matched by this rule, which
+    /// makes `later` unreachable when it comes after this rule. Connections fall through rules
+    /// whose script returns no action, so they shadow nothing.
+    fn shadows(&self, later: &Rule) -> bool {
+        if *self.action_ref() == Action::Script {
+            return false;
+        }
+        match (self, later) {
+            (Rule::Match(_), later) => !later.matches_ips(),
+            (Rule::Domain(a, _), Rule::Domain(b, _)) => a == b,
+            (Rule::DomainSuffix(a, _), Rule::Domain(b, _))
+            | (Rule::DomainSuffix(a, _), Rule::DomainSuffix(b, _)) => b.ends_with(a.as_str()),
+            (Rule::DomainKeyword(a, _), Rule::Domain(b, _))
+            | (Rule::DomainKeyword(a, _), Rule::DomainSuffix(b, _))
+            | (Rule::DomainKeyword(a, _), Rule::DomainKeyword(b, _)) => b.contains(a.as_str()),
+            (Rule::DomainRegex(a, _), Rule::DomainRegex(b, _)) => a.as_str() == b.as_str(),
+            (Rule::IpCidr(a, _), Rule::IpCidr(b, _)) => {
+                a.prefix_len() <= b.prefix_len() && a.contains_addr(&b.address())
+            }
+            (Rule::IpCidr6(a, _), Rule::IpCidr6(b, _)) => {
+                a.prefix_len() <= b.prefix_len() && a.contains_addr(&b.address())
+            }
+            (Rule::DstPort(a, _), Rule::DstPort(b, _))
+            | (Rule::SrcPort(a, _), Rule::SrcPort(b, _)) => {
+                a.start() <= b.start() && b.end() <= a.end()
+            }
+            (Rule::ProcessName(a, _), Rule::ProcessName(b, _))
+            | (Rule::RuleSet(a, _), Rule::RuleSet(b, _)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, Hash)]
 pub enum Action {
     Reject,
     Direct,
     Proxy,
     Probe,
+    /// Let the rule script decide the action.
+    Script,
+    /// Proxy through the server group with this name, eg. `STREAMING` in
+    /// `DOMAIN-SUFFIX,netflix.com,STREAMING`.
+    Group(String),
+}
+
+/// Rules loaded from a rule provider. Only the criteria of the rules are used, the action
+/// comes from the `RULE-SET` rule referencing the set.
+#[derive(Debug, Clone, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    pub fn from_payload(behavior: RuleSetBehavior, payload: &[String]) -> Self {
+        let rules = payload
+            .iter()
+            .map(|item| item.trim())
+            .filter(|item| !item.is_empty() && !item.starts_with('#'))
+            .filter_map(|item| match behavior {
+                RuleSetBehavior::Domain => Some(parse_domain_item(item)),
+                RuleSetBehavior::IpCidr if item.contains(':') => {
+                    parse_cidr6(item).map(|cidr| Rule::IpCidr6(cidr, Action::Direct))
+                }
+                RuleSetBehavior::IpCidr => {
+                    parse_cidr(item).map(|cidr| Rule::IpCidr(cidr, Action::Direct))
+                }
+                RuleSetBehavior::Classical => {
+                    let item = item.trim_end_matches(",no-resolve");
+                    Rule::from_str(&format!("{},DIRECT", item)).ok()
+                }
+            })
+            .collect();
+        RuleSet { rules }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn matches(&self, meta: &ConnectionMeta) -> bool {
+        self.rules.iter().any(|rule| rule.matches(meta))
+    }
+
+    fn has_process_rules(&self) -> bool {
+        self.rules.iter().any(Rule::has_process_rule)
+    }
+}
+
+/// Parse an item of a `domain` rule set: `+.example.com` matches the domain and all its
+/// subdomains, `.example.com` and `*.example.com` match subdomains only.
+fn parse_domain_item(item: &str) -> Rule {
+    if item.starts_with("+.") {
+        Rule::DomainSuffix(item[2..].to_string(), Action::Direct)
+    } else if item.starts_with('.') {
+        Rule::DomainSuffix(item.to_string(), Action::Direct)
+    } else if item.starts_with("*.") {
+        let re = format!(r"^[^.]+\.{}$", regex::escape(&item[2..]));
+        Rule::DomainRegex(Regex::new(&re).expect("escaped regex"), Action::Direct)
+    } else {
+        Rule::Domain(item.to_string(), Action::Direct)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RuleList {
+    rules: Vec<Rule>,
+    script: Option<Arc<RuleScript>>,
+    groups: HashSet<String>,
 }
 
 #[derive(Debug, Clone)]
 pub struct ProxyRules {
-    rules: Arc<Vec<Rule>>,
+    /// Swapped as a whole by `replace`, matching uses a snapshot.
+    list: Arc<RwLock<Arc<RuleList>>>,
+    rule_sets: Arc<RwLock<HashMap<String, Arc<RuleSet>>>>,
 }
 
 impl ProxyRules {
     pub fn new(rules: Vec<Rule>) -> Self {
         Self {
-            rules: Arc::new(rules),
+            list: Arc::new(RwLock::new(Arc::new(RuleList {
+                rules,
+                script: None,
+                groups: HashSet::new(),
+            }))),
+            rule_sets: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
-    pub fn action_for_domain(&self, domain: &str) -> Option<Action> {
-        self.rules
+    fn list(&self) -> Arc<RuleList> {
+        self.list.read().clone()
+    }
+
+    /// The rules in the matching order.
+    pub fn rules(&self) -> Vec<Rule> {
+        self.list().rules.clone()
+    }
+
+    /// Replace the rules, script and groups with the ones of `other`, eg. after the config is
+    /// reloaded. All clones of this `ProxyRules` see the new rules, rule sets are kept.
+    pub fn replace(&self, other: &ProxyRules) {
+        *self.list.write() = other.list();
+    }
+
+    /// Set the names of the defined server groups. The script can only return these groups.
+    pub fn set_groups(&mut self, groups: impl IntoIterator<Item = String>) {
+        Arc::make_mut(&mut self.list.write()).groups = groups.into_iter().collect();
+    }
+
+    /// Groups used by rules but not defined.
+    pub fn unknown_groups(&self) -> Vec<String> {
+        let list = self.list();
+        list.rules
             .iter()
-            .filter_map(|rule| match rule {
-                Rule::Domain(d, action) if d == domain => Some(*action),
-                Rule::DomainSuffix(d, action) if domain.ends_with(d) => Some(*action),
-                Rule::DomainKeyword(d, action) if domain.contains(d) => Some(*action),
-                Rule::Match(action) => Some(*action),
+            .filter_map(|rule| match rule.action_ref() {
+                Action::Group(name) if !list.groups.contains(name) => Some(name.clone()),
                 _ => None,
             })
-            .take(1)
-            .next()
+            .collect()
     }
 
-    #[allow(dead_code)]
-    pub fn action_for_ip(&self, ip: Ipv4Addr) -> Option<Action> {
-        self.rules
+    /// Rule sets used by rules, including the ones in logical rules, which `provided` doesn't
+    /// accept.
+    pub fn unknown_rule_sets(&self, provided: impl Fn(&str) -> bool) -> Vec<String> {
+        let list = self.list();
+        let mut names = vec![];
+        list.rules
             .iter()
-            .filter_map(|rule| match rule {
-                Rule::IpCidr(cidr, action) if cidr.contains_addr(&ip.into()) => Some(*action),
-                _ => None,
+            .for_each(|rule| rule.collect_rule_sets(&mut names));
+        names
+            .into_iter()
+            .filter(|name| !provided(name))
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    pub fn set_script(&mut self, script: RuleScript) {
+        Arc::make_mut(&mut self.list.write()).script = Some(Arc::new(script));
+    }
+
+    pub fn has_script_rules(&self) -> bool {
+        self.list()
+            .rules
+            .iter()
+            .any(|rule| *rule.action_ref() == Action::Script)
+    }
+
+    /// Replace the rule set named `name`. All clones of this `ProxyRules` see the new set.
+    pub fn update_rule_set(&self, name: &str, rule_set: RuleSet) {
+        self.rule_sets
+            .write()
+            .insert(name.to_string(), Arc::new(rule_set));
+    }
+
+    fn rule_set(&self, name: &str) -> Option<Arc<RuleSet>> {
+        self.rule_sets.read().get(name).cloned()
+    }
+
+    fn rule_matches(&self, rule: &Rule, meta: &ConnectionMeta) -> bool {
+        match rule {
+            Rule::RuleSet(name, _) => self
+                .rule_set(name)
+                .map_or(false, |rule_set| rule_set.matches(meta)),
+            Rule::Match(_) => true,
+            Rule::And(rules, _) => rules.iter().all(|rule| self.rule_matches(rule, meta)),
+            Rule::Or(rules, _) => rules.iter().any(|rule| self.rule_matches(rule, meta)),
+            Rule::Not(rule, _) => !self.rule_matches(rule, meta),
+            rule => rule.matches(meta),
+        }
+    }
+
+    /// Whether matching needs the process name of connections, which is costly to look up.
+    pub fn has_process_rules(&self) -> bool {
+        self.list().rules.iter().any(Rule::has_process_rule)
+            || self
+                .rule_sets
+                .read()
+                .values()
+                .any(|rule_set| rule_set.has_process_rules())
+    }
+
+    /// Rules are evaluated in order and the first matched rule wins. A rule with the `SCRIPT`
+    /// action is skipped if the script doesn't return an action.
+    pub fn action_for_meta(&self, meta: &ConnectionMeta) -> Option<Action> {
+        self.rule_for_meta(meta).map(|(_, action)| action)
+    }
+
+    /// The first matched rule with its action, which is decided by the script for `SCRIPT`
+    /// rules.
+    pub fn rule_for_meta(&self, meta: &ConnectionMeta) -> Option<(Rule, Action)> {
+        let list = self.list();
+        self.first_rule(&list, list.rules.iter(), meta)
+    }
+
+    fn first_rule<'a>(
+        &self,
+        list: &RuleList,
+        mut rules: impl Iterator<Item = &'a Rule>,
+        meta: &ConnectionMeta,
+    ) -> Option<(Rule, Action)> {
+        rules.find_map(|rule| {
+            if !self.rule_matches(rule, meta) {
+                return None;
+            }
+            let action = match rule.action() {
+                Action::Script => match list.script.as_ref()?.action_for_meta(meta)? {
+                    Action::Group(name) if !list.groups.contains(&name) => return None,
+                    action => action,
+                },
+                action => action,
+            };
+            Some((rule.clone(), action))
+        })
+    }
+
+    pub fn action_for_domain(&self, domain: &str) -> Option<Action> {
+        self.action_for_meta(&ConnectionMeta {
+            domain: Some(domain.to_string()),
+            ..Default::default()
+        })
+    }
+
+    /// Used for connections to an IP instead of a domain. Only IP and port rules can match,
+    /// `MATCH` is ignored.
+    pub fn action_for_ip(&self, ip: IpAddr, src_port: u16, dst_port: u16) -> Option<Action> {
+        self.rule_for_ip(ip, src_port, dst_port)
+            .map(|(_, action)| action)
+    }
+
+    /// Same as `action_for_ip` with the matched rule.
+    pub fn rule_for_ip(&self, ip: IpAddr, src_port: u16, dst_port: u16) -> Option<(Rule, Action)> {
+        let meta = ConnectionMeta {
+            ip: Some(ip),
+            src_port: Some(src_port),
+            dst_port: Some(dst_port),
+            ..Default::default()
+        };
+        let list = self.list();
+        let rules = list
+            .rules
+            .iter()
+            .filter(|rule| !matches!(rule, Rule::Match(_)));
+        self.first_rule(&list, rules, &meta)
+    }
+
+    /// Rules which can never match because a rule before them matches everything they match,
+    /// paired with the first rule shadowing them.
+    pub fn unreachable_rules(&self) -> Vec<(Rule, Rule)> {
+        let list = self.list();
+        list.rules
+            .iter()
+            .enumerate()
+            .filter_map(|(i, rule)| {
+                list.rules[..i]
+                    .iter()
+                    .find(|earlier| earlier.shadows(rule))
+                    .map(|earlier| (rule.clone(), earlier.clone()))
             })
-            .take(1)
-            .next()
+            .collect()
     }
 
+    /// Action for connections to domains matching no rule, which is the action of the `MATCH`
+    /// or `FINAL` rule. `DIRECT` if there is no such rule, or if its `SCRIPT` action doesn't
+    /// return an action.
     pub fn default_action(&self) -> Action {
-        Action::Direct
+        self.list()
+            .rules
+            .iter()
+            .find_map(|rule| match rule {
+                Rule::Match(action) if *action != Action::Script => Some(action.clone()),
+                _ => None,
+            })
+            .unwrap_or(Action::Direct)
     }
 }
 
@@ -75,42 +494,409 @@ impl FromStr for Action {
             "DIRECT" => Action::Direct,
             "PROXY" => Action::Proxy,
             "PROBE" => Action::Probe,
-            _ => unreachable!(),
+            "SCRIPT" => Action::Script,
+            "" => return Err(()),
+            group => Action::Group(group.to_string()),
         })
     }
 }
 
+impl fmt::Display for Rule {
+    /// Same as the rule in the config, eg. `DOMAIN-SUFFIX,google.com,PROXY`.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.fmt_criteria(f)?;
+        match self.action_ref() {
+            Action::Group(name) => write!(f, ",{}", name),
+            action => write!(f, ",{}", action.to_string().to_uppercase()),
+        }
+    }
+}
+
 impl fmt::Display for Action {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Action::Group(name) => write!(f, "{}", name),
+            action => write!(f, "{:?}", action),
+        }
+    }
+}
+
+fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, ()> {
+    let mut segments = s.splitn(2, '-');
+    let start = segments
+        .next()
+        .and_then(|p| p.trim().parse().ok())
+        .ok_or(())?;
+    let end = match segments.next() {
+        Some(p) => p.trim().parse().map_err(|_| ())?,
+        None => start,
+    };
+    if start > end {
+        return Err(());
+    }
+    Ok(start..=end)
+}
+
+fn fmt_ports(f: &mut Formatter<'_>, ports: &RangeInclusive<u16>) -> fmt::Result {
+    if ports.start() == ports.end() {
+        write!(f, "{}", ports.start())
+    } else {
+        write!(f, "{}-{}", ports.start(), ports.end())
+    }
+}
+
+/// Write sub rules of a logical rule, eg. `((DST-PORT,443),(DOMAIN,google.com))`.
+fn fmt_sub_rules(f: &mut Formatter<'_>, rules: &[&Rule]) -> fmt::Result {
+    write!(f, "(")?;
+    for (i, rule) in rules.iter().enumerate() {
+        if i > 0 {
+            write!(f, ",")?;
+        }
+        write!(f, "(")?;
+        rule.fmt_criteria(f)?;
+        write!(f, ")")?;
+    }
+    write!(f, ")")
+}
+
+/// Parse sub rules of a logical rule, eg. `((DST-PORT,443),(NOT,((DOMAIN,google.com))))`.
+fn parse_sub_rules(s: &str) -> Result<Vec<Rule>, ()> {
+    let s = s.trim();
+    if !s.starts_with('(') || !s.ends_with(')') {
+        return Err(());
+    }
+    let inner = &s[1..s.len() - 1];
+    let mut rules = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' => {
+                if depth == 0 {
+                    start = i + 1;
+                }
+                depth += 1;
+            }
+            ')' if depth == 0 => return Err(()),
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    // Sub rules have no action, add a placeholder to reuse the parser.
+                    let rule = format!("{},DIRECT", &inner[start..i]);
+                    rules.push(Rule::from_str(&rule)?);
+                }
+            }
+            c if depth == 0 && c != ',' && !c.is_whitespace() => return Err(()),
+            _ => {}
+        }
     }
+    if depth != 0 || rules.is_empty() {
+        return Err(());
+    }
+    Ok(rules)
 }
 
 impl FromStr for Rule {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let segments = s.splitn(3, ',').collect::<Vec<_>>();
-        let (rule, criteria, action) = match segments.len() {
-            2 => (segments[0], "", segments[1]),
-            3 => (segments[0], segments[1], segments[2]),
-            _ => unreachable!(),
+        // The action is always the last segment, so criteria such as regexes may contain commas.
+        let (rule, criteria, action) = match (s.find(','), s.rfind(',')) {
+            (Some(first), Some(last)) if first == last => (&s[..first], "", &s[last + 1..]),
+            (Some(first), Some(last)) => (&s[..first], &s[first + 1..last], &s[last + 1..]),
+            _ => return Err(()),
         };
 
         Ok(match rule {
-            "DOMAIN" => Rule::Domain(criteria.to_string(), Action::from_str(action).unwrap()),
-            "DOMAIN-SUFFIX" => {
-                Rule::DomainSuffix(criteria.to_string(), Action::from_str(action).unwrap())
-            }
+            "DOMAIN" => Rule::Domain(criteria.to_string(), Action::from_str(action)?),
+            "DOMAIN-SUFFIX" => Rule::DomainSuffix(criteria.to_string(), Action::from_str(action)?),
             "DOMAIN-KEYWORD" => {
-                Rule::DomainKeyword(criteria.to_string(), Action::from_str(action).unwrap())
+                Rule::DomainKeyword(criteria.to_string(), Action::from_str(action)?)
             }
-            "IP-CIDR" => Rule::IpCidr(
-                parse_cidr(criteria.to_string()),
-                Action::from_str(action).unwrap(),
+            "DOMAIN-REGEX" => Rule::DomainRegex(
+                Regex::new(criteria).map_err(|_| ())?,
+                Action::from_str(action)?,
             ),
-            "MATCH" => Rule::Match(Action::from_str(action).unwrap()),
-            _ => unreachable!(),
+            "IP-CIDR6" => {
+                Rule::IpCidr6(parse_cidr6(criteria).ok_or(())?, Action::from_str(action)?)
+            }
+            "IP-CIDR" => Rule::IpCidr(parse_cidr(criteria).ok_or(())?, Action::from_str(action)?),
+            "DST-PORT" => Rule::DstPort(parse_port_range(criteria)?, Action::from_str(action)?),
+            "SRC-PORT" => Rule::SrcPort(parse_port_range(criteria)?, Action::from_str(action)?),
+            "PROCESS-NAME" => Rule::ProcessName(criteria.to_string(), Action::from_str(action)?),
+            "RULE-SET" => Rule::RuleSet(criteria.to_string(), Action::from_str(action)?),
+            "AND" => Rule::And(parse_sub_rules(criteria)?, Action::from_str(action)?),
+            "OR" => Rule::Or(parse_sub_rules(criteria)?, Action::from_str(action)?),
+            "NOT" => {
+                let mut rules = parse_sub_rules(criteria)?;
+                if rules.len() != 1 {
+                    return Err(());
+                }
+                Rule::Not(Box::new(rules.remove(0)), Action::from_str(action)?)
+            }
+            "MATCH" | "FINAL" => Rule::Match(Action::from_str(action)?),
+            _ => return Err(()),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_regex() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str(r"DOMAIN-REGEX,^ads?\d*\.,REJECT").unwrap(),
+            Rule::from_str(r"DOMAIN-REGEX,^[a-z]{1,2}\.cdn\.,PROXY").unwrap(),
+            Rule::from_str("MATCH,DIRECT").unwrap(),
+        ]);
+        assert_eq!(
+            rules.action_for_domain("ad.google.com"),
+            Some(Action::Reject)
+        );
+        assert_eq!(
+            rules.action_for_domain("ads12.google.com"),
+            Some(Action::Reject)
+        );
+        assert_eq!(
+            rules.action_for_domain("bad.google.com"),
+            Some(Action::Direct)
+        );
+        assert_eq!(rules.action_for_domain("xy.cdn.net"), Some(Action::Proxy));
+        assert!(Rule::from_str("DOMAIN-REGEX,(ads,REJECT").is_err());
+    }
+
+    #[test]
+    fn test_rule_set() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("RULE-SET,ads,REJECT").unwrap(),
+            Rule::from_str("RULE-SET,lan,DIRECT").unwrap(),
+            Rule::from_str("MATCH,PROXY").unwrap(),
+        ]);
+        assert_eq!(rules.action_for_domain("ad.com"), Some(Action::Proxy));
+        assert_eq!(rules.unknown_rule_sets(|name| name == "ads"), vec!["lan"]);
+
+        let payload = ["+.ad.com", ".tracker.net", "*.cdn.org", "ads.example.com"];
+        let payload: Vec<String> = payload.iter().map(|s| s.to_string()).collect();
+        rules.update_rule_set(
+            "ads",
+            RuleSet::from_payload(RuleSetBehavior::Domain, &payload),
+        );
+        let cloned = rules.clone();
+        assert_eq!(cloned.action_for_domain("ad.com"), Some(Action::Reject));
+        assert_eq!(cloned.action_for_domain("x.ad.com"), Some(Action::Reject));
+        assert_eq!(cloned.action_for_domain("tracker.net"), Some(Action::Proxy));
+        assert_eq!(
+            cloned.action_for_domain("a.tracker.net"),
+            Some(Action::Reject)
+        );
+        assert_eq!(cloned.action_for_domain("a.cdn.org"), Some(Action::Reject));
+        assert_eq!(cloned.action_for_domain("a.b.cdn.org"), Some(Action::Proxy));
+        assert_eq!(
+            cloned.action_for_domain("ads.example.com"),
+            Some(Action::Reject)
+        );
+
+        let payload = vec!["192.168.0.0/16".to_string()];
+        rules.update_rule_set(
+            "lan",
+            RuleSet::from_payload(RuleSetBehavior::IpCidr, &payload),
+        );
+        assert_eq!(
+            cloned.action_for_ip("192.168.1.1".parse().unwrap(), 1000, 80),
+            Some(Action::Direct)
+        );
+        assert_eq!(
+            cloned.action_for_ip("10.0.0.1".parse().unwrap(), 1000, 80),
+            None
+        );
+    }
+
+    #[test]
+    fn test_process_name() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-SUFFIX,google.com,PROXY").unwrap(),
+            Rule::from_str("PROCESS-NAME,ssh,DIRECT").unwrap(),
+            Rule::from_str("MATCH,PROXY").unwrap(),
+        ]);
+        assert!(rules.has_process_rules());
+        let meta = |domain: &str, process: Option<&str>| ConnectionMeta {
+            domain: Some(domain.to_string()),
+            process_name: process.map(|p| p.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            rules.action_for_meta(&meta("github.com", Some("ssh"))),
+            Some(Action::Direct)
+        );
+        assert_eq!(
+            rules.action_for_meta(&meta("google.com", Some("ssh"))),
+            Some(Action::Proxy)
+        );
+        assert_eq!(
+            rules.action_for_meta(&meta("github.com", Some("curl"))),
+            Some(Action::Proxy)
+        );
+        assert_eq!(
+            rules.action_for_meta(&meta("github.com", None)),
+            Some(Action::Proxy)
+        );
+    }
+
+    #[test]
+    fn test_port() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DST-PORT,25,REJECT").unwrap(),
+            Rule::from_str("DST-PORT,6881-6889,DIRECT").unwrap(),
+            Rule::from_str("SRC-PORT,10000,DIRECT").unwrap(),
+            Rule::from_str("MATCH,PROXY").unwrap(),
+        ]);
+        let meta = |src_port: u16, dst_port: u16| ConnectionMeta {
+            domain: Some("example.com".to_string()),
+            src_port: Some(src_port),
+            dst_port: Some(dst_port),
+            ..Default::default()
+        };
+        assert_eq!(rules.action_for_meta(&meta(5000, 25)), Some(Action::Reject));
+        assert_eq!(
+            rules.action_for_meta(&meta(5000, 6885)),
+            Some(Action::Direct)
+        );
+        assert_eq!(
+            rules.action_for_meta(&meta(10000, 443)),
+            Some(Action::Direct)
+        );
+        assert_eq!(rules.action_for_meta(&meta(5000, 443)), Some(Action::Proxy));
+        let ip = "1.1.1.1".parse().unwrap();
+        assert_eq!(rules.action_for_ip(ip, 5000, 25), Some(Action::Reject));
+        assert_eq!(rules.action_for_ip(ip, 5000, 443), None);
+        assert!(Rule::from_str("DST-PORT,abc,DIRECT").is_err());
+        assert!(Rule::from_str("DST-PORT,100-10,DIRECT").is_err());
+    }
+
+    #[test]
+    fn test_ip_cidr6() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("IP-CIDR,10.0.0.0/8,DIRECT").unwrap(),
+            Rule::from_str("IP-CIDR6,2001:db8::/32,REJECT").unwrap(),
+            Rule::from_str("RULE-SET,lan,DIRECT").unwrap(),
+        ]);
+        let payload = vec!["fe80::/10".to_string()];
+        rules.update_rule_set(
+            "lan",
+            RuleSet::from_payload(RuleSetBehavior::IpCidr, &payload),
+        );
+        let action = |ip: &str| rules.action_for_ip(ip.parse().unwrap(), 1000, 443);
+        assert_eq!(action("10.1.1.1"), Some(Action::Direct));
+        assert_eq!(action("2001:db8::1"), Some(Action::Reject));
+        assert_eq!(action("2001:db9::1"), None);
+        assert_eq!(action("fe80::1"), Some(Action::Direct));
+        assert_eq!(action("::ffff:10.1.1.1"), None);
+        assert!(Rule::from_str("IP-CIDR6,2001:db8::/129,REJECT").is_err());
+    }
+
+    #[test]
+    fn test_unreachable_rules() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-SUFFIX,google.com,PROXY").unwrap(),
+            Rule::from_str("DOMAIN,www.google.com,DIRECT").unwrap(),
+            Rule::from_str("DOMAIN-KEYWORD,youtube,PROXY").unwrap(),
+            Rule::from_str("DOMAIN-SUFFIX,youtube.com,DIRECT").unwrap(),
+            Rule::from_str("DOMAIN-SUFFIX,github.com,DIRECT").unwrap(),
+            Rule::from_str("IP-CIDR,10.0.0.0/8,DIRECT").unwrap(),
+            Rule::from_str("IP-CIDR,10.1.0.0/16,PROXY").unwrap(),
+            Rule::from_str("DST-PORT,6881-6889,DIRECT").unwrap(),
+            Rule::from_str("DST-PORT,6882,REJECT").unwrap(),
+            Rule::from_str("FINAL,PROXY").unwrap(),
+            Rule::from_str("DOMAIN,example.com,DIRECT").unwrap(),
+            Rule::from_str("IP-CIDR,192.168.0.0/16,DIRECT").unwrap(),
+            Rule::from_str("PROCESS-NAME,curl,DIRECT").unwrap(),
+        ]);
+        let unreachable = rules.unreachable_rules();
+        assert_eq!(unreachable.len(), 6);
+        assert!(matches!(&unreachable[0].0, Rule::Domain(d, _) if d == "www.google.com"));
+        assert!(matches!(&unreachable[1].0, Rule::DomainSuffix(d, _) if d == "youtube.com"));
+        assert!(matches!(unreachable[2].0, Rule::IpCidr(_, Action::Proxy)));
+        assert!(matches!(unreachable[3].0, Rule::DstPort(_, Action::Reject)));
+        assert!(matches!(unreachable[4], (Rule::Domain(..), Rule::Match(_))));
+        assert!(matches!(
+            unreachable[5],
+            (Rule::ProcessName(..), Rule::Match(_))
+        ));
+        assert_eq!(rules.action_for_domain("example.com"), Some(Action::Proxy));
+        assert_eq!(rules.default_action(), Action::Proxy);
+        assert_eq!(
+            rules.action_for_ip("192.168.1.1".parse().unwrap(), 1000, 80),
+            Some(Action::Direct)
+        );
+        assert_eq!(ProxyRules::new(vec![]).default_action(), Action::Direct);
+
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-SUFFIX,google.com,SCRIPT").unwrap(),
+            Rule::from_str("DOMAIN,www.google.com,DIRECT").unwrap(),
+            Rule::from_str("MATCH,SCRIPT").unwrap(),
+            Rule::from_str("DOMAIN,example.com,PROXY").unwrap(),
+        ]);
+        assert!(rules.unreachable_rules().is_empty());
+    }
+
+    #[test]
+    fn test_logical_rules() {
+        let rules = ProxyRules::new(vec![
+            Rule::from_str("AND,((DST-PORT,443),(DOMAIN-SUFFIX,google.com)),PROXY").unwrap(),
+            Rule::from_str("OR,((DOMAIN,a.com),(DOMAIN-KEYWORD,ads)),REJECT").unwrap(),
+            Rule::from_str("AND,((DST-PORT,80),(NOT,((DOMAIN-SUFFIX,cn)))),PROXY").unwrap(),
+            Rule::from_str("MATCH,DIRECT").unwrap(),
+        ]);
+        let meta = |domain: &str, dst_port: u16| ConnectionMeta {
+            domain: Some(domain.to_string()),
+            dst_port: Some(dst_port),
+            ..Default::default()
+        };
+        let action = |domain, port| rules.action_for_meta(&meta(domain, port));
+        assert_eq!(action("www.google.com", 443), Some(Action::Proxy));
+        assert_eq!(action("www.google.com", 8443), Some(Action::Direct));
+        assert_eq!(action("a.com", 443), Some(Action::Reject));
+        assert_eq!(action("ads.b.com", 443), Some(Action::Reject));
+        assert_eq!(action("example.com", 80), Some(Action::Proxy));
+        assert_eq!(action("baidu.cn", 80), Some(Action::Direct));
+
+        assert!(Rule::from_str("AND,(DST-PORT,443),PROXY").is_err());
+        assert!(Rule::from_str("AND,((DST-PORT,443),PROXY").is_err());
+        assert!(Rule::from_str("NOT,((DST-PORT,443),(DST-PORT,80)),PROXY").is_err());
+        assert!(Rule::from_str("OR,((GEOIP,US)),PROXY").is_err());
+    }
+
+    #[test]
+    fn test_display_rule() {
+        for rule in &[
+            "DOMAIN-SUFFIX,google.com,PROXY",
+            r"DOMAIN-REGEX,^ads?\d*\.,REJECT",
+            "IP-CIDR,192.168.0.0/16,DIRECT",
+            "DST-PORT,6881-6889,DIRECT",
+            "SRC-PORT,25,REJECT",
+            "RULE-SET,reject,REJECT",
+            "AND,((DST-PORT,80),(NOT,((DOMAIN-SUFFIX,cn)))),STREAMING",
+            "MATCH,PROBE",
+        ] {
+            assert_eq!(Rule::from_str(rule).unwrap().to_string(), *rule);
+        }
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut rules = ProxyRules::new(vec![
+            Rule::from_str("DOMAIN-SUFFIX,netflix.com,STREAMING").unwrap(),
+            Rule::from_str("DOMAIN-SUFFIX,hbo.com,VIDEO").unwrap(),
+            Rule::from_str("MATCH,PROXY").unwrap(),
+        ]);
+        rules.set_groups(vec!["STREAMING".to_string()]);
+        assert_eq!(rules.unknown_groups(), vec!["VIDEO"]);
+        let action = rules.action_for_domain("www.netflix.com").unwrap();
+        assert_eq!(action, Action::Group("STREAMING".to_string()));
+        assert_eq!(action.to_string(), "STREAMING");
+        assert_eq!(Action::Proxy.to_string(), "Proxy");
+        assert!(Rule::from_str("DOMAIN,example.com,").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use crate::rule::RuleSet;
+use serde::Deserialize;
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// How the payload of a rule provider is interpreted. Same as clash.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSetBehavior {
+    /// `example.com`, `+.example.com`, `.example.com` or `*.example.com`
+    Domain,
+    /// `192.168.0.0/16`
+    #[serde(rename = "ipcidr")]
+    IpCidr,
+    /// Rules without action, eg. `DOMAIN-SUFFIX,example.com`
+    Classical,
+}
+
+/// A remote rule-set referenced by `RULE-SET,<name>,<ACTION>` rules.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RuleProviderConfig {
+    pub behavior: RuleSetBehavior,
+    pub url: String,
+    /// Where the downloaded rule-set is cached. Defaults to `rule_providers/<name>.yaml`.
+    pub path: Option<PathBuf>,
+    #[serde(with = "crate::duration", default = "default_interval")]
+    pub interval: Duration,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(24 * 3600)
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    payload: Vec<String>,
+}
+
+impl RuleProviderConfig {
+    pub fn cache_path(&self, name: &str) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("rule_providers").join(format!("{}.yaml", name)))
+    }
+
+    /// Parse a clash rule-provider file, which is a yaml document with a `payload` list.
+    pub fn load_rule_set<R: Read>(&self, reader: R) -> io::Result<RuleSet> {
+        let payload: Payload = serde_yaml::from_reader(reader)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(RuleSet::from_payload(self.behavior, &payload.payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_rule_set() {
+        let provider: RuleProviderConfig = serde_yaml::from_str(
+            "behavior: classical\nurl: https://example.com/rules.yaml\ninterval: 3600s",
+        )
+        .unwrap();
+        assert_eq!(provider.interval, Duration::from_secs(3600));
+        assert_eq!(
+            provider.cache_path("test"),
+            PathBuf::from("rule_providers/test.yaml")
+        );
+
+        let content = "payload:\n  - DOMAIN-SUFFIX,google.com\n  - IP-CIDR,10.0.0.0/8,no-resolve\n  - UNKNOWN,foo\n";
+        let rule_set = provider.load_rule_set(content.as_bytes()).unwrap();
+        assert_eq!(rule_set.len(), 2);
+        assert!(provider.load_rule_set("rules: []".as_bytes()).is_err());
+    }
+}
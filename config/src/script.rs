@@ -0,0 +1,121 @@
+//! Rule script used by the `SCRIPT` action.
+//!
+//! The script is evaluated with these variables in scope: `domain`, `ip`, `process_name`
+//! (empty string if unknown), `src_port` and `dst_port` (0 if unknown). It should evaluate to
+//! one of `"PROXY"`, `"DIRECT"`, `"REJECT"` or `"PROBE"`.
+//!
+//! ```rhai
+//! if dst_port == 22 || process_name == "ssh" { "DIRECT" } else { "PROXY" }
+//! ```
+use crate::rule::{Action, ConnectionMeta};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+#[cfg(feature = "script")]
+pub struct RuleScript {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+}
+
+#[cfg(feature = "script")]
+impl RuleScript {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let engine = rhai::Engine::new();
+        let ast = engine
+            .compile_file(path.as_ref().to_path_buf())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(RuleScript { engine, ast })
+    }
+
+    /// Returns `None` if the script fails or evaluates to `SCRIPT`. Strings other than the
+    /// builtin actions are server group names.
+    pub fn action_for_meta(&self, meta: &ConnectionMeta) -> Option<Action> {
+        let mut scope = rhai::Scope::new();
+        scope.push("domain", meta.domain.clone().unwrap_or_default());
+        scope.push("ip", meta.ip.map(|ip| ip.to_string()).unwrap_or_default());
+        scope.push(
+            "process_name",
+            meta.process_name.clone().unwrap_or_default(),
+        );
+        scope.push("src_port", meta.src_port.unwrap_or_default() as i64);
+        scope.push("dst_port", meta.dst_port.unwrap_or_default() as i64);
+        let result = self
+            .engine
+            .eval_ast_with_scope::<rhai::Dynamic>(&mut scope, &self.ast)
+            .ok()?;
+        match result.take_string().ok()?.parse() {
+            Ok(Action::Script) | Err(_) => None,
+            Ok(action) => Some(action),
+        }
+    }
+}
+
+#[cfg(not(feature = "script"))]
+pub struct RuleScript;
+
+#[cfg(not(feature = "script"))]
+impl RuleScript {
+    pub fn from_file<P: AsRef<Path>>(_path: P) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "seeker is built without the `script` feature",
+        ))
+    }
+
+    pub fn action_for_meta(&self, _meta: &ConnectionMeta) -> Option<Action> {
+        None
+    }
+}
+
+impl fmt::Debug for RuleScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RuleScript")
+    }
+}
+
+#[cfg(all(test, feature = "script"))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_rule_script() {
+        let path = std::env::temp_dir().join("seeker_test_rule_script.rhai");
+        let mut file = std::fs::File::create(&path).unwrap();
+        file.write_all(
+            br#"
+            if process_name == "ssh" { "DIRECT" }
+            else if domain == "baidu.cn" { "DIRECT" }
+            else if dst_port == 25 { "REJECT" }
+            else if dst_port == 0 { "SCRIPT" }
+            else { "PROXY" }
+            "#,
+        )
+        .unwrap();
+        let script = RuleScript::from_file(&path).unwrap();
+        let meta = |domain: &str, dst_port: u16, process_name: Option<&str>| ConnectionMeta {
+            domain: Some(domain.to_string()),
+            dst_port: Some(dst_port),
+            process_name: process_name.map(|p| p.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            script.action_for_meta(&meta("google.com", 443, Some("ssh"))),
+            Some(Action::Direct)
+        );
+        assert_eq!(
+            script.action_for_meta(&meta("baidu.cn", 443, None)),
+            Some(Action::Direct)
+        );
+        assert_eq!(
+            script.action_for_meta(&meta("google.com", 25, None)),
+            Some(Action::Reject)
+        );
+        assert_eq!(
+            script.action_for_meta(&meta("google.com", 443, None)),
+            Some(Action::Proxy)
+        );
+        assert_eq!(script.action_for_meta(&meta("google.com", 0, None)), None);
+    }
+}
@@ -0,0 +1,61 @@
+use std::env;
+
+/// Resolve `env:<VAR>` to the environment variable `VAR` and `keyring:<entry>` to the password
+/// of `entry` of the `seeker` service in the keyring of the OS. Other values are returned as is.
+pub fn resolve_secret(value: &str) -> Result<String, String> {
+    resolve(value, |name| env::var(name).ok(), keyring_password)
+}
+
+fn resolve(
+    value: &str,
+    lookup_env: impl Fn(&str) -> Option<String>,
+    lookup_keyring: impl Fn(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    if value.starts_with("env:") {
+        let name = &value["env:".len()..];
+        lookup_env(name).ok_or_else(|| format!("environment variable {} is not set", name))
+    } else if value.starts_with("keyring:") {
+        lookup_keyring(&value["keyring:".len()..])
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_password(entry: &str) -> Result<String, String> {
+    keyring::Keyring::new("seeker", entry)
+        .get_password()
+        .map_err(|e| format!("read keyring entry {} error: {}", entry, e))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_password(_entry: &str) -> Result<String, String> {
+    Err("seeker is built without the `keyring` feature".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_secret() {
+        let lookup_env = |name: &str| match name {
+            "SS_PASSWORD" => Some("secret".to_string()),
+            _ => None,
+        };
+        let lookup_keyring = |entry: &str| Ok(format!("{}-password", entry));
+        assert_eq!(
+            resolve("env:SS_PASSWORD", lookup_env, lookup_keyring),
+            Ok("secret".to_string())
+        );
+        assert!(resolve("env:NOT_SET", lookup_env, lookup_keyring).is_err());
+        assert_eq!(
+            resolve("keyring:hk", lookup_env, lookup_keyring),
+            Ok("hk-password".to_string())
+        );
+        assert_eq!(
+            resolve("password", lookup_env, lookup_keyring),
+            Ok("password".to_string())
+        );
+    }
+}
@@ -3,11 +3,14 @@ use std::{
     net::SocketAddr,
     str::FromStr,
     string::ToString,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    time::Duration,
 };
 
+use crate::secret::resolve_secret;
 use crate::Address;
-use bytes::Bytes;
-use crypto::CipherType;
+use crypto::{CipherCategory, CipherType, SecretKey};
 use serde::Deserialize;
 
 /// Server address
@@ -87,6 +90,74 @@ pub struct ProxyServerConfig {
     pub addr: Address,
 }
 
+/// Retries of connecting to a server. The n-th retry waits `base_delay * 2^(n-1)` plus a random
+/// delay up to `jitter`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Attempts including the first one, 1 disables retries.
+    pub max_attempts: usize,
+    #[serde(with = "crate::duration")]
+    pub base_delay: Duration,
+    #[serde(with = "crate::duration")]
+    pub jitter: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+        }
+    }
+}
+
+/// Cap on connections open to all servers together. Connects beyond it wait for a connection to
+/// close, up to `wait_timeout`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct ConnectionLimitConfig {
+    pub max_connections: usize,
+    #[serde(with = "crate::duration")]
+    pub wait_timeout: Duration,
+}
+
+impl Default for ConnectionLimitConfig {
+    fn default() -> Self {
+        ConnectionLimitConfig {
+            max_connections: 256,
+            wait_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Multiplexing of proxied tcp streams over a few long-lived connections to the server.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default)]
+pub struct MuxConfig {
+    /// Streams carried by one connection before another connection is opened.
+    pub max_streams: usize,
+    /// Interval of keepalive frames. A connection without frames from the server for 3
+    /// intervals is closed.
+    #[serde(with = "crate::duration")]
+    pub keepalive: Duration,
+    /// Age after which a connection takes no new streams, and is closed once its streams are
+    /// done, so it's replaced by a connection with a new salt. `0s` keeps connections forever.
+    #[serde(with = "crate::duration")]
+    pub max_lifetime: Duration,
+}
+
+impl Default for MuxConfig {
+    fn default() -> Self {
+        MuxConfig {
+            max_streams: 16,
+            keepalive: Duration::from_secs(30),
+            max_lifetime: Duration::from_secs(0),
+        }
+    }
+}
+
 /// Configuration for a server
 #[derive(Clone, Debug, Deserialize)]
 pub struct ShadowsocksServerConfig {
@@ -97,9 +168,44 @@ pub struct ShadowsocksServerConfig {
     addr: Address,
     /// Encryption password (key)
     password: String,
+    /// Passwords tried in turn after `password` while the server rejects the one in use, so the
+    /// server can rotate its password without all clients switching at once
+    #[serde(default)]
+    fallback_passwords: Vec<String>,
+    /// Index of the password in use among `password` and `fallback_passwords`, shared by clones
+    #[serde(skip)]
+    password_index: Arc<AtomicUsize>,
     /// Encryption type (method)
     #[serde(with = "cipher_type")]
     method: CipherType,
+    /// Retries of connecting to the server
+    #[serde(default)]
+    retry: RetryConfig,
+    /// Share of new connections in `load-balance` groups relative to the other servers
+    #[serde(default = "default_weight")]
+    weight: u32,
+    /// SIP003 plugin, eg. `obfs-local`
+    #[serde(default)]
+    plugin: Option<PluginConfig>,
+    /// Carry tcp streams over multiplexed connections
+    #[serde(default)]
+    mux: Option<MuxConfig>,
+    /// Send the first data of connections in the SYN with Tcp Fast Open, linux only
+    #[serde(default)]
+    fast_open: bool,
+}
+
+/// SIP003 plugin of a server
+#[derive(Clone, Debug, Deserialize)]
+pub struct PluginConfig {
+    /// Plugin executable, eg. `obfs-local`
+    pub name: String,
+    /// Plugin options, eg. `obfs=http;obfs-host=example.com`
+    pub opts: Option<String>,
+}
+
+fn default_weight() -> u32 {
+    1
 }
 
 mod cipher_type {
@@ -146,7 +252,14 @@ impl ShadowsocksServerConfig {
             name,
             addr,
             password: pwd,
+            fallback_passwords: vec![],
+            password_index: Arc::default(),
             method,
+            retry: RetryConfig::default(),
+            weight: default_weight(),
+            plugin: None,
+            mux: None,
+            fast_open: false,
         }
     }
 
@@ -185,18 +298,288 @@ impl ShadowsocksServerConfig {
         &self.addr
     }
 
-    /// Get encryption key
-    pub fn key(&self) -> Bytes {
-        self.method.bytes_to_key(self.password.as_bytes())
+    /// Get encryption key of the password in use
+    pub fn key(&self) -> SecretKey {
+        self.method.bytes_to_key(self.password().as_bytes())
     }
 
-    /// Get password
+    /// Get the password in use
     pub fn password(&self) -> &str {
-        &self.password[..]
+        match self.password_index.load(Ordering::Relaxed) {
+            0 => &self.password,
+            i => self.fallback_passwords.get(i - 1).unwrap_or(&self.password),
+        }
+    }
+
+    /// Switch to the next of the passwords, after the last back to `password`. Returns false if
+    /// there are no fallback passwords.
+    pub fn next_password(&self) -> bool {
+        if self.fallback_passwords.is_empty() {
+            return false;
+        }
+        let count = 1 + self.fallback_passwords.len();
+        let _ = self
+            .password_index
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |index| {
+                Some((index + 1) % count)
+            });
+        true
+    }
+
+    /// Replace `env:<VAR>` and `keyring:<entry>` references with the passwords
+    pub fn resolve_password(&mut self) -> Result<(), String> {
+        self.password = resolve_secret(&self.password)?;
+        for password in &mut self.fallback_passwords {
+            *password = resolve_secret(password)?;
+        }
+        Ok(())
     }
 
     /// Get method
     pub fn method(&self) -> CipherType {
         self.method
     }
+
+    /// Whether the method is a legacy stream cipher, which doesn't protect the integrity of data,
+    /// or `plain`, which doesn't encrypt at all
+    pub fn has_insecure_cipher(&self) -> bool {
+        self.method.category() == CipherCategory::Stream
+    }
+
+    /// Get retry config
+    pub fn retry(&self) -> RetryConfig {
+        self.retry
+    }
+
+    /// Get weight, at least 1
+    pub fn weight(&self) -> u32 {
+        self.weight.max(1)
+    }
+
+    /// Set weight
+    pub fn set_weight(&mut self, weight: u32) {
+        self.weight = weight;
+    }
+
+    /// Get plugin
+    pub fn plugin(&self) -> Option<&PluginConfig> {
+        self.plugin.as_ref()
+    }
+
+    /// Get mux config
+    pub fn mux(&self) -> Option<MuxConfig> {
+        self.mux
+    }
+
+    /// Whether to use Tcp Fast Open
+    pub fn fast_open(&self) -> bool {
+        self.fast_open
+    }
+}
+
+impl FromStr for ShadowsocksServerConfig {
+    type Err = String;
+
+    /// Parse a SIP002 url, eg. `ss://YWVzLTI1Ni1nY206cGFzcw@example.com:8388/?plugin=...#name`,
+    /// or a legacy url, eg. `ss://YWVzLTI1Ni1nY206cGFzc0BleGFtcGxlLmNvbTo4Mzg4#name`. The name
+    /// defaults to `host:port`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid ss url {}: {}", s, reason);
+        if !s.starts_with("ss://") {
+            return Err(invalid("expected ss://"));
+        }
+        let rest = &s["ss://".len()..];
+        let (rest, tag) = match rest.find('#') {
+            Some(pos) => (&rest[..pos], percent_decode(&rest[pos + 1..])),
+            None => (rest, String::new()),
+        };
+        let (rest, query) = match rest.find('?') {
+            Some(pos) => (&rest[..pos], &rest[pos + 1..]),
+            None => (rest, ""),
+        };
+        let rest = rest.trim_end_matches('/');
+
+        let (userinfo, host) = match rest.rfind('@') {
+            Some(at) => {
+                let userinfo = &rest[..at];
+                // AEAD ciphers may use a percent encoded userinfo instead of base64
+                let userinfo = if userinfo.contains(':') {
+                    percent_decode(userinfo)
+                } else {
+                    decode_base64(userinfo).ok_or_else(|| invalid("invalid base64 userinfo"))?
+                };
+                (userinfo, rest[at + 1..].to_string())
+            }
+            None => {
+                let decoded = decode_base64(rest).ok_or_else(|| invalid("invalid base64"))?;
+                let at = decoded.rfind('@').ok_or_else(|| invalid("missing host"))?;
+                (decoded[..at].to_string(), decoded[at + 1..].to_string())
+            }
+        };
+        let mut userinfo = userinfo.splitn(2, ':');
+        let method = userinfo.next().unwrap_or_default();
+        let password = userinfo.next().ok_or_else(|| invalid("missing password"))?;
+        if method.is_empty() {
+            return Err(invalid("missing method"));
+        }
+        let method = CipherType::from_str(method).map_err(|_| invalid("unknown method"))?;
+        let addr = Address::from_str(&host).map_err(|_| invalid("invalid host"))?;
+
+        // plugin=obfs-local;obfs=http;obfs-host=example.com
+        let plugin = query
+            .split('&')
+            .filter_map(|param| {
+                let mut param = param.splitn(2, '=');
+                match (param.next(), param.next()) {
+                    (Some("plugin"), Some(value)) => Some(percent_decode(value)),
+                    _ => None,
+                }
+            })
+            .find(|plugin| !plugin.is_empty())
+            .map(|plugin| {
+                let mut plugin = plugin.splitn(2, ';');
+                PluginConfig {
+                    name: plugin.next().unwrap_or_default().to_string(),
+                    opts: plugin.next().map(str::to_string),
+                }
+            });
+
+        let name = if tag.is_empty() { host } else { tag };
+        let mut config = ShadowsocksServerConfig::new(name, addr, password.to_string(), method);
+        config.plugin = plugin;
+        Ok(config)
+    }
+}
+
+/// Decode standard or url safe base64, padded or not.
+pub(crate) fn decode_base64(s: &str) -> Option<String> {
+    let s: String = s
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .collect();
+    let bytes = base64::decode_config(&s, base64::URL_SAFE_NO_PAD)
+        .or_else(|_| base64::decode_config(&s, base64::STANDARD_NO_PAD))
+        .ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |b: u8| (b as char).to_digit(16);
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(h), Some(l)) => {
+                    decoded.push((h * 16 + l) as u8);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ss_url() {
+        let config: ShadowsocksServerConfig =
+            "ss://YWVzLTI1Ni1nY206dGVzdA@192.168.100.1:8888#Example%20Server"
+                .parse()
+                .unwrap();
+        assert_eq!(config.name(), "Example Server");
+        assert_eq!(config.method().to_string(), "aes-256-gcm");
+        assert_eq!(config.password(), "test");
+        assert_eq!(
+            config.addr(),
+            &Address::SocketAddress("192.168.100.1:8888".parse().unwrap())
+        );
+
+        let config: ShadowsocksServerConfig = "ss://chacha20-ietf-poly1305:p%40ss@example.com:443/"
+            .parse()
+            .unwrap();
+        assert_eq!(config.name(), "example.com:443");
+        assert_eq!(config.password(), "p@ss");
+
+        let config: ShadowsocksServerConfig =
+            "ss://YWVzLTI1Ni1nY206dGVzdA@example.com:8388/?plugin=obfs-local%3Bobfs%3Dhttp#obfs"
+                .parse()
+                .unwrap();
+        let plugin = config.plugin().unwrap();
+        assert_eq!(plugin.name, "obfs-local");
+        assert_eq!(plugin.opts.as_deref(), Some("obfs=http"));
+
+        // legacy url, base64 of `aes-256-gcm:p@ss@example.com:8388`
+        let config: ShadowsocksServerConfig =
+            "ss://YWVzLTI1Ni1nY206cEBzc0BleGFtcGxlLmNvbTo4Mzg4#legacy"
+                .parse()
+                .unwrap();
+        assert_eq!(config.name(), "legacy");
+        assert_eq!(config.password(), "p@ss");
+        assert_eq!(
+            config.addr(),
+            &Address::DomainNameAddress("example.com".to_string(), 8388)
+        );
+        assert!(config.plugin().is_none());
+
+        assert!("ss://dW5rbm93bjp0ZXN0@example.com:8388"
+            .parse::<ShadowsocksServerConfig>()
+            .is_err());
+        assert!("http://example.com"
+            .parse::<ShadowsocksServerConfig>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_mux_config() {
+        let yaml = r#"
+name: server1
+addr: 1.2.3.4:8388
+method: aes-256-gcm
+password: password
+mux:
+  keepalive: 10s
+  max_lifetime: 3600s
+"#;
+        let config: ShadowsocksServerConfig = serde_yaml::from_str(yaml).unwrap();
+        let mux = config.mux().unwrap();
+        assert_eq!(mux.max_streams, 16);
+        assert_eq!(mux.keepalive, Duration::from_secs(10));
+        assert_eq!(mux.max_lifetime, Duration::from_secs(3600));
+        assert!("ss://YWVzLTI1Ni1nY206dGVzdA@1.2.3.4:8388"
+            .parse::<ShadowsocksServerConfig>()
+            .unwrap()
+            .mux()
+            .is_none());
+    }
+
+    #[test]
+    fn test_next_password() {
+        let config: ShadowsocksServerConfig = serde_yaml::from_str(
+            "{name: a, addr: 'example.com:8388', method: aes-256-gcm, password: new, fallback_passwords: [old]}",
+        )
+        .unwrap();
+        let clone = config.clone();
+        assert_eq!(config.password(), "new");
+        assert!(config.next_password());
+        assert_eq!(clone.password(), "old");
+        assert!(config.next_password());
+        assert_eq!(config.password(), "new");
+
+        let config = ShadowsocksServerConfig::new(
+            "b".to_string(),
+            Address::DomainNameAddress("example.com".to_string(), 8388),
+            "only".to_string(),
+            CipherType::ChaCha20IetfPoly1305,
+        );
+        assert!(!config.next_password());
+        assert_eq!(config.password(), "only");
+    }
 }
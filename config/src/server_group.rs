@@ -0,0 +1,207 @@
+use crate::Address;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use std::fmt::{self, Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the active shadowsocks server is chosen.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupMode {
+    /// Keep using the current server as long as it is alive.
+    Sticky,
+    /// Probe all servers every `interval` and switch to the fastest one.
+    Auto,
+    /// Distribute new connections across all alive servers according to `strategy`.
+    #[serde(rename = "load-balance")]
+    LoadBalance,
+    /// Use the first alive server in the order of `shadowsocks_servers`.
+    Fallback,
+}
+
+impl Default for GroupMode {
+    fn default() -> Self {
+        GroupMode::Sticky
+    }
+}
+
+/// How `load-balance` groups pick the server of a new connection.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalanceStrategy {
+    RoundRobin,
+    /// The server with the fewest active connections.
+    LeastConnections,
+    /// Connections to the same destination host always use the same server.
+    ConsistentHashing,
+}
+
+impl Default for BalanceStrategy {
+    fn default() -> Self {
+        BalanceStrategy::RoundRobin
+    }
+}
+
+/// How servers are probed by all modes except `sticky`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeMethod {
+    /// `GET` the url through the server, expecting `204 No Content`.
+    Http,
+    /// `HEAD` the url through the server, expecting a 2xx or 3xx status.
+    Head,
+    /// TCP handshake with the server.
+    Tcp,
+    /// ICMP ping of the server, using the `ping` command.
+    Icmp,
+}
+
+impl Default for ProbeMethod {
+    fn default() -> Self {
+        ProbeMethod::Http
+    }
+}
+
+/// Http url probed through the servers, eg. `http://www.gstatic.com/generate_204`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ProbeUrl {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl ProbeUrl {
+    pub fn address(&self) -> Address {
+        match self.host.parse::<IpAddr>() {
+            Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, self.port)),
+            Err(_) => Address::DomainNameAddress(self.host.clone(), self.port),
+        }
+    }
+}
+
+impl FromStr for ProbeUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid probe url: {}, expected http://host/path", s);
+        if !s.starts_with("http://") {
+            return Err(invalid());
+        }
+        let rest = &s["http://".len()..];
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, "/"),
+        };
+        let mut parts = authority.splitn(2, ':');
+        let host = parts.next().unwrap_or_default();
+        let port = match parts.next() {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ProbeUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Display for ProbeUrl {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProbeUrl {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(Error::custom)
+    }
+}
+
+/// Selection of the shadowsocks server used for proxied connections.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerGroupConfig {
+    pub mode: GroupMode,
+    pub strategy: BalanceStrategy,
+    pub probe: ProbeMethod,
+    /// Url requested by the `http` and `head` probes.
+    pub url: ProbeUrl,
+    #[serde(with = "crate::duration")]
+    pub interval: Duration,
+    /// The current server is kept unless another one is faster by more than this.
+    #[serde(with = "crate::duration")]
+    pub tolerance: Duration,
+    /// Close connections through other servers when a server is selected at runtime,
+    /// otherwise they are kept until closed.
+    pub drain_on_select: bool,
+    /// Consecutive connect errors after which a server is taken down.
+    pub max_failures: usize,
+    /// A taken down server is not used or pinged for this long. The cooldown doubles each
+    /// time the server is still down afterwards, up to `max_cooldown`.
+    #[serde(with = "crate::duration")]
+    pub cooldown: Duration,
+    #[serde(with = "crate::duration")]
+    pub max_cooldown: Duration,
+}
+
+/// Server group which rules can target by name, eg. `DOMAIN-SUFFIX,netflix.com,STREAMING`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NamedServerGroup {
+    pub name: String,
+    /// Names of servers in `shadowsocks_servers`.
+    pub servers: Vec<String>,
+    #[serde(flatten)]
+    pub config: ServerGroupConfig,
+}
+
+impl Default for ServerGroupConfig {
+    fn default() -> Self {
+        ServerGroupConfig {
+            mode: GroupMode::default(),
+            strategy: BalanceStrategy::default(),
+            probe: ProbeMethod::default(),
+            url: ProbeUrl {
+                host: "www.gstatic.com".to_string(),
+                port: 80,
+                path: "/generate_204".to_string(),
+            },
+            interval: Duration::from_secs(30),
+            tolerance: Duration::from_millis(50),
+            drain_on_select: false,
+            max_failures: 3,
+            cooldown: Duration::from_secs(30),
+            max_cooldown: Duration::from_secs(1800),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_probe_url() {
+        let url: ProbeUrl = "http://www.gstatic.com/generate_204".parse().unwrap();
+        assert_eq!(url, ServerGroupConfig::default().url);
+        let url: ProbeUrl = "http://1.1.1.1:8080".parse().unwrap();
+        assert_eq!(url.path, "/");
+        assert_eq!(
+            url.address(),
+            Address::SocketAddress("1.1.1.1:8080".parse().unwrap())
+        );
+        assert!("https://www.gstatic.com/generate_204"
+            .parse::<ProbeUrl>()
+            .is_err());
+        assert!("http://:80/".parse::<ProbeUrl>().is_err());
+    }
+}
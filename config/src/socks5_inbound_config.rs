@@ -0,0 +1,120 @@
+use crate::secret::resolve_secret;
+use crate::{parse_cidr, parse_cidr6};
+use serde::{Deserialize, Deserializer};
+use smoltcp::wire::{IpAddress, IpCidr};
+use socks5_client::Address;
+
+/// Local socks5 proxy, for apps and hosts of the LAN preferring an explicit proxy.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Socks5InboundConfig {
+    /// Listen address, eg. `127.0.0.1:1080`.
+    pub listen: String,
+    /// Users authenticated by username and password (RFC 1929). Anyone may connect without
+    /// authentication if empty.
+    #[serde(default)]
+    pub users: Vec<Socks5User>,
+}
+
+impl Socks5InboundConfig {
+    /// The user of `username` if `password` matches.
+    pub fn authenticate(&self, username: &str, password: &str) -> Option<&Socks5User> {
+        self.users
+            .iter()
+            .find(|user| user.username == username && user.password == password)
+    }
+
+    /// Replace `env:<VAR>` and `keyring:<entry>` references in the passwords of the users.
+    pub fn resolve_passwords(&mut self) -> Result<(), String> {
+        for user in &mut self.users {
+            user.password = resolve_secret(&user.password)
+                .map_err(|e| format!("password of socks5 user {}: {}", user.username, e))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Socks5User {
+    pub username: String,
+    password: String,
+    /// Destinations the user may connect to, all if empty.
+    #[serde(default)]
+    allow: Vec<AllowedDestination>,
+}
+
+impl Socks5User {
+    pub fn allows(&self, addr: &Address) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|allowed| allowed.matches(addr))
+    }
+}
+
+/// A domain with its subdomains, or an ip network.
+#[derive(Debug, Clone, PartialEq)]
+enum AllowedDestination {
+    Domain(String),
+    Network(IpCidr),
+}
+
+impl AllowedDestination {
+    fn parse(s: &str) -> Self {
+        if let Some(cidr) = parse_cidr(s) {
+            return AllowedDestination::Network(IpCidr::Ipv4(cidr));
+        }
+        if let Some(cidr) = parse_cidr6(s) {
+            return AllowedDestination::Network(IpCidr::Ipv6(cidr));
+        }
+        let domain = s.trim_start_matches("*.").trim_end_matches('.');
+        AllowedDestination::Domain(domain.to_lowercase())
+    }
+
+    fn matches(&self, addr: &Address) -> bool {
+        match (self, addr) {
+            (AllowedDestination::Domain(allowed), Address::DomainNameAddress(domain, _)) => {
+                let domain = domain.trim_end_matches('.').to_lowercase();
+                domain == *allowed || domain.ends_with(&format!(".{}", allowed))
+            }
+            (AllowedDestination::Network(cidr), Address::SocketAddress(addr)) => {
+                cidr.contains_addr(&IpAddress::from(addr.ip()))
+            }
+            _ => false,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for AllowedDestination {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(AllowedDestination::parse(&s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let user: Socks5User = serde_yaml::from_str(
+            "{username: guest, password: secret, allow: [example.com, 10.0.0.0/8, 'fd00::/8']}",
+        )
+        .unwrap();
+        let domain = |d: &str| Address::DomainNameAddress(d.to_string(), 443);
+        let ip = |s: &str| Address::SocketAddress(s.parse().unwrap());
+        assert!(user.allows(&domain("example.com")));
+        assert!(user.allows(&domain("www.Example.com")));
+        assert!(!user.allows(&domain("badexample.com")));
+        assert!(user.allows(&ip("10.1.2.3:80")));
+        assert!(!user.allows(&ip("11.1.2.3:80")));
+        assert!(user.allows(&ip("[fd00::1]:80")));
+
+        let config = Socks5InboundConfig {
+            listen: "0.0.0.0:1080".to_string(),
+            users: vec![user],
+        };
+        assert!(config.authenticate("guest", "secret").is_some());
+        assert!(config.authenticate("guest", "wrong").is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::server_config::decode_base64;
+use crate::{Address, ShadowsocksServerConfig};
+use crypto::CipherType;
+use serde::Deserialize;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A remote list of shadowsocks servers merged into `shadowsocks_servers`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SubscriptionConfig {
+    pub url: String,
+    /// Where the downloaded list is cached. Defaults to `subscriptions/<name>.txt`.
+    pub path: Option<PathBuf>,
+    #[serde(with = "crate::duration", default = "default_interval")]
+    pub interval: Duration,
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(24 * 3600)
+}
+
+/// SIP008 online configuration.
+#[derive(Deserialize)]
+struct Sip008 {
+    servers: Vec<Sip008Server>,
+}
+
+#[derive(Deserialize)]
+struct Sip008Server {
+    server: String,
+    server_port: u16,
+    password: String,
+    method: String,
+    #[serde(default)]
+    remarks: String,
+    #[serde(default)]
+    plugin: String,
+}
+
+impl SubscriptionConfig {
+    pub fn cache_path(&self, name: &str) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("subscriptions").join(format!("{}.txt", name)))
+    }
+
+    /// Parse a SIP008 JSON document, or `ss://` urls separated by newlines which may be base64
+    /// encoded as a whole. Servers which can't be used, eg. with plugins, are skipped.
+    pub fn parse_servers(&self, content: &str) -> io::Result<Vec<ShadowsocksServerConfig>> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let content = content.trim();
+        if content.starts_with('{') {
+            let sip008: Sip008 =
+                serde_json::from_str(content).map_err(|e| invalid(e.to_string()))?;
+            return Ok(sip008
+                .servers
+                .into_iter()
+                .filter(|server| server.plugin.is_empty())
+                .filter_map(|server| {
+                    let addr =
+                        Address::from_str(&format!("{}:{}", server.server, server.server_port))
+                            .ok()?;
+                    let method = CipherType::from_str(&server.method).ok()?;
+                    let name = if server.remarks.is_empty() {
+                        format!("{}:{}", server.server, server.server_port)
+                    } else {
+                        server.remarks
+                    };
+                    Some(ShadowsocksServerConfig::new(
+                        name,
+                        addr,
+                        server.password,
+                        method,
+                    ))
+                })
+                .collect());
+        }
+
+        let lines = if content.starts_with("ss://") {
+            content.to_string()
+        } else {
+            decode_base64(content).ok_or_else(|| invalid("invalid subscription".to_string()))?
+        };
+        Ok(lines
+            .lines()
+            .filter_map(|line| line.trim().parse::<ShadowsocksServerConfig>().ok())
+            .filter(|server| server.plugin().is_none())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription() -> SubscriptionConfig {
+        serde_yaml::from_str("url: https://example.com/sub").unwrap()
+    }
+
+    #[test]
+    fn test_parse_sip008() {
+        let content = r#"{
+            "version": 1,
+            "servers": [
+                {"id": "1", "remarks": "hk", "server": "hk.example.com", "server_port": 8388,
+                 "password": "test", "method": "aes-256-gcm"},
+                {"id": "2", "server": "1.2.3.4", "server_port": 443,
+                 "password": "test", "method": "chacha20-ietf-poly1305"},
+                {"id": "3", "server": "obfs.example.com", "server_port": 443, "password": "test",
+                 "method": "aes-256-gcm", "plugin": "obfs-local", "plugin_opts": "obfs=http"}
+            ]
+        }"#;
+        let servers = subscription().parse_servers(content).unwrap();
+        let names: Vec<&str> = servers.iter().map(|server| server.name()).collect();
+        assert_eq!(names, vec!["hk", "1.2.3.4:443"]);
+        assert_eq!(
+            servers[0].addr(),
+            &Address::DomainNameAddress("hk.example.com".to_string(), 8388)
+        );
+        assert!(subscription().parse_servers("{}").is_err());
+    }
+
+    #[test]
+    fn test_parse_base64() {
+        let subscription = subscription();
+        assert_eq!(
+            subscription.cache_path("test"),
+            PathBuf::from("subscriptions/test.txt")
+        );
+        let lines = "ss://YWVzLTI1Ni1nY206dGVzdA@1.2.3.4:8388#a\nss://invalid\nss://YWVzLTI1Ni1nY206dGVzdA@5.6.7.8:8388#b\nss://YWVzLTI1Ni1nY206dGVzdA@9.9.9.9:8388/?plugin=obfs-local#c\n";
+        let servers = subscription.parse_servers(&base64::encode(lines)).unwrap();
+        assert_eq!(servers.len(), 2);
+        assert_eq!(servers[1].name(), "b");
+        assert_eq!(subscription.parse_servers(lines).unwrap().len(), 2);
+        assert!(subscription.parse_servers("not base64!").is_err());
+    }
+}
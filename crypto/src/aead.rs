@@ -1,15 +1,9 @@
 //! Aead Ciphers
 
+use crate::backend::backend;
 use crate::cipher::{CipherCategory, CipherResult, CipherType};
+use crate::secret::SecretKey;
 
-#[cfg(feature = "use-ring")]
-use crate::ring::RingAeadCipher;
-#[cfg(feature = "miscreant")]
-use crate::siv::MiscreantCipher;
-#[cfg(feature = "sodium")]
-use crate::sodium::SodiumAeadCipher;
-
-use bytes::{Bytes, BytesMut};
 use hkdf::Hkdf;
 use sha1::Sha1;
 
@@ -33,6 +27,12 @@ pub trait AeadDecryptor {
     /// +----------------------------------------+-----------------------+
     /// ```
     fn decrypt(&mut self, input: &[u8], output: &mut [u8]) -> CipherResult<()>;
+
+    /// Like `decrypt`, but `input` may be overwritten, so ciphers opening in place don't have to
+    /// copy it first.
+    fn decrypt_mut(&mut self, input: &mut [u8], output: &mut [u8]) -> CipherResult<()> {
+        self.decrypt(input, output)
+    }
 }
 
 /// Variant `AeadDecryptor`
@@ -43,46 +43,27 @@ pub type BoxAeadEncryptor = Box<dyn AeadEncryptor + Send + 'static>;
 
 /// Generate a specific AEAD cipher encryptor
 pub fn new_aead_encryptor(t: CipherType, key: &[u8], nonce: &[u8]) -> BoxAeadEncryptor {
-    assert!(t.category() == CipherCategory::Aead);
-
-    match t {
-        #[cfg(feature = "use-ring")]
-        CipherType::Aes128Gcm | CipherType::Aes256Gcm | CipherType::ChaCha20IetfPoly1305 => {
-            Box::new(RingAeadCipher::new(t, key, nonce, true))
-        }
-
-        #[cfg(feature = "sodium")]
-        CipherType::XChaCha20IetfPoly1305 => Box::new(SodiumAeadCipher::new(t, key, nonce)),
-
-        #[cfg(feature = "miscreant")]
-        CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv => {
-            Box::new(MiscreantCipher::new(t, key, nonce))
-        }
-
-        _ => unreachable!(),
-    }
+    new_aead_encryptor_with_subkey(t, &make_skey(t, key, nonce))
 }
 
 /// Generate a specific AEAD cipher decryptor
 pub fn new_aead_decryptor(t: CipherType, key: &[u8], nonce: &[u8]) -> BoxAeadDecryptor {
-    assert!(t.category() == CipherCategory::Aead);
+    new_aead_decryptor_with_subkey(t, &make_skey(t, key, nonce))
+}
 
-    match t {
-        #[cfg(feature = "use-ring")]
-        CipherType::Aes128Gcm | CipherType::Aes256Gcm | CipherType::ChaCha20IetfPoly1305 => {
-            Box::new(RingAeadCipher::new(t, key, nonce, false))
-        }
+/// Generate a specific AEAD cipher encryptor with a session subkey made by `make_skey`, which
+/// can be kept to skip the HKDF when the salt is seen again.
+pub fn new_aead_encryptor_with_subkey(t: CipherType, skey: &[u8]) -> BoxAeadEncryptor {
+    assert!(t.category() == CipherCategory::Aead);
 
-        #[cfg(feature = "sodium")]
-        CipherType::XChaCha20IetfPoly1305 => Box::new(SodiumAeadCipher::new(t, key, nonce)),
+    backend(t).new_aead_encryptor(t, skey)
+}
 
-        #[cfg(feature = "miscreant")]
-        CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv => {
-            Box::new(MiscreantCipher::new(t, key, nonce))
-        }
+/// Generate a specific AEAD cipher decryptor with a session subkey made by `make_skey`
+pub fn new_aead_decryptor_with_subkey(t: CipherType, skey: &[u8]) -> BoxAeadDecryptor {
+    assert!(t.category() == CipherCategory::Aead);
 
-        _ => unreachable!(),
-    }
+    backend(t).new_aead_decryptor(t, skey)
 }
 
 const SUBKEY_INFO: &[u8] = b"ss-subkey";
@@ -115,19 +96,15 @@ const SUBKEY_INFO: &[u8] = b"ss-subkey";
 /// 4. For each chunk, encrypt and authenticate payload using SK with a counting nonce
 ///    (starting from 0 and increment by 1 after each use)
 /// 5. Send encrypted chunk
-pub fn make_skey(t: CipherType, key: &[u8], salt: &[u8]) -> Bytes {
+pub fn make_skey(t: CipherType, key: &[u8], salt: &[u8]) -> SecretKey {
     assert!(t.category() == CipherCategory::Aead);
 
     let hkdf = Hkdf::<Sha1>::new(Some(salt), key);
 
-    let mut skey = BytesMut::with_capacity(key.len());
-    unsafe {
-        skey.set_len(key.len());
-    }
-
+    let mut skey = vec![0; key.len()];
     hkdf.expand(SUBKEY_INFO, &mut skey).unwrap();
 
-    skey.freeze()
+    SecretKey::from(skey)
 }
 
 /// Increase nonce by 1
@@ -0,0 +1,114 @@
+//! Libraries providing the ciphers, each enabled by its feature. A cipher is created by the first
+//! enabled backend supporting it, so backends are added or swapped here without touching the
+//! callers of `new_stream`, `new_aead_encryptor` and `new_aead_decryptor`.
+
+#[cfg(feature = "gcm-siv")]
+use crate::gcm_siv::GcmSivBackend;
+#[cfg(feature = "openssl")]
+use crate::openssl::OpenSSLBackend;
+#[cfg(feature = "rc4")]
+use crate::rc4_md5::Rc4Md5Backend;
+#[cfg(feature = "use-ring")]
+use crate::ring::RingBackend;
+#[cfg(feature = "miscreant")]
+use crate::siv::MiscreantBackend;
+#[cfg(feature = "sodium")]
+use crate::sodium::SodiumBackend;
+use crate::{
+    dummy, table, BoxAeadDecryptor, BoxAeadEncryptor, BoxStreamCipher, CipherType, CryptoMode,
+};
+
+/// Ciphers of a library. Only the constructors of the category of the supported ciphers need
+/// to be implemented.
+pub trait CryptoBackend: Sync {
+    fn name(&self) -> &'static str;
+
+    fn supports(&self, t: CipherType) -> bool;
+
+    fn new_stream(
+        &self,
+        t: CipherType,
+        _key: &[u8],
+        _iv: &[u8],
+        _mode: CryptoMode,
+    ) -> BoxStreamCipher {
+        unreachable!("{} doesn't provide the stream cipher {}", self.name(), t)
+    }
+
+    /// The AEAD cipher `t` with the session subkey `skey`, derived by `make_skey`.
+    fn new_aead_encryptor(&self, t: CipherType, _skey: &[u8]) -> BoxAeadEncryptor {
+        unreachable!("{} doesn't provide the AEAD cipher {}", self.name(), t)
+    }
+
+    fn new_aead_decryptor(&self, t: CipherType, _skey: &[u8]) -> BoxAeadDecryptor {
+        unreachable!("{} doesn't provide the AEAD cipher {}", self.name(), t)
+    }
+}
+
+/// Enabled backends, in order of preference.
+const BACKENDS: &[&dyn CryptoBackend] = &[
+    &BuiltinBackend,
+    #[cfg(feature = "use-ring")]
+    &RingBackend,
+    #[cfg(feature = "sodium")]
+    &SodiumBackend,
+    #[cfg(feature = "gcm-siv")]
+    &GcmSivBackend,
+    #[cfg(feature = "openssl")]
+    &OpenSSLBackend,
+    #[cfg(feature = "rc4")]
+    &Rc4Md5Backend,
+    #[cfg(feature = "miscreant")]
+    &MiscreantBackend,
+];
+
+/// The backend providing `t`. Every `CipherType` comes with the feature of a backend.
+pub fn backend(t: CipherType) -> &'static dyn CryptoBackend {
+    BACKENDS
+        .iter()
+        .copied()
+        .find(|backend| backend.supports(t))
+        .unwrap_or_else(|| panic!("no backend provides {}", t))
+}
+
+/// `table` and `plain`, which need no library.
+struct BuiltinBackend;
+
+impl CryptoBackend for BuiltinBackend {
+    fn name(&self) -> &'static str {
+        "builtin"
+    }
+
+    fn supports(&self, t: CipherType) -> bool {
+        matches!(t, CipherType::Table | CipherType::Plain)
+    }
+
+    fn new_stream(
+        &self,
+        t: CipherType,
+        key: &[u8],
+        _iv: &[u8],
+        mode: CryptoMode,
+    ) -> BoxStreamCipher {
+        match t {
+            CipherType::Table => Box::new(table::TableCipher::new(key, mode)),
+            _ => Box::new(dummy::DummyCipher),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend() {
+        assert_eq!(backend(CipherType::Plain).name(), "builtin");
+        #[cfg(feature = "use-ring")]
+        assert_eq!(backend(CipherType::ChaCha20IetfPoly1305).name(), "ring");
+        #[cfg(feature = "sodium")]
+        assert_eq!(backend(CipherType::XChaCha20IetfPoly1305).name(), "sodium");
+        #[cfg(feature = "aes-cfb")]
+        assert_eq!(backend(CipherType::Aes256Cfb).name(), "openssl");
+    }
+}
@@ -3,12 +3,13 @@
 use std::{
     convert::From,
     fmt::{self, Debug, Display},
-    io, mem,
+    io,
     str::{self, FromStr},
 };
 
 use crate::digest::{self, Digest, DigestType};
-use bytes::{BufMut, Bytes, BytesMut};
+use crate::secret::{zeroize, SecretKey};
+use bytes::{Bytes, BytesMut};
 #[cfg(feature = "camellia-cfb")]
 use openssl::nid::Nid;
 #[cfg(feature = "openssl")]
@@ -166,6 +167,8 @@ const CIPHER_AES_256_GCM: &str = "aes-256-gcm";
 const CIPHER_CHACHA20_IETF_POLY1305: &str = "chacha20-ietf-poly1305";
 #[cfg(feature = "sodium")]
 const CIPHER_XCHACHA20_IETF_POLY1305: &str = "xchacha20-ietf-poly1305";
+#[cfg(feature = "gcm-siv")]
+const CIPHER_AES_256_GCM_SIV: &str = "aes-256-gcm-siv";
 
 /// ShadowSocks cipher type
 #[derive(Clone, Debug, Copy)]
@@ -256,6 +259,9 @@ pub enum CipherType {
     #[cfg(feature = "sodium")]
     XChaCha20IetfPoly1305,
 
+    #[cfg(feature = "gcm-siv")]
+    Aes256GcmSiv,
+
     #[cfg(feature = "miscreant")]
     Aes128PmacSiv,
     #[cfg(feature = "miscreant")]
@@ -378,6 +384,9 @@ impl CipherType {
             #[cfg(feature = "sodium")]
             CipherType::XChaCha20IetfPoly1305 => 32,
 
+            #[cfg(feature = "gcm-siv")]
+            CipherType::Aes256GcmSiv => 32,
+
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv => 32,
             #[cfg(feature = "miscreant")]
@@ -385,39 +394,46 @@ impl CipherType {
         }
     }
 
-    fn classic_bytes_to_key(self, key: &[u8]) -> Bytes {
+    fn classic_bytes_to_key(self, key: &[u8]) -> SecretKey {
         let iv_len = self.iv_size();
         let key_len = self.key_size();
 
         if iv_len + key_len == 0 {
-            return Bytes::new();
+            return SecretKey::from(Vec::new());
         }
 
         let mut digest = digest::with_type(DigestType::Md5);
+        let digest_len = digest.digest_len();
 
-        let total_loop = (key_len + iv_len + digest.digest_len() - 1) / digest.digest_len();
-        let m_length = digest.digest_len() + key.len();
+        let total_loop = (key_len + iv_len + digest_len - 1) / digest_len;
 
-        let mut result = BytesMut::with_capacity(total_loop * digest.digest_len());
-        let mut m = BytesMut::with_capacity(key.len());
+        // Buffers are allocated large enough up front, so no copies are left behind by growing.
+        let mut result = Vec::with_capacity(total_loop * digest_len);
+        let mut m = Vec::with_capacity(digest_len);
+        let mut vkey = Vec::with_capacity(digest_len + key.len());
 
         for _ in 0..total_loop {
-            let mut vkey = mem::replace(&mut m, BytesMut::with_capacity(m_length));
-            vkey.put(key);
+            vkey.clear();
+            vkey.extend_from_slice(&m);
+            vkey.extend_from_slice(key);
 
             digest.update(&vkey);
+            m.clear();
             digest.digest(&mut m);
             digest.reset();
 
-            result.put_slice(&m);
+            result.extend_from_slice(&m);
         }
 
+        zeroize(&mut m);
+        zeroize(&mut vkey);
+        zeroize(&mut result[key_len..]);
         result.truncate(key_len);
-        result.freeze()
+        SecretKey::from(result)
     }
 
     /// Extends key to match the required key length
-    pub fn bytes_to_key(self, key: &[u8]) -> Bytes {
+    pub fn bytes_to_key(self, key: &[u8]) -> SecretKey {
         self.classic_bytes_to_key(key)
     }
 
@@ -559,6 +575,8 @@ impl CipherType {
             CipherType::ChaCha20IetfPoly1305 => CHACHA20_POLY1305.nonce_len(),
             #[cfg(feature = "sodium")]
             CipherType::XChaCha20IetfPoly1305 => 24,
+            #[cfg(feature = "gcm-siv")]
+            CipherType::Aes256GcmSiv => 12,
 
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv => 8,
@@ -595,6 +613,9 @@ impl CipherType {
             #[cfg(feature = "sodium")]
             CipherType::XChaCha20IetfPoly1305 => CipherCategory::Aead,
 
+            #[cfg(feature = "gcm-siv")]
+            CipherType::Aes256GcmSiv => CipherCategory::Aead,
+
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv => CipherCategory::Aead,
 
@@ -615,6 +636,8 @@ impl CipherType {
             CipherType::ChaCha20IetfPoly1305 => CHACHA20_POLY1305.tag_len(),
             #[cfg(feature = "sodium")]
             CipherType::XChaCha20IetfPoly1305 => 16,
+            #[cfg(feature = "gcm-siv")]
+            CipherType::Aes256GcmSiv => 16,
 
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv => 16,
@@ -724,6 +747,8 @@ impl FromStr for CipherType {
             CIPHER_CHACHA20_IETF_POLY1305 => Ok(CipherType::ChaCha20IetfPoly1305),
             #[cfg(feature = "sodium")]
             CIPHER_XCHACHA20_IETF_POLY1305 => Ok(CipherType::XChaCha20IetfPoly1305),
+            #[cfg(feature = "gcm-siv")]
+            CIPHER_AES_256_GCM_SIV => Ok(CipherType::Aes256GcmSiv),
 
             #[cfg(feature = "miscreant")]
             CIPHER_AES_128_PMAC_SIV => Ok(CipherType::Aes128PmacSiv),
@@ -821,6 +846,8 @@ impl Display for CipherType {
             CipherType::ChaCha20IetfPoly1305 => write!(f, "{}", CIPHER_CHACHA20_IETF_POLY1305),
             #[cfg(feature = "sodium")]
             CipherType::XChaCha20IetfPoly1305 => write!(f, "{}", CIPHER_XCHACHA20_IETF_POLY1305),
+            #[cfg(feature = "gcm-siv")]
+            CipherType::Aes256GcmSiv => write!(f, "{}", CIPHER_AES_256_GCM_SIV),
 
             #[cfg(feature = "miscreant")]
             CipherType::Aes128PmacSiv => write!(f, "{}", CIPHER_AES_128_PMAC_SIV),
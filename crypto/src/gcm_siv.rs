@@ -0,0 +1,130 @@
+//! Cipher defined with the pure rust `aes-gcm-siv`, enabled by the `gcm-siv` feature
+//!
+//! AES-GCM-SIV doesn't leak the key stream or the authentication key when a nonce is reused, the
+//! only leak is whether two chunks are the same.
+
+use aes_gcm_siv::{
+    aead::{generic_array::GenericArray, AeadInPlace, NewAead},
+    Aes256GcmSiv,
+};
+
+use crate::{
+    aead::increase_nonce, cipher::Error, AeadDecryptor, AeadEncryptor, BoxAeadDecryptor,
+    BoxAeadEncryptor, CipherResult, CipherType, CryptoBackend,
+};
+
+const NONCE_LEN: usize = 12;
+
+/// AEAD Cipher context
+///
+/// According to SIP004, the `nonce` has to incr 1 after each encrypt/decrypt.
+pub struct GcmSivCipher {
+    cipher: Aes256GcmSiv,
+    cipher_type: CipherType,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl GcmSivCipher {
+    /// Initialize context with the session subkey `skey`
+    pub fn new(t: CipherType, skey: &[u8]) -> GcmSivCipher {
+        assert_eq!(t.iv_size(), NONCE_LEN);
+
+        let cipher = match t {
+            CipherType::Aes256GcmSiv => Aes256GcmSiv::new(GenericArray::from_slice(skey)),
+            _ => panic!("unsupported cipher in aes-gcm-siv {:?}", t),
+        };
+        GcmSivCipher {
+            cipher,
+            cipher_type: t,
+            nonce: [0u8; NONCE_LEN],
+        }
+    }
+}
+
+impl AeadEncryptor for GcmSivCipher {
+    fn encrypt(&mut self, input: &[u8], output: &mut [u8]) {
+        let tag_len = self.cipher_type.tag_size();
+        assert_eq!(output.len(), input.len() + tag_len);
+
+        let (text, tag_out) = output.split_at_mut(input.len());
+        text.copy_from_slice(input);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(GenericArray::from_slice(&self.nonce), b"", text)
+            .unwrap();
+        tag_out.copy_from_slice(&tag);
+
+        increase_nonce(&mut self.nonce);
+    }
+}
+
+impl AeadDecryptor for GcmSivCipher {
+    fn decrypt(&mut self, input: &[u8], output: &mut [u8]) -> CipherResult<()> {
+        let tag_len = self.cipher_type.tag_size();
+        assert_eq!(output.len() + tag_len, input.len());
+
+        let (text, tag) = input.split_at(output.len());
+        output.copy_from_slice(text);
+        let result = self.cipher.decrypt_in_place_detached(
+            GenericArray::from_slice(&self.nonce),
+            b"",
+            output,
+            GenericArray::from_slice(tag),
+        );
+        increase_nonce(&mut self.nonce);
+
+        result.map_err(|_| Error::AeadDecryptFailed)
+    }
+}
+
+/// AEAD ciphers of aes-gcm-siv
+pub struct GcmSivBackend;
+
+impl CryptoBackend for GcmSivBackend {
+    fn name(&self) -> &'static str {
+        "aes-gcm-siv"
+    }
+
+    fn supports(&self, t: CipherType) -> bool {
+        matches!(t, CipherType::Aes256GcmSiv)
+    }
+
+    fn new_aead_encryptor(&self, t: CipherType, skey: &[u8]) -> BoxAeadEncryptor {
+        Box::new(GcmSivCipher::new(t, skey))
+    }
+
+    fn new_aead_decryptor(&self, t: CipherType, skey: &[u8]) -> BoxAeadDecryptor {
+        Box::new(GcmSivCipher::new(t, skey))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::aead::make_skey;
+
+    #[test]
+    fn test_aes_256_gcm_siv() {
+        let ct = CipherType::Aes256GcmSiv;
+        let key = ct.bytes_to_key(b"PassWORD");
+        let salt = ct.gen_salt();
+        let skey = make_skey(ct, &key, &salt);
+        let mut enc = GcmSivCipher::new(ct, &skey);
+        let mut dec = GcmSivCipher::new(ct, &skey);
+
+        for message in &[&b"message"[..], &b"another message"[..]] {
+            let mut encrypted_msg = vec![0u8; message.len() + ct.tag_size()];
+            enc.encrypt(message, &mut encrypted_msg);
+            assert_ne!(message, &&encrypted_msg[..message.len()]);
+
+            let mut decrypted_msg = vec![0u8; message.len()];
+            dec.decrypt(&encrypted_msg[..], &mut decrypted_msg).unwrap();
+            assert_eq!(&decrypted_msg[..], *message);
+        }
+
+        let mut encrypted_msg = vec![0u8; 7 + ct.tag_size()];
+        enc.encrypt(b"message", &mut encrypted_msg);
+        encrypted_msg[0] ^= 1;
+        assert!(dec.decrypt(&encrypted_msg, &mut [0u8; 7]).is_err());
+    }
+}
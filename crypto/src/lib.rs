@@ -2,25 +2,32 @@
 
 pub use self::{
     aead::{
-        new_aead_decryptor, new_aead_encryptor, AeadDecryptor, AeadEncryptor, BoxAeadDecryptor,
+        make_skey, new_aead_decryptor, new_aead_decryptor_with_subkey, new_aead_encryptor,
+        new_aead_encryptor_with_subkey, AeadDecryptor, AeadEncryptor, BoxAeadDecryptor,
         BoxAeadEncryptor,
     },
+    backend::CryptoBackend,
     cipher::{CipherCategory, CipherResult, CipherType},
+    secret::{zeroize, SecretKey},
     stream::{new_stream, BoxStreamCipher, StreamCipher},
 };
 #[cfg(feature = "openssl")]
 use ::openssl::symm;
 
 pub mod aead;
+pub mod backend;
 pub mod cipher;
 pub mod digest;
 pub mod dummy;
+#[cfg(feature = "gcm-siv")]
+pub mod gcm_siv;
 #[cfg(feature = "openssl")]
 pub mod openssl;
 #[cfg(feature = "rc4")]
 pub mod rc4_md5;
 #[cfg(feature = "use-ring")]
 pub mod ring;
+pub mod secret;
 #[cfg(feature = "miscreant")]
 pub mod siv;
 #[cfg(feature = "sodium")]
@@ -2,7 +2,7 @@
 
 use std::convert::From;
 
-use super::{cipher, CipherResult, CipherType, StreamCipher};
+use super::{cipher, BoxStreamCipher, CipherResult, CipherType, CryptoBackend, StreamCipher};
 
 use super::CryptoMode;
 
@@ -200,3 +200,62 @@ impl StreamCipher for OpenSSLCipher {
         self.worker.buffer_size(data)
     }
 }
+
+/// Stream ciphers of OpenSSL
+pub struct OpenSSLBackend;
+
+impl CryptoBackend for OpenSSLBackend {
+    fn name(&self) -> &'static str {
+        "openssl"
+    }
+
+    fn supports(&self, t: CipherType) -> bool {
+        match t {
+            #[cfg(feature = "aes-cfb")]
+            CipherType::Aes128Cfb
+            | CipherType::Aes128Cfb1
+            | CipherType::Aes128Cfb8
+            | CipherType::Aes128Cfb128
+            | CipherType::Aes192Cfb
+            | CipherType::Aes192Cfb1
+            | CipherType::Aes192Cfb8
+            | CipherType::Aes192Cfb128
+            | CipherType::Aes256Cfb
+            | CipherType::Aes256Cfb1
+            | CipherType::Aes256Cfb8
+            | CipherType::Aes256Cfb128 => true,
+
+            #[cfg(feature = "aes-ctr")]
+            CipherType::Aes128Ctr | CipherType::Aes192Ctr | CipherType::Aes256Ctr => true,
+
+            #[cfg(feature = "camellia-cfb")]
+            CipherType::Camellia128Cfb
+            | CipherType::Camellia128Cfb1
+            | CipherType::Camellia128Cfb8
+            | CipherType::Camellia128Cfb128
+            | CipherType::Camellia192Cfb
+            | CipherType::Camellia192Cfb1
+            | CipherType::Camellia192Cfb8
+            | CipherType::Camellia192Cfb128
+            | CipherType::Camellia256Cfb
+            | CipherType::Camellia256Cfb1
+            | CipherType::Camellia256Cfb8
+            | CipherType::Camellia256Cfb128 => true,
+
+            #[cfg(feature = "rc4")]
+            CipherType::Rc4 => true,
+
+            _ => false,
+        }
+    }
+
+    fn new_stream(
+        &self,
+        t: CipherType,
+        key: &[u8],
+        iv: &[u8],
+        mode: CryptoMode,
+    ) -> BoxStreamCipher {
+        Box::new(OpenSSLCipher::new(t, key, iv, mode))
+    }
+}
@@ -3,7 +3,7 @@
 use crate::{
     digest::{self, Digest, DigestType},
     openssl::OpenSSLCrypto,
-    CipherResult, CipherType, CryptoMode, StreamCipher,
+    BoxStreamCipher, CipherResult, CipherType, CryptoBackend, CryptoMode, StreamCipher,
 };
 
 use bytes::{BufMut, BytesMut};
@@ -43,6 +43,29 @@ impl StreamCipher for Rc4Md5Cipher {
 
 unsafe impl Send for Rc4Md5Cipher {}
 
+/// The rc4-md5 cipher, built on the rc4 of OpenSSL
+pub struct Rc4Md5Backend;
+
+impl CryptoBackend for Rc4Md5Backend {
+    fn name(&self) -> &'static str {
+        "rc4-md5"
+    }
+
+    fn supports(&self, t: CipherType) -> bool {
+        matches!(t, CipherType::Rc4Md5)
+    }
+
+    fn new_stream(
+        &self,
+        _: CipherType,
+        key: &[u8],
+        iv: &[u8],
+        mode: CryptoMode,
+    ) -> BoxStreamCipher {
+        Box::new(Rc4Md5Cipher::new(key, iv, mode))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
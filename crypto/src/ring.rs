@@ -1,4 +1,7 @@
 //! Cipher defined with Ring
+//!
+//! Chunks are sealed in place in the output buffer, and opened in place in the input buffer by
+//! `decrypt_mut`.
 
 use ring::{
     aead::{
@@ -9,14 +12,10 @@ use ring::{
 };
 
 use crate::{
-    aead::{increase_nonce, make_skey},
-    cipher::Error,
-    AeadDecryptor, AeadEncryptor, CipherResult, CipherType,
+    aead::increase_nonce, cipher::Error, AeadDecryptor, AeadEncryptor, BoxAeadDecryptor,
+    BoxAeadEncryptor, CipherResult, CipherType, CryptoBackend,
 };
 
-use byte_string::ByteStr;
-use bytes::{BufMut, BytesMut};
-
 /// AEAD ciphers provided by Ring
 pub enum RingAeadCryptoVariant {
     Seal(SealingKey<RingAeadNonceSequence>),
@@ -49,21 +48,21 @@ impl NonceSequence for RingAeadNonceSequence {
 pub struct RingAeadCipher {
     cipher: RingAeadCryptoVariant,
     cipher_type: CipherType,
+    /// Ciphertext with the tag being opened by `decrypt`, reused by the chunks.
+    buf: Vec<u8>,
 }
 
 impl RingAeadCipher {
-    /// Initialize context
-    pub fn new(t: CipherType, key: &[u8], salt: &[u8], is_seal: bool) -> RingAeadCipher {
-        // TODO: Check if salt is duplicated
-
+    /// Initialize context with the session subkey `skey`
+    pub fn new(t: CipherType, skey: &[u8], is_seal: bool) -> RingAeadCipher {
         // Nonce is 12 bytes
         assert_eq!(t.iv_size(), NONCE_LEN);
 
-        let skey = make_skey(t, key, salt);
-        let cipher = RingAeadCipher::new_variant(t, &skey, is_seal);
+        let cipher = RingAeadCipher::new_variant(t, skey, is_seal);
         RingAeadCipher {
             cipher,
             cipher_type: t,
+            buf: Vec::new(),
         }
     }
 
@@ -99,20 +98,17 @@ impl RingAeadCipher {
 impl AeadEncryptor for RingAeadCipher {
     fn encrypt(&mut self, input: &[u8], output: &mut [u8]) {
         let tag_len = self.cipher_type.tag_size();
-        let buf_len = input.len() + tag_len;
-        assert_eq!(output.len(), buf_len);
+        assert_eq!(output.len(), input.len() + tag_len);
 
-        let mut buf = BytesMut::with_capacity(output.len());
-        buf.put_slice(input);
+        let (text, tag_out) = output.split_at_mut(input.len());
+        text.copy_from_slice(input);
 
         if let RingAeadCryptoVariant::Seal(ref mut key) = self.cipher {
-            key.seal_in_place_append_tag(Aad::empty(), &mut buf)
-                .unwrap();
+            let tag = key.seal_in_place_separate_tag(Aad::empty(), text).unwrap();
+            tag_out.copy_from_slice(tag.as_ref());
         } else {
             unreachable!("encrypt is called on a non-seal cipher");
         }
-
-        output.copy_from_slice(&buf[..buf_len]);
     }
 }
 
@@ -121,35 +117,69 @@ impl AeadDecryptor for RingAeadCipher {
         let tag_len = self.cipher_type.tag_size();
         assert_eq!(output.len() + tag_len, input.len());
 
-        let mut buf = BytesMut::with_capacity(input.len());
-        buf.put_slice(input);
-
-        if let RingAeadCryptoVariant::Open(ref mut key) = self.cipher {
-            match key.open_in_place(Aad::empty(), &mut buf) {
-                Ok(obuf) => {
-                    output.copy_from_slice(obuf);
-                    Ok(())
-                }
-                Err(..) => {
-                    eprintln!(
-                        "AEAD decrypt failed, input={:?}, tag={:?}, opening: {:?}",
-                        ByteStr::new(&input[..output.len()]),
-                        ByteStr::new(&input[output.len()..]),
-                        key,
-                    );
-                    Err(Error::AeadDecryptFailed)
-                }
+        // Ring only opens in place.
+        self.buf.clear();
+        self.buf.extend_from_slice(input);
+        open(&mut self.cipher, &mut self.buf, output)
+    }
+
+    fn decrypt_mut(&mut self, input: &mut [u8], output: &mut [u8]) -> CipherResult<()> {
+        let tag_len = self.cipher_type.tag_size();
+        assert_eq!(output.len() + tag_len, input.len());
+
+        open(&mut self.cipher, input, output)
+    }
+}
+
+/// Open `in_out`, the encrypted text followed by the tag, in place and copy the text to `output`.
+fn open(
+    cipher: &mut RingAeadCryptoVariant,
+    in_out: &mut [u8],
+    output: &mut [u8],
+) -> CipherResult<()> {
+    if let RingAeadCryptoVariant::Open(ref mut key) = cipher {
+        match key.open_in_place(Aad::empty(), in_out) {
+            Ok(obuf) => {
+                output.copy_from_slice(obuf);
+                Ok(())
             }
-        } else {
-            unreachable!("decrypt is called on a non-open cipher");
+            // `in_out` is left unspecified. Not logged, servers try every key on new streams and
+            // most of the attempts fail.
+            Err(..) => Err(Error::AeadDecryptFailed),
         }
+    } else {
+        unreachable!("decrypt is called on a non-open cipher");
+    }
+}
+
+/// AEAD ciphers of ring
+pub struct RingBackend;
+
+impl CryptoBackend for RingBackend {
+    fn name(&self) -> &'static str {
+        "ring"
+    }
+
+    fn supports(&self, t: CipherType) -> bool {
+        matches!(
+            t,
+            CipherType::Aes128Gcm | CipherType::Aes256Gcm | CipherType::ChaCha20IetfPoly1305
+        )
+    }
+
+    fn new_aead_encryptor(&self, t: CipherType, skey: &[u8]) -> BoxAeadEncryptor {
+        Box::new(RingAeadCipher::new(t, skey, true))
+    }
+
+    fn new_aead_decryptor(&self, t: CipherType, skey: &[u8]) -> BoxAeadDecryptor {
+        Box::new(RingAeadCipher::new(t, skey, false))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::CipherType;
+    use crate::{aead::make_skey, CipherType};
 
     fn test_ring_aead(ct: CipherType) {
         let key = ct.bytes_to_key(b"PassWORD");
@@ -157,20 +187,57 @@ mod test {
 
         let iv = ct.gen_init_vec();
 
-        let mut enc = RingAeadCipher::new(ct, &key[..], &iv[..], true);
+        let mut enc = RingAeadCipher::new(ct, &make_skey(ct, &key, &iv), true);
 
         let mut encrypted_msg = vec![0u8; message.len() + ct.tag_size()];
         enc.encrypt(message, &mut encrypted_msg);
 
         assert_ne!(message, &encrypted_msg[..]);
 
-        let mut dec = RingAeadCipher::new(ct, &key[..], &iv[..], false);
+        let mut dec = RingAeadCipher::new(ct, &make_skey(ct, &key, &iv), false);
         let mut decrypted_msg = vec![0u8; message.len()];
         dec.decrypt(&encrypted_msg[..], &mut decrypted_msg).unwrap();
 
         assert_eq!(&decrypted_msg[..], message);
     }
 
+    #[test]
+    fn test_ring_chunks() {
+        let ct = CipherType::Aes256Gcm;
+        let key = ct.bytes_to_key(b"PassWORD");
+        let iv = ct.gen_init_vec();
+        let mut enc = RingAeadCipher::new(ct, &make_skey(ct, &key, &iv), true);
+        let mut dec = RingAeadCipher::new(ct, &make_skey(ct, &key, &iv), false);
+
+        let mut chunks = vec![];
+        for message in &[&b"first"[..], &b"a longer second chunk"[..], &b""[..]] {
+            let mut encrypted_msg = vec![0u8; message.len() + ct.tag_size()];
+            enc.encrypt(message, &mut encrypted_msg);
+            chunks.push(encrypted_msg);
+        }
+        for (i, (encrypted_msg, message)) in chunks
+            .iter()
+            .zip(&["first", "a longer second chunk", ""])
+            .enumerate()
+        {
+            let mut decrypted_msg = vec![0u8; message.len()];
+            // Opened in a copy or in place, the nonce goes on either way.
+            if i % 2 == 0 {
+                dec.decrypt(encrypted_msg, &mut decrypted_msg).unwrap();
+            } else {
+                let mut encrypted_msg = encrypted_msg.clone();
+                dec.decrypt_mut(&mut encrypted_msg, &mut decrypted_msg)
+                    .unwrap();
+            }
+            assert_eq!(decrypted_msg, message.as_bytes());
+        }
+
+        let mut tampered = chunks[0].clone();
+        tampered[0] ^= 1;
+        let mut dec = RingAeadCipher::new(ct, &make_skey(ct, &key, &iv), false);
+        assert!(dec.decrypt(&tampered, &mut [0u8; 5]).is_err());
+    }
+
     #[test]
     fn test_ring_aes128gcm() {
         test_ring_aead(CipherType::Aes128Gcm);
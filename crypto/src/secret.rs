@@ -0,0 +1,71 @@
+//! Keys wiped from memory when they are dropped, so they don't linger in freed memory where a core
+//! dump or a memory disclosure bug may expose them. Copies held by the cipher libraries are out of
+//! reach.
+
+use std::fmt::{self, Debug};
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::Arc;
+
+/// Overwrite `buf` with zeros, without the writes being optimized out.
+pub fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// A key shared by the connections to a server, wiped once the last clone is dropped.
+#[derive(Clone)]
+pub struct SecretKey(Arc<SecretBuf>);
+
+struct SecretBuf(Vec<u8>);
+
+impl Drop for SecretBuf {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl From<Vec<u8>> for SecretKey {
+    fn from(key: Vec<u8>) -> Self {
+        SecretKey(Arc::new(SecretBuf(key)))
+    }
+}
+
+impl Deref for SecretKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &(self.0).0
+    }
+}
+
+impl AsRef<[u8]> for SecretKey {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey({} bytes)", self.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zeroize() {
+        let mut buf = vec![1u8; 32];
+        zeroize(&mut buf);
+        assert!(buf.iter().all(|b| *b == 0));
+
+        let key = SecretKey::from(vec![7u8; 16]);
+        assert_eq!(&*key.clone(), &[7u8; 16][..]);
+        assert_eq!(format!("{:?}", key), "SecretKey(16 bytes)");
+    }
+}
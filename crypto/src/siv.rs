@@ -5,9 +5,8 @@ use std::ptr;
 use miscreant::{Aead, Aes128PmacSivAead, Aes256PmacSivAead};
 
 use crate::{
-    aead::{increase_nonce, make_skey},
-    cipher::Error,
-    AeadDecryptor, AeadEncryptor, CipherResult, CipherType,
+    aead::increase_nonce, cipher::Error, AeadDecryptor, AeadEncryptor, BoxAeadDecryptor,
+    BoxAeadEncryptor, CipherResult, CipherType, CryptoBackend,
 };
 
 use byte_string::ByteStr;
@@ -30,10 +29,8 @@ pub struct MiscreantCipher {
 }
 
 impl MiscreantCipher {
-    /// Initialize context
-    pub fn new(t: CipherType, key: &[u8], salt: &[u8]) -> Self {
-        // NOTE: Don't need check salt is duplicated. :)
-
+    /// Initialize context with the session subkey `skey`
+    pub fn new(t: CipherType, skey: &[u8]) -> Self {
         let nonce_size = t.iv_size();
         let mut nonce = BytesMut::with_capacity(nonce_size);
         unsafe {
@@ -41,8 +38,7 @@ impl MiscreantCipher {
             ptr::write_bytes(nonce.as_mut_ptr(), 0, nonce_size);
         }
 
-        let skey = make_skey(t, key, salt);
-        let cipher = Self::new_variant(t, &skey);
+        let cipher = Self::new_variant(t, skey);
         MiscreantCipher {
             cipher_type: t,
             cipher: cipher,
@@ -133,9 +129,31 @@ impl AeadDecryptor for MiscreantCipher {
     }
 }
 
+/// AEAD ciphers of miscreant
+pub struct MiscreantBackend;
+
+impl CryptoBackend for MiscreantBackend {
+    fn name(&self) -> &'static str {
+        "miscreant"
+    }
+
+    fn supports(&self, t: CipherType) -> bool {
+        matches!(t, CipherType::Aes128PmacSiv | CipherType::Aes256PmacSiv)
+    }
+
+    fn new_aead_encryptor(&self, t: CipherType, skey: &[u8]) -> BoxAeadEncryptor {
+        Box::new(MiscreantCipher::new(t, skey))
+    }
+
+    fn new_aead_decryptor(&self, t: CipherType, skey: &[u8]) -> BoxAeadDecryptor {
+        Box::new(MiscreantCipher::new(t, skey))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::aead::make_skey;
 
     fn test_miscreant(ct: CipherType) {
         let key = ct.bytes_to_key(b"PassWORD");
@@ -143,14 +161,14 @@ mod test {
 
         let iv = ct.gen_init_vec();
 
-        let mut enc = MiscreantCipher::new(ct, &key[..], &iv[..]);
+        let mut enc = MiscreantCipher::new(ct, &make_skey(ct, &key, &iv));
 
         let mut encrypted_msg = vec![0u8; message.len() + ct.tag_size()];
         enc.encrypt(message, &mut encrypted_msg);
 
         assert_ne!(message, &encrypted_msg[..]);
 
-        let mut dec = MiscreantCipher::new(ct, &key[..], &iv[..]);
+        let mut dec = MiscreantCipher::new(ct, &make_skey(ct, &key, &iv));
         let mut decrypted_msg = vec![0u8; message.len()];
         dec.decrypt(&encrypted_msg[..], &mut decrypted_msg).unwrap();
 
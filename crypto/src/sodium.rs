@@ -2,7 +2,7 @@
 
 use std::{ptr, sync::Once};
 
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{BufMut, BytesMut};
 
 use libc::c_ulonglong;
 use libsodium_sys::{
@@ -12,9 +12,9 @@ use libsodium_sys::{
 };
 
 use crate::{
-    aead::{increase_nonce, make_skey},
-    cipher::Error,
-    AeadDecryptor, AeadEncryptor, CipherResult, CipherType, StreamCipher,
+    aead::increase_nonce, cipher::Error, secret::SecretKey, AeadDecryptor, AeadEncryptor,
+    BoxAeadDecryptor, BoxAeadEncryptor, BoxStreamCipher, CipherResult, CipherType, CryptoBackend,
+    CryptoMode, StreamCipher,
 };
 
 static SODIUM_INIT_FLAG: Once = Once::new();
@@ -22,7 +22,7 @@ static SODIUM_INIT_FLAG: Once = Once::new();
 /// Cipher provided by `libsodium`
 pub struct SodiumStreamCipher {
     cipher_type: CipherType,
-    key: Vec<u8>,
+    key: SecretKey,
     iv: Vec<u8>,
     counter: usize,
 }
@@ -46,7 +46,7 @@ impl SodiumStreamCipher {
 
         SodiumStreamCipher {
             cipher_type: t,
-            key: key.to_owned(),
+            key: SecretKey::from(key.to_owned()),
             iv: iv.to_owned(),
             counter: 0,
         }
@@ -156,13 +156,17 @@ impl StreamCipher for SodiumStreamCipher {
 /// Cipher provided by `libsodium`
 pub struct SodiumAeadCipher {
     cipher_type: CipherType,
-    key: Bytes,
+    key: SecretKey,
     nonce: BytesMut,
 }
 
 impl SodiumAeadCipher {
-    pub fn new(t: CipherType, key: &[u8], salt: &[u8]) -> SodiumAeadCipher {
-        // TODO: Check if salt is duplicated
+    /// Initialize context with the session subkey `skey`
+    pub fn new(t: CipherType, skey: &[u8]) -> SodiumAeadCipher {
+        // Picks the fastest implementations for the cpu.
+        SODIUM_INIT_FLAG.call_once(|| unsafe {
+            assert_eq!(sodium_init(), 0);
+        });
 
         let nonce_size = t.iv_size();
         let mut nonce = BytesMut::with_capacity(nonce_size);
@@ -171,11 +175,9 @@ impl SodiumAeadCipher {
             ptr::write_bytes(nonce.as_mut_ptr(), 0, nonce_size);
         }
 
-        let skey = make_skey(t, key, salt);
-
         SodiumAeadCipher {
             cipher_type: t,
-            key: skey,
+            key: SecretKey::from(skey.to_vec()),
             nonce,
         }
     }
@@ -255,10 +257,42 @@ impl AeadDecryptor for SodiumAeadCipher {
     }
 }
 
+/// Stream and AEAD ciphers of libsodium
+pub struct SodiumBackend;
+
+impl CryptoBackend for SodiumBackend {
+    fn name(&self) -> &'static str {
+        "sodium"
+    }
+
+    fn supports(&self, t: CipherType) -> bool {
+        matches!(
+            t,
+            CipherType::ChaCha20
+                | CipherType::Salsa20
+                | CipherType::XSalsa20
+                | CipherType::ChaCha20Ietf
+                | CipherType::XChaCha20IetfPoly1305
+        )
+    }
+
+    fn new_stream(&self, t: CipherType, key: &[u8], iv: &[u8], _: CryptoMode) -> BoxStreamCipher {
+        Box::new(SodiumStreamCipher::new(t, key, iv))
+    }
+
+    fn new_aead_encryptor(&self, t: CipherType, skey: &[u8]) -> BoxAeadEncryptor {
+        Box::new(SodiumAeadCipher::new(t, skey))
+    }
+
+    fn new_aead_decryptor(&self, t: CipherType, skey: &[u8]) -> BoxAeadDecryptor {
+        Box::new(SodiumAeadCipher::new(t, skey))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{CipherType, StreamCipher};
+    use crate::{aead::make_skey, CipherType, StreamCipher};
 
     fn test_sodium(ct: CipherType) {
         let key = ct.bytes_to_key(b"PassWORD");
@@ -285,14 +319,14 @@ mod test {
 
         let iv = ct.gen_init_vec();
 
-        let mut enc = SodiumAeadCipher::new(ct, &key[..], &iv[..]);
+        let mut enc = SodiumAeadCipher::new(ct, &make_skey(ct, &key, &iv));
 
         let mut encrypted_msg = vec![0u8; message.len() + ct.tag_size()];
         enc.encrypt(message, &mut encrypted_msg);
 
         assert_ne!(message, &encrypted_msg[..]);
 
-        let mut dec = SodiumAeadCipher::new(ct, &key[..], &iv[..]);
+        let mut dec = SodiumAeadCipher::new(ct, &make_skey(ct, &key, &iv));
         let mut decrypted_msg = vec![0u8; message.len()];
         dec.decrypt(&encrypted_msg[..], &mut decrypted_msg).unwrap();
 
@@ -1,14 +1,9 @@
 //! Stream ciphers
 
-#[cfg(feature = "openssl")]
-use crate::openssl;
-#[cfg(feature = "rc4")]
-use crate::rc4_md5;
-#[cfg(feature = "sodium")]
-use crate::sodium;
 use crate::{
+    backend::backend,
     cipher::{CipherCategory, CipherResult, CipherType},
-    dummy, table, CryptoMode,
+    CryptoMode,
 };
 
 use bytes::BufMut;
@@ -27,59 +22,11 @@ pub trait StreamCipher {
 pub type BoxStreamCipher = Box<dyn StreamCipher + Send + 'static>;
 
 /// Generate a specific Cipher with key and initialize vector
-#[allow(unused_variables)]
 pub fn new_stream(t: CipherType, key: &[u8], iv: &[u8], mode: CryptoMode) -> BoxStreamCipher {
     assert!(
         t.category() == CipherCategory::Stream,
         "only allow initializing with stream cipher"
     );
 
-    match t {
-        CipherType::Table => Box::new(table::TableCipher::new(key, mode)),
-        CipherType::Plain => Box::new(dummy::DummyCipher),
-
-        #[cfg(feature = "sodium")]
-        CipherType::ChaCha20
-        | CipherType::Salsa20
-        | CipherType::XSalsa20
-        | CipherType::ChaCha20Ietf => Box::new(sodium::SodiumStreamCipher::new(t, key, iv)),
-
-        #[cfg(feature = "rc4")]
-        CipherType::Rc4Md5 => Box::new(rc4_md5::Rc4Md5Cipher::new(key, iv, mode)),
-
-        #[cfg(feature = "aes-cfb")]
-        CipherType::Aes128Cfb
-        | CipherType::Aes128Cfb1
-        | CipherType::Aes128Cfb8
-        | CipherType::Aes128Cfb128
-        | CipherType::Aes192Cfb
-        | CipherType::Aes192Cfb1
-        | CipherType::Aes192Cfb8
-        | CipherType::Aes192Cfb128
-        | CipherType::Aes256Cfb
-        | CipherType::Aes256Cfb1
-        | CipherType::Aes256Cfb8
-        | CipherType::Aes256Cfb128 => Box::new(openssl::OpenSSLCipher::new(t, key, iv, mode)),
-
-        #[cfg(feature = "aes-ctr")]
-        CipherType::Aes128Ctr | CipherType::Aes192Ctr | CipherType::Aes256Ctr => {
-            Box::new(openssl::OpenSSLCipher::new(t, key, iv, mode))
-        }
-
-        #[cfg(feature = "camellia-cfb")]
-        CipherType::Camellia128Cfb
-        | CipherType::Camellia128Cfb1
-        | CipherType::Camellia128Cfb8
-        | CipherType::Camellia128Cfb128
-        | CipherType::Camellia192Cfb
-        | CipherType::Camellia192Cfb1
-        | CipherType::Camellia192Cfb8
-        | CipherType::Camellia192Cfb128
-        | CipherType::Camellia256Cfb
-        | CipherType::Camellia256Cfb1
-        | CipherType::Camellia256Cfb8
-        | CipherType::Camellia256Cfb128 => Box::new(openssl::OpenSSLCipher::new(t, key, iv, mode)),
-
-        _ => unreachable!("{} is not a stream cipher", t),
-    }
+    backend(t).new_stream(t, key, iv, mode)
 }
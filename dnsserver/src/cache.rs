@@ -0,0 +1,194 @@
+use config::DnsCacheConfig;
+use hermesdns::{DnsPacket, DnsRecord, QueryType, ResultCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize,
+}
+
+struct Entry {
+    rescode: ResultCode,
+    answers: Vec<DnsRecord>,
+    expires: Instant,
+}
+
+/// TTL respecting cache of upstream responses, keyed by domain and query type.
+pub(crate) struct DnsCache {
+    config: DnsCacheConfig,
+    entries: Mutex<HashMap<(String, QueryType), Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl DnsCache {
+    pub fn new(config: DnsCacheConfig) -> Self {
+        DnsCache {
+            config,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached response with TTLs set to the remaining time.
+    pub fn get(&self, domain: &str, qtype: QueryType) -> Option<DnsPacket> {
+        if self.config.size == 0 {
+            return None;
+        }
+        let key = (domain.to_ascii_lowercase(), qtype);
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let packet = match entries.get(&key) {
+            Some(entry) if entry.expires > now => {
+                let ttl = (entry.expires - now).as_secs() as u32;
+                let mut packet = DnsPacket::new();
+                packet.header.rescode = entry.rescode;
+                packet.answers = entry.answers.clone();
+                for record in &mut packet.answers {
+                    record.set_ttl(ttl);
+                }
+                Some(packet)
+            }
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        };
+        let counter = if packet.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        packet
+    }
+
+    pub fn put(&self, domain: &str, qtype: QueryType, packet: &DnsPacket) {
+        if self.config.size == 0 {
+            return;
+        }
+        let ttl = match packet.header.rescode {
+            ResultCode::NOERROR if !packet.answers.is_empty() => {
+                let ttl = packet
+                    .answers
+                    .iter()
+                    .map(|record| record.get_ttl())
+                    .min()
+                    .unwrap_or(0);
+                Duration::from_secs(ttl as u64)
+                    .max(self.config.min_ttl)
+                    .min(self.config.max_ttl)
+            }
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => self.config.negative_ttl,
+            _ => return,
+        };
+        if ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.config.size {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+        if entries.len() >= self.config.size {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                entries.remove(&key);
+            }
+        }
+        entries.insert(
+            (domain.to_ascii_lowercase(), qtype),
+            Entry {
+                rescode: packet.header.rescode,
+                answers: packet.answers.clone(),
+                expires: now + ttl,
+            },
+        );
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: self.entries.lock().unwrap().len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hermesdns::TransientTtl;
+
+    fn response(rescode: ResultCode, ttl: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = rescode;
+        if rescode == ResultCode::NOERROR {
+            packet.answers.push(DnsRecord::A {
+                domain: "example.com".to_string(),
+                addr: "1.2.3.4".parse().unwrap(),
+                ttl: TransientTtl(ttl),
+            });
+        }
+        packet
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = DnsCache::new(DnsCacheConfig {
+            size: 2,
+            min_ttl: Duration::from_secs(60),
+            max_ttl: Duration::from_secs(600),
+            negative_ttl: Duration::from_secs(30),
+        });
+        assert!(cache.get("example.com", QueryType::A).is_none());
+
+        cache.put(
+            "example.com",
+            QueryType::A,
+            &response(ResultCode::NOERROR, 1),
+        );
+        let packet = cache.get("Example.com", QueryType::A).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert!(packet.answers[0].get_ttl() > 50);
+        assert!(cache.get("example.com", QueryType::AAAA).is_none());
+
+        cache.put("nx.com", QueryType::A, &response(ResultCode::NXDOMAIN, 0));
+        assert_eq!(
+            cache.get("nx.com", QueryType::A).unwrap().header.rescode,
+            ResultCode::NXDOMAIN
+        );
+        cache.put("fail.com", QueryType::A, &response(ResultCode::SERVFAIL, 0));
+        assert!(cache.get("fail.com", QueryType::A).is_none());
+
+        // Evicts nx.com, which expires first.
+        cache.put(
+            "new.com",
+            QueryType::A,
+            &response(ResultCode::NOERROR, 3000),
+        );
+        assert!(cache.get("nx.com", QueryType::A).is_none());
+        assert!(cache.get("example.com", QueryType::A).is_some());
+
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 4,
+                size: 2
+            }
+        );
+    }
+}
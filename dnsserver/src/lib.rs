@@ -1,21 +1,34 @@
+mod cache;
+mod query_log;
 pub mod resolver;
+mod upstream;
+
+pub use cache::CacheStats;
+pub use query_log::QueryLogEntry;
+pub use upstream::Upstream;
 
-use async_std_resolver::AsyncStdResolver;
 use config::rule::ProxyRules;
+use config::{AaaaStrategy, Hosts};
 use hermesdns::DnsUdpServer;
 use resolver::RuleBasedDnsResolver;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
 use std::path::Path;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn create_dns_server<P: AsRef<Path>>(
     path: P,
     listen: String,
-    start_ip: Ipv4Addr,
+    ips: Range<Ipv4Addr>,
+    start_ipv6: Ipv6Addr,
     rules: ProxyRules,
-    async_resolver: AsyncStdResolver,
+    hosts: Hosts,
+    aaaa: AaaaStrategy,
+    upstream: Upstream,
 ) -> (DnsUdpServer, RuleBasedDnsResolver) {
-    let n = u32::from_be_bytes(start_ip.octets());
-    let resolver = RuleBasedDnsResolver::new(path, n, rules, async_resolver).await;
+    let ips = u32::from(ips.start)..u32::from(ips.end);
+    let n6 = u128::from_be_bytes(start_ipv6.octets());
+    let resolver = RuleBasedDnsResolver::new(path, ips, n6, rules, hosts, aaaa, upstream).await;
     let server = DnsUdpServer::new(listen, Box::new(resolver.clone())).await;
     (server, resolver)
 }
@@ -25,7 +38,7 @@ pub(crate) mod tests {
     use super::*;
     use async_std::io;
     use async_std::task;
-    use async_std_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+    use config::DnsServerAddr;
     use hermesdns::{DnsClient, DnsNetworkClient, QueryType};
     use std::time::Duration;
 
@@ -40,16 +53,9 @@ pub(crate) mod tests {
         resp.get_random_a()
     }
 
-    pub(crate) async fn new_resolver(ip: String, port: u16) -> AsyncStdResolver {
-        let name_servers = NameServerConfigGroup::from_ips_clear(&[ip.parse().unwrap()], port);
-
-        // Construct a new Resolver with default configuration options
-        async_std_resolver::resolver(
-            ResolverConfig::from_parts(None, Vec::new(), name_servers),
-            ResolverOpts::default(),
-        )
-        .await
-        .expect("failed to create resolver")
+    pub(crate) fn new_upstream(ip: String, port: u16) -> Upstream {
+        let addr = DnsServerAddr::Udp((ip.parse::<std::net::IpAddr>().unwrap(), port).into());
+        Upstream::new(&[addr], Duration::from_secs(5))
     }
 
     #[test]
@@ -57,13 +63,16 @@ pub(crate) mod tests {
         let dir = tempfile::tempdir().unwrap();
         let dns = std::env::var("DNS").unwrap_or_else(|_| "223.5.5.5".to_string());
         task::block_on(async {
-            let resolver = new_resolver(dns, 53).await;
+            let upstream = new_upstream(dns, 53);
             let (server, resolver) = create_dns_server(
                 dir.path(),
                 format!("0.0.0.0:{}", LOCAL_UDP_PORT),
-                "10.0.0.1".parse().unwrap(),
+                "10.0.0.1".parse().unwrap().."10.0.255.255".parse().unwrap(),
+                "fd00::1".parse().unwrap(),
                 ProxyRules::new(vec![]),
-                resolver,
+                Hosts::default(),
+                AaaaStrategy::Drop,
+                upstream,
             )
             .await;
             task::spawn(server.run_server());
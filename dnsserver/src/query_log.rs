@@ -0,0 +1,85 @@
+use hermesdns::DnsRecord;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryLogEntry {
+    /// Unix timestamp in milliseconds.
+    pub time: u64,
+    pub domain: String,
+    pub qtype: String,
+    pub answers: Vec<String>,
+    /// Where the answer came from: `hosts`, `/etc/hosts`, `fake`, `reject`, `drop` or the
+    /// upstream server.
+    pub source: String,
+    /// Action of the matched rule, none if no rule matched.
+    pub action: Option<String>,
+    pub latency_ms: u64,
+    pub error: Option<String>,
+}
+
+/// Ring buffer of the most recent dns queries.
+pub(crate) struct QueryLog {
+    entries: Mutex<VecDeque<QueryLogEntry>>,
+    capacity: usize,
+}
+
+impl QueryLog {
+    pub fn new(capacity: usize) -> Self {
+        QueryLog {
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, entry: QueryLogEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Entries from the oldest to the newest.
+    pub fn entries(&self) -> Vec<QueryLogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+pub(crate) fn format_record(record: &DnsRecord) -> String {
+    match record {
+        DnsRecord::A { addr, .. } => addr.to_string(),
+        DnsRecord::AAAA { addr, .. } => addr.to_string(),
+        DnsRecord::CNAME { host, .. } => format!("CNAME {}", host),
+        record => format!("{:?}", record),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(domain: &str) -> QueryLogEntry {
+        QueryLogEntry {
+            time: 0,
+            domain: domain.to_string(),
+            qtype: "A".to_string(),
+            answers: vec![],
+            source: "fake".to_string(),
+            action: None,
+            latency_ms: 0,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_query_log() {
+        let log = QueryLog::new(2);
+        log.push(entry("a.com"));
+        log.push(entry("b.com"));
+        log.push(entry("c.com"));
+        let domains: Vec<String> = log.entries().into_iter().map(|e| e.domain).collect();
+        assert_eq!(domains, vec!["b.com", "c.com"]);
+    }
+}
@@ -1,20 +1,28 @@
-use async_std::net::IpAddr;
-use async_std_resolver::AsyncStdResolver;
+use crate::query_log::{format_record, QueryLog, QueryLogEntry};
+use crate::upstream::Upstream;
 use async_trait::async_trait;
 use config::rule::{Action, ProxyRules};
+use config::AaaaStrategy;
 use hermesdns::{DnsPacket, DnsRecord, DnsResolver, Hosts, QueryType, TransientTtl};
-use sled::Db;
+use sled::{Db, Tree};
 use std::any::Any;
-use std::io;
-use std::io::Result;
-use std::net::Ipv4Addr;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::Range;
 use std::path::Path;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::debug;
-use trust_dns_proto::rr::RData;
 
 const NEXT_IP: &str = "next_ip";
+const NEXT_IP6: &str = "next_ip6";
+/// Prefix of the keys mapping domains to fake IPv6 addresses.
+const IPV6_PREFIX: &str = "v6:";
+const LAST_USED: &str = "last_used";
+/// Last-used times are only rewritten once they are older than this many seconds, so busy domains
+/// don't cause a db write on every lookup.
+const TOUCH_INTERVAL: u64 = 60;
+const QUERY_LOG_SIZE: usize = 1000;
 
 /// A Forwarding DNS Resolver
 ///
@@ -26,39 +34,67 @@ pub struct RuleBasedDnsResolver {
 
 struct Inner {
     hosts: Hosts,
+    static_hosts: config::Hosts,
     rules: ProxyRules,
     db: Db,
-    next_ip: AtomicU32,
-    resolver: AsyncStdResolver,
+    /// Unix timestamp in seconds when a fake IP was last resolved or connected to, keyed like the
+    /// domain to fake IP mappings, so the IPv4 and IPv6 mappings of a domain have their own times.
+    last_used: Tree,
+    /// Fake IPv4 addresses handed out, `next_ip` starts over at the beginning after the end.
+    ips: Range<u32>,
+    next_ip: Mutex<u32>,
+    next_ip6: Mutex<u128>,
+    aaaa: AaaaStrategy,
+    upstream: Upstream,
+    query_log: QueryLog,
 }
 
 impl RuleBasedDnsResolver {
     pub async fn new<P: AsRef<Path>>(
         path: P,
-        next_ip: u32,
+        ips: Range<u32>,
+        next_ip6: u128,
         rules: ProxyRules,
-        resolver: AsyncStdResolver,
+        static_hosts: config::Hosts,
+        aaaa: AaaaStrategy,
+        upstream: Upstream,
     ) -> Self {
         let db = sled::open(path).expect("open db error");
         let next_ip = match db.get(NEXT_IP.as_bytes()) {
             Ok(Some(v)) => {
                 let mut s = [0; 4];
                 s.copy_from_slice(&v);
-                u32::from_be_bytes(s)
+                Some(u32::from_be_bytes(s)).filter(|ip| ips.contains(ip))
             }
             _ => {
                 db.clear().unwrap();
-                next_ip
+                None
             }
+        }
+        .unwrap_or(ips.start);
+        let next_ip6 = match db.get(NEXT_IP6.as_bytes()) {
+            Ok(Some(v)) => {
+                let mut s = [0; 16];
+                s.copy_from_slice(&v);
+                u128::from_be_bytes(s)
+            }
+            _ => next_ip6,
         };
+        let last_used = db.open_tree(LAST_USED).expect("open last_used tree");
 
         RuleBasedDnsResolver {
             inner: Arc::new(Inner {
                 hosts: Hosts::load().expect("load /etc/hosts"),
+                static_hosts,
                 rules,
-                next_ip: AtomicU32::new(next_ip),
+                ips,
+                next_ip: Mutex::new(next_ip),
+                next_ip6: Mutex::new(next_ip6),
+                aaaa,
                 db,
-                resolver,
+                last_used,
+                upstream,
+                query_log: QueryLog::new(QUERY_LOG_SIZE),
             }),
         }
     }
@@ -71,20 +107,201 @@ impl RuleBasedDnsResolver {
             .unwrap()
             .map(|host| String::from_utf8(host.to_vec()).unwrap());
         debug!("lookup host: {}, addr: {:?}", addr, host);
+        if let Some(host) = &host {
+            if addr.parse::<Ipv6Addr>().is_ok() {
+                self.touch(&format!("{}{}", IPV6_PREFIX, host));
+            } else {
+                self.touch(host);
+            }
+        }
         host
     }
 
-    fn gen_ipaddr(&self) -> String {
-        let [a, b, c, d] = (self.inner.next_ip.load(Ordering::SeqCst) as u32).to_be_bytes();
-        self.inner.next_ip.fetch_add(1, Ordering::SeqCst);
-        // TODO: assert next_ip is not to large
-        let addr = Ipv4Addr::new(a, b, c, d);
-        debug!("Resolver.gen_ipaddr: {}", addr);
+    /// Recent queries from the oldest to the newest.
+    pub fn query_log(&self) -> Vec<QueryLogEntry> {
+        self.inner.query_log.entries()
+    }
+
+    /// Record that the mapping of `key`, a domain with or without the IPv6 prefix, is used now.
+    fn touch(&self, key: &str) {
+        let now = now_secs();
+        if let Some(last_used) = self.last_used(key) {
+            if now.saturating_sub(last_used) < TOUCH_INTERVAL {
+                return;
+            }
+        }
+        self.inner
+            .last_used
+            .insert(key.as_bytes(), &now.to_be_bytes())
+            .unwrap();
+    }
+
+    fn last_used(&self, key: &str) -> Option<u64> {
+        self.inner.last_used.get(key).unwrap().map(|v| {
+            let mut s = [0; 8];
+            s.copy_from_slice(&v);
+            u64::from_be_bytes(s)
+        })
+    }
+
+    /// Remove fake IP mappings which have not been used for `max_age`. The IPv4 and IPv6
+    /// mappings of a domain have their own last-used times and are removed independently.
+    ///
+    /// Mappings created before last-used times were recorded are treated as used now.
+    /// Returns the number of domains without any mapping left.
+    pub fn remove_expired(&self, max_age: Duration) -> usize {
+        let now = now_secs();
+        let mut removed = 0;
+        for item in self.inner.db.iter() {
+            let (key, value) = item.unwrap();
+            // Keys are either `next_ip`, `next_ip6`, fake IPs, or domains with or without
+            // the IPv6 prefix.
+            let k = match std::str::from_utf8(&key) {
+                Ok(k) if k == NEXT_IP || k == NEXT_IP6 || k.parse::<IpAddr>().is_ok() => continue,
+                Ok(k) => k,
+                Err(_) => continue,
+            };
+            let (domain, other_key) = if k.starts_with(IPV6_PREFIX) {
+                let domain = &k[IPV6_PREFIX.len()..];
+                (domain, domain.to_string())
+            } else {
+                (k, format!("{}{}", IPV6_PREFIX, k))
+            };
+            let last_used = match self.last_used(k) {
+                Some(last_used) => last_used,
+                None => {
+                    self.touch(k);
+                    continue;
+                }
+            };
+            if now.saturating_sub(last_used) > max_age.as_secs() {
+                debug!("remove expired fake ip, domain: {}", domain);
+                self.inner.db.remove(&key).unwrap();
+                self.inner.db.remove(&value).unwrap();
+                self.inner.last_used.remove(&key).unwrap();
+                if !self.inner.db.contains_key(other_key.as_bytes()).unwrap() {
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    /// Allocate the next fake IPv4 not mapped to any domain. After the end of the range it starts
+    /// over, reusing the addresses freed by `remove_expired`.
+    fn gen_ipaddr(&self) -> Result<String> {
+        let ips = &self.inner.ips;
+        let mut next_ip = self.inner.next_ip.lock().unwrap();
+        for _ in ips.clone() {
+            let ip = *next_ip;
+            *next_ip = if ip + 1 < ips.end { ip + 1 } else { ips.start };
+            let addr = Ipv4Addr::from(ip).to_string();
+            if !self.inner.db.contains_key(addr.as_bytes()).unwrap() {
+                self.inner
+                    .db
+                    .insert(NEXT_IP.as_bytes(), &next_ip.to_be_bytes())
+                    .unwrap();
+                debug!("Resolver.gen_ipaddr: {}", addr);
+                return Ok(addr);
+            }
+        }
+        Err(Error::new(ErrorKind::Other, "fake ip range exhausted"))
+    }
+
+    fn gen_ipv6addr(&self) -> String {
+        let mut next_ip6 = self.inner.next_ip6.lock().unwrap();
+        let addr = Ipv6Addr::from(*next_ip6);
+        *next_ip6 += 1;
+        self.inner
+            .db
+            .insert(NEXT_IP6.as_bytes(), &next_ip6.to_be_bytes())
+            .unwrap();
+        debug!("Resolver.gen_ipv6addr: {}", addr);
         addr.to_string()
     }
 
-    async fn resolve(&self, domain: &str) -> Result<DnsPacket> {
+    fn get_or_create_fake_ip(&self, domain: &str, ipv6: bool) -> Result<String> {
+        let key = if ipv6 {
+            format!("{}{}", IPV6_PREFIX, domain)
+        } else {
+            domain.to_string()
+        };
+        let ip = if let Some(addr) = self.inner.db.get(&key).expect("get domain") {
+            let ip = String::from_utf8(addr.to_vec()).unwrap();
+            debug!("lookup host from cache, domain: {}, ip: {}", domain, &ip);
+            ip
+        } else {
+            let ip = if ipv6 {
+                self.gen_ipv6addr()
+            } else {
+                self.gen_ipaddr()?
+            };
+            debug!("lookup host gen ip, domain: {}, ip: {}", domain, &ip);
+
+            self.inner.db.insert(key.as_bytes(), ip.as_bytes()).unwrap();
+            self.inner
+                .db
+                .insert(ip.as_bytes(), domain.as_bytes())
+                .unwrap();
+            ip
+        };
+        self.touch(&key);
+        Ok(ip)
+    }
+
+    async fn resolve(&self, domain: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let start = Instant::now();
+        let action = self.inner.rules.action_for_domain(domain);
+        let ret = self.lookup(domain, qtype, action.as_ref()).await;
+        let (answers, source, error) = match &ret {
+            Ok((packet, source)) => (
+                packet.answers.iter().map(format_record).collect(),
+                source.clone(),
+                None,
+            ),
+            Err(e) => (vec![], String::new(), Some(e.to_string())),
+        };
+        self.inner.query_log.push(QueryLogEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("time went backwards")
+                .as_millis() as u64,
+            domain: domain.to_string(),
+            qtype: format!("{:?}", qtype),
+            answers,
+            source,
+            action: action.map(|action| action.to_string()),
+            latency_ms: start.elapsed().as_millis() as u64,
+            error,
+        });
+        ret.map(|(packet, _)| packet)
+    }
+
+    /// Returns the response and where it came from.
+    async fn lookup(
+        &self,
+        domain: &str,
+        qtype: QueryType,
+        action: Option<&Action>,
+    ) -> Result<(DnsPacket, String)> {
         let mut packet = DnsPacket::new();
+        if let Some(ip) = self.inner.static_hosts.get(domain) {
+            match (ip, qtype) {
+                (IpAddr::V4(addr), QueryType::A) => packet.answers.push(DnsRecord::A {
+                    domain: domain.to_string(),
+                    addr,
+                    ttl: TransientTtl(60),
+                }),
+                (IpAddr::V6(addr), QueryType::AAAA) => packet.answers.push(DnsRecord::AAAA {
+                    domain: domain.to_string(),
+                    addr,
+                    ttl: TransientTtl(60),
+                }),
+                _ => {}
+            }
+            debug!("lookup host for hosts domain: {}, ip: {}", domain, ip);
+            return Ok((packet, "hosts".to_string()));
+        }
         if let Some(ip) = self.inner.hosts.get(domain) {
             packet.answers.push(DnsRecord::A {
                 domain: domain.to_string(),
@@ -95,92 +312,68 @@ impl RuleBasedDnsResolver {
                 "lookup host for /etc/hosts domain: {}, ip: {:?}",
                 domain, ip
             );
-            return Ok(packet);
+            return Ok((packet, "/etc/hosts".to_string()));
         }
 
-        match self.inner.rules.action_for_domain(domain) {
+        match action {
             Some(Action::Direct) => {
-                let lookup_ip = self
-                    .inner
-                    .resolver
-                    .lookup_ip(domain)
-                    .await
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
-                let mut ips: Vec<IpAddr> = vec![];
-                for record in lookup_ip.as_lookup().record_iter() {
-                    let rdata = match record.rdata() {
-                        RData::A(ip) => {
-                            ips.push(IpAddr::V4(*ip));
-                            DnsRecord::A {
-                                domain: domain.to_string(),
-                                addr: *ip,
-                                ttl: TransientTtl(record.ttl()),
-                            }
-                        }
-                        RData::AAAA(ip) => {
-                            ips.push(IpAddr::V6(*ip));
-                            DnsRecord::AAAA {
-                                domain: domain.to_string(),
-                                addr: *ip,
-                                ttl: TransientTtl(record.ttl()),
-                            }
-                        }
-                        _ => continue,
-                    };
-                    packet.answers.push(rdata)
-                }
-
-                debug!("lookup host for direct domain: {}, ip: {:?}", domain, ips);
-                return Ok(packet);
+                let (resp, source) = self.inner.upstream.query_with_source(domain, qtype).await?;
+                debug!(
+                    "lookup host for direct domain: {}, answers: {:?}",
+                    domain, resp.answers
+                );
+                packet.header.rescode = resp.header.rescode;
+                packet.answers = resp.answers;
+                return Ok((packet, source));
             }
-            Some(Action::Reject) => return Ok(packet),
+            Some(Action::Reject) => return Ok((packet, "reject".to_string())),
             _ => {}
         };
 
-        let ip = if let Some(addr) = self.inner.db.get(domain).expect("get domain") {
-            let ip = String::from_utf8(addr.to_vec()).unwrap();
-            debug!("lookup host from cache, domain: {}, ip: {}", domain, &ip);
-            ip
-        } else {
-            let ip = self.gen_ipaddr();
-            debug!("lookup host gen ip, domain: {}, ip: {}", domain, &ip);
-
-            self.inner
-                .db
-                .insert(
-                    NEXT_IP.as_bytes(),
-                    &self.inner.next_ip.load(Ordering::SeqCst).to_be_bytes(),
-                )
-                .unwrap();
-            self.inner
-                .db
-                .insert(domain.as_bytes(), ip.as_bytes())
-                .unwrap();
-            self.inner
-                .db
-                .insert(ip.as_bytes(), domain.as_bytes())
-                .unwrap();
-            ip
-        };
+        if qtype == QueryType::AAAA {
+            let source = match self.inner.aaaa {
+                AaaaStrategy::Drop => "drop".to_string(),
+                AaaaStrategy::Passthrough => {
+                    let (resp, source) =
+                        self.inner.upstream.query_with_source(domain, qtype).await?;
+                    packet.header.rescode = resp.header.rescode;
+                    packet.answers = resp.answers;
+                    source
+                }
+                AaaaStrategy::Fake => {
+                    let ip = self.get_or_create_fake_ip(domain, true)?;
+                    packet.answers.push(DnsRecord::AAAA {
+                        domain: domain.to_string(),
+                        addr: ip.parse().unwrap(),
+                        ttl: TransientTtl(5),
+                    });
+                    "fake".to_string()
+                }
+            };
+            return Ok((packet, source));
+        }
 
+        let ip = self.get_or_create_fake_ip(domain, false)?;
         packet.answers.push(DnsRecord::A {
             domain: domain.to_string(),
             addr: ip.parse().unwrap(),
             ttl: TransientTtl(5),
         });
-        Ok(packet)
+        Ok((packet, "fake".to_string()))
     }
 }
 
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_secs()
+}
+
 #[async_trait]
 impl DnsResolver for RuleBasedDnsResolver {
-    async fn resolve(
-        &self,
-        domain: &str,
-        _qtype: QueryType,
-        _recursive: bool,
-    ) -> Result<DnsPacket> {
-        self.resolve(domain).await
+    async fn resolve(&self, domain: &str, qtype: QueryType, _recursive: bool) -> Result<DnsPacket> {
+        self.resolve(domain, qtype).await
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -191,7 +384,7 @@ impl DnsResolver for RuleBasedDnsResolver {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::tests::new_resolver;
+    use crate::tests::new_upstream;
     use async_std::task;
 
     #[test]
@@ -203,18 +396,25 @@ mod tests {
         task::block_on(async {
             let resolver = RuleBasedDnsResolver::new(
                 dir.path(),
-                n,
+                n..n + 10,
+                u128::from_be_bytes("fd00::1".parse::<Ipv6Addr>().unwrap().octets()),
                 ProxyRules::new(vec![]),
-                new_resolver(dns, 53).await,
+                config::Hosts::default(),
+                AaaaStrategy::Fake,
+                new_upstream(dns, 53),
             )
             .await;
             assert_eq!(
-                resolver.resolve("baidu.com").await.unwrap().get_random_a(),
+                resolver
+                    .resolve("baidu.com", QueryType::A)
+                    .await
+                    .unwrap()
+                    .get_random_a(),
                 Some("10.0.0.1".to_string())
             );
             assert_eq!(
                 resolver
-                    .resolve("www.ali.com")
+                    .resolve("www.ali.com", QueryType::A)
                     .await
                     .unwrap()
                     .get_random_a(),
@@ -227,4 +427,142 @@ mod tests {
             assert_eq!(resolver.lookup_host("10.1.0.1"), None);
         });
     }
+
+    #[test]
+    fn test_remove_expired() {
+        let dir = tempfile::tempdir().unwrap();
+        let start_ip = "10.0.0.1".parse::<Ipv4Addr>().unwrap();
+        let n = u32::from_be_bytes(start_ip.octets());
+        task::block_on(async {
+            let resolver = RuleBasedDnsResolver::new(
+                dir.path(),
+                n..n + 10,
+                u128::from_be_bytes("fd00::1".parse::<Ipv6Addr>().unwrap().octets()),
+                ProxyRules::new(vec![]),
+                config::Hosts::default(),
+                AaaaStrategy::Fake,
+                new_upstream("127.0.0.1".to_string(), 53),
+            )
+            .await;
+            resolver.resolve("baidu.com", QueryType::A).await.unwrap();
+            resolver.resolve("www.ali.com", QueryType::A).await.unwrap();
+            let packet = resolver
+                .resolve("baidu.com", QueryType::AAAA)
+                .await
+                .unwrap();
+            assert_eq!(
+                packet.answers[0],
+                DnsRecord::AAAA {
+                    domain: "baidu.com".to_string(),
+                    addr: "fd00::1".parse().unwrap(),
+                    ttl: TransientTtl(5),
+                }
+            );
+            assert_eq!(
+                resolver.lookup_host("fd00::1"),
+                Some("baidu.com".to_string())
+            );
+            let log = resolver.query_log();
+            assert_eq!(log.len(), 3);
+            assert_eq!(log[2].domain, "baidu.com");
+            assert_eq!(log[2].qtype, "AAAA");
+            assert_eq!(log[2].answers, vec!["fd00::1".to_string()]);
+            assert_eq!(log[2].source, "fake");
+            resolver
+                .inner
+                .last_used
+                .insert("baidu.com", &0u64.to_be_bytes())
+                .unwrap();
+            // The IPv6 mapping was used recently, only the IPv4 one expires.
+            assert_eq!(resolver.remove_expired(Duration::from_secs(3600)), 0);
+            assert_eq!(resolver.lookup_host("10.0.0.1"), None);
+            assert_eq!(
+                resolver.lookup_host("fd00::1"),
+                Some("baidu.com".to_string())
+            );
+            resolver
+                .inner
+                .last_used
+                .insert("v6:baidu.com", &0u64.to_be_bytes())
+                .unwrap();
+            assert_eq!(resolver.remove_expired(Duration::from_secs(3600)), 1);
+            assert_eq!(resolver.lookup_host("fd00::1"), None);
+            assert_eq!(
+                resolver.lookup_host("10.0.0.2"),
+                Some("www.ali.com".to_string())
+            );
+
+            resolver
+                .resolve("v6only.com", QueryType::AAAA)
+                .await
+                .unwrap();
+            resolver
+                .inner
+                .last_used
+                .insert("v6:v6only.com", &0u64.to_be_bytes())
+                .unwrap();
+            assert_eq!(resolver.remove_expired(Duration::from_secs(3600)), 1);
+            assert_eq!(resolver.lookup_host("fd00::2"), None);
+            assert!(resolver
+                .inner
+                .last_used
+                .get("v6:v6only.com")
+                .unwrap()
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn test_fake_ip_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let n = u32::from("10.0.0.1".parse::<Ipv4Addr>().unwrap());
+        task::block_on(async {
+            let resolver = RuleBasedDnsResolver::new(
+                dir.path(),
+                n..n + 2,
+                u128::from("fd00::1".parse::<Ipv6Addr>().unwrap()),
+                ProxyRules::new(vec![]),
+                config::Hosts::default(),
+                AaaaStrategy::Drop,
+                new_upstream("127.0.0.1".to_string(), 53),
+            )
+            .await;
+            for domain in &["baidu.com", "www.ali.com"] {
+                resolver.resolve(domain, QueryType::A).await.unwrap();
+            }
+            assert!(resolver.resolve("qq.com", QueryType::A).await.is_err());
+
+            // Recently used domains are not touched again.
+            let recent = now_secs() - 10;
+            resolver
+                .inner
+                .last_used
+                .insert("www.ali.com", &recent.to_be_bytes())
+                .unwrap();
+            resolver.lookup_host("10.0.0.2");
+            assert_eq!(resolver.last_used("www.ali.com"), Some(recent));
+            resolver
+                .inner
+                .last_used
+                .insert("www.ali.com", &(recent - TOUCH_INTERVAL).to_be_bytes())
+                .unwrap();
+            resolver.lookup_host("10.0.0.2");
+            assert!(resolver.last_used("www.ali.com").unwrap() > recent);
+
+            resolver
+                .inner
+                .last_used
+                .insert("baidu.com", &0u64.to_be_bytes())
+                .unwrap();
+            assert_eq!(resolver.remove_expired(Duration::from_secs(3600)), 1);
+            assert_eq!(
+                resolver
+                    .resolve("qq.com", QueryType::A)
+                    .await
+                    .unwrap()
+                    .get_random_a(),
+                Some("10.0.0.1".to_string())
+            );
+        });
+    }
 }
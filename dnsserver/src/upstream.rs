@@ -0,0 +1,420 @@
+mod https;
+mod tls;
+mod udp;
+
+use crate::cache::{CacheStats, DnsCache};
+use async_std::io::timeout;
+use async_trait::async_trait;
+use config::{ClientSubnet, DnsCacheConfig, DnsServerAddr, DnsTlsConfig, IpBlacklist};
+use futures_util::future::select_ok;
+use hermesdns::{DnsPacket, DnsQuestion, DnsRecord, QueryType};
+use https::HttpsClient;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tls::TlsClient;
+use tracing::debug;
+use udp::UdpClient;
+
+/// Sends a query in wire format to an upstream server and returns the response.
+#[async_trait]
+trait UpstreamClient: Send + Sync {
+    async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+type Servers = Vec<(DnsServerAddr, Box<dyn UpstreamClient>)>;
+
+fn new_servers(servers: &[DnsServerAddr], tls: &Arc<DnsTlsConfig>) -> Servers {
+    servers
+        .iter()
+        .map(|addr| {
+            let client: Box<dyn UpstreamClient> = match addr {
+                DnsServerAddr::Udp(addr) => Box::new(UdpClient::new(*addr)),
+                DnsServerAddr::Https(url) => Box::new(HttpsClient::new(url.clone(), tls)),
+                DnsServerAddr::Tls(addr, name) => {
+                    Box::new(TlsClient::new(*addr, name.clone(), tls.clone()))
+                }
+            };
+            (addr.clone(), client)
+        })
+        .collect()
+}
+
+fn new_domain_servers(
+    domain_servers: &HashMap<String, Vec<DnsServerAddr>>,
+    tls: &Arc<DnsTlsConfig>,
+) -> HashMap<String, Servers> {
+    domain_servers
+        .iter()
+        .map(|(suffix, servers)| {
+            let suffix = suffix.trim_end_matches('.').to_ascii_lowercase();
+            (suffix, new_servers(servers, tls))
+        })
+        .collect()
+}
+
+fn server_addrs(servers: &Servers) -> Vec<DnsServerAddr> {
+    servers.iter().map(|(addr, _)| addr.clone()).collect()
+}
+
+#[derive(Clone)]
+struct Routes {
+    servers: Arc<Servers>,
+    domain_servers: Arc<HashMap<String, Servers>>,
+}
+
+impl Routes {
+    fn servers_for(&self, domain: &str) -> &Servers {
+        if !self.domain_servers.is_empty() {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            let mut suffix = domain.as_str();
+            loop {
+                if let Some(servers) = self.domain_servers.get(suffix) {
+                    return servers;
+                }
+                match suffix.find('.') {
+                    Some(pos) => suffix = &suffix[pos + 1..],
+                    None => break,
+                }
+            }
+        }
+        &self.servers
+    }
+}
+
+/// Upstream dns servers used to resolve domains which are not faked.
+///
+/// Servers are tried in order until one of them answers, or concurrently if `with_race` is
+/// set. Answers containing blacklisted IPs are dropped. Domains under a suffix configured by
+/// `with_domain_servers` are sent to that suffix's servers instead. Responses are cached as
+/// configured by `with_cache`.
+#[derive(Clone)]
+pub struct Upstream {
+    routes: Arc<RwLock<Routes>>,
+    cache: Arc<DnsCache>,
+    client_subnet: Option<ClientSubnet>,
+    race: bool,
+    ip_blacklist: Arc<IpBlacklist>,
+    tls: Arc<DnsTlsConfig>,
+    timeout: Duration,
+}
+
+impl Upstream {
+    pub fn new(servers: &[DnsServerAddr], timeout: Duration) -> Self {
+        let tls = Arc::new(DnsTlsConfig::default());
+        Upstream {
+            routes: Arc::new(RwLock::new(Routes {
+                servers: Arc::new(new_servers(servers, &tls)),
+                domain_servers: Arc::new(HashMap::new()),
+            })),
+            cache: Arc::new(DnsCache::new(DnsCacheConfig::default())),
+            client_subnet: None,
+            race: false,
+            ip_blacklist: Arc::new(IpBlacklist::default()),
+            tls,
+            timeout,
+        }
+    }
+
+    pub fn with_cache(mut self, config: DnsCacheConfig) -> Self {
+        self.cache = Arc::new(DnsCache::new(config));
+        self
+    }
+
+    /// Attach an EDNS Client Subnet option to queries.
+    pub fn with_client_subnet(mut self, client_subnet: Option<ClientSubnet>) -> Self {
+        self.client_subnet = client_subnet;
+        self
+    }
+
+    pub fn with_race(mut self, race: bool) -> Self {
+        self.race = race;
+        self
+    }
+
+    pub fn with_ip_blacklist(mut self, ip_blacklist: IpBlacklist) -> Self {
+        self.ip_blacklist = Arc::new(ip_blacklist);
+        self
+    }
+
+    /// Verify the certificates of DoT and DoH servers by `tls`, the servers are recreated.
+    pub fn with_tls(mut self, tls: DnsTlsConfig) -> Self {
+        self.tls = Arc::new(tls);
+        let routes = self.routes.read().unwrap().clone();
+        let domain_servers = routes
+            .domain_servers
+            .iter()
+            .map(|(suffix, servers)| (suffix.clone(), server_addrs(servers)))
+            .collect();
+        self.routes = Arc::new(RwLock::new(Routes {
+            servers: Arc::new(new_servers(&server_addrs(&routes.servers), &self.tls)),
+            domain_servers: Arc::new(new_domain_servers(&domain_servers, &self.tls)),
+        }));
+        self
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Use `servers` for the suffix and all of its subdomains, the longest suffix wins.
+    pub fn with_domain_servers(
+        mut self,
+        domain_servers: &HashMap<String, Vec<DnsServerAddr>>,
+    ) -> Self {
+        let servers = self.routes.read().unwrap().servers.clone();
+        self.routes = Arc::new(RwLock::new(Routes {
+            servers,
+            domain_servers: Arc::new(new_domain_servers(domain_servers, &self.tls)),
+        }));
+        self
+    }
+
+    /// Replace the servers, eg. after the config is reloaded. All clones of this `Upstream`
+    /// use the new servers, the cache is kept.
+    pub fn set_servers(
+        &self,
+        servers: &[DnsServerAddr],
+        domain_servers: &HashMap<String, Vec<DnsServerAddr>>,
+    ) {
+        let routes = Routes {
+            servers: Arc::new(new_servers(servers, &self.tls)),
+            domain_servers: Arc::new(new_domain_servers(domain_servers, &self.tls)),
+        };
+        *self.routes.write().unwrap() = routes;
+    }
+
+    pub async fn query(&self, domain: &str, qtype: QueryType) -> io::Result<DnsPacket> {
+        self.query_with_source(domain, qtype)
+            .await
+            .map(|(resp, _)| resp)
+    }
+
+    /// Like `query`, also returns where the response came from, `cache` or the server.
+    pub async fn query_with_source(
+        &self,
+        domain: &str,
+        qtype: QueryType,
+    ) -> io::Result<(DnsPacket, String)> {
+        if let Some(resp) = self.cache.get(domain, qtype) {
+            return Ok((resp, "cache".to_string()));
+        }
+
+        let mut packet = DnsPacket::new();
+        packet.header.id = rand::random();
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(domain.to_string(), qtype));
+        if let Some(subnet) = &self.client_subnet {
+            packet.resources.push(DnsRecord::OPT {
+                packet_len: 4096,
+                flags: 0,
+                data: client_subnet_option(subnet),
+            });
+        }
+        let query = packet.to_bytes()?;
+        let id = packet.header.id;
+
+        let routes = self.routes.read().unwrap().clone();
+        let servers = routes.servers_for(domain);
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no dns server");
+        if self.race && !servers.is_empty() {
+            let exchanges = servers.iter().map(|(addr, client)| {
+                let query = &query;
+                Box::pin(async move {
+                    let resp = self
+                        .exchange(addr, client.as_ref(), query, id, domain, qtype)
+                        .await?;
+                    Ok::<_, io::Error>((resp, addr))
+                })
+            });
+            match select_ok(exchanges).await {
+                Ok(((resp, addr), _)) => {
+                    self.cache.put(domain, qtype, &resp);
+                    return Ok((resp, addr.to_string()));
+                }
+                Err(e) => last_err = e,
+            }
+        } else {
+            for (addr, client) in servers.iter() {
+                match self
+                    .exchange(addr, client.as_ref(), &query, id, domain, qtype)
+                    .await
+                {
+                    Ok(resp) => {
+                        self.cache.put(domain, qtype, &resp);
+                        return Ok((resp, addr.to_string()));
+                    }
+                    Err(e) => last_err = e,
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn exchange(
+        &self,
+        addr: &DnsServerAddr,
+        client: &dyn UpstreamClient,
+        query: &[u8],
+        id: u16,
+        domain: &str,
+        qtype: QueryType,
+    ) -> io::Result<DnsPacket> {
+        let resp = timeout(self.timeout, client.exchange(query))
+            .await
+            .and_then(|resp| DnsPacket::from_bytes(&resp))
+            .and_then(|resp| {
+                if resp.header.id != id {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "mismatched dns id",
+                    ))
+                } else if self.is_poisoned(&resp) {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "blacklisted ip in answers",
+                    ))
+                } else {
+                    Ok(resp)
+                }
+            });
+        if let Err(e) = &resp {
+            debug!(server = %addr, domain, ?qtype, err = ?e, "upstream query error");
+        }
+        resp
+    }
+
+    fn is_poisoned(&self, resp: &DnsPacket) -> bool {
+        if self.ip_blacklist.is_empty() {
+            return false;
+        }
+        resp.answers.iter().any(|record| match record {
+            DnsRecord::A { addr, .. } => self.ip_blacklist.contains(IpAddr::V4(*addr)),
+            DnsRecord::AAAA { addr, .. } => self.ip_blacklist.contains(IpAddr::V6(*addr)),
+            _ => false,
+        })
+    }
+
+    /// Returns IPv4 addresses if there are any, otherwise IPv6 addresses.
+    pub async fn lookup_ip(&self, domain: &str) -> io::Result<Vec<IpAddr>> {
+        for qtype in &[QueryType::A, QueryType::AAAA] {
+            let ips: Vec<IpAddr> = self
+                .query(domain, *qtype)
+                .await?
+                .answers
+                .iter()
+                .filter_map(|record| match record {
+                    DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                    DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                    _ => None,
+                })
+                .collect();
+            if !ips.is_empty() {
+                return Ok(ips);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} not resolved", domain),
+        ))
+    }
+}
+
+/// Encode the EDNS Client Subnet option, the address is truncated to the prefix length.
+fn client_subnet_option(subnet: &ClientSubnet) -> Vec<u8> {
+    let (family, octets): (u16, Vec<u8>) = match subnet.addr {
+        IpAddr::V4(addr) => (1, addr.octets().to_vec()),
+        IpAddr::V6(addr) => (2, addr.octets().to_vec()),
+    };
+    let len = (subnet.prefix as usize + 7) / 8;
+    let mut addr = octets[..len].to_vec();
+    if subnet.prefix % 8 != 0 {
+        addr[len - 1] &= 0xff << (8 - subnet.prefix % 8);
+    }
+
+    let mut data = Vec::with_capacity(8 + len);
+    data.extend_from_slice(&8u16.to_be_bytes());
+    data.extend_from_slice(&(4 + len as u16).to_be_bytes());
+    data.extend_from_slice(&family.to_be_bytes());
+    // Source prefix length, and scope prefix length which must be 0 in queries.
+    data.push(subnet.prefix);
+    data.push(0);
+    data.extend_from_slice(&addr);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    #[test]
+    fn test_servers_for() {
+        let addr = |s: &str| DnsServerAddr::Udp(s.parse().unwrap());
+        let mut domain_servers = HashMap::new();
+        domain_servers.insert("example.com".to_string(), vec![addr("10.0.0.2:53")]);
+        domain_servers.insert("corp.example.com".to_string(), vec![addr("10.0.0.3:53")]);
+        let upstream = Upstream::new(&[addr("223.5.5.5:53")], Duration::from_secs(1))
+            .with_domain_servers(&domain_servers);
+        let server = |domain: &str| {
+            upstream.routes.read().unwrap().servers_for(domain)[0]
+                .0
+                .clone()
+        };
+        assert_eq!(server("baidu.com"), addr("223.5.5.5:53"));
+        assert_eq!(server("example.com"), addr("10.0.0.2:53"));
+        assert_eq!(server("www.Example.com."), addr("10.0.0.2:53"));
+        assert_eq!(server("git.corp.example.com"), addr("10.0.0.3:53"));
+        assert_eq!(server("notexample.com"), addr("223.5.5.5:53"));
+
+        upstream
+            .clone()
+            .set_servers(&[addr("8.8.8.8:53")], &HashMap::new());
+        assert_eq!(server("example.com"), addr("8.8.8.8:53"));
+    }
+
+    #[test]
+    fn test_client_subnet_option() {
+        let subnet = "1.2.3.0/24".parse().unwrap();
+        assert_eq!(
+            client_subnet_option(&subnet),
+            vec![0, 8, 0, 7, 0, 1, 24, 0, 1, 2, 3]
+        );
+        let subnet = "1.2.255.0/20".parse().unwrap();
+        assert_eq!(
+            client_subnet_option(&subnet),
+            vec![0, 8, 0, 7, 0, 1, 20, 0, 1, 2, 240]
+        );
+        let subnet = "2001:db8::/32".parse().unwrap();
+        assert_eq!(
+            client_subnet_option(&subnet),
+            vec![0, 8, 0, 8, 0, 2, 32, 0, 0x20, 0x01, 0x0d, 0xb8]
+        );
+    }
+
+    #[test]
+    fn test_lookup_ip() {
+        let dns = std::env::var("DNS").unwrap_or_else(|_| "223.5.5.5".to_string());
+        let servers = vec![
+            DnsServerAddr::Udp("127.0.0.1:1".parse().unwrap()),
+            dns.parse().unwrap(),
+        ];
+        task::block_on(async {
+            let upstream = Upstream::new(&servers, Duration::from_secs(3));
+            let ips = upstream.lookup_ip("baidu.com").await.unwrap();
+            assert!(!ips.is_empty());
+
+            let upstream = Upstream::new(&servers, Duration::from_secs(3)).with_race(true);
+            let ips = upstream.lookup_ip("baidu.com").await.unwrap();
+            assert!(!ips.is_empty());
+
+            let upstream = Upstream::new(&servers, Duration::from_secs(3))
+                .with_ip_blacklist("0.0.0.0/0".parse().unwrap());
+            assert!(upstream.query("baidu.com", QueryType::A).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,59 @@
+use super::UpstreamClient;
+use async_std::io::ReadExt;
+use async_trait::async_trait;
+use config::DnsTlsConfig;
+use isahc::config::CaCertificate;
+use isahc::http::Request;
+use isahc::HttpClient;
+use std::io;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// DNS over HTTPS (RFC 8484) client.
+///
+/// Connections are pooled and reused by the http client, which negotiates HTTP/2 through
+/// ALPN so concurrent queries are multiplexed on a single connection. The host in the url
+/// should be an IP, otherwise resolving it would go through seeker itself. The http client doesn't
+/// expose the certificates, so the config is rejected if `tls` has pins.
+pub(super) struct HttpsClient {
+    url: String,
+    client: HttpClient,
+}
+
+impl HttpsClient {
+    pub fn new(url: String, tls: &DnsTlsConfig) -> Self {
+        let mut builder = HttpClient::builder();
+        if let Some(ca_file) = &tls.ca_file {
+            builder = builder.ssl_ca_certificate(CaCertificate::file(ca_file));
+        }
+        HttpsClient {
+            url,
+            client: builder.build().expect("create http client"),
+        }
+    }
+}
+
+fn other_err<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+#[async_trait]
+impl UpstreamClient for HttpsClient {
+    async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let request = Request::post(&self.url)
+            .header("content-type", DNS_MESSAGE)
+            .header("accept", DNS_MESSAGE)
+            .body(query.to_vec())
+            .map_err(other_err)?;
+        let mut response = self.client.send_async(request).await.map_err(other_err)?;
+        if !response.status().is_success() {
+            return Err(other_err(format!(
+                "doh server responded {}",
+                response.status()
+            )));
+        }
+        let mut buf = vec![];
+        response.body_mut().read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+}
@@ -0,0 +1,310 @@
+use super::UpstreamClient;
+use async_native_tls::{Certificate, TlsConnector, TlsStream};
+use async_std::fs;
+use async_std::net::TcpStream;
+use async_std::sync::{channel, Mutex, Sender};
+use async_std::task;
+use async_trait::async_trait;
+use config::DnsTlsConfig;
+use futures_util::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use ring::digest;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::debug;
+
+type PendingQueries = Arc<std::sync::Mutex<HashMap<u16, Sender<Vec<u8>>>>>;
+
+/// DNS over TLS (RFC 7858) client.
+///
+/// A single TLS connection is kept open and reused. Queries are pipelined on it and responses
+/// are dispatched by their id, so they may arrive out of order. Every query gets a fresh id on the
+/// connection so that concurrent queries with the same id don't steal each other's response.
+///
+/// Besides the system roots, the certificate may be signed by the CA of `tls`, and its public key
+/// must match one of the pins of `tls` if there are any.
+pub(super) struct TlsClient {
+    addr: SocketAddr,
+    name: String,
+    tls: Arc<DnsTlsConfig>,
+    conn: Mutex<Option<Connection>>,
+}
+
+struct Connection {
+    writer: WriteHalf<TlsStream<TcpStream>>,
+    pending: PendingQueries,
+    closed: Arc<AtomicBool>,
+    next_id: u16,
+}
+
+/// Register a query under the next id not in use, returns that id.
+fn register(pending: &PendingQueries, next_id: &mut u16, sender: Sender<Vec<u8>>) -> u16 {
+    let mut pending = pending.lock().unwrap();
+    // Each query waits for its own response, so there are fewer pending queries than ids.
+    loop {
+        *next_id = next_id.wrapping_add(1);
+        if !pending.contains_key(next_id) {
+            pending.insert(*next_id, sender);
+            return *next_id;
+        }
+    }
+}
+
+/// Removes the pending query when the exchange ends, whether it's answered, fails, times out or is
+/// cancelled.
+struct PendingGuard {
+    pending: PendingQueries,
+    id: u16,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TlsClient {
+    pub fn new(addr: SocketAddr, name: String, tls: Arc<DnsTlsConfig>) -> Self {
+        TlsClient {
+            addr,
+            name,
+            tls,
+            conn: Mutex::new(None),
+        }
+    }
+
+    async fn connect(&self) -> io::Result<Connection> {
+        let mut connector = TlsConnector::new();
+        if let Some(ca_file) = &self.tls.ca_file {
+            let pem = fs::read(ca_file).await?;
+            connector =
+                connector.add_root_certificate(Certificate::from_pem(&pem).map_err(other_err)?);
+        }
+        let stream = TcpStream::connect(self.addr).await?;
+        let stream = connector
+            .connect(self.name.as_str(), stream)
+            .await
+            .map_err(other_err)?;
+        if !self.tls.pinned_spki.is_empty() {
+            let cert = stream
+                .peer_certificate()
+                .map_err(other_err)?
+                .ok_or_else(|| other_err("no certificate"))?
+                .to_der()
+                .map_err(other_err)?;
+            let spki = spki(&cert).ok_or_else(|| other_err("invalid certificate"))?;
+            let hash = digest::digest(&digest::SHA256, spki);
+            if !self
+                .tls
+                .pinned_spki
+                .iter()
+                .any(|pin| pin.as_slice() == hash.as_ref())
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("certificate of {} not pinned", self.name),
+                ));
+            }
+        }
+        let (reader, writer) = stream.split();
+        let pending = PendingQueries::default();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let pending_clone = pending.clone();
+        let closed_clone = closed.clone();
+        let addr = self.addr;
+        let _ = task::spawn(async move {
+            let ret = read_responses(reader, &pending_clone).await;
+            debug!(?addr, ?ret, "dns over tls connection closed");
+            closed_clone.store(true, Ordering::SeqCst);
+            // Dropping the senders wakes up queries waiting on this connection.
+            pending_clone.lock().unwrap().clear();
+        });
+
+        Ok(Connection {
+            writer,
+            pending,
+            closed,
+            next_id: 0,
+        })
+    }
+}
+
+fn other_err<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// The DER encoded SubjectPublicKeyInfo of a DER encoded X.509 certificate.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _) = der_element(cert)?;
+    let (_, mut tbs, _) = der_element(cert)?;
+    // Skip the optional version, serialNumber, signature, issuer, validity and subject.
+    let mut skip = 5;
+    if tbs.first() == Some(&0xa0) {
+        skip += 1;
+    }
+    for _ in 0..skip {
+        tbs = der_element(tbs)?.2;
+    }
+    let (element, _, _) = der_element(tbs)?;
+    Some(element)
+}
+
+/// Split the first element off `buf`, returns the element, its content and the rest.
+fn der_element(buf: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *buf.get(1)?;
+    let (header_len, len) = if first < 0x80 {
+        (2, first as usize)
+    } else {
+        let size = (first & 0x7f) as usize;
+        if size == 0 || size > 4 {
+            return None;
+        }
+        let len = buf
+            .get(2..2 + size)?
+            .iter()
+            .fold(0, |len, b| len << 8 | *b as usize);
+        (2 + size, len)
+    };
+    let end = header_len
+        .checked_add(len)
+        .filter(|end| *end <= buf.len())?;
+    Some((&buf[..end], &buf[header_len..end], &buf[end..]))
+}
+
+async fn read_responses(
+    mut reader: ReadHalf<TlsStream<TcpStream>>,
+    pending: &PendingQueries,
+) -> io::Result<()> {
+    loop {
+        let mut len = [0; 2];
+        reader.read_exact(&mut len).await?;
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        reader.read_exact(&mut buf).await?;
+        if buf.len() < 2 {
+            continue;
+        }
+        let id = u16::from_be_bytes([buf[0], buf[1]]);
+        let sender = pending.lock().unwrap().remove(&id);
+        if let Some(sender) = sender {
+            sender.send(buf).await;
+        }
+    }
+}
+
+#[async_trait]
+impl UpstreamClient for TlsClient {
+    async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        if query.len() < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "query too short",
+            ));
+        }
+        let (sender, receiver) = channel(1);
+
+        let _guard = {
+            let mut conn = self.conn.lock().await;
+            let reconnect = match &*conn {
+                Some(c) => c.closed.load(Ordering::SeqCst),
+                None => true,
+            };
+            if reconnect {
+                *conn = Some(self.connect().await?);
+            }
+            let c = conn.as_mut().expect("connection");
+            let id = register(&c.pending, &mut c.next_id, sender);
+            let guard = PendingGuard {
+                pending: c.pending.clone(),
+                id,
+            };
+            let frame = frame(query, id);
+            let ret = async {
+                c.writer.write_all(&frame).await?;
+                c.writer.flush().await
+            }
+            .await;
+            if let Err(e) = ret {
+                *conn = None;
+                return Err(e);
+            }
+            guard
+        };
+
+        let mut response = receiver.recv().await.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "dns over tls connection closed",
+            )
+        })?;
+        response[..2].copy_from_slice(&query[..2]);
+        Ok(response)
+    }
+}
+
+/// Length prefix `query` and replace its id with `id`.
+fn frame(query: &[u8], id: u16) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(query.len() + 2);
+    frame.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&query[2..]);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spki() {
+        let spki_der = [0x30, 0x02, 0x05, 0x00];
+        let mut tbs = vec![0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01];
+        tbs.extend(&[0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00]);
+        tbs.extend(&spki_der);
+        tbs.extend(&[0xa3, 0x00]);
+        let mut cert = vec![0x30, 0x81, tbs.len() as u8 + 2 + 5, 0x30, tbs.len() as u8];
+        cert.extend(&tbs);
+        cert.extend(&[0x30, 0x00, 0x03, 0x01, 0x00]);
+        assert_eq!(spki(&cert), Some(&spki_der[..]));
+
+        // Without the version.
+        let cert = [
+            0x30, 0x11, 0x30, 0x0f, 0x02, 0x01, 0x01, 0x30, 0x00, 0x30, 0x00, 0x30, 0x00, 0x30,
+            0x00, 0x30, 0x02, 0x05, 0x00,
+        ];
+        assert_eq!(spki(&cert), Some(&spki_der[..]));
+        assert_eq!(spki(&cert[..cert.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame(&[0x12, 0x34, 1, 2], 7), vec![0, 4, 0, 7, 1, 2]);
+    }
+
+    #[test]
+    fn test_pending_ids() {
+        let pending = PendingQueries::default();
+        let mut next_id = u16::MAX - 1;
+        let mut ids = vec![];
+        for _ in 0..3 {
+            let (sender, _) = channel(1);
+            ids.push(register(&pending, &mut next_id, sender));
+        }
+        assert_eq!(ids, vec![u16::MAX, 0, 1]);
+        let (sender, _) = channel(1);
+        next_id = u16::MAX - 1;
+        assert_eq!(register(&pending, &mut next_id, sender), 2);
+
+        {
+            let _guard = PendingGuard {
+                pending: pending.clone(),
+                id: 0,
+            };
+        }
+        let mut left: Vec<_> = pending.lock().unwrap().keys().copied().collect();
+        left.sort_unstable();
+        assert_eq!(left, vec![1, 2, u16::MAX]);
+    }
+}
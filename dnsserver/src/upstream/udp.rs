@@ -0,0 +1,38 @@
+use super::UpstreamClient;
+use async_std::net::UdpSocket;
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+
+pub(super) struct UdpClient {
+    addr: SocketAddr,
+}
+
+impl UdpClient {
+    pub fn new(addr: SocketAddr) -> Self {
+        UdpClient { addr }
+    }
+}
+
+#[async_trait]
+impl UpstreamClient for UdpClient {
+    async fn exchange(&self, query: &[u8]) -> io::Result<Vec<u8>> {
+        let bind_addr = if self.addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        // A new socket per query, so the source port is random.
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.send_to(query, self.addr).await?;
+        let mut buf = vec![0; 4096];
+        loop {
+            let (size, src) = socket.recv_from(&mut buf).await?;
+            // Ignore packets not from the server or not answering our query.
+            if src == self.addr && size >= 2 && buf[..2] == query[..2] {
+                buf.truncate(size);
+                return Ok(buf);
+            }
+        }
+    }
+}
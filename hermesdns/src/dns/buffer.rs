@@ -161,18 +161,23 @@ impl VectorPacketBuffer {
 
 impl PacketBuffer for VectorPacketBuffer {
     fn read(&mut self) -> Result<u8> {
-        let res = self.buffer[self.pos];
+        let res = self.get(self.pos)?;
         self.pos += 1;
 
         Ok(res)
     }
 
     fn get(&mut self, pos: usize) -> Result<u8> {
-        Ok(self.buffer[pos])
+        self.buffer
+            .get(pos)
+            .cloned()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "End of buffer"))
     }
 
     fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        Ok(&self.buffer[start..start + len as usize])
+        self.buffer
+            .get(start..start + len as usize)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "End of buffer"))
     }
 
     fn write(&mut self, val: u8) -> Result<()> {
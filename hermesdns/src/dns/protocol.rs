@@ -153,7 +153,7 @@ pub enum DnsRecord {
     OPT {
         packet_len: u16,
         flags: u32,
-        data: String,
+        data: Vec<u8>,
     }, // 41
 }
 
@@ -297,12 +297,8 @@ impl DnsRecord {
                 })
             }
             QueryType::OPT => {
-                let mut data = String::new();
-
                 let cur_pos = buffer.pos();
-                data.push_str(&String::from_utf8_lossy(
-                    buffer.get_range(cur_pos, data_len as usize)?,
-                ));
+                let data = buffer.get_range(cur_pos, data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::OPT {
@@ -485,7 +481,22 @@ impl DnsRecord {
                     buffer.write_u8(*b)?;
                 }
             }
-            DnsRecord::OPT { .. } => {}
+            DnsRecord::OPT {
+                packet_len,
+                flags,
+                ref data,
+            } => {
+                // The owner of an OPT record is always the root domain.
+                buffer.write_u8(0)?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(packet_len)?;
+                buffer.write_u32(flags)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
+            }
             DnsRecord::UNKNOWN { .. } => {
                 println!("Skipping record: {:?}", self);
             }
@@ -565,6 +576,21 @@ impl DnsRecord {
             DnsRecord::OPT { .. } => 0,
         }
     }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::TXT { ttl, .. } => *ttl = TransientTtl(new_ttl),
+            DnsRecord::OPT { .. } => {}
+        }
+    }
 }
 
 /// The result code for a DNS query, as described in the specification
@@ -986,9 +1012,29 @@ impl DnsPacket {
         None
     }
 
+    /// Parse a packet in wire format, eg. a response from an upstream server.
+    pub fn from_bytes(bytes: &[u8]) -> Result<DnsPacket> {
+        let mut buffer = VectorPacketBuffer::new();
+        buffer.buffer.extend_from_slice(bytes);
+        DnsPacket::from_buffer(&mut buffer)
+    }
+
+    /// Serialize the packet in wire format without size limit.
+    pub fn to_bytes(&mut self) -> Result<Vec<u8>> {
+        let mut buffer = VectorPacketBuffer::new();
+        self.write(&mut buffer, 0xFFFF)?;
+        Ok(buffer.buffer)
+    }
+
     pub fn write<T: PacketBuffer>(&mut self, buffer: &mut T, max_size: usize) -> Result<()> {
         let mut test_buffer = VectorPacketBuffer::new();
 
+        // The counts are recomputed below, reset them in case the packet was parsed or
+        // written before.
+        self.header.answers = 0;
+        self.header.authoritative_entries = 0;
+        self.header.resource_entries = 0;
+
         let mut size = self.header.binary_len();
         for question in &self.questions {
             size += question.binary_len();
@@ -1090,4 +1136,22 @@ mod tests {
         assert_eq!(packet.answers[2], parsed_packet.answers[2]);
         assert_eq!(packet.answers[3], parsed_packet.answers[3]);
     }
+
+    #[test]
+    fn test_opt_record() {
+        let mut packet = DnsPacket::new();
+        packet
+            .questions
+            .push(DnsQuestion::new("google.com".to_string(), QueryType::A));
+        packet.resources.push(DnsRecord::OPT {
+            packet_len: 4096,
+            flags: 0,
+            data: vec![0, 8, 0, 7, 0, 1, 24, 0, 1, 2, 3],
+        });
+
+        let parsed_packet = DnsPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(parsed_packet.header.resource_entries, 1);
+        assert_eq!(packet.resources[0], parsed_packet.resources[0]);
+    }
 }
@@ -0,0 +1,208 @@
+//! Dump of the plaintext of relayed tcp connections in the pcap format, so wireshark can
+//! decode the application protocol. Tcp segments are synthesized from the relayed data, they
+//! don't match the packets on the wire.
+
+use config::CaptureConfig;
+use parking_lot::Mutex;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+/// Link type of packets starting with the ip header.
+const LINKTYPE_RAW: u32 = 101;
+const TCP_FLAGS_PSH_ACK: u8 = 0x18;
+
+pub struct CaptureFile {
+    path: PathBuf,
+    source: SocketAddr,
+    destination: SocketAddr,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    file: File,
+    /// Sequence numbers of the next segment from the source and from the destination.
+    up_seq: u32,
+    down_seq: u32,
+}
+
+impl CaptureFile {
+    /// Start capturing connection `id` if `destination_name`, a domain or ip, is configured to be
+    /// captured. Errors are logged, the connection isn't captured then.
+    pub fn open(
+        config: &CaptureConfig,
+        id: u64,
+        destination_name: &str,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Option<Self> {
+        if !config.matches(destination_name) {
+            return None;
+        }
+        let path = config.dir.join(format!(
+            "{}-{}.pcap",
+            id,
+            destination_name.replace(':', "_")
+        ));
+        match Self::create(path.clone(), source, destination) {
+            Ok(capture) => Some(capture),
+            Err(e) => {
+                error!(?e, ?path, "create capture file");
+                None
+            }
+        }
+    }
+
+    fn create(path: PathBuf, source: SocketAddr, destination: SocketAddr) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(&path)?;
+        let mut header = vec![];
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        // Time zone and accuracy of timestamps.
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        // Max length of packets.
+        header.extend_from_slice(&65535u32.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        file.write_all(&header)?;
+        Ok(CaptureFile {
+            path,
+            source,
+            destination,
+            inner: Mutex::new(Inner {
+                file,
+                up_seq: 1,
+                down_seq: 1,
+            }),
+        })
+    }
+
+    /// Data sent from the local client to the remote.
+    pub fn up(&self, data: &[u8]) {
+        self.write(true, data)
+    }
+
+    /// Data sent from the remote to the local client.
+    pub fn down(&self, data: &[u8]) {
+        self.write(false, data)
+    }
+
+    fn write(&self, up: bool, data: &[u8]) {
+        let mut inner = self.inner.lock();
+        let (from, to, seq, ack) = if up {
+            (self.source, self.destination, inner.up_seq, inner.down_seq)
+        } else {
+            (self.destination, self.source, inner.down_seq, inner.up_seq)
+        };
+        // Segments are split so the ip total length fits in u16.
+        let mut offset = 0;
+        for chunk in data.chunks(65535 - 40) {
+            let packet = tcp_packet(from, to, seq.wrapping_add(offset), ack, chunk);
+            if let Err(e) = write_record(&mut inner.file, &packet) {
+                error!(?e, path = ?self.path, "write capture file");
+                return;
+            }
+            offset = offset.wrapping_add(chunk.len() as u32);
+        }
+        if up {
+            inner.up_seq = inner.up_seq.wrapping_add(offset);
+        } else {
+            inner.down_seq = inner.down_seq.wrapping_add(offset);
+        }
+    }
+}
+
+fn write_record(file: &mut File, packet: &[u8]) -> io::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut record = Vec::with_capacity(16 + packet.len());
+    record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+    record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(packet);
+    file.write_all(&record)
+}
+
+/// Ipv4 packet of a tcp segment carrying `payload`. Ipv6 addresses are written as `0.0.0.0`
+/// since the tun device only relays ipv4. The tcp checksum is left 0.
+fn tcp_packet(from: SocketAddr, to: SocketAddr, seq: u32, ack: u32, payload: &[u8]) -> Vec<u8> {
+    let ipv4 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+    };
+    let total_len = (40 + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(total_len as usize);
+    // Version 4 and 5 words of header, dscp.
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&total_len.to_be_bytes());
+    // Identification, don't fragment, ttl 64, protocol tcp and the checksum filled below.
+    packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+    packet.extend_from_slice(&ipv4(from).octets());
+    packet.extend_from_slice(&ipv4(to).octets());
+    let checksum = ipv4_checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(&from.port().to_be_bytes());
+    packet.extend_from_slice(&to.port().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    // 5 words of header, flags, window, checksum and urgent pointer.
+    packet.extend_from_slice(&[0x50, TCP_FLAGS_PSH_ACK, 0xff, 0xff, 0, 0, 0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_file() {
+        let dir = std::env::temp_dir().join(format!("seeker-capture-{}", std::process::id()));
+        let config = CaptureConfig {
+            destinations: vec!["example.com".to_string()],
+            dir: dir.clone(),
+        };
+        let source: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let destination: SocketAddr = "11.0.0.2:80".parse().unwrap();
+        assert!(CaptureFile::open(&config, 1, "other.com", source, destination).is_none());
+        let capture =
+            CaptureFile::open(&config, 1, "www.example.com", source, destination).unwrap();
+        capture.up(b"GET / HTTP/1.1\r\n\r\n");
+        capture.down(b"HTTP/1.1 200 OK\r\n\r\n");
+        capture.up(b"GET /a HTTP/1.1\r\n\r\n");
+
+        let data = fs::read(dir.join("1-www.example.com.pcap")).unwrap();
+        assert_eq!(data[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        // Global header, then the first record header and the ip header.
+        let packet = &data[24 + 16..];
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+        assert_eq!(packet[12..16], [10, 0, 0, 1]);
+        assert_eq!(&packet[40..58], b"GET / HTTP/1.1\r\n\r\n");
+        // The third segment continues the sequence of the first one.
+        let third = &data[24 + (16 + 40 + 18) + (16 + 40 + 19) + 16..];
+        assert_eq!(third[24..28], 19u32.to_be_bytes());
+        assert_eq!(third[28..32], 20u32.to_be_bytes());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,540 @@
+//! Subcommands talking to the controller of a running seeker. The controller is `host:port`,
+//! `https://host:port` if it serves https, or `unix:<path>`.
+
+use crate::connections::ConnectionInfo;
+use crate::controller::{NatSession, SelectServer};
+use crate::metrics::{DomainTraffic, TotalTraffic};
+use crate::server_chooser::ServerStatus;
+use anyhow::Context;
+use dnsserver::{CacheStats, QueryLogEntry};
+use std::fmt::Write;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_CONTROLLER: &str = "127.0.0.1:9000";
+pub const CONTROLLER_HELP: &str =
+    "Controller address of the running seeker, https://host:port for https, or unix:<path>";
+
+fn request(
+    method: &str,
+    controller: &str,
+    path: &str,
+    query: &[(&str, &str)],
+    body: Option<String>,
+) -> anyhow::Result<String> {
+    let token = std::env::var("SEEKER_TOKEN").ok();
+    if controller.starts_with("unix:") {
+        let mut target = path.to_string();
+        for (i, (key, value)) in query.iter().enumerate() {
+            target.push(if i == 0 { '?' } else { '&' });
+            target.push_str(&percent_encode(key));
+            target.push('=');
+            target.push_str(&percent_encode(value));
+        }
+        let (status, body) = unix_request(
+            &controller[5..],
+            method,
+            &target,
+            token.as_deref(),
+            body.as_deref(),
+        )
+        .with_context(|| format!("Connect to controller {} error", controller))?;
+        if status != 200 {
+            return Err(anyhow::anyhow!("Controller error: {}", body));
+        }
+        return Ok(body);
+    }
+    let url = if controller.starts_with("https://") {
+        format!("{}{}", controller, path)
+    } else {
+        format!("http://{}{}", controller, path)
+    };
+    let mut request = ureq::request(method, &url);
+    request.timeout_connect(5000).timeout_read(5000);
+    if let Some(token) = token {
+        request.set("Authorization", &format!("Bearer {}", token));
+    }
+    for (key, value) in query {
+        request.query(key, value);
+    }
+    let resp = match body {
+        Some(body) => request
+            .set("Content-Type", "application/json")
+            .send_string(&body),
+        None => request.call(),
+    };
+    if let Some(e) = resp.synthetic_error() {
+        return Err(anyhow::anyhow!(
+            "Connect to controller {} error: {}",
+            controller,
+            e
+        ));
+    }
+    let ok = resp.ok();
+    let body = resp
+        .into_string()
+        .context("Read controller response error")?;
+    if !ok {
+        return Err(anyhow::anyhow!("Controller error: {}", body));
+    }
+    Ok(body)
+}
+
+/// Send a request to the controller on the unix socket at `socket`, returns the status and the
+/// body. ureq only speaks tcp.
+#[cfg(unix)]
+fn unix_request(
+    socket: &str,
+    method: &str,
+    target: &str,
+    token: Option<&str>,
+    body: Option<&str>,
+) -> anyhow::Result<(u16, String)> {
+    use std::io::{Read, Write as _};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let body = body.unwrap_or_default();
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        target,
+        body.len()
+    );
+    if let Some(token) = token {
+        head.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    // The controller closes the connection after the response.
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .context("Invalid controller response")?;
+    let body = match response.find("\r\n\r\n") {
+        Some(pos) => response[pos + 4..].to_string(),
+        None => String::new(),
+    };
+    Ok((status, body))
+}
+
+#[cfg(not(unix))]
+fn unix_request(
+    _socket: &str,
+    _method: &str,
+    _target: &str,
+    _token: Option<&str>,
+    _body: Option<&str>,
+) -> anyhow::Result<(u16, String)> {
+    Err(anyhow::anyhow!("Unix sockets are only supported on unix"))
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Print recent dns queries, one per line.
+pub fn dns_log(controller: &str, domain: Option<&str>, limit: Option<&str>) -> anyhow::Result<()> {
+    let mut query = vec![];
+    if let Some(domain) = domain {
+        query.push(("domain", domain));
+    }
+    if let Some(limit) = limit {
+        query.push(("limit", limit));
+    }
+    let body = request("GET", controller, "/dns/log", &query, None)?;
+    let entries: Vec<QueryLogEntry> =
+        serde_json::from_str(&body).context("Parse controller response error")?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64;
+    for entry in entries {
+        let result = match &entry.error {
+            Some(e) => format!("error: {}", e),
+            None => entry.answers.join(", "),
+        };
+        println!(
+            "{:>5}s ago {:>5}ms {:<5} {} action={} source={} {}",
+            now.saturating_sub(entry.time) / 1000,
+            entry.latency_ms,
+            entry.qtype,
+            entry.domain,
+            entry.action.as_deref().unwrap_or("-"),
+            entry.source,
+            result,
+        );
+    }
+    Ok(())
+}
+
+/// Select the server named `name`, then print all servers.
+pub fn select_server(controller: &str, name: Option<&str>) -> anyhow::Result<()> {
+    let body = match name {
+        Some(name) => {
+            let select = SelectServer {
+                name: name.to_string(),
+            };
+            request(
+                "PUT",
+                controller,
+                "/servers/selected",
+                &[],
+                Some(serde_json::to_string(&select)?),
+            )?
+        }
+        None => request("GET", controller, "/servers", &[], None)?,
+    };
+    let servers: Vec<ServerStatus> =
+        serde_json::from_str(&body).context("Parse controller response error")?;
+    for server in servers {
+        let latency = match server.latency_ms {
+            Some(latency) => format!("{}ms", latency),
+            None => "-".to_string(),
+        };
+        println!(
+            "{} {:<20} {:<30} alive={} latency={} connections={}{}",
+            if server.active { "*" } else { " " },
+            server.name,
+            server.addr,
+            server.alive,
+            latency,
+            server.connections,
+            if server.selected { " (selected)" } else { "" },
+        );
+    }
+    Ok(())
+}
+
+/// Close the connection `kill` if set, otherwise print the open connections.
+pub fn connections(controller: &str, kill: Option<&str>) -> anyhow::Result<()> {
+    if let Some(id) = kill {
+        request(
+            "DELETE",
+            controller,
+            &format!("/connections/{}", id),
+            &[],
+            None,
+        )?;
+        println!("connection {} closed", id);
+        return Ok(());
+    }
+    let body = request("GET", controller, "/connections", &[], None)?;
+    let connections: Vec<ConnectionInfo> =
+        serde_json::from_str(&body).context("Parse controller response error")?;
+    for connection in connections {
+        println!(
+            "{:>6} {:>6}s {:<21} {:<40} {:<15} up={} down={} rule={}",
+            connection.id,
+            connection.age_secs,
+            connection.source,
+            connection.destination,
+            connection.outbound,
+            format_bytes(connection.upload),
+            format_bytes(connection.download),
+            connection.rule,
+        );
+    }
+    Ok(())
+}
+
+/// Print the sessions of the nat of the tun device.
+pub fn sessions(controller: &str) -> anyhow::Result<()> {
+    let body = request("GET", controller, "/sessions", &[], None)?;
+    let sessions: Vec<NatSession> =
+        serde_json::from_str(&body).context("Parse controller response error")?;
+    for session in sessions {
+        let destination = match &session.domain {
+            Some(domain) => format!("{} ({})", session.destination, domain),
+            None => session.destination.to_string(),
+        };
+        println!(
+            "{:>5} {:>5}s {:<21} {:<50} {}",
+            session.port,
+            session.idle_secs,
+            session.source,
+            destination,
+            format_bytes(session.bytes),
+        );
+    }
+    Ok(())
+}
+
+/// Print the domains with the most traffic.
+pub fn top_domains(controller: &str, limit: Option<&str>) -> anyhow::Result<()> {
+    let mut query = vec![];
+    if let Some(limit) = limit {
+        query.push(("limit", limit));
+    }
+    let body = request("GET", controller, "/traffic/domains", &query, None)?;
+    let domains: Vec<DomainTraffic> =
+        serde_json::from_str(&body).context("Parse controller response error")?;
+    for domain in domains {
+        println!(
+            "{:<40} total={:<9} up={:<9} down={}",
+            domain.domain,
+            format_bytes(domain.upload + domain.download),
+            format_bytes(domain.upload),
+            format_bytes(domain.download),
+        );
+    }
+    Ok(())
+}
+
+/// Rows of connections and dns queries shown by `top`.
+const TOP_CONNECTIONS: usize = 20;
+const TOP_QUERIES: usize = 8;
+
+/// State of the running seeker polled by `top`.
+struct TopSnapshot {
+    time: Instant,
+    traffic: TotalTraffic,
+    dns: CacheStats,
+    connections: Vec<ConnectionInfo>,
+    servers: Vec<ServerStatus>,
+    queries: Vec<QueryLogEntry>,
+}
+
+fn get<T: serde::de::DeserializeOwned>(
+    controller: &str,
+    path: &str,
+    query: &[(&str, &str)],
+) -> anyhow::Result<T> {
+    let body = request("GET", controller, path, query, None)?;
+    serde_json::from_str(&body).context("Parse controller response error")
+}
+
+fn top_snapshot(controller: &str) -> anyhow::Result<TopSnapshot> {
+    let limit = TOP_QUERIES.to_string();
+    Ok(TopSnapshot {
+        time: Instant::now(),
+        traffic: get(controller, "/traffic", &[])?,
+        dns: get(controller, "/dns/stats", &[])?,
+        connections: get(controller, "/connections", &[])?,
+        // There are no servers without shadowsocks servers configured.
+        servers: get(controller, "/servers", &[]).unwrap_or_default(),
+        queries: get(controller, "/dns/log", &[("limit", &limit)])?,
+    })
+}
+
+/// Redraw the throughput, connections, dns queries and servers every `interval` until killed.
+pub fn top(controller: &str, interval: Duration) -> anyhow::Result<()> {
+    let mut previous: Option<TopSnapshot> = None;
+    loop {
+        let screen = match top_snapshot(controller) {
+            Ok(snapshot) => {
+                let screen = render_top(&snapshot, previous.as_ref());
+                previous = Some(snapshot);
+                screen
+            }
+            Err(e) => format!("{:#}\n", e),
+        };
+        // Clear the screen and move the cursor to the top left.
+        print!("\x1b[2J\x1b[H{}", screen);
+        thread::sleep(interval);
+    }
+}
+
+fn render_top(snapshot: &TopSnapshot, previous: Option<&TopSnapshot>) -> String {
+    let rate = |current: u64, previous: u64, secs: f64| {
+        format!(
+            "{}/s",
+            format_bytes((current.saturating_sub(previous) as f64 / secs) as u64)
+        )
+    };
+    let (up, down, queries) = match previous {
+        Some(previous) => {
+            let secs = (snapshot.time - previous.time).as_secs_f64().max(0.001);
+            let queries = (snapshot.dns.hits + snapshot.dns.misses)
+                .saturating_sub(previous.dns.hits + previous.dns.misses);
+            (
+                rate(snapshot.traffic.upload, previous.traffic.upload, secs),
+                rate(snapshot.traffic.download, previous.traffic.download, secs),
+                format!("{:.1}/s", queries as f64 / secs),
+            )
+        }
+        None => ("-".to_string(), "-".to_string(), "-".to_string()),
+    };
+    let lookups = snapshot.dns.hits + snapshot.dns.misses;
+    let hit_rate = if lookups == 0 {
+        0.0
+    } else {
+        snapshot.dns.hits as f64 * 100.0 / lookups as f64
+    };
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "up {:<12} down {:<12} total up {} down {}",
+        up,
+        down,
+        format_bytes(snapshot.traffic.upload),
+        format_bytes(snapshot.traffic.download),
+    );
+    let _ = writeln!(
+        out,
+        "connections {:<6} dns queries {:<8} cache hits {:.1}% cache size {}",
+        snapshot.connections.len(),
+        queries,
+        hit_rate,
+        snapshot.dns.size,
+    );
+
+    if !snapshot.servers.is_empty() {
+        let _ = writeln!(
+            out,
+            "\n  {:<20} {:<6} {:>8} {:>6}",
+            "SERVER", "ALIVE", "LATENCY", "CONNS"
+        );
+        for server in &snapshot.servers {
+            let latency = match server.latency_ms {
+                Some(latency) => format!("{}ms", latency),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "{} {:<20} {:<6} {:>8} {:>6}{}",
+                if server.active { "*" } else { " " },
+                server.name,
+                server.alive,
+                latency,
+                server.connections,
+                if server.selected { " (selected)" } else { "" },
+            );
+        }
+    }
+
+    let mut connections: Vec<&ConnectionInfo> = snapshot.connections.iter().collect();
+    connections.sort_by_key(|c| std::cmp::Reverse(c.upload + c.download));
+    let _ = writeln!(
+        out,
+        "\n{:>6} {:>6} {:<40} {:<15} {:>9} {:>9}",
+        "ID", "AGE", "DESTINATION", "OUTBOUND", "UP", "DOWN"
+    );
+    for connection in connections.into_iter().take(TOP_CONNECTIONS) {
+        let _ = writeln!(
+            out,
+            "{:>6} {:>5}s {:<40} {:<15} {:>9} {:>9}",
+            connection.id,
+            connection.age_secs,
+            connection.destination,
+            connection.outbound,
+            format_bytes(connection.upload),
+            format_bytes(connection.download),
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "\n{:<5} {:>7} {:<40} ANSWERS",
+        "TYPE", "LATENCY", "DOMAIN"
+    );
+    for entry in snapshot.queries.iter().rev() {
+        let result = match &entry.error {
+            Some(e) => format!("error: {}", e),
+            None => entry.answers.join(", "),
+        };
+        let _ = writeln!(
+            out,
+            "{:<5} {:>5}ms {:<40} {}",
+            entry.qtype, entry.latency_ms, entry.domain, result
+        );
+    }
+    out
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(100), "100B");
+        assert_eq!(format_bytes(1536), "1.5KB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024 * 1024), "5120.0GB");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_request() {
+        use std::io::{Read, Write as _};
+        use std::os::unix::net::UnixListener;
+
+        let socket =
+            std::env::temp_dir().join(format!("seeker-controller-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let size = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n[]")
+                .unwrap();
+            String::from_utf8_lossy(&buf[..size]).to_string()
+        });
+
+        let controller = format!("unix:{}", socket.display());
+        let body = request("GET", &controller, "/dns/log", &[("domain", "a b")], None).unwrap();
+        assert_eq!(body, "[]");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("GET /dns/log?domain=a%20b HTTP/1.1\r\n"));
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[test]
+    fn test_render_top() {
+        let snapshot = |secs: u64, upload: u64, hits: u64| TopSnapshot {
+            time: Instant::now() + Duration::from_secs(secs),
+            traffic: TotalTraffic {
+                upload,
+                download: 0,
+            },
+            dns: CacheStats {
+                hits,
+                misses: 0,
+                size: 1,
+            },
+            connections: vec![],
+            servers: vec![],
+            queries: vec![],
+        };
+        let first = snapshot(0, 0, 0);
+        let second = snapshot(2, 4096, 10);
+        let screen = render_top(&second, Some(&first));
+        let mut lines = screen.lines();
+        assert!(lines.next().unwrap().starts_with("up 2.0KB/s"));
+        assert!(lines.next().unwrap().contains("dns queries 5.0/s"));
+        assert!(render_top(&first, None).starts_with("up -"));
+    }
+}
@@ -0,0 +1,36 @@
+use async_std::task::sleep;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Watches the modification time of the config file.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        ConfigWatcher { path, modified }
+    }
+
+    /// Resolves when the file is modified after the last change.
+    pub async fn changed(&mut self) {
+        loop {
+            sleep(POLL_INTERVAL).await;
+            let modified = modified(&self.path);
+            if modified.is_some() && modified != self.modified {
+                self.modified = modified;
+                return;
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).ok()?.modified().ok()
+}
@@ -0,0 +1,112 @@
+//! Cap on connections open to the servers, so a burst of new connections waits for old ones to
+//! close instead of opening sockets without bound.
+
+use async_std::io::timeout;
+use config::ConnectionLimitConfig;
+use futures_util::future::poll_fn;
+use parking_lot::Mutex;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Arc;
+use std::task::{Poll, Waker};
+use std::time::Duration;
+
+struct LimitState {
+    open: usize,
+    waiters: Vec<Waker>,
+}
+
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    max_connections: usize,
+    wait_timeout: Duration,
+    state: Arc<Mutex<LimitState>>,
+}
+
+impl ConnectionLimit {
+    pub fn new(config: ConnectionLimitConfig) -> Self {
+        ConnectionLimit {
+            max_connections: config.max_connections,
+            wait_timeout: config.wait_timeout,
+            state: Arc::new(Mutex::new(LimitState {
+                open: 0,
+                waiters: vec![],
+            })),
+        }
+    }
+
+    /// Wait until a connection can be opened, the returned permit is held until it's closed.
+    pub async fn acquire(&self) -> Result<ConnectionPermit> {
+        let acquire = poll_fn(|cx| {
+            let mut state = self.state.lock();
+            if state.open < self.max_connections {
+                state.open += 1;
+                Poll::Ready(Ok(ConnectionPermit {
+                    state: self.state.clone(),
+                }))
+            } else {
+                state.waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        });
+        timeout(self.wait_timeout, acquire).await.map_err(|e| {
+            if e.kind() == ErrorKind::TimedOut {
+                Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "{} server connections are open, waited {:?} for one to close",
+                        self.max_connections, self.wait_timeout
+                    ),
+                )
+            } else {
+                e
+            }
+        })
+    }
+}
+
+/// A connection counted against the limit until dropped.
+pub struct ConnectionPermit {
+    state: Arc<Mutex<LimitState>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.state.lock();
+            state.open -= 1;
+            std::mem::replace(&mut state.waiters, vec![])
+        };
+        // Wake all of them, the first may have timed out already.
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::{block_on, sleep, spawn};
+
+    #[test]
+    fn test_connection_limit() {
+        block_on(async {
+            let limit = ConnectionLimit::new(ConnectionLimitConfig {
+                max_connections: 2,
+                wait_timeout: Duration::from_millis(50),
+            });
+            let p1 = limit.acquire().await.unwrap();
+            let _p2 = limit.acquire().await.unwrap();
+            assert_eq!(limit.state.lock().open, 2);
+            let e = limit.acquire().await.err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::TimedOut);
+
+            let limit_clone = limit.clone();
+            let waiting = spawn(async move { limit_clone.acquire().await });
+            sleep(Duration::from_millis(10)).await;
+            drop(p1);
+            let _p3 = waiting.await.unwrap();
+            assert_eq!(limit.state.lock().open, 2);
+        });
+    }
+}
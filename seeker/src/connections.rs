@@ -0,0 +1,126 @@
+//! Registry of the relayed tcp connections, listed and closed by the controller.
+
+use crate::metrics::Traffic;
+use async_std::net::TcpStream;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    /// Address of the local client.
+    pub source: SocketAddr,
+    /// Domain or ip with port.
+    pub destination: String,
+    /// Server name, `DIRECT`, `socks5` or `http_proxy`.
+    pub outbound: String,
+    /// The matched rule, or why no rule is matched.
+    pub rule: String,
+    pub upload: u64,
+    pub download: u64,
+    pub age_secs: u64,
+}
+
+struct Entry {
+    source: SocketAddr,
+    destination: String,
+    outbound: String,
+    rule: String,
+    traffic: Arc<Traffic>,
+    started: Instant,
+    /// The local side of the relay, shut down to close the connection.
+    stream: TcpStream,
+}
+
+#[derive(Default)]
+pub struct Connections {
+    next_id: AtomicU64,
+    entries: Mutex<HashMap<u64, Entry>>,
+}
+
+/// A registered connection, removed from the registry when dropped.
+pub struct RegisteredConnection {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+impl RegisteredConnection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Bytes relayed by the connection.
+    pub fn traffic(&self) -> Arc<Traffic> {
+        self.connections.entries.lock()[&self.id].traffic.clone()
+    }
+}
+
+impl Drop for RegisteredConnection {
+    fn drop(&mut self) {
+        self.connections.entries.lock().remove(&self.id);
+    }
+}
+
+impl Connections {
+    pub fn register(
+        self: Arc<Self>,
+        source: SocketAddr,
+        destination: String,
+        outbound: String,
+        rule: String,
+        stream: TcpStream,
+    ) -> RegisteredConnection {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let entry = Entry {
+            source,
+            destination,
+            outbound,
+            rule,
+            traffic: Arc::new(Traffic::default()),
+            started: Instant::now(),
+            stream,
+        };
+        self.entries.lock().insert(id, entry);
+        RegisteredConnection {
+            id,
+            connections: self,
+        }
+    }
+
+    /// Open connections, the oldest first.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<ConnectionInfo> = self
+            .entries
+            .lock()
+            .iter()
+            .map(|(id, entry)| ConnectionInfo {
+                id: *id,
+                source: entry.source,
+                destination: entry.destination.clone(),
+                outbound: entry.outbound.clone(),
+                rule: entry.rule.clone(),
+                upload: entry.traffic.up.load(Ordering::Relaxed),
+                download: entry.traffic.down.load(Ordering::Relaxed),
+                age_secs: entry.started.elapsed().as_secs(),
+            })
+            .collect();
+        connections.sort_by_key(|connection| connection.id);
+        connections
+    }
+
+    /// Close the connection, returns false if there is no connection with `id`.
+    pub fn close(&self, id: u64) -> bool {
+        match self.entries.lock().get(&id) {
+            Some(entry) => {
+                let _ = entry.stream.shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,680 @@
+//! Http api for inspecting and controlling a running seeker, used by the `seeker` subcommands
+//! and Prometheus. Requests need `Authorization: Bearer <token>` if `token` is configured. Served
+//! over https if `tls` is configured, or on a unix socket.
+//!
+//! `GET /` serves a dashboard of the connections, traffic and servers, which asks for the token
+//! in the browser and uses the api. `GET /proxy.pac` serves a proxy auto-config file pointing to
+//! the local http and socks5 proxies, for devices of the LAN.
+//!
+//! Only the small subset of HTTP/1.1 needed by the api is supported: one request per connection
+//! and JSON bodies.
+
+use crate::connections::Connections;
+use crate::metrics::Metrics;
+use crate::pac::{generate_pac, PacProxy};
+use crate::server_chooser::ShadowsocksServerChooser;
+use async_native_tls::TlsAcceptor;
+use async_std::fs::File;
+use async_std::io::{timeout, Read, Write};
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::sync::Sender;
+use async_std::task::spawn;
+use config::rule::ProxyRules;
+use config::{ControllerConfig, ControllerTlsConfig};
+use dnsserver::resolver::RuleBasedDnsResolver;
+use dnsserver::Upstream;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use tun_nat::SessionManager;
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const DEFAULT_LOG_LIMIT: usize = 100;
+const DEFAULT_TOP_DOMAINS: usize = 20;
+const DASHBOARD: &str = include_str!("dashboard.html");
+/// Clients not sending the whole request, or not finishing the tls handshake, within it are
+/// closed, so they can't hold connections open.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    /// Header names are lowercase.
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json<T: Serialize>(value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Response {
+                status: 200,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Response::error(500, &e.to_string()),
+        }
+    }
+
+    pub fn text(body: String) -> Self {
+        Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body,
+        }
+    }
+
+    pub fn html(body: &str) -> Self {
+        Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn pac(body: String) -> Self {
+        Response {
+            status: 200,
+            content_type: "application/x-ns-proxy-autoconfig",
+            body,
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        #[derive(Serialize)]
+        struct Error<'a> {
+            error: &'a str,
+        }
+        Response {
+            status,
+            content_type: "application/json",
+            body: serde_json::to_string(&Error { error: message }).expect("serialize error"),
+        }
+    }
+}
+
+/// Body of `PUT /servers/selected`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelectServer {
+    pub name: String,
+}
+
+/// A session of the nat of the tun device, returned by `GET /sessions`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NatSession {
+    /// Port the relay sees the connection from.
+    pub port: u16,
+    /// Address of the application.
+    pub source: SocketAddr,
+    /// Original destination, usually a fake ip.
+    pub destination: SocketAddr,
+    /// Domain of the fake ip of `destination`.
+    pub domain: Option<String>,
+    pub idle_secs: u64,
+    pub bytes: u64,
+}
+
+pub struct Controller {
+    config: ControllerConfig,
+    resolver: RuleBasedDnsResolver,
+    upstream: Upstream,
+    rules: ProxyRules,
+    server_chooser: Option<Arc<ShadowsocksServerChooser>>,
+    metrics: Arc<Metrics>,
+    connections: Arc<Connections>,
+    session_manager: SessionManager,
+    reload_requested: Sender<()>,
+    pac_proxies: Vec<PacProxy>,
+}
+
+impl Controller {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: ControllerConfig,
+        resolver: RuleBasedDnsResolver,
+        upstream: Upstream,
+        rules: ProxyRules,
+        server_chooser: Option<Arc<ShadowsocksServerChooser>>,
+        metrics: Arc<Metrics>,
+        connections: Arc<Connections>,
+        session_manager: SessionManager,
+        reload_requested: Sender<()>,
+        pac_proxies: Vec<PacProxy>,
+    ) -> Self {
+        Controller {
+            config,
+            resolver,
+            upstream,
+            rules,
+            server_chooser,
+            metrics,
+            connections,
+            session_manager,
+            reload_requested,
+            pac_proxies,
+        }
+    }
+
+    pub async fn run(self: Arc<Self>) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = self.config.unix_socket() {
+                let path = path.to_string();
+                return self.run_unix(&path).await;
+            }
+        }
+        let acceptor = match &self.config.tls {
+            Some(tls) => Some(Arc::new(tls_acceptor(tls).await?)),
+            None => None,
+        };
+        let addr = self.config.addr.clone();
+        let listener = TcpListener::bind(&addr).await?;
+        info!(%addr, tls = acceptor.is_some(), "controller listening");
+        if !listener.local_addr()?.ip().is_loopback() {
+            if self.config.token.is_none() {
+                warn!(%addr, "controller is reachable from the network without a token");
+            } else if acceptor.is_none() {
+                warn!(%addr, "controller token is sent over the network in plain text");
+            }
+        }
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = stream?;
+            let controller = self.clone();
+            let acceptor = acceptor.clone();
+            spawn(async move {
+                if let Err(e) = controller.serve_tcp(stream, acceptor.as_deref()).await {
+                    debug!(?e, "controller serve error");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Serve on the unix socket at `path`, a stale socket left there is replaced.
+    #[cfg(unix)]
+    async fn run_unix(self: Arc<Self>, path: &str) -> io::Result<()> {
+        use async_std::os::unix::net::UnixListener;
+        use std::fs::{remove_file, set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let _ = remove_file(path);
+        let listener = UnixListener::bind(path).await?;
+        set_permissions(path, Permissions::from_mode(0o600))?;
+        info!(path, "controller listening");
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = stream?;
+            let controller = self.clone();
+            spawn(async move {
+                let local_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+                if let Err(e) = controller.serve(stream, local_ip).await {
+                    debug!(?e, "controller serve error");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn serve_tcp(&self, stream: TcpStream, acceptor: Option<&TlsAcceptor>) -> io::Result<()> {
+        let local_ip = stream.local_addr()?.ip();
+        match acceptor {
+            Some(acceptor) => {
+                let stream = timeout(REQUEST_TIMEOUT, async {
+                    acceptor
+                        .accept(stream)
+                        .await
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+                })
+                .await?;
+                self.serve(stream, local_ip).await
+            }
+            None => self.serve(stream, local_ip).await,
+        }
+    }
+
+    /// `local_ip` is the address the client connected to.
+    async fn serve<S: Read + Write + Unpin>(
+        &self,
+        mut stream: S,
+        local_ip: IpAddr,
+    ) -> io::Result<()> {
+        let response = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+            Ok(request) => self.handle(&request, local_ip).await,
+            Err(e) => Response::error(400, &e.to_string()),
+        };
+        write_response(&mut stream, &response).await
+    }
+
+    /// `local_ip` is the address the client connected to.
+    async fn handle(&self, request: &Request, local_ip: IpAddr) -> Response {
+        // The page holds no data, so it's served without the token.
+        if request.method == "GET" && request.path == "/" {
+            return Response::html(DASHBOARD);
+        }
+        // Devices fetching PAC files can't send the token.
+        if request.method == "GET" && request.path == "/proxy.pac" {
+            return self.pac(local_ip);
+        }
+        if let Some(token) = &self.config.token {
+            let authorization = request
+                .headers
+                .get("authorization")
+                .map(String::as_str)
+                .unwrap_or_default();
+            if !constant_time_eq(
+                authorization.as_bytes(),
+                format!("Bearer {}", token).as_bytes(),
+            ) {
+                return Response::error(401, "invalid token");
+            }
+        }
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/dns/log") => self.dns_log(request),
+            ("GET", "/dns/stats") => Response::json(&self.upstream.cache_stats()),
+            ("GET", "/servers") => match &self.server_chooser {
+                Some(chooser) => Response::json(&chooser.servers_status()),
+                None => Response::error(404, "no shadowsocks servers"),
+            },
+            ("PUT", "/servers/selected") => self.select_server(request),
+            ("GET", "/rules") => {
+                let rules: Vec<String> = self
+                    .rules
+                    .rules()
+                    .iter()
+                    .map(|rule| rule.to_string())
+                    .collect();
+                Response::json(&rules)
+            }
+            ("POST", "/reload") => {
+                self.reload_requested.send(()).await;
+                Response::json(&HashMap::<String, String>::new())
+            }
+            ("GET", "/traffic") => Response::json(&self.metrics.total_traffic()),
+            ("GET", "/traffic/domains") => {
+                match request.query.get("limit").map(|l| l.parse::<usize>()) {
+                    Some(Ok(limit)) => Response::json(&self.metrics.top_domains(limit)),
+                    Some(Err(_)) => Response::error(400, "invalid limit"),
+                    None => Response::json(&self.metrics.top_domains(DEFAULT_TOP_DOMAINS)),
+                }
+            }
+            ("GET", "/connections") => Response::json(&self.connections.list()),
+            ("GET", "/sessions") => Response::json(&self.nat_sessions()),
+            ("DELETE", path) if path.starts_with("/connections/") => {
+                match path["/connections/".len()..].parse() {
+                    Ok(id) if self.connections.close(id) => {
+                        Response::json(&HashMap::<String, String>::new())
+                    }
+                    _ => Response::error(404, "unknown connection"),
+                }
+            }
+            ("GET", "/metrics") => {
+                let servers = self
+                    .server_chooser
+                    .as_ref()
+                    .map(|chooser| chooser.servers_status())
+                    .unwrap_or_default();
+                Response::text(self.metrics.render(
+                    &self.upstream.cache_stats(),
+                    &servers,
+                    &self.session_manager.stats(),
+                ))
+            }
+            _ => Response::error(404, "not found"),
+        }
+    }
+
+    fn pac(&self, local_ip: IpAddr) -> Response {
+        if self.pac_proxies.is_empty() {
+            return Response::error(404, "no http or socks5 proxy without authentication");
+        }
+        Response::pac(generate_pac(
+            &self.rules.rules(),
+            &self.rules.default_action(),
+            &self.pac_proxies,
+            local_ip,
+        ))
+    }
+
+    fn select_server(&self, request: &Request) -> Response {
+        let chooser = match &self.server_chooser {
+            Some(chooser) => chooser,
+            None => return Response::error(404, "no shadowsocks servers"),
+        };
+        let select: SelectServer = match serde_json::from_str(&request.body) {
+            Ok(select) => select,
+            Err(e) => return Response::error(400, &e.to_string()),
+        };
+        if !chooser.select(&select.name) {
+            return Response::error(404, &format!("unknown server: {}", select.name));
+        }
+        Response::json(&chooser.servers_status())
+    }
+
+    /// Sessions of the nat, the most recently active first.
+    fn nat_sessions(&self) -> Vec<NatSession> {
+        let mut sessions = self.session_manager.sessions();
+        sessions.sort_by_key(|session| session.idle);
+        sessions
+            .into_iter()
+            .map(|session| NatSession {
+                port: session.port,
+                source: session.src,
+                destination: session.dest,
+                domain: self.resolver.lookup_host(&session.dest.ip().to_string()),
+                idle_secs: session.idle.as_secs(),
+                bytes: session.bytes,
+            })
+            .collect()
+    }
+
+    /// The most recent queries, filtered by `domain` if set. At most `limit` entries are
+    /// returned, from the oldest to the newest.
+    fn dns_log(&self, request: &Request) -> Response {
+        let limit = match request.query.get("limit").map(|l| l.parse::<usize>()) {
+            Some(Ok(limit)) => limit,
+            Some(Err(_)) => return Response::error(400, "invalid limit"),
+            None => DEFAULT_LOG_LIMIT,
+        };
+        let mut entries = self.resolver.query_log();
+        if let Some(domain) = request.query.get("domain") {
+            entries.retain(|entry| entry.domain.contains(domain.as_str()));
+        }
+        let skip = entries.len().saturating_sub(limit);
+        Response::json(&entries[skip..])
+    }
+}
+
+async fn tls_acceptor(tls: &ControllerTlsConfig) -> io::Result<TlsAcceptor> {
+    let file = File::open(&tls.pkcs12).await?;
+    TlsAcceptor::new(file, &tls.password)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+async fn read_request<S: Read + Unpin>(stream: &mut S) -> io::Result<Request> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let header_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(invalid("request too large"));
+        }
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(invalid("unexpected eof"));
+        }
+        buf.extend_from_slice(&chunk[..size]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..header_end]).to_string();
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line
+        .next()
+        .ok_or_else(|| invalid("invalid request"))?;
+
+    let headers: HashMap<String, String> = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut header = line.splitn(2, ':');
+            let name = header.next()?.trim().to_lowercase();
+            let value = header.next()?.trim().to_string();
+            Some((name, value))
+        })
+        .collect();
+    let content_length = headers
+        .get("content-length")
+        .map(|value| value.parse::<usize>())
+        .unwrap_or(Ok(0))
+        .map_err(|_| invalid("invalid content-length"))?;
+    let request_end = header_end + content_length;
+    if request_end > MAX_REQUEST_SIZE {
+        return Err(invalid("request too large"));
+    }
+    while buf.len() < request_end {
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(invalid("unexpected eof"));
+        }
+        buf.extend_from_slice(&chunk[..size]);
+    }
+    let body = String::from_utf8_lossy(&buf[header_end..request_end]).to_string();
+
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap_or_default().to_string();
+    let query = target.next().map(parse_query).unwrap_or_default();
+    Ok(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    })
+}
+
+async fn write_response<S: Write + Unpin>(stream: &mut S, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.flush().await
+}
+
+/// Compares every byte, so the time taken doesn't tell how much of a guessed token is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut pair = pair.splitn(2, '=');
+            let key = percent_decode(pair.next().unwrap_or_default());
+            let value = percent_decode(pair.next().unwrap_or_default());
+            (key, value)
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = |b: u8| (b as char).to_digit(16);
+                match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                    (Some(h), Some(l)) => {
+                        decoded.push((h * 16 + l) as u8);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::sync::channel;
+    use async_std::task::{block_on, sleep};
+    use config::{AaaaStrategy, Hosts};
+    use std::path::Path;
+
+    async fn new_controller(config: ControllerConfig, db: &Path) -> Arc<Controller> {
+        let upstream = Upstream::new(&[], Duration::from_secs(1));
+        let rules = ProxyRules::new(vec![]);
+        let resolver = RuleBasedDnsResolver::new(
+            db,
+            1..u32::MAX,
+            1,
+            rules.clone(),
+            Hosts::default(),
+            AaaaStrategy::Drop,
+            upstream.clone(),
+        )
+        .await;
+        let (reload_requested, _) = channel(1);
+        Arc::new(Controller::new(
+            config,
+            resolver,
+            upstream,
+            rules,
+            None,
+            Arc::new(Metrics::default()),
+            Arc::new(Connections::default()),
+            SessionManager::new(10),
+            reload_requested,
+            vec![],
+        ))
+    }
+
+    /// The status line of the response to `GET /rules`.
+    async fn request_rules<S: Read + Write + Unpin>(mut stream: S, token: Option<&str>) -> String {
+        let authorization = token
+            .map(|token| format!("Authorization: Bearer {}\r\n", token))
+            .unwrap_or_default();
+        let request = format!("GET /rules HTTP/1.1\r\n{}\r\n", authorization);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut buf = vec![0; 1024];
+        let mut len = 0;
+        while !buf[..len].windows(2).any(|w| w == b"\r\n") {
+            let size = stream.read(&mut buf[len..]).await.unwrap();
+            assert_ne!(size, 0);
+            len += size;
+        }
+        let response = String::from_utf8_lossy(&buf[..len]);
+        response.lines().next().unwrap().to_string()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_token() {
+        use async_std::os::unix::net::UnixStream;
+
+        let dir =
+            std::env::temp_dir().join(format!("seeker-controller-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("controller.sock").display().to_string();
+        block_on(async {
+            let config = ControllerConfig {
+                addr: format!("unix:{}", socket),
+                token: Some("secret".to_string()),
+                tls: None,
+            };
+            let controller = new_controller(config, &dir.join("db")).await;
+            spawn(controller.run());
+            let connect = || async {
+                loop {
+                    match UnixStream::connect(&socket).await {
+                        Ok(stream) => break stream,
+                        Err(_) => sleep(Duration::from_millis(10)).await,
+                    }
+                }
+            };
+            for (token, status) in vec![
+                (None, "HTTP/1.1 401 Unauthorized"),
+                (Some("wrong"), "HTTP/1.1 401 Unauthorized"),
+                (Some("secret"), "HTTP/1.1 200 OK"),
+            ] {
+                assert_eq!(request_rules(connect().await, token).await, status);
+            }
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_tls_token() {
+        let dir =
+            std::env::temp_dir().join(format!("seeker-controller-tls-{}", std::process::id()));
+        block_on(async {
+            let tls = ControllerTlsConfig {
+                pkcs12: Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/controller.p12"),
+                password: "seeker".to_string(),
+            };
+            let config = ControllerConfig {
+                addr: "127.0.0.1:0".to_string(),
+                token: Some("secret".to_string()),
+                tls: Some(tls.clone()),
+            };
+            let controller = new_controller(config, &dir.join("db")).await;
+            let acceptor = tls_acceptor(&tls).await.unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn(async move {
+                let mut incoming = listener.incoming();
+                while let Some(stream) = incoming.next().await {
+                    let _ = controller.serve_tcp(stream.unwrap(), Some(&acceptor)).await;
+                }
+            });
+            let connector = async_native_tls::TlsConnector::new().danger_accept_invalid_certs(true);
+            for (token, status) in vec![
+                (None, "HTTP/1.1 401 Unauthorized"),
+                (Some("wrong"), "HTTP/1.1 401 Unauthorized"),
+                (Some("secret"), "HTTP/1.1 200 OK"),
+            ] {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let stream = connector.connect("localhost", stream).await.unwrap();
+                assert_eq!(request_rules(stream, token).await, status);
+            }
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"Bearer secret", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer secreT", b"Bearer secret"));
+        assert!(!constant_time_eq(b"Bearer", b"Bearer secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query("domain=google.com&limit=10&q=a%20b+c&empty=");
+        assert_eq!(query["domain"], "google.com");
+        assert_eq!(query["limit"], "10");
+        assert_eq!(query["q"], "a b c");
+        assert_eq!(query["empty"], "");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+}
@@ -1,61 +1,82 @@
-use async_std_resolver::config::{
-    NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
-};
-use async_std_resolver::{resolver, AsyncStdResolver};
-use config::Address;
+use async_std::task::sleep;
+use config::{Address, Hosts};
+use dnsserver::Upstream;
+use parking_lot::RwLock;
+use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Result};
 use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
+use tracing::{error, info};
 
 #[derive(Clone)]
 pub struct DnsClient {
-    resolver: AsyncStdResolver,
+    upstream: Upstream,
+    hosts: Hosts,
+    /// Resolved addresses of servers configured by domain name.
+    server_ips: Arc<RwLock<HashMap<String, ServerIps>>>,
 }
 
-impl DnsClient {
-    pub async fn new(dns_servers: &[SocketAddr], timeout: Duration) -> Self {
-        let mut name_servers = NameServerConfigGroup::with_capacity(dns_servers.len());
-
-        for addr in dns_servers {
-            let udp = NameServerConfig {
-                socket_addr: *addr,
-                protocol: Protocol::Udp,
-                tls_dns_name: None,
-            };
-            name_servers.push(udp);
+/// Addresses of a server, connections go to `ips[current]` until connecting to it fails.
+#[derive(Debug, PartialEq)]
+struct ServerIps {
+    ips: Vec<IpAddr>,
+    current: usize,
+}
+
+impl ServerIps {
+    fn new(ips: Vec<IpAddr>) -> Self {
+        ServerIps { ips, current: 0 }
+    }
+
+    fn current(&self) -> IpAddr {
+        self.ips[self.current]
+    }
+
+    /// Fail over to the next address, unless another connection has done it already.
+    fn failed(&mut self, ip: IpAddr) {
+        if self.current() == ip {
+            self.current = (self.current + 1) % self.ips.len();
         }
+    }
 
-        let num_concurrent_reqs = name_servers.len();
-
-        // Construct a new Resolver with default configuration options
-        let resolver = resolver(
-            ResolverConfig::from_parts(None, Vec::new(), name_servers),
-            ResolverOpts {
-                timeout,
-                num_concurrent_reqs,
-                ..Default::default()
-            },
-        )
-        .await
-        .expect("failed to create resolver");
-
-        DnsClient { resolver }
+    /// Replace the addresses, keeping the current one if it is still returned.
+    fn refreshed(&self, ips: Vec<IpAddr>) -> Self {
+        let current = ips.iter().position(|ip| *ip == self.current()).unwrap_or(0);
+        ServerIps { ips, current }
     }
+}
 
-    pub fn resolver(&self) -> AsyncStdResolver {
-        self.resolver.clone()
+impl DnsClient {
+    pub fn new(upstream: Upstream, hosts: Hosts) -> Self {
+        DnsClient {
+            upstream,
+            hosts,
+            server_ips: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn upstream(&self) -> Upstream {
+        self.upstream.clone()
     }
+
     pub async fn lookup(&self, domain: &str) -> Result<IpAddr> {
-        let response = self
-            .resolver
-            .lookup_ip(domain)
-            .await
-            .map_err(|_| Error::new(ErrorKind::NotFound, format!("{} not resolved", domain)))?;
-        response
-            .iter()
-            .next()
-            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} not resolved", domain)))
+        Ok(self.lookup_all(domain).await?[0])
+    }
+
+    async fn lookup_all(&self, domain: &str) -> Result<Vec<IpAddr>> {
+        if let Some(ip) = self.hosts.get(domain) {
+            return Ok(vec![ip]);
+        }
+        let ips = self.upstream.lookup_ip(domain).await?;
+        if ips.is_empty() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("no address of {}", domain),
+            ));
+        }
+        Ok(ips)
     }
 
     pub async fn lookup_address(&self, addr: &Address) -> Result<SocketAddr> {
@@ -67,4 +88,85 @@ impl DnsClient {
             }
         }
     }
+
+    /// Like `lookup_address`, for server addresses. A domain name is resolved once and then
+    /// refreshed by `refresh_servers_forever`.
+    pub async fn lookup_server(&self, addr: &Address) -> Result<SocketAddr> {
+        let (domain, port) = match addr {
+            Address::SocketAddress(a) => return Ok(*a),
+            Address::DomainNameAddress(domain, port) => (domain, *port),
+        };
+        if let Some(ips) = self.server_ips.read().get(domain) {
+            return Ok(SocketAddr::new(ips.current(), port));
+        }
+        let ips = self.lookup_all(domain).await?;
+        let ip = self
+            .server_ips
+            .write()
+            .entry(domain.clone())
+            .or_insert_with(|| ServerIps::new(ips))
+            .current();
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    /// Connecting to `failed`, resolved from the server address `addr`, has failed. Following
+    /// connections use the next address of the server.
+    pub fn server_failed(&self, addr: &Address, failed: SocketAddr) {
+        if let Address::DomainNameAddress(domain, _) = addr {
+            if let Some(ips) = self.server_ips.write().get_mut(domain) {
+                ips.failed(failed.ip());
+            }
+        }
+    }
+
+    /// Resolve the domain names of servers again every `interval`, so servers changing their
+    /// ips keep working.
+    pub async fn refresh_servers_forever(&self, interval: Duration) {
+        loop {
+            sleep(interval).await;
+            let domains: Vec<String> = self.server_ips.read().keys().cloned().collect();
+            for domain in domains {
+                let ips = match self.lookup_all(&domain).await {
+                    Ok(ips) => ips,
+                    Err(e) => {
+                        // Keep using the resolved addresses.
+                        error!(?e, %domain, "refresh server address");
+                        continue;
+                    }
+                };
+                let mut server_ips = self.server_ips.write();
+                if let Some(old) = server_ips.get_mut(&domain) {
+                    let new = old.refreshed(ips);
+                    if new.current() != old.current() {
+                        info!(%domain, ip = %new.current(), "server address changed");
+                    }
+                    *old = new;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_ips() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let mut ips = ServerIps::new(vec![ip("1.1.1.1"), ip("2.2.2.2")]);
+        ips.failed(ip("1.1.1.1"));
+        assert_eq!(ips.current(), ip("2.2.2.2"));
+        // Another connection to the failed address doesn't switch again.
+        ips.failed(ip("1.1.1.1"));
+        assert_eq!(ips.current(), ip("2.2.2.2"));
+        ips.failed(ip("2.2.2.2"));
+        assert_eq!(ips.current(), ip("1.1.1.1"));
+
+        ips.failed(ip("1.1.1.1"));
+        let refreshed = ips.refreshed(vec![ip("3.3.3.3"), ip("2.2.2.2")]);
+        assert_eq!(refreshed.current(), ip("2.2.2.2"));
+        let refreshed = ips.refreshed(vec![ip("3.3.3.3"), ip("4.4.4.4")]);
+        assert_eq!(refreshed.current(), ip("3.3.3.3"));
+    }
 }
@@ -0,0 +1,109 @@
+//! Dns queries to any resolver redirected to the embedded dns server, so the domains of apps with
+//! hard-coded resolvers get fake ips and match the rules too. The embedded server only serves udp,
+//! queries over tcp are relayed to it one by one.
+
+use async_std::io::timeout;
+use async_std::net::{TcpStream, UdpSocket};
+use async_std::prelude::*;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// The address to reach the dns server listening on `dns_listen` from this host.
+pub fn local_dns_addr(dns_listen: &str) -> io::Result<SocketAddr> {
+    let addr: SocketAddr = dns_listen
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    Ok(SocketAddr::new(ip, addr.port()))
+}
+
+/// Answer the length prefixed queries of `conn` by the udp dns server at `dns_addr`, until `conn`
+/// is closed.
+pub async fn relay_tcp_queries(
+    mut conn: TcpStream,
+    dns_addr: SocketAddr,
+    read_timeout: Duration,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(match dns_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    })
+    .await?;
+    socket.connect(dns_addr).await?;
+    let mut buf = vec![0; 65536];
+    loop {
+        let mut len = [0; 2];
+        match timeout(read_timeout, conn.read_exact(&mut len)).await {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            ret => ret?,
+        }
+        let len = usize::from(u16::from_be_bytes(len));
+        timeout(read_timeout, conn.read_exact(&mut buf[..len])).await?;
+        socket.send(&buf[..len]).await?;
+        let size = timeout(read_timeout, socket.recv(&mut buf)).await?;
+        conn.write_all(&(size as u16).to_be_bytes()).await?;
+        conn.write_all(&buf[..size]).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task::{block_on, spawn};
+
+    #[test]
+    fn test_local_dns_addr() {
+        assert_eq!(
+            local_dns_addr("0.0.0.0:53").unwrap(),
+            "127.0.0.1:53".parse().unwrap()
+        );
+        assert_eq!(
+            local_dns_addr("192.168.1.1:5353").unwrap(),
+            "192.168.1.1:5353".parse().unwrap()
+        );
+        assert!(local_dns_addr("localhost:53").is_err());
+    }
+
+    #[test]
+    fn test_relay_tcp_queries() {
+        block_on(async {
+            let dns = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let dns_addr = dns.local_addr().unwrap();
+            spawn(async move {
+                let mut buf = [0; 512];
+                loop {
+                    let (size, peer) = dns.recv_from(&mut buf).await.unwrap();
+                    buf[..size].reverse();
+                    dns.send_to(&buf[..size], peer).await.unwrap();
+                }
+            });
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            spawn(async move {
+                let (conn, _) = listener.accept().await.unwrap();
+                relay_tcp_queries(conn, dns_addr, Duration::from_secs(1))
+                    .await
+                    .unwrap();
+            });
+
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            for query in &[&b"abc"[..], &b"query"[..]] {
+                conn.write_all(&(query.len() as u16).to_be_bytes())
+                    .await
+                    .unwrap();
+                conn.write_all(query).await.unwrap();
+                let mut reply = vec![0; 2 + query.len()];
+                conn.read_exact(&mut reply).await.unwrap();
+                let mut expected = (query.len() as u16).to_be_bytes().to_vec();
+                expected.extend(query.iter().rev());
+                assert_eq!(reply, expected);
+            }
+        });
+    }
+}
@@ -0,0 +1,179 @@
+//! Local http proxy, for apps and hosts of the LAN preferring an explicit proxy to the tun device.
+//! `CONNECT` tunnels and plain http requests with absolute URIs are relayed by the same rules as
+//! connections through the tun device.
+
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use config::Address;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const CONNECT_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection established\r\n\r\n";
+pub const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+pub const FORBIDDEN: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+pub const BAD_GATEWAY: &[u8] =
+    b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// A request read from a client of the proxy.
+#[derive(Debug, PartialEq)]
+pub struct ProxyRequest {
+    pub host: Address,
+    /// Written to the client once the host is connected.
+    pub reply: &'static [u8],
+    /// Written to the host before relaying the rest of the connection.
+    pub first_data: Vec<u8>,
+}
+
+pub async fn read_request(stream: &mut TcpStream) -> io::Result<ProxyRequest> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let head_end = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(invalid("request too large"));
+        }
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(invalid("unexpected eof"));
+        }
+        buf.extend_from_slice(&chunk[..size]);
+    };
+    parse_request(&buf[..head_end], &buf[head_end..])
+}
+
+/// Parse the head of a request, `rest` is read after it. Plain http requests are rewritten to
+/// the origin form and the connection is closed after the response, so following requests to
+/// other hosts are sent through new connections.
+fn parse_request(head: &[u8], rest: &[u8]) -> io::Result<ProxyRequest> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line
+        .next()
+        .ok_or_else(|| invalid("invalid request"))?;
+    let version = request_line.next().unwrap_or("HTTP/1.1");
+
+    if method == "CONNECT" {
+        return Ok(ProxyRequest {
+            host: parse_authority(target, 443)?,
+            reply: CONNECT_ESTABLISHED,
+            first_data: rest.to_vec(),
+        });
+    }
+
+    if !target.starts_with("http://") {
+        return Err(invalid("only absolute http uris are supported"));
+    }
+    let target = &target["http://".len()..];
+    let (authority, path) = match target.find('/') {
+        Some(pos) => (&target[..pos], &target[pos..]),
+        None => (target, "/"),
+    };
+    let mut first_data = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.splitn(2, ':').next().unwrap_or_default();
+        let name = name.trim().to_lowercase();
+        if name.starts_with("proxy-") || name == "connection" || name == "keep-alive" {
+            continue;
+        }
+        first_data.push_str(line);
+        first_data.push_str("\r\n");
+    }
+    first_data.push_str("Connection: close\r\n\r\n");
+    let mut first_data = first_data.into_bytes();
+    first_data.extend_from_slice(rest);
+    Ok(ProxyRequest {
+        host: parse_authority(authority, 80)?,
+        reply: b"",
+        first_data,
+    })
+}
+
+/// Parse `host[:port]`, IPv6 addresses are enclosed in brackets.
+fn parse_authority(authority: &str, default_port: u16) -> io::Result<Address> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Ok(Address::SocketAddress(addr));
+    }
+    let (host, port) = match authority.rfind(':') {
+        Some(pos) if !authority[pos..].contains(']') => {
+            let port = authority[pos + 1..]
+                .parse()
+                .map_err(|_| invalid("invalid port"))?;
+            (&authority[..pos], port)
+        }
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid("empty host"));
+    }
+    Ok(match host.parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, port)),
+        Err(_) => Address::DomainNameAddress(host.to_string(), port),
+    })
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(
+            b"CONNECT example.com:8443 HTTP/1.1\r\nHost: example.com:8443\r\n\r\n",
+            b"hello",
+        )
+        .unwrap();
+        assert_eq!(
+            request,
+            ProxyRequest {
+                host: Address::DomainNameAddress("example.com".to_string(), 8443),
+                reply: CONNECT_ESTABLISHED,
+                first_data: b"hello".to_vec(),
+            }
+        );
+
+        let request = parse_request(
+            b"GET http://[::1]/a?b=1 HTTP/1.1\r\nHost: [::1]\r\nProxy-Connection: keep-alive\r\n\r\n",
+            b"",
+        )
+        .unwrap();
+        assert_eq!(
+            request.host,
+            Address::SocketAddress("[::1]:80".parse().unwrap())
+        );
+        assert_eq!(
+            request.first_data,
+            b"GET /a?b=1 HTTP/1.1\r\nHost: [::1]\r\nConnection: close\r\n\r\n".to_vec()
+        );
+
+        assert!(parse_request(b"GET /a HTTP/1.1\r\n\r\n", b"").is_err());
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        assert_eq!(
+            parse_authority("example.com", 80).unwrap(),
+            Address::DomainNameAddress("example.com".to_string(), 80)
+        );
+        assert_eq!(
+            parse_authority("1.1.1.1:8080", 80).unwrap(),
+            Address::SocketAddress("1.1.1.1:8080".parse().unwrap())
+        );
+        assert_eq!(
+            parse_authority("[::1]:8080", 80).unwrap(),
+            Address::SocketAddress("[::1]:8080".parse().unwrap())
+        );
+        assert!(parse_authority("example.com:x", 80).is_err());
+    }
+}
@@ -1,64 +1,165 @@
-use file_rotate::{FileRotate, RotationMode};
+use config::{LogConfig, LogFormat, LogRotation, OtlpConfig};
 use std::error::Error;
-use std::io;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
+/// A log file rotated by size and time. `path` is renamed to `path.1`, `path.1` to `path.2` and
+/// so on, keeping at most `keep` rotated files.
+struct RotatingFile {
+    config: LogConfig,
+    file: File,
+    size: u64,
+    /// The hour or day of the logs in `file`, see `period`.
+    period: u64,
+}
+
+impl RotatingFile {
+    fn open(config: LogConfig) -> io::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        let period = period(config.rotation, metadata.modified()?);
+        Ok(RotatingFile {
+            config,
+            file,
+            size: metadata.len(),
+            period,
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path: OsString = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.config.keep == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            for index in (1..self.config.keep).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.config.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = period(self.config.rotation, SystemTime::now());
+        let too_large = self.config.max_size > 0
+            && self.size > 0
+            && self.size + buf.len() as u64 > self.config.max_size;
+        if too_large || now != self.period {
+            self.rotate()?;
+            self.period = now;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Index of the hour or day of `time` in UTC, always 0 without time based rotation.
+fn period(rotation: LogRotation, time: SystemTime) -> u64 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    match rotation {
+        LogRotation::Never => 0,
+        LogRotation::Hourly => secs / 3600,
+        LogRotation::Daily => secs / 86400,
+    }
+}
+
 #[derive(Clone)]
 struct TracingWriter {
-    file_rotate: Arc<Mutex<FileRotate>>,
+    file: Arc<Mutex<RotatingFile>>,
 }
 
 impl TracingWriter {
-    fn new(file_rotate: Arc<Mutex<FileRotate>>) -> Self {
-        TracingWriter { file_rotate }
+    fn new(file: Arc<Mutex<RotatingFile>>) -> Self {
+        TracingWriter { file }
     }
 }
 
 impl io::Write for TracingWriter {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut guard = self.file_rotate.lock().unwrap();
+        let mut guard = self.file.lock().unwrap();
         guard.write(buf)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        let mut guard = self.file_rotate.lock().unwrap();
+        let mut guard = self.file.lock().unwrap();
         guard.flush()
     }
 }
 
-pub fn setup_logger(log_path: Option<&str>) -> Result<(), Box<dyn Error>> {
+/// Keeps exporting traces until dropped, spans not exported yet are flushed then.
+pub struct LoggerGuard {
+    #[cfg(feature = "otlp")]
+    _otlp: Option<opentelemetry_otlp::Uninstall>,
+}
+
+pub fn setup_logger(
+    log: Option<&LogConfig>,
+    format: LogFormat,
+    otlp: Option<&OtlpConfig>,
+) -> Result<LoggerGuard, Box<dyn Error>> {
     let env_filter = EnvFilter::new("seeker=trace")
         .add_directive("dnsserver=debug".parse()?)
         .add_directive("seeker=trace".parse()?)
         .add_directive("sysconfig=info".parse()?)
         .add_directive("tun_nat=info".parse()?);
 
-    if let Some(log_path) = log_path {
-        if let Some(path) = PathBuf::from(log_path).parent() {
-            std::fs::create_dir_all(path)?;
+    let builder = FmtSubscriber::builder().with_env_filter(env_filter);
+    let guard = match (log, format) {
+        (Some(log), LogFormat::Text) => {
+            let logger = Arc::new(Mutex::new(RotatingFile::open(log.clone())?));
+            let subscriber = builder
+                .with_ansi(false)
+                .with_writer(move || TracingWriter::new(logger.clone()))
+                .finish();
+            install(subscriber, otlp)?
         }
-        let logger = Arc::new(Mutex::new(FileRotate::new(
-            log_path,
-            RotationMode::Lines(100_000),
-            20,
-        )));
-        let my_subscriber = FmtSubscriber::builder()
-            .with_env_filter(env_filter)
-            .with_ansi(false)
-            .with_writer(move || TracingWriter::new(logger.clone()))
-            .finish();
-        tracing::subscriber::set_global_default(my_subscriber)
-            .expect("setting tracing default failed");
-    } else {
-        let subscriber = FmtSubscriber::builder()
-            .with_env_filter(env_filter)
-            .compact()
-            .finish();
-
-        tracing::subscriber::set_global_default(subscriber)
-            .expect("setting tracing default failed");
+        (Some(log), LogFormat::Json) => {
+            let logger = Arc::new(Mutex::new(RotatingFile::open(log.clone())?));
+            let subscriber = builder
+                .json()
+                .with_writer(move || TracingWriter::new(logger.clone()))
+                .finish();
+            install(subscriber, otlp)?
+        }
+        (None, LogFormat::Text) => install(builder.compact().finish(), otlp)?,
+        (None, LogFormat::Json) => install(builder.json().finish(), otlp)?,
     };
 
     // #[cfg(debug_assertions)]
@@ -86,5 +187,77 @@ pub fn setup_logger(log_path: Option<&str>) -> Result<(), Box<dyn Error>> {
             }
         });
     } // only for #[cfg]
-    Ok(())
+    Ok(guard)
+}
+
+fn install<S>(subscriber: S, otlp: Option<&OtlpConfig>) -> Result<LoggerGuard, Box<dyn Error>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync + 'static,
+{
+    match otlp {
+        Some(otlp) => install_otlp(subscriber, otlp),
+        None => {
+            tracing::subscriber::set_global_default(subscriber)?;
+            Ok(LoggerGuard {
+                #[cfg(feature = "otlp")]
+                _otlp: None,
+            })
+        }
+    }
+}
+
+/// Export spans to an opentelemetry collector besides logging.
+#[cfg(feature = "otlp")]
+fn install_otlp<S>(subscriber: S, otlp: &OtlpConfig) -> Result<LoggerGuard, Box<dyn Error>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync + 'static,
+{
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::KeyValue;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let (tracer, uninstall) = opentelemetry_otlp::new_pipeline()
+        .with_endpoint(&otlp.endpoint)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                otlp.service_name.clone(),
+            )])),
+        )
+        .install()?;
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer);
+    tracing::subscriber::set_global_default(subscriber.with(layer))?;
+    Ok(LoggerGuard {
+        _otlp: Some(uninstall),
+    })
+}
+
+#[cfg(not(feature = "otlp"))]
+fn install_otlp<S>(_subscriber: S, _otlp: &OtlpConfig) -> Result<LoggerGuard, Box<dyn Error>> {
+    Err("seeker is built without the `otlp` feature".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("seeker-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = LogConfig::new(dir.join("seeker.log"));
+        config.max_size = 10;
+        config.keep = 2;
+        let mut file = RotatingFile::open(config).unwrap();
+        for line in &["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("seeker.log"), "line 4\n");
+        assert_eq!(read("seeker.log.1"), "line 3\n");
+        assert_eq!(read("seeker.log.2"), "line 2\n");
+        assert!(!dir.join("seeker.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
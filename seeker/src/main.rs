@@ -1,26 +1,64 @@
 #[macro_use]
 mod macros;
+mod capture;
+mod cli;
 mod config_encryptor;
+mod config_watcher;
+mod connection_limit;
+mod connections;
+mod controller;
 mod dns_client;
+mod dns_hijack;
+mod http_inbound;
 mod logger;
+mod metrics;
+mod mux;
+mod pac;
 mod proxy_client;
 mod proxy_tcp_stream;
 mod proxy_udp_socket;
+#[cfg(target_os = "linux")]
+mod redir;
+mod remote_file;
+mod retry;
+mod rule_provider;
 mod server_chooser;
+mod socks5_inbound;
+#[cfg(target_os = "linux")]
+mod splice;
+mod ss_server;
+mod subscription;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
 
 use std::error::Error;
 
+use crate::config_watcher::ConfigWatcher;
 use crate::logger::setup_logger;
-use crate::proxy_client::ProxyClient;
+use crate::proxy_client::{setup_nat, ProxyClient};
+use crate::rule_provider::setup_rule_providers;
+use crate::ss_server::{ProbeDefense, SsServer};
+use crate::subscription::{merge_subscriptions, setup_subscriptions};
 use anyhow::Context;
 use async_signals::Signals;
+use async_std::future;
 use async_std::prelude::{FutureExt, StreamExt};
-use async_std::task::block_on;
-use clap::{App, Arg};
-use config::Config;
-use crypto::CipherType;
+use async_std::sync::channel;
+use async_std::task::{block_on, spawn_blocking};
+use clap::{App, Arg, SubCommand};
+#[cfg(target_os = "linux")]
+use config::RedirFirewall;
+use config::{Config, LogConfig, LogFormat};
+use crypto::{CipherCategory, CipherType};
 use std::fs::File;
-use sysconfig::{set_rlimit_no_file, DNSSetup, IpForward};
+#[cfg(target_os = "linux")]
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use sysconfig::{restore_crashed, set_rlimit_no_file, DNSSetup, IpForward};
+#[cfg(target_os = "linux")]
+use sysconfig::{Firewall, RedirRules};
+use tracing::{error, warn};
 
 fn main() -> Result<(), Box<dyn Error>> {
     let version = env!("CARGO_PKG_VERSION");
@@ -69,11 +107,321 @@ fn main() -> Result<(), Box<dyn Error>> {
                 .short("l")
                 .long("log")
                 .value_name("PATH")
-                .help("Log file")
+                .help("Log file, overrides the path of `log` in the config")
                 .required(false),
         )
+        .subcommand(
+            SubCommand::with_name("dns")
+                .about("Inspect the dns server of a running seeker")
+                .arg(
+                    Arg::with_name("controller")
+                        .long("controller")
+                        .value_name("ADDR")
+                        .help(cli::CONTROLLER_HELP)
+                        .default_value(cli::DEFAULT_CONTROLLER),
+                )
+                .subcommand(
+                    SubCommand::with_name("log")
+                        .about("Show recent dns queries")
+                        .arg(
+                            Arg::with_name("domain")
+                                .long("domain")
+                                .value_name("DOMAIN")
+                                .help("Only show queries of domains containing DOMAIN"),
+                        )
+                        .arg(
+                            Arg::with_name("limit")
+                                .long("limit")
+                                .value_name("N")
+                                .help("Show at most N queries"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("select")
+                .about("Select the shadowsocks server of a running seeker, list servers if no NAME")
+                .arg(
+                    Arg::with_name("controller")
+                        .long("controller")
+                        .value_name("ADDR")
+                        .help(cli::CONTROLLER_HELP)
+                        .default_value(cli::DEFAULT_CONTROLLER),
+                )
+                .arg(Arg::with_name("name").value_name("NAME").help("Server name")),
+        )
+        .subcommand(
+            SubCommand::with_name("conns")
+                .about("List the connections of a running seeker")
+                .arg(
+                    Arg::with_name("controller")
+                        .long("controller")
+                        .value_name("ADDR")
+                        .help(cli::CONTROLLER_HELP)
+                        .default_value(cli::DEFAULT_CONTROLLER),
+                )
+                .arg(
+                    Arg::with_name("kill")
+                        .long("kill")
+                        .value_name("ID")
+                        .help("Close the connection with ID"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("sessions")
+                .about("List the nat sessions of the tun device of a running seeker")
+                .arg(
+                    Arg::with_name("controller")
+                        .long("controller")
+                        .value_name("ADDR")
+                        .help(cli::CONTROLLER_HELP)
+                        .default_value(cli::DEFAULT_CONTROLLER),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("traffic")
+                .about("Show the domains with the most traffic through a running seeker")
+                .arg(
+                    Arg::with_name("controller")
+                        .long("controller")
+                        .value_name("ADDR")
+                        .help(cli::CONTROLLER_HELP)
+                        .default_value(cli::DEFAULT_CONTROLLER),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Show at most N domains"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("top")
+                .about("Show live traffic, connections, dns queries and servers of a running seeker")
+                .arg(
+                    Arg::with_name("controller")
+                        .long("controller")
+                        .value_name("ADDR")
+                        .help(cli::CONTROLLER_HELP)
+                        .default_value(cli::DEFAULT_CONTROLLER),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .value_name("SECS")
+                        .help("Seconds between refreshes")
+                        .default_value("1"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check-config")
+                .about("Check the config file without starting seeker")
+                .arg(
+                    Arg::with_name("config")
+                        .short("c")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Config file to check")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Convert the config of other proxy tools to seeker config, which can be included by the seeker config")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Format of the config to import")
+                        .possible_values(&["clash", "surge"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("Config to import")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("server")
+                .about("Run a shadowsocks server relaying tcp connections, eg. for testing seeker")
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .value_name("ADDR")
+                        .help("Listen address")
+                        .default_value("0.0.0.0:8388"),
+                )
+                .arg(
+                    Arg::with_name("method")
+                        .long("method")
+                        .value_name("METHOD")
+                        .help("Cipher method")
+                        .default_value("chacha20-ietf-poly1305"),
+                )
+                .arg(
+                    Arg::with_name("password")
+                        .long("password")
+                        .value_name("PASSWORD")
+                        .env("SS_PASSWORD")
+                        .help(
+                            "Password, read from SS_PASSWORD if not given. Repeat to accept \
+                             several, eg. while rotating the password",
+                        )
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("SECS")
+                        .help("Seconds before closing idle connections")
+                        .default_value("300"),
+                )
+                .arg(
+                    Arg::with_name("on-probe")
+                        .long("on-probe")
+                        .value_name("DEFENSE")
+                        .help(
+                            "What to do with connections failing authentication, likely active \
+                             probes: close them, stall until they close, drip random bytes \
+                             back, or relay them to the --decoy server",
+                        )
+                        .possible_values(&["close", "stall", "drip", "decoy"])
+                        .default_value("stall"),
+                )
+                .arg(
+                    Arg::with_name("allow-insecure-ciphers")
+                        .long("allow-insecure-ciphers")
+                        .help("Allow the legacy stream ciphers, which have no integrity checks, and plain"),
+                )
+                .arg(
+                    Arg::with_name("decoy")
+                        .long("decoy")
+                        .value_name("ADDR")
+                        .help("Server probes are relayed to, eg. a web server on 127.0.0.1:80")
+                        .required_if("on-probe", "decoy"),
+                ),
+        )
         .get_matches();
 
+    if let Some(dns_matches) = matches.subcommand_matches("dns") {
+        let controller = dns_matches.value_of("controller").unwrap();
+        if let Some(log_matches) = dns_matches.subcommand_matches("log") {
+            cli::dns_log(
+                controller,
+                log_matches.value_of("domain"),
+                log_matches.value_of("limit"),
+            )?;
+        } else {
+            println!("{}", dns_matches.usage());
+        }
+        return Ok(());
+    }
+
+    if let Some(select_matches) = matches.subcommand_matches("select") {
+        let controller = select_matches.value_of("controller").unwrap();
+        cli::select_server(controller, select_matches.value_of("name"))?;
+        return Ok(());
+    }
+
+    if let Some(conns_matches) = matches.subcommand_matches("conns") {
+        let controller = conns_matches.value_of("controller").unwrap();
+        cli::connections(controller, conns_matches.value_of("kill"))?;
+        return Ok(());
+    }
+
+    if let Some(sessions_matches) = matches.subcommand_matches("sessions") {
+        let controller = sessions_matches.value_of("controller").unwrap();
+        cli::sessions(controller)?;
+        return Ok(());
+    }
+
+    if let Some(traffic_matches) = matches.subcommand_matches("traffic") {
+        let controller = traffic_matches.value_of("controller").unwrap();
+        cli::top_domains(controller, traffic_matches.value_of("limit"))?;
+        return Ok(());
+    }
+
+    if let Some(top_matches) = matches.subcommand_matches("top") {
+        let controller = top_matches.value_of("controller").unwrap();
+        let interval: u64 = top_matches
+            .value_of("interval")
+            .unwrap()
+            .parse()
+            .context("Invalid interval")?;
+        cli::top(controller, Duration::from_secs(interval.max(1)))?;
+        return Ok(());
+    }
+
+    if let Some(check_matches) = matches.subcommand_matches("check-config") {
+        let path = check_matches.value_of("config").unwrap();
+        let report = config::check_config_file(path);
+        for warning in &report.warnings {
+            println!("warning: {}", warning);
+        }
+        for error in &report.errors {
+            println!("error: {}", error);
+        }
+        if !report.errors.is_empty() {
+            std::process::exit(1);
+        }
+        println!("{} is ok", path);
+        return Ok(());
+    }
+
+    if let Some(import_matches) = matches.subcommand_matches("import") {
+        let file = import_matches.value_of("file").unwrap();
+        let content = std::fs::read_to_string(file)?;
+        let imported = match import_matches.value_of("format") {
+            Some("surge") => config::import_surge(&content)?,
+            _ => config::import_clash(&content)?,
+        };
+        for warning in &imported.warnings {
+            eprintln!("warning: {}", warning);
+        }
+        print!("{}", serde_yaml::to_string(&imported.config)?);
+        return Ok(());
+    }
+
+    if let Some(server_matches) = matches.subcommand_matches("server") {
+        let method = server_matches.value_of("method").unwrap();
+        let method = CipherType::from_str(method)
+            .map_err(|_| anyhow::anyhow!("Unknown method {}", method))?;
+        if method.category() == CipherCategory::Stream
+            && !server_matches.is_present("allow-insecure-ciphers")
+        {
+            return Err(anyhow::anyhow!(
+                "{} is an insecure cipher, use an AEAD cipher or --allow-insecure-ciphers",
+                method
+            )
+            .into());
+        }
+        let idle_timeout: u64 = server_matches
+            .value_of("timeout")
+            .unwrap()
+            .parse()
+            .context("Invalid timeout")?;
+        let _logger = setup_logger(None, LogFormat::default(), None)?;
+        let passwords: Vec<_> = server_matches.values_of("password").unwrap().collect();
+        let probe_defense = match server_matches.value_of("on-probe").unwrap() {
+            "close" => ProbeDefense::Close,
+            "drip" => ProbeDefense::Drip,
+            "decoy" => ProbeDefense::Decoy(server_matches.value_of("decoy").unwrap().to_string()),
+            _ => ProbeDefense::Stall,
+        };
+        let server = SsServer::new(
+            method,
+            &passwords,
+            Duration::from_secs(10),
+            Duration::from_secs(idle_timeout),
+            probe_defense,
+        );
+        block_on(server.run(server_matches.value_of("listen").unwrap()))?;
+        return Ok(());
+    }
+
     let path = matches.value_of("config");
     let key = matches.value_of("key");
     let to_encrypt = matches.is_present("encrypt");
@@ -85,39 +433,163 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
     let config_url = matches.value_of("config-url");
-    let config = load_config(path, config_url, key)?;
+    let mut config = load_config(path, config_url, key)?;
 
     let uid = matches.value_of("user_id").map(|uid| uid.parse().unwrap());
-    let log_path = matches.value_of("log");
+    let log = match matches.value_of("log") {
+        Some(path) => Some(match &config.log {
+            Some(log) => LogConfig {
+                path: path.into(),
+                ..log.clone()
+            },
+            None => LogConfig::new(path.into()),
+        }),
+        None => config.log.clone(),
+    };
+
+    let _logger = setup_logger(log.as_ref(), config.log_format, config.otlp.as_ref())?;
 
-    setup_logger(log_path)?;
+    // Rules are matched in order and the first matched rule wins.
+    for (rule, shadowed_by) in config.rules.unreachable_rules() {
+        warn!(?rule, ?shadowed_by, "rule is unreachable");
+    }
 
-    let mut signals = Signals::new(vec![libc::SIGINT, libc::SIGTERM]).unwrap();
+    let mut signals = Signals::new(vec![libc::SIGINT, libc::SIGTERM, libc::SIGHUP]).unwrap();
 
     set_rlimit_no_file(10240)?;
 
-    let _dns_setup = DNSSetup::new("".to_string());
+    setup_rule_providers(&config.rules, &config.rule_providers);
+    // Notified by subscription updates and the controller. A sender is kept until exit, so
+    // receiving only returns on requests.
+    let (reload_requested, reload_requests) = channel(1);
+    setup_subscriptions(&config.subscriptions, reload_requested.clone());
+    merge_subscriptions(&mut config);
+    for server in config
+        .shadowsocks_servers
+        .iter()
+        .flat_map(|servers| servers.iter())
+        .filter(|server| server.has_insecure_cipher())
+    {
+        if let CipherType::Plain = server.method() {
+            warn!(
+                name = server.name(),
+                "INSECURE: server uses the plain method, its traffic is sent unencrypted. Only use \
+                 it for testing"
+            );
+        } else {
+            warn!(
+                name = server.name(),
+                method = %server.method(),
+                "INSECURE: server uses a stream cipher without integrity checks, its traffic can be \
+                 tampered with undetected. Switch to an AEAD cipher"
+            );
+        }
+    }
+
+    // Settings left changed by a crashed seeker would be taken as the original ones.
+    restore_crashed();
+    // A tun device set up beforehand comes with the dns of the system pointed to seeker.
+    let _dns_setup = if config.tun_persistent || config.tun_fd.is_some() {
+        None
+    } else {
+        Some(DNSSetup::new("".to_string()))
+    };
     let _ip_forward = if config.gateway_mode {
         // In gateway mode, dns server need be accessible from the network.
         Some(IpForward::new())
     } else {
         None
     };
+    #[cfg(target_os = "linux")]
+    let _redir_rules = setup_redir_rules(&config)?;
+    let session_manager = setup_nat(&config);
+    // Before the runtime starts its threads, which wouldn't keep the capabilities otherwise.
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(user) = &config.user {
+            sysconfig::drop_privileges(user, config.group.as_deref())
+                .context("Drop privileges error")?;
+        }
+    }
 
     block_on(async {
-        let client = ProxyClient::new(config, uid).await;
-        client
-            .run()
-            .race(async {
-                signals.next().await.unwrap();
-            })
-            .await;
+        let client = ProxyClient::new(config, session_manager, uid, reload_requested.clone()).await;
+        // Reload the config on SIGHUP, when the config file changes, a subscription is
+        // updated or the controller asks to, stop on other signals.
+        let reload = async {
+            let mut watcher = path.map(ConfigWatcher::new);
+            loop {
+                let file_changed = async {
+                    match &mut watcher {
+                        Some(watcher) => watcher.changed().await,
+                        None => future::pending().await,
+                    }
+                    Some(libc::SIGHUP)
+                };
+                let reload_requested = async {
+                    let _ = reload_requests.recv().await;
+                    Some(libc::SIGHUP)
+                };
+                let signal = signals
+                    .next()
+                    .race(file_changed)
+                    .race(reload_requested)
+                    .await;
+                if signal != Some(libc::SIGHUP) {
+                    break;
+                }
+                let path = path.map(str::to_string);
+                let config_url = config_url.map(str::to_string);
+                let key = key.map(str::to_string);
+                let config = spawn_blocking(move || {
+                    let mut config =
+                        load_config(path.as_deref(), config_url.as_deref(), key.as_deref())?;
+                    merge_subscriptions(&mut config);
+                    Ok::<_, anyhow::Error>(config)
+                })
+                .await;
+                match config {
+                    Ok(config) => client.reload(config).await,
+                    Err(e) => error!(?e, "reload config error"),
+                }
+            }
+        };
+        client.run().race(reload).await;
     });
+    drop(reload_requested);
 
     println!("Stop server. Bye bye...");
     Ok(())
 }
 
+/// Set up the firewall rules of the redir mode, if asked to.
+#[cfg(target_os = "linux")]
+fn setup_redir_rules(config: &Config) -> anyhow::Result<Option<RedirRules>> {
+    let redir = match &config.redir {
+        Some(redir) => redir,
+        None => return Ok(None),
+    };
+    let firewall = match redir.firewall {
+        Some(RedirFirewall::Auto) => {
+            Firewall::detect().context("Neither nftables nor iptables is available")?
+        }
+        Some(RedirFirewall::Nftables) => Firewall::Nftables,
+        Some(RedirFirewall::Iptables) => Firewall::Iptables,
+        None => return Ok(None),
+    };
+    let port = |listen: &str| listen.parse::<SocketAddr>().map(|addr| addr.port());
+    let tcp_port = port(&redir.tcp_listen).context("Invalid redir tcp_listen")?;
+    let udp_port = redir
+        .udp_listen
+        .as_deref()
+        .map(port)
+        .transpose()
+        .context("Invalid redir udp_listen")?;
+    let rules = RedirRules::new(firewall, &config.tun_cidr.to_string(), tcp_port, udp_port)
+        .context("Set up redir firewall rules error")?;
+    Ok(Some(rules))
+}
+
 fn load_config(
     path: Option<&str>,
     url: Option<&str>,
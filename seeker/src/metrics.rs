@@ -0,0 +1,393 @@
+//! Counters of the relayed connections, exported by the controller at `GET /metrics` in the
+//! Prometheus text format.
+
+use crate::server_chooser::ServerStatus;
+use dnsserver::CacheStats;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tun_nat::NatStats;
+
+/// Upper bounds in seconds of the buckets of `seeker_connect_duration_seconds`.
+const CONNECT_DURATION_BUCKETS: [f64; 10] =
+    [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Domains tracked by `domain_traffic`, the traffic of other domains is added to `OTHER_DOMAINS`.
+const MAX_DOMAINS: usize = 10000;
+const OTHER_DOMAINS: &str = "(other)";
+
+/// Bytes relayed through a server or to a domain.
+#[derive(Default)]
+pub struct Traffic {
+    /// From the local client to the remote.
+    pub up: AtomicU64,
+    /// From the remote to the local client.
+    pub down: AtomicU64,
+}
+
+/// Traffic of a destination domain, or ip if the destination has no domain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DomainTraffic {
+    pub domain: String,
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// Bytes relayed by all connections since start.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TotalTraffic {
+    pub upload: u64,
+    pub download: u64,
+}
+
+struct Histogram {
+    /// Observations of each bucket, not cumulative. The last one is `+Inf`.
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: (0..=CONNECT_DURATION_BUCKETS.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let index = CONNECT_DURATION_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(CONNECT_DURATION_BUCKETS.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct Metrics {
+    active_connections: AtomicI64,
+    connections: AtomicU64,
+    connect_errors: AtomicU64,
+    connect_duration: Histogram,
+    /// By server name, `DIRECT` for direct connections.
+    traffic: Mutex<HashMap<String, Arc<Traffic>>>,
+    domains: Mutex<HashMap<String, Arc<Traffic>>>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            active_connections: AtomicI64::new(0),
+            connections: AtomicU64::new(0),
+            connect_errors: AtomicU64::new(0),
+            connect_duration: Histogram::new(),
+            traffic: Mutex::new(HashMap::new()),
+            domains: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Metrics {
+    /// A connection to the remote was established in `duration`.
+    pub fn connected(&self, duration: Duration) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connect_duration.observe(duration);
+    }
+
+    pub fn connect_failed(&self) {
+        self.connect_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn traffic(&self, server: &str) -> Arc<Traffic> {
+        self.traffic
+            .lock()
+            .entry(server.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn domain_traffic(&self, domain: &str) -> Arc<Traffic> {
+        let mut domains = self.domains.lock();
+        let domain = if domains.len() >= MAX_DOMAINS && !domains.contains_key(domain) {
+            OTHER_DOMAINS
+        } else {
+            domain
+        };
+        domains.entry(domain.to_string()).or_default().clone()
+    }
+
+    pub fn total_traffic(&self) -> TotalTraffic {
+        let mut total = TotalTraffic::default();
+        for traffic in self.traffic.lock().values() {
+            total.upload += traffic.up.load(Ordering::Relaxed);
+            total.download += traffic.down.load(Ordering::Relaxed);
+        }
+        total
+    }
+
+    /// The `limit` domains with the most traffic since start.
+    pub fn top_domains(&self, limit: usize) -> Vec<DomainTraffic> {
+        let mut domains: Vec<DomainTraffic> = self
+            .domains
+            .lock()
+            .iter()
+            .map(|(domain, traffic)| DomainTraffic {
+                domain: domain.clone(),
+                upload: traffic.up.load(Ordering::Relaxed),
+                download: traffic.down.load(Ordering::Relaxed),
+            })
+            .collect();
+        domains.sort_by(|a, b| {
+            (b.upload + b.download)
+                .cmp(&(a.upload + a.download))
+                .then_with(|| a.domain.cmp(&b.domain))
+        });
+        domains.truncate(limit);
+        domains
+    }
+
+    pub fn render(
+        &self,
+        dns_cache: &CacheStats,
+        servers: &[ServerStatus],
+        nat: &NatStats,
+    ) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        let value = |v: u64| vec![(String::new(), v.to_string())];
+
+        metric(
+            "seeker_active_connections",
+            "gauge",
+            "Open relayed tcp connections.",
+            vec![(
+                String::new(),
+                self.active_connections.load(Ordering::Relaxed).to_string(),
+            )],
+        );
+        metric(
+            "seeker_connections_total",
+            "counter",
+            "Tcp connections established to remotes.",
+            value(self.connections.load(Ordering::Relaxed)),
+        );
+        metric(
+            "seeker_connect_errors_total",
+            "counter",
+            "Tcp connections which failed to connect to remotes.",
+            value(self.connect_errors.load(Ordering::Relaxed)),
+        );
+
+        let mut traffic: Vec<(String, Arc<Traffic>)> = self
+            .traffic
+            .lock()
+            .iter()
+            .map(|(server, traffic)| (server.clone(), traffic.clone()))
+            .collect();
+        traffic.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut samples = vec![];
+        for (server, traffic) in traffic {
+            for (direction, bytes) in &[("up", &traffic.up), ("down", &traffic.down)] {
+                samples.push((
+                    format!(
+                        "{{server=\"{}\",direction=\"{}\"}}",
+                        escape_label(&server),
+                        direction
+                    ),
+                    bytes.load(Ordering::Relaxed).to_string(),
+                ));
+            }
+        }
+        metric(
+            "seeker_traffic_bytes_total",
+            "counter",
+            "Bytes relayed through each server.",
+            samples,
+        );
+
+        let label =
+            |server: &ServerStatus| format!("{{server=\"{}\"}}", escape_label(&server.name));
+        metric(
+            "seeker_server_connections",
+            "gauge",
+            "Open connections through each shadowsocks server.",
+            servers
+                .iter()
+                .map(|server| (label(server), server.connections.to_string()))
+                .collect(),
+        );
+        metric(
+            "seeker_server_alive",
+            "gauge",
+            "Whether each shadowsocks server is alive.",
+            servers
+                .iter()
+                .map(|server| (label(server), (server.alive as u8).to_string()))
+                .collect(),
+        );
+
+        metric(
+            "seeker_dns_cache_hits_total",
+            "counter",
+            "Dns queries answered from the cache.",
+            value(dns_cache.hits),
+        );
+        metric(
+            "seeker_dns_cache_misses_total",
+            "counter",
+            "Dns queries sent to the upstream servers.",
+            value(dns_cache.misses),
+        );
+        metric(
+            "seeker_dns_cache_size",
+            "gauge",
+            "Entries in the dns cache.",
+            value(dns_cache.size as u64),
+        );
+
+        metric(
+            "seeker_nat_sessions",
+            "gauge",
+            "Sessions in the nat table of the tun device.",
+            value(nat.sessions as u64),
+        );
+        metric(
+            "seeker_nat_sessions_evicted_total",
+            "counter",
+            "Least recently used nat sessions evicted for new ones.",
+            value(nat.evicted),
+        );
+
+        let mut samples = vec![];
+        let mut cumulative = 0;
+        for (i, count) in self.connect_duration.buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = match CONNECT_DURATION_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            samples.push((
+                format!("_bucket{{le=\"{}\"}}", bound),
+                cumulative.to_string(),
+            ));
+        }
+        let sum = self.connect_duration.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        samples.push(("_sum".to_string(), sum.to_string()));
+        samples.push(("_count".to_string(), cumulative.to_string()));
+        metric(
+            "seeker_connect_duration_seconds",
+            "histogram",
+            "Time to connect to remotes, including the proxy handshake.",
+            samples,
+        );
+        out
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.connection_opened();
+        metrics.connected(Duration::from_millis(20));
+        metrics.connected(Duration::from_secs(10));
+        metrics.connect_failed();
+        metrics.traffic("hk").up.fetch_add(100, Ordering::Relaxed);
+        metrics.traffic("hk").down.fetch_add(200, Ordering::Relaxed);
+        let dns_cache = CacheStats {
+            hits: 3,
+            misses: 1,
+            size: 2,
+        };
+        let nat = NatStats {
+            sessions: 5,
+            evicted: 2,
+        };
+        let out = metrics.render(&dns_cache, &[], &nat);
+        let lines: Vec<&str> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        for line in &[
+            "seeker_active_connections 1",
+            "seeker_connections_total 2",
+            "seeker_connect_errors_total 1",
+            "seeker_traffic_bytes_total{server=\"hk\",direction=\"up\"} 100",
+            "seeker_traffic_bytes_total{server=\"hk\",direction=\"down\"} 200",
+            "seeker_dns_cache_hits_total 3",
+            "seeker_nat_sessions 5",
+            "seeker_nat_sessions_evicted_total 2",
+            "seeker_connect_duration_seconds_bucket{le=\"0.01\"} 0",
+            "seeker_connect_duration_seconds_bucket{le=\"0.025\"} 1",
+            "seeker_connect_duration_seconds_bucket{le=\"5\"} 1",
+            "seeker_connect_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "seeker_connect_duration_seconds_sum 10.02",
+            "seeker_connect_duration_seconds_count 2",
+        ] {
+            assert!(lines.contains(line), "missing {}", line);
+        }
+        assert_eq!(escape_label("a\"b"), "a\\\"b");
+        metrics.traffic("DIRECT").up.fetch_add(5, Ordering::Relaxed);
+        let total = metrics.total_traffic();
+        assert_eq!((total.upload, total.download), (105, 200));
+    }
+
+    #[test]
+    fn test_top_domains() {
+        let metrics = Metrics::default();
+        metrics
+            .domain_traffic("a.com")
+            .up
+            .fetch_add(10, Ordering::Relaxed);
+        metrics
+            .domain_traffic("b.com")
+            .down
+            .fetch_add(30, Ordering::Relaxed);
+        metrics
+            .domain_traffic("c.com")
+            .up
+            .fetch_add(20, Ordering::Relaxed);
+        metrics
+            .domain_traffic("a.com")
+            .down
+            .fetch_add(15, Ordering::Relaxed);
+        let top: Vec<(String, u64)> = metrics
+            .top_domains(2)
+            .into_iter()
+            .map(|d| (d.domain, d.upload + d.download))
+            .collect();
+        assert_eq!(
+            top,
+            vec![("b.com".to_string(), 30), ("a.com".to_string(), 25)]
+        );
+    }
+}
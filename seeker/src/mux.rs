@@ -0,0 +1,664 @@
+//! Multiplexing of proxied tcp streams over a few long-lived connections to a shadowsocks server,
+//! so new streams skip the tcp and cipher handshakes. Connections target the mux address of
+//! sing-box, `sp.mux.sing-box.arpa:444`, and carry smux (v1) frames after the sing-box mux
+//! header. Each stream starts with a big-endian u16 of flags, 0 for tcp, and the socks address of
+//! its destination, and the server replies with a status byte before the data.
+
+use async_std::io::{timeout, Read, Write};
+use async_std::prelude::*;
+use async_std::sync::{channel, Sender};
+use async_std::task::{sleep, spawn};
+use config::{Address, MuxConfig, ShadowsocksServerConfig};
+use futures_util::future::poll_fn;
+use futures_util::ready;
+use parking_lot::Mutex;
+use ssclient::SSTcpStream;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tracing::{error, trace};
+
+const MUX_DOMAIN: &str = "sp.mux.sing-box.arpa";
+const MUX_PORT: u16 = 444;
+
+const SMUX_VERSION: u8 = 1;
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+const HEADER_SIZE: usize = 8;
+/// Max payload of a frame, its length is a u16.
+const MAX_FRAME_SIZE: usize = 65535;
+
+/// Frames queued for the writer of a session.
+const FRAME_QUEUE_SIZE: usize = 64;
+/// Received bytes buffered for a stream before the session stops reading the connection.
+const STREAM_BUFFER_SIZE: usize = 1024 * 1024;
+
+fn frame(cmd: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.push(SMUX_VERSION);
+    frame.push(cmd);
+    frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    frame.extend_from_slice(&id.to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// The first frame of a tcp stream to `addr`.
+fn stream_request(addr: &Address) -> Vec<u8> {
+    let mut request = Vec::with_capacity(2 + addr.serialized_len());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    addr.write_to_buf(&mut request);
+    request
+}
+
+fn session_closed() -> Error {
+    Error::new(ErrorKind::ConnectionAborted, "mux session closed")
+}
+
+#[derive(Default)]
+struct StreamState {
+    data: VecDeque<Vec<u8>>,
+    buffered: usize,
+    /// The server has finished sending.
+    fin: bool,
+    /// The stream waiting for data.
+    read_waker: Option<Waker>,
+    /// The session waiting for the stream to consume its data.
+    drain_waker: Option<Waker>,
+}
+
+#[derive(Default)]
+struct Shared {
+    streams: Mutex<HashMap<u32, StreamState>>,
+    closed: AtomicBool,
+    /// The session takes no new streams, and is closed once its streams are done.
+    retired: AtomicBool,
+}
+
+impl Shared {
+    async fn push(&self, id: u32, data: Vec<u8>) {
+        // Streams are read in order, a slow stream holds up the others of the session.
+        poll_fn(|cx| {
+            let mut streams = self.streams.lock();
+            match streams.get_mut(&id) {
+                Some(state) if state.buffered >= STREAM_BUFFER_SIZE => {
+                    state.drain_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(()),
+            }
+        })
+        .await;
+        let mut streams = self.streams.lock();
+        if let Some(state) = streams.get_mut(&id) {
+            state.buffered += data.len();
+            state.data.push_back(data);
+            if let Some(waker) = state.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn finish(&self, id: u32) {
+        let mut streams = self.streams.lock();
+        if let Some(state) = streams.get_mut(&id) {
+            state.fin = true;
+            if let Some(waker) = state.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn close(&self) {
+        let mut streams = self.streams.lock();
+        self.closed.store(true, Ordering::SeqCst);
+        for state in streams.values_mut() {
+            if let Some(waker) = state.read_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// A multiplexed connection to a server.
+pub struct MuxSession {
+    frames: Sender<Vec<u8>>,
+    shared: Arc<Shared>,
+    next_id: AtomicU32,
+    created: Instant,
+}
+
+impl MuxSession {
+    /// Start a session over the halves of a connection, frames are read and written by spawned
+    /// tasks.
+    pub fn new<R, W>(mut reader: R, mut writer: W, keepalive: Duration) -> Self
+    where
+        R: Read + Unpin + Send + 'static,
+        W: Write + Unpin + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let (frames, frame_receiver) = channel(FRAME_QUEUE_SIZE);
+
+        let shared_clone = shared.clone();
+        spawn(async move {
+            let ret: Result<()> = async {
+                // sing-box mux header: version 0 and protocol smux.
+                writer.write_all(&[0, 0]).await?;
+                while let Some(frame) = frame_receiver.recv().await {
+                    writer.write_all(&frame).await?;
+                }
+                writer.close().await
+            }
+            .await;
+            if let Err(e) = ret {
+                trace!(?e, "mux session write error");
+                shared_clone.close();
+                // Keep receiving so writers of the closed session don't wait forever.
+                while frame_receiver.recv().await.is_some() {}
+            }
+        });
+
+        let shared_clone = shared.clone();
+        spawn(async move {
+            let e = read_frames(&mut reader, &shared_clone, keepalive).await;
+            trace!(?e, "mux session read error");
+            shared_clone.close();
+        });
+
+        let shared_clone = shared.clone();
+        let keepalive_frames = frames.clone();
+        spawn(async move {
+            while !shared_clone.closed.load(Ordering::SeqCst) {
+                sleep(keepalive).await;
+                if shared_clone.retired.load(Ordering::SeqCst)
+                    && shared_clone.streams.lock().is_empty()
+                {
+                    // The writer closes the connection once the senders of the streams are
+                    // dropped too.
+                    break;
+                }
+                keepalive_frames.send(frame(CMD_NOP, 0, &[])).await;
+            }
+        });
+
+        MuxSession {
+            frames,
+            shared,
+            // Stream ids of the client are odd.
+            next_id: AtomicU32::new(3),
+            created: Instant::now(),
+        }
+    }
+
+    /// Connect to the mux address of the server through shadowsocks and start a session.
+    pub async fn connect(
+        server_config: &ShadowsocksServerConfig,
+        server_addr: SocketAddr,
+        server_alive: Arc<AtomicBool>,
+        mux: MuxConfig,
+    ) -> Result<Self> {
+        let stream = SSTcpStream::connect(
+            Address::DomainNameAddress(MUX_DOMAIN.to_string(), MUX_PORT),
+            server_addr,
+            server_alive,
+            server_config.method(),
+            server_config.key(),
+            server_config.fast_open(),
+        )
+        .await?;
+        match stream.into_split() {
+            Ok((reader, writer)) => Ok(MuxSession::new(reader, writer, mux.keepalive)),
+            Err(_) => unreachable!("the stream is not cloned"),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    /// The session has been open for `max_lifetime`, 0 for never.
+    pub fn is_expired(&self, max_lifetime: Duration) -> bool {
+        max_lifetime > Duration::from_secs(0) && self.created.elapsed() >= max_lifetime
+    }
+
+    /// Streams not closed yet.
+    pub fn stream_count(&self) -> usize {
+        self.shared.streams.lock().len()
+    }
+
+    /// Open a stream to `addr`.
+    pub async fn open(&self, addr: &Address) -> Result<MuxStream> {
+        if self.is_closed() {
+            return Err(session_closed());
+        }
+        let id = self.next_id.fetch_add(2, Ordering::SeqCst);
+        self.shared
+            .streams
+            .lock()
+            .insert(id, StreamState::default());
+        self.frames.send(frame(CMD_SYN, id, &[])).await;
+        self.frames
+            .send(frame(CMD_PSH, id, &stream_request(addr)))
+            .await;
+        let read_half = MuxReadHalf {
+            id,
+            shared: self.shared.clone(),
+            status_read: false,
+        };
+        let write_half = MuxWriteHalf {
+            id,
+            frames: self.frames.clone(),
+            shared: self.shared.clone(),
+            pending: None,
+            fin_sent: false,
+        };
+        Ok(MuxStream {
+            read_half: Arc::new(Mutex::new(read_half)),
+            write_half: Arc::new(Mutex::new(write_half)),
+        })
+    }
+}
+
+impl Drop for MuxSession {
+    fn drop(&mut self) {
+        // Streams opened keep working, the connection is closed after them.
+        self.shared.retired.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Read frames until the connection is broken, or no frame is received for 3 keepalive
+/// intervals.
+async fn read_frames<R: Read + Unpin>(
+    reader: &mut R,
+    shared: &Shared,
+    keepalive: Duration,
+) -> Error {
+    let mut header = [0u8; HEADER_SIZE];
+    loop {
+        if let Err(e) = timeout(keepalive * 3, reader.read_exact(&mut header)).await {
+            return e;
+        }
+        let cmd = header[1];
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let id = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let mut payload = vec![0; len];
+        if let Err(e) = reader.read_exact(&mut payload).await {
+            return e;
+        }
+        match cmd {
+            CMD_PSH if len > 0 => shared.push(id, payload).await,
+            CMD_FIN => shared.finish(id),
+            _ => {}
+        }
+    }
+}
+
+pub struct MuxReadHalf {
+    id: u32,
+    shared: Arc<Shared>,
+    /// The status byte replied by the server has been read.
+    status_read: bool,
+}
+
+pub struct MuxWriteHalf {
+    id: u32,
+    frames: Sender<Vec<u8>>,
+    shared: Arc<Shared>,
+    /// A frame being queued and the bytes of the write it carries.
+    pending: Option<(usize, Pin<Box<dyn Future<Output = ()> + Send>>)>,
+    fin_sent: bool,
+}
+
+/// A stream of a mux session. Clones share the halves behind locks like `SSTcpStream`.
+#[derive(Clone)]
+pub struct MuxStream {
+    read_half: Arc<Mutex<MuxReadHalf>>,
+    write_half: Arc<Mutex<MuxWriteHalf>>,
+}
+
+impl MuxStream {
+    /// Split into halves which read and write without locking. The stream is returned back if
+    /// it has been cloned.
+    pub fn into_split(self) -> std::result::Result<(MuxReadHalf, MuxWriteHalf), MuxStream> {
+        let MuxStream {
+            read_half,
+            write_half,
+        } = self;
+        match (Arc::try_unwrap(read_half), Arc::try_unwrap(write_half)) {
+            (Ok(read_half), Ok(write_half)) => {
+                Ok((read_half.into_inner(), write_half.into_inner()))
+            }
+            (read_half, write_half) => Err(MuxStream {
+                read_half: read_half.map(Arc::new).unwrap_or_else(|r| r),
+                write_half: write_half.map(Arc::new).unwrap_or_else(|w| w),
+            }),
+        }
+    }
+}
+
+impl Read for MuxReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let mut streams = this.shared.streams.lock();
+        let state = match streams.get_mut(&this.id) {
+            Some(state) => state,
+            None => return Poll::Ready(Err(session_closed())),
+        };
+        while let Some(mut data) = state.data.pop_front() {
+            state.buffered -= data.len();
+            if !this.status_read {
+                this.status_read = true;
+                if data[0] != 0 {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::ConnectionRefused,
+                        "mux stream refused by the server",
+                    )));
+                }
+                data.remove(0);
+            }
+            if data.is_empty() {
+                continue;
+            }
+            let n = buf.len().min(data.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            if n < data.len() {
+                data.drain(..n);
+                state.buffered += data.len();
+                state.data.push_front(data);
+            }
+            if let Some(waker) = state.drain_waker.take() {
+                waker.wake();
+            }
+            return Poll::Ready(Ok(n));
+        }
+        if state.fin {
+            return Poll::Ready(Ok(0));
+        }
+        if this.shared.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(session_closed()));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for MuxReadHalf {
+    fn drop(&mut self) {
+        let state = self.shared.streams.lock().remove(&self.id);
+        if let Some(waker) = state.and_then(|state| state.drain_waker) {
+            waker.wake();
+        }
+    }
+}
+
+impl MuxWriteHalf {
+    /// Queue the frame built if no frame is pending, and wait for the pending one.
+    fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        build: impl FnOnce() -> (usize, Vec<u8>),
+    ) -> Poll<Result<usize>> {
+        if self.shared.closed.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(session_closed()));
+        }
+        if self.pending.is_none() {
+            let (size, frame) = build();
+            let frames = self.frames.clone();
+            self.pending = Some((size, Box::pin(async move { frames.send(frame).await })));
+        }
+        if let Some((size, send)) = &mut self.pending {
+            ready!(send.as_mut().poll(cx));
+            let size = *size;
+            self.pending = None;
+            return Poll::Ready(Ok(size));
+        }
+        unreachable!("a frame is pending")
+    }
+}
+
+impl Write for MuxWriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let id = this.id;
+        this.poll_send(cx, || {
+            let size = buf.len().min(MAX_FRAME_SIZE);
+            (size, frame(CMD_PSH, id, &buf[..size]))
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if this.fin_sent {
+            return Poll::Ready(Ok(()));
+        }
+        let id = this.id;
+        ready!(this.poll_send(cx, || (0, frame(CMD_FIN, id, &[]))))?;
+        this.fin_sent = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxWriteHalf {
+    fn drop(&mut self) {
+        if !self.fin_sent && !self.shared.closed.load(Ordering::SeqCst) {
+            let frames = self.frames.clone();
+            let fin = frame(CMD_FIN, self.id, &[]);
+            spawn(async move { frames.send(fin).await });
+        }
+    }
+}
+
+impl Read for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut *self.read_half.lock()).poll_read(cx, buf)
+    }
+}
+
+impl Write for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut *self.write_half.lock()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut *self.write_half.lock()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut *self.write_half.lock()).poll_close(cx)
+    }
+}
+
+/// Mux sessions of the servers with `mux` configured, by server name.
+#[derive(Default)]
+pub struct MuxSessions {
+    sessions: Mutex<HashMap<String, Vec<Arc<MuxSession>>>>,
+}
+
+impl MuxSessions {
+    /// Open a stream to `addr` on a session of the server with room for it, or on a new
+    /// session.
+    pub async fn open(
+        &self,
+        server_config: &ShadowsocksServerConfig,
+        server_addr: SocketAddr,
+        server_alive: Arc<AtomicBool>,
+        mux: MuxConfig,
+        addr: &Address,
+    ) -> Result<MuxStream> {
+        let session = {
+            let mut sessions = self.sessions.lock();
+            let server_sessions = sessions
+                .entry(server_config.name().to_string())
+                .or_insert_with(Vec::new);
+            // Expired sessions are dropped here, and closed once their streams are done.
+            server_sessions
+                .retain(|session| !session.is_closed() && !session.is_expired(mux.max_lifetime));
+            server_sessions
+                .iter()
+                .find(|session| session.stream_count() < mux.max_streams)
+                .cloned()
+        };
+        let session = match session {
+            Some(session) => session,
+            None => {
+                trace!(name = server_config.name(), "new mux session");
+                let session = Arc::new(
+                    MuxSession::connect(server_config, server_addr, server_alive, mux)
+                        .await
+                        .map_err(|e| {
+                            error!(?e, name = server_config.name(), "connect mux session");
+                            e
+                        })?,
+                );
+                self.sessions
+                    .lock()
+                    .entry(server_config.name().to_string())
+                    .or_insert_with(Vec::new)
+                    .push(session.clone());
+                session
+            }
+        };
+        session.open(addr).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::block_on;
+
+    /// A server echoing the data of each stream after the status byte.
+    async fn echo_server(mut conn: TcpStream) -> Result<()> {
+        let mut mux_header = [0u8; 2];
+        conn.read_exact(&mut mux_header).await?;
+        assert_eq!(mux_header, [0, 0]);
+        let mut address_read = HashMap::new();
+        let mut header = [0u8; HEADER_SIZE];
+        loop {
+            conn.read_exact(&mut header).await?;
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+            let id = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+            let mut payload = vec![0; len];
+            conn.read_exact(&mut payload).await?;
+            match header[1] {
+                CMD_SYN => {
+                    address_read.insert(id, false);
+                }
+                CMD_PSH if !address_read[&id] => {
+                    assert_eq!(&payload[..2], &[0, 0]);
+                    address_read.insert(id, true);
+                    conn.write_all(&frame(CMD_PSH, id, &[0])).await?;
+                }
+                CMD_PSH => conn.write_all(&frame(CMD_PSH, id, &payload)).await?,
+                CMD_FIN => conn.write_all(&frame(CMD_FIN, id, &[])).await?,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_request() {
+        // A tcp stream request to example.com:80 as encoded by sing-mux.
+        let mut expected = vec![0x00, 0x00, 0x03, 0x0b];
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&[0x00, 0x50]);
+        let dest = Address::DomainNameAddress("example.com".to_string(), 80);
+        assert_eq!(stream_request(&dest), expected);
+
+        let dest = Address::SocketAddress("1.2.3.4:443".parse().unwrap());
+        assert_eq!(
+            stream_request(&dest),
+            vec![0x00, 0x00, 0x01, 1, 2, 3, 4, 0x01, 0xbb]
+        );
+    }
+
+    #[test]
+    fn test_mux_session() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            spawn(echo_server(server));
+
+            let session = MuxSession::new(client.clone(), client, Duration::from_secs(10));
+            let dest = Address::DomainNameAddress("example.com".to_string(), 80);
+            let mut s1 = session.open(&dest).await.unwrap();
+            let mut s2 = session.open(&dest).await.unwrap();
+            assert_eq!(session.stream_count(), 2);
+
+            s1.write_all(b"hello").await.unwrap();
+            s2.write_all(b"world").await.unwrap();
+            let mut buf = [0u8; 5];
+            s2.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+            s1.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // The server closes the stream after our FIN.
+            s1.close().await.unwrap();
+            assert_eq!(s1.read(&mut buf).await.unwrap(), 0);
+            drop(s1);
+            assert_eq!(session.stream_count(), 1);
+        });
+    }
+
+    #[test]
+    fn test_retired_session() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            spawn(echo_server(server));
+
+            let keepalive = Duration::from_millis(50);
+            let session = MuxSession::new(client.clone(), client, keepalive);
+            let shared = session.shared.clone();
+            let dest = Address::DomainNameAddress("example.com".to_string(), 80);
+            let mut s = session.open(&dest).await.unwrap();
+            sleep(Duration::from_millis(1)).await;
+            assert!(!session.is_expired(Duration::from_secs(0)));
+            assert!(session.is_expired(Duration::from_millis(1)));
+            drop(session);
+
+            // The stream outlives the session.
+            sleep(keepalive * 2).await;
+            s.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            s.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            assert!(!shared.closed.load(Ordering::SeqCst));
+
+            // And the connection is closed after it.
+            s.close().await.unwrap();
+            drop(s);
+            sleep(keepalive * 4).await;
+            assert!(shared.closed.load(Ordering::SeqCst));
+        });
+    }
+}
@@ -0,0 +1,193 @@
+//! Proxy auto-config file generated from the rules, for devices which can't run seeker but can
+//! use its local http and socks5 proxies. Connections the rules send directly are made directly by
+//! the browser, others go through the local proxies, where the rules apply again.
+//!
+//! Only rules on domains and ipv4 networks can be evaluated by the browser. From the first rule
+//! which can't, eg. a `RULE-SET` or `DST-PORT` rule, everything is left to the proxies.
+
+use config::rule::{Action, Rule};
+use config::Config;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// A local proxy announced by the PAC file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacProxy {
+    Http(SocketAddr),
+    Socks5(SocketAddr),
+}
+
+impl PacProxy {
+    /// The local proxies of `config`. Socks5 proxies requiring authentication are left out, the
+    /// browser can't authenticate to them by the PAC file.
+    pub fn from_config(config: &Config) -> Vec<PacProxy> {
+        let mut proxies = vec![];
+        if let Some(addr) = config.http_listen.as_ref().and_then(|a| a.parse().ok()) {
+            proxies.push(PacProxy::Http(addr));
+        }
+        if let Some(addr) = config.mixed_listen.as_ref().and_then(|a| a.parse().ok()) {
+            proxies.push(PacProxy::Http(addr));
+            proxies.push(PacProxy::Socks5(addr));
+        }
+        if let Some(socks5) = &config.socks5_inbound {
+            if let (true, Ok(addr)) = (socks5.users.is_empty(), socks5.listen.parse()) {
+                proxies.push(PacProxy::Socks5(addr));
+            }
+        }
+        proxies
+    }
+
+    /// Proxies listening on all addresses are reached at `local_ip`, the address the device
+    /// fetched the PAC file from.
+    fn directive(&self, local_ip: IpAddr) -> String {
+        let (kind, addr) = match self {
+            PacProxy::Http(addr) => ("PROXY", addr),
+            PacProxy::Socks5(addr) => ("SOCKS5", addr),
+        };
+        let ip = if addr.ip().is_unspecified() {
+            local_ip
+        } else {
+            addr.ip()
+        };
+        format!("{} {}", kind, SocketAddr::new(ip, addr.port()))
+    }
+}
+
+pub fn generate_pac(
+    rules: &[Rule],
+    default_action: &Action,
+    proxies: &[PacProxy],
+    local_ip: IpAddr,
+) -> String {
+    let proxy = proxies
+        .iter()
+        .map(|proxy| proxy.directive(local_ip))
+        .collect::<Vec<_>>()
+        .join("; ");
+    let mut pac = format!("var proxy = {};\n\n", quote(&proxy));
+    pac.push_str("function FindProxyForURL(url, host) {\n");
+    // Connections to ips are matched by ip rules only, and proxied if none matches.
+    pac.push_str("  if (host.indexOf(\":\") >= 0) return proxy;\n");
+    pac.push_str("  if (/^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host)) {\n");
+    for rule in rules {
+        let condition = match rule {
+            Rule::IpCidr(cidr, _) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(cidr.prefix_len()))
+                    .unwrap_or(0);
+                format!(
+                    "isInNet(host, \"{}\", \"{}\")",
+                    cidr.address(),
+                    Ipv4Addr::from(mask)
+                )
+            }
+            Rule::Domain(..)
+            | Rule::DomainSuffix(..)
+            | Rule::DomainKeyword(..)
+            | Rule::DomainRegex(..)
+            | Rule::IpCidr6(..)
+            | Rule::Match(_) => continue,
+            _ => break,
+        };
+        match result(&rule.action()) {
+            Some(result) => pac.push_str(&format!("    if ({}) return {};\n", condition, result)),
+            None => break,
+        }
+    }
+    pac.push_str("    return proxy;\n  }\n");
+
+    let mut default_action = Some(default_action.clone());
+    for rule in rules {
+        let condition = match rule {
+            Rule::Domain(domain, _) => format!("host == {}", quote(domain)),
+            Rule::DomainSuffix(suffix, _) => format!("dnsDomainIs(host, {})", quote(suffix)),
+            Rule::DomainKeyword(keyword, _) => format!("host.indexOf({}) >= 0", quote(keyword)),
+            // Flags and other extensions of rust regexes aren't supported by javascript.
+            Rule::DomainRegex(re, _) if !re.as_str().contains("(?") => {
+                format!("new RegExp({}).test(host)", quote(re.as_str()))
+            }
+            Rule::IpCidr(..) | Rule::IpCidr6(..) => continue,
+            Rule::Match(action) => {
+                default_action = Some(action.clone());
+                break;
+            }
+            _ => {
+                default_action = None;
+                break;
+            }
+        };
+        match result(&rule.action()) {
+            Some(result) => pac.push_str(&format!("  if ({}) return {};\n", condition, result)),
+            None => {
+                default_action = None;
+                break;
+            }
+        }
+    }
+    let last = default_action
+        .as_ref()
+        .and_then(result)
+        .unwrap_or_else(|| "proxy".to_string());
+    pac.push_str(&format!("  return {};\n}}\n", last));
+    pac
+}
+
+/// The PAC result of an action, None if decided by the script.
+fn result(action: &Action) -> Option<String> {
+    match action {
+        Action::Direct => Some("\"DIRECT\"".to_string()),
+        Action::Script => None,
+        // Rejected or probed by the proxies.
+        _ => Some("proxy".to_string()),
+    }
+}
+
+/// A javascript string literal.
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).expect("serialize string")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_generate_pac() {
+        let rules: Vec<Rule> = [
+            "DOMAIN-SUFFIX,cn,DIRECT",
+            "IP-CIDR,192.168.0.0/16,DIRECT",
+            "DOMAIN-KEYWORD,google,PROXY",
+            "DST-PORT,22,DIRECT",
+            "DOMAIN,example.com,DIRECT",
+        ]
+        .iter()
+        .map(|r| Rule::from_str(r).unwrap())
+        .collect();
+        let proxies = [
+            PacProxy::Http("0.0.0.0:8118".parse().unwrap()),
+            PacProxy::Socks5("192.168.1.1:1080".parse().unwrap()),
+        ];
+        let pac = generate_pac(
+            &rules,
+            &Action::Direct,
+            &proxies,
+            "192.168.1.1".parse().unwrap(),
+        );
+        assert_eq!(
+            pac,
+            r#"var proxy = "PROXY 192.168.1.1:8118; SOCKS5 192.168.1.1:1080";
+
+function FindProxyForURL(url, host) {
+  if (host.indexOf(":") >= 0) return proxy;
+  if (/^\d+\.\d+\.\d+\.\d+$/.test(host)) {
+    if (isInNet(host, "192.168.0.0", "255.255.0.0")) return "DIRECT";
+    return proxy;
+  }
+  if (dnsDomainIs(host, "cn")) return "DIRECT";
+  if (host.indexOf("google") >= 0) return proxy;
+  return proxy;
+}
+"#
+        );
+    }
+}
@@ -1,27 +1,69 @@
+use crate::capture::CaptureFile;
+use crate::connection_limit::ConnectionLimit;
+use crate::connections::Connections;
+use crate::controller::Controller;
 use crate::dns_client::DnsClient;
+use crate::dns_hijack;
+use crate::http_inbound::{read_request, BAD_GATEWAY, BAD_REQUEST, FORBIDDEN};
+use crate::metrics::{Metrics, Traffic};
+use crate::mux::MuxSessions;
+use crate::pac::PacProxy;
 use crate::proxy_tcp_stream::ProxyTcpStream;
 use crate::proxy_udp_socket::ProxyUdpSocket;
+#[cfg(target_os = "linux")]
+use crate::redir::{self, TproxyUdpSocket};
+use crate::retry::retry_with_backoff;
+use crate::rule_provider::setup_rule_providers;
 use crate::server_chooser::ShadowsocksServerChooser;
-use async_std::io::{timeout, Read, Write};
+use crate::socks5_inbound;
+#[cfg(target_os = "linux")]
+use crate::splice::splice_copy;
+use async_std::future::pending;
+use async_std::io::timeout;
 use async_std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
 use async_std::prelude::*;
-use async_std::task::spawn;
-use async_std_resolver::AsyncStdResolver;
-use config::rule::Action;
-use config::{Address, Config};
+#[cfg(target_os = "linux")]
+use async_std::sync::channel;
+use async_std::sync::Sender;
+#[cfg(target_os = "linux")]
+use async_std::task::block_on;
+use async_std::task::{spawn, spawn_blocking};
+use config::rule::{Action, ConnectionMeta};
+#[cfg(target_os = "linux")]
+use config::RedirConfig;
+use config::{
+    Address, Config, ForwardConfig, ServerGroupConfig, ShadowsocksServerConfig, Socks5InboundConfig,
+};
 use dnsserver::create_dns_server;
 use dnsserver::resolver::RuleBasedDnsResolver;
+use dnsserver::Upstream;
+use futures_util::stream::FuturesUnordered;
 use http_proxy_client::HttpProxyTcpStream;
 use parking_lot::RwLock;
-use socks5_client::{Socks5TcpStream, Socks5UdpSocket};
+use socks5_client::{Socks5TcpStream, Socks5UdpSocket, SOCKS5_VERSION};
 use ssclient::{SSTcpStream, SSUdpSocket};
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
 use std::io;
 use std::io::Result;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tracing::{error, trace, trace_span};
+#[cfg(target_os = "linux")]
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info, trace, trace_span, warn};
 use tracing_futures::Instrument;
-use tun_nat::{run_nat, SessionManager};
+use tun_nat::{run_nat, SessionManager, TunDevice};
+
+/// Interval of resolving the domain names of servers again.
+const SERVER_ADDR_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Proxy sockets of udp packets diverted by `TPROXY`, by their sources and original destinations.
+#[cfg(target_os = "linux")]
+type TproxyUdpSessions =
+    Arc<RwLock<HashMap<(SocketAddr, SocketAddr), (ProxyUdpSocket, SocketAddr)>>>;
 
 pub struct ProxyClient {
     config: Config,
@@ -30,70 +72,119 @@ pub struct ProxyClient {
     udp_manager: Arc<RwLock<HashMap<u16, (ProxyUdpSocket, SocketAddr)>>>,
     resolver: RuleBasedDnsResolver,
     dns_client: DnsClient,
-    extra_directly_servers: Vec<String>,
+    extra_directly_servers: RwLock<Vec<String>>,
     ss_server_chooser: Option<Arc<ShadowsocksServerChooser>>,
+    /// Choosers of `server_groups`, used by rules with a group action.
+    group_choosers: HashMap<String, Arc<ShadowsocksServerChooser>>,
+    metrics: Arc<Metrics>,
+    connections: Arc<Connections>,
+    mux_sessions: MuxSessions,
+    connection_limit: Option<ConnectionLimit>,
+}
+
+/// Set up the tun device and run the nat on it. Needs root, unlike the rest of `ProxyClient`.
+pub fn setup_nat(config: &Config) -> SessionManager {
+    let device = match config.tun_fd {
+        Some(fd) => TunDevice::Fd(fd),
+        None if config.tun_persistent => TunDevice::Persistent(&config.tun_name),
+        None => TunDevice::Create(&config.tun_name),
+    };
+    // Redirected connections don't go through the nat, whose table is left empty.
+    if config.redir.is_some() {
+        SessionManager::new(config.tun_max_sessions)
+    } else {
+        run_nat(
+            device,
+            config.tun_ip,
+            config.tun_cidr,
+            config.tun_ipv6.map(|tun_ipv6| (tun_ipv6.ip, tun_ipv6.cidr)),
+            config.tun_mtu,
+            config.tun_max_sessions,
+            config.tun_queues,
+            config.tun_offload,
+            1300,
+        )
+        .expect("run nat")
+    }
 }
 
 impl ProxyClient {
-    pub async fn new(config: Config, uid: Option<u32>) -> Self {
-        let session_manager =
-            run_nat(&config.tun_name, config.tun_ip, config.tun_cidr, 1300).expect("run nat");
-        let dns_client = DnsClient::new(&config.dns_servers, config.dns_timeout).await;
+    /// `session_manager` is the one returned by `setup_nat`. `reload_requested` is notified when
+    /// the controller is asked to reload the config.
+    pub async fn new(
+        config: Config,
+        session_manager: SessionManager,
+        uid: Option<u32>,
+        reload_requested: Sender<()>,
+    ) -> Self {
+        let upstream = Upstream::new(&config.dns_servers, config.dns_timeout)
+            .with_tls(config.dns_tls.clone())
+            .with_domain_servers(&config.dns_domain_servers)
+            .with_cache(config.dns_cache)
+            .with_client_subnet(config.dns_client_subnet)
+            .with_race(config.dns_race)
+            .with_ip_blacklist(config.dns_ip_blacklist.clone());
+        let dns_client = DnsClient::new(upstream, config.hosts.clone());
 
-        let resolver = run_dns_resolver(&config, dns_client.resolver()).await;
+        let resolver = run_dns_resolver(&config, dns_client.upstream()).await;
+        let dns_client_clone = dns_client.clone();
+        spawn(async move {
+            dns_client_clone
+                .refresh_servers_forever(SERVER_ADDR_REFRESH_INTERVAL)
+                .await
+        });
 
-        let mut extra_directly_servers = vec![];
-        // always pass proxy for socks5 server
-        if let Some(socks5_addr) = &config.socks5_server {
-            extra_directly_servers.push(socks5_addr.addr.to_string());
-        }
-        // always pass proxy for socks5 server
-        if let Some(c) = &config.http_proxy_server {
-            extra_directly_servers.push(c.addr.to_string());
-        }
+        let extra_directly_servers = RwLock::new(extra_directly_servers(&config));
 
-        if let Some(shadowsocks_servers) = &config.shadowsocks_servers {
-            for shadowsocks_server in shadowsocks_servers.iter() {
-                extra_directly_servers.push(shadowsocks_server.addr().to_string());
-            }
-        }
-
-        let server_chooser = match (&config.socks5_server, &config.shadowsocks_servers) {
-            (None, Some(shadowsocks_servers)) => {
-                let ping_url = vec![
-                    (
-                        Address::DomainNameAddress("google.com".to_string(), 80),
-                        "/".to_string(),
-                    ),
-                    (
-                        Address::DomainNameAddress("twitter.com".to_string(), 80),
-                        "/".to_string(),
-                    ),
-                    (
-                        Address::DomainNameAddress("github.com".to_string(), 80),
-                        "/".to_string(),
-                    ),
-                    (
-                        Address::DomainNameAddress("youtube.com".to_string(), 80),
-                        "/".to_string(),
-                    ),
-                ];
-                let chooser = Arc::new(
-                    ShadowsocksServerChooser::new(
+        let (server_chooser, group_choosers) =
+            match (&config.socks5_server, &config.shadowsocks_servers) {
+                (None, Some(shadowsocks_servers)) => {
+                    let chooser = run_server_chooser(
                         shadowsocks_servers.clone(),
                         dns_client.clone(),
-                        ping_url,
                         config.ping_timeout,
+                        config.server_group.clone(),
                     )
-                    .await,
-                );
-                let chooser_clone = chooser.clone();
-                let _ = spawn(async move { chooser_clone.ping_servers_forever().await.unwrap() });
-                chooser.ping_servers().await;
-                Some(chooser)
-            }
-            _ => None,
-        };
+                    .await;
+                    let mut group_choosers = HashMap::new();
+                    for group in &config.server_groups {
+                        let group_chooser = run_server_chooser(
+                            group_servers(shadowsocks_servers, &group.servers),
+                            dns_client.clone(),
+                            config.ping_timeout,
+                            group.config.clone(),
+                        )
+                        .await;
+                        group_choosers.insert(group.name.clone(), group_chooser);
+                    }
+                    (Some(chooser), group_choosers)
+                }
+                _ => (None, HashMap::new()),
+            };
+
+        let metrics = Arc::new(Metrics::default());
+        let connections = Arc::new(Connections::default());
+        if let Some(controller_config) = &config.controller {
+            let controller = Arc::new(Controller::new(
+                controller_config.clone(),
+                resolver.clone(),
+                dns_client.upstream(),
+                config.rules.clone(),
+                server_chooser.clone(),
+                metrics.clone(),
+                connections.clone(),
+                session_manager.clone(),
+                reload_requested,
+                PacProxy::from_config(&config),
+            ));
+            spawn(async move {
+                if let Err(e) = controller.run().await {
+                    error!(?e, "controller error");
+                }
+            });
+        }
+
+        let connection_limit = config.server_connection_limit.map(ConnectionLimit::new);
 
         Self {
             resolver,
@@ -104,64 +195,133 @@ impl ProxyClient {
             uid,
             session_manager,
             ss_server_chooser: server_chooser,
+            group_choosers,
+            metrics,
+            connections,
+            mux_sessions: MuxSessions::default(),
+            connection_limit,
+        }
+    }
+
+    /// Apply a reloaded config. Rules, hosts, dns servers and shadowsocks servers are
+    /// replaced, open connections are kept. Other settings need a restart.
+    pub async fn reload(&self, config: Config) {
+        for (rule, shadowed_by) in config.rules.unreachable_rules() {
+            warn!(?rule, ?shadowed_by, "rule is unreachable");
+        }
+        self.config.rules.replace(&config.rules);
+        let new_providers: HashMap<_, _> = config
+            .rule_providers
+            .iter()
+            .filter(|(name, _)| !self.config.rule_providers.contains_key(*name))
+            .map(|(name, provider)| (name.clone(), provider.clone()))
+            .collect();
+        let rules = self.config.rules.clone();
+        let _ = spawn_blocking(move || setup_rule_providers(&rules, &new_providers)).await;
+
+        self.config.hosts.replace(&config.hosts);
+        self.dns_client
+            .upstream()
+            .set_servers(&config.dns_servers, &config.dns_domain_servers);
+        *self.extra_directly_servers.write() = extra_directly_servers(&config);
+
+        if let (Some(chooser), Some(servers)) =
+            (&self.ss_server_chooser, &config.shadowsocks_servers)
+        {
+            chooser.update_servers(servers.clone()).await;
+            for group in &config.server_groups {
+                match self.group_choosers.get(&group.name) {
+                    Some(group_chooser) => {
+                        group_chooser
+                            .update_servers(group_servers(servers, &group.servers))
+                            .await
+                    }
+                    None => warn!(name = %group.name, "new server group needs a restart"),
+                }
+            }
         }
+        info!("config reloaded");
     }
 
+    /// The action with the matched rule, or why no rule is matched. Matched before the address is
+    /// resolved, so `PROBE` is left to `resolve_probe`.
     async fn get_action_for_addr(
         &self,
         original_addr: SocketAddr,
-        socket_addr: SocketAddr,
         addr: &Address,
-    ) -> Result<Action> {
-        let mut pass_proxy = false;
-        let domain = match &addr {
-            // 如果是 IP 说明是用户手动改了路由表，必须要走代理。
-            Address::SocketAddress(_) => {
-                return Ok(Action::Proxy);
-            }
-            Address::DomainNameAddress(domain, _port) => domain.to_string(),
+    ) -> Result<(Action, String)> {
+        let mut pass_proxy = None;
+        let (domain, port) = match &addr {
+            // 如果是 IP 说明是用户手动改了路由表，除非匹配 IP 或端口规则，否则必须要走代理。
+            Address::SocketAddress(addr) => {
+                let (action, rule) = match self.config.rules.rule_for_ip(
+                    addr.ip(),
+                    original_addr.port(),
+                    addr.port(),
+                ) {
+                    Some((rule, action)) => (action, rule.to_string()),
+                    None => (Action::Proxy, "ip without rule".to_string()),
+                };
+                return Ok((action, rule));
+            }
+            Address::DomainNameAddress(domain, port) => (domain.to_string(), *port),
         };
-        if self.extra_directly_servers.contains(&domain) {
-            pass_proxy = true;
+        if self.extra_directly_servers.read().contains(&domain) {
+            pass_proxy = Some("proxy server");
         }
         if let Some(uid) = self.uid {
             if !socket_addr_belong_to_user(original_addr, uid)? {
-                pass_proxy = true;
+                pass_proxy = Some("other user");
             }
         }
-        let mut action = if pass_proxy {
-            Action::Direct
+        let (action, rule) = if let Some(reason) = pass_proxy {
+            (Action::Direct, reason.to_string())
         } else {
-            self.config
-                .rules
-                .action_for_domain(&domain)
-                .unwrap_or_else(|| self.config.rules.default_action())
-        };
-
-        if action == Action::Probe {
-            if self.probe_connectivity(socket_addr).await {
-                action = Action::Direct;
+            let process_name = if self.config.rules.has_process_rules() {
+                find_process_name(original_addr)
             } else {
-                action = Action::Proxy;
+                None
+            };
+            let meta = ConnectionMeta {
+                domain: Some(domain),
+                src_port: Some(original_addr.port()),
+                dst_port: Some(port),
+                process_name,
+                ..Default::default()
+            };
+            trace!(?meta, "match rules");
+            match self.config.rules.rule_for_meta(&meta) {
+                Some((rule, action)) => (action, rule.to_string()),
+                None => (self.config.rules.default_action(), "default".to_string()),
             }
-        }
+        };
 
-        Ok(action)
+        Ok((action, rule))
     }
 
-    async fn choose_proxy_tcp_stream(
+    async fn resolve_probe(&self, action: Action, socket_addr: SocketAddr) -> Action {
+        if action != Action::Probe {
+            return action;
+        }
+        if self.probe_connectivity(socket_addr).await {
+            Action::Direct
+        } else {
+            Action::Proxy
+        }
+    }
+
+    async fn connect_tcp_stream(
         &self,
-        original_addr: SocketAddr,
+        action: Action,
         sock_addr: SocketAddr,
         remote_addr: &Address,
     ) -> Result<ProxyTcpStream> {
-        let action = self
-            .get_action_for_addr(original_addr, sock_addr, &remote_addr)
-            .await?;
-        trace!(?action, "selected action");
-
+        if let Some(chooser) = self.group_chooser(&action) {
+            return self.connect_shadowsocks_tcp(chooser, remote_addr).await;
+        }
         match action {
-            Action::Proxy => {
+            // groups are only available with shadowsocks servers, use the proxy instead
+            Action::Proxy | Action::Group(_) => {
                 if let Some(socks5_config) = &self.config.socks5_server {
                     let server = self.dns_client.lookup_address(&socks5_config.addr).await?;
                     trace!("choose_proxy_tcp_stream: socks5");
@@ -176,31 +336,7 @@ impl ProxyClient {
                 }
 
                 if let Some(chooser) = &self.ss_server_chooser {
-                    return retry!(3, async {
-                        let (ss_server, server_alive) =
-                            chooser.candidate().expect("no candidate available");
-                        let server = self.dns_client.lookup_address(&ss_server.addr()).await?;
-                        trace!("choose_proxy_tcp_stream: shadowsocks");
-                        let stream = timeout(
-                            self.config.connect_timeout,
-                            SSTcpStream::connect(
-                                remote_addr.clone(),
-                                server,
-                                server_alive.clone(),
-                                ss_server.method(),
-                                ss_server.key(),
-                            ),
-                        )
-                        .await;
-                        match stream {
-                            Ok(s) => Ok(ProxyTcpStream::Shadowsocks(s)),
-                            Err(e) => {
-                                chooser.take_down_current_and_move_next().await;
-                                Err(e)
-                            }
-                        }
-                    })
-                    .await;
+                    return self.connect_shadowsocks_tcp(chooser, remote_addr).await;
                 }
 
                 if let Some(proxy_config) = &self.config.http_proxy_server {
@@ -219,7 +355,8 @@ impl ProxyClient {
                 // fallback to direct
             }
             Action::Direct => {}
-            _ => unimplemented!(),
+            Action::Reject => return Err(rejected()),
+            Action::Probe | Action::Script => unreachable!("resolved by the rules"),
         }
         trace!("choose_proxy_tcp_stream: direct");
         Ok(ProxyTcpStream::Direct(
@@ -238,12 +375,14 @@ impl ProxyClient {
         sock_addr: SocketAddr,
         addr: &Address,
     ) -> Result<ProxyUdpSocket> {
-        let action = self
-            .get_action_for_addr(original_addr, sock_addr, &addr)
-            .await?;
+        let (action, _) = self.get_action_for_addr(original_addr, &addr).await?;
+        let action = self.resolve_probe(action, sock_addr).await;
 
+        if let Some(chooser) = self.group_chooser(&action) {
+            return self.connect_shadowsocks_udp(chooser, addr).await;
+        }
         match action {
-            Action::Proxy => {
+            Action::Proxy | Action::Group(_) => {
                 if let Some(socks5_config) = &self.config.socks5_server {
                     let server = self.dns_client.lookup_address(&socks5_config.addr).await?;
                     trace!("choose_proxy_udp_socket: socks5");
@@ -258,30 +397,14 @@ impl ProxyClient {
                 }
 
                 if let Some(chooser) = &self.ss_server_chooser {
-                    return retry!(3, async {
-                        let (ss_server, _) = chooser.candidate().expect("no candidate available");
-                        let server = self.dns_client.lookup_address(&ss_server.addr()).await?;
-                        trace!("choose_proxy_udp_socket: shadowsocks");
-                        let udp = timeout(
-                            self.config.connect_timeout,
-                            SSUdpSocket::new(server, ss_server.method(), ss_server.key()),
-                        )
-                        .await;
-                        match udp {
-                            Ok(s) => Ok(ProxyUdpSocket::Shadowsocks(Arc::new(s))),
-                            Err(e) => {
-                                chooser.take_down_current_and_move_next().await;
-                                Err(e)
-                            }
-                        }
-                    })
-                    .await;
+                    return self.connect_shadowsocks_udp(chooser, addr).await;
                 }
 
                 // fallback to direct
             }
             Action::Direct => {}
-            _ => unreachable!(),
+            Action::Reject => return Err(rejected()),
+            Action::Probe | Action::Script => unreachable!("resolved by the rules"),
         }
 
         trace!("choose_proxy_udp_socket: direct");
@@ -290,14 +413,131 @@ impl ProxyClient {
         )))
     }
 
+    fn group_chooser(&self, action: &Action) -> Option<&ShadowsocksServerChooser> {
+        match action {
+            Action::Group(name) => self
+                .group_choosers
+                .get(name)
+                .map(|chooser| chooser.as_ref()),
+            _ => None,
+        }
+    }
+
+    async fn connect_shadowsocks_tcp(
+        &self,
+        chooser: &ShadowsocksServerChooser,
+        remote_addr: &Address,
+    ) -> Result<ProxyTcpStream> {
+        retry!(3, async {
+            let (ss_server, server_alive) =
+                chooser.candidate(remote_addr).ok_or_else(no_candidate)?;
+            let server = self.dns_client.lookup_server(&ss_server.addr()).await?;
+            trace!(
+                name = ss_server.name(),
+                "choose_proxy_tcp_stream: shadowsocks"
+            );
+            if let Some(mux) = ss_server.mux() {
+                let stream = timeout(
+                    self.config.connect_timeout,
+                    self.mux_sessions.open(
+                        &ss_server,
+                        server,
+                        server_alive.clone(),
+                        mux,
+                        remote_addr,
+                    ),
+                )
+                .await;
+                return match stream {
+                    Ok(s) => Ok(ProxyTcpStream::Mux(
+                        s,
+                        Arc::new(chooser.track_connection(&ss_server, None)),
+                    )),
+                    Err(e) => {
+                        self.dns_client.server_failed(ss_server.addr(), server);
+                        chooser.connect_failed(&ss_server).await;
+                        Err(e)
+                    }
+                };
+            }
+            // Waiting for the limit is not a failure of the server.
+            let permit = match &self.connection_limit {
+                Some(limit) => Some(limit.acquire().await?),
+                None => None,
+            };
+            let stream = retry_with_backoff(ss_server.retry(), || {
+                timeout(
+                    self.config.connect_timeout,
+                    SSTcpStream::connect(
+                        remote_addr.clone(),
+                        server,
+                        server_alive.clone(),
+                        ss_server.method(),
+                        ss_server.key(),
+                        ss_server.fast_open(),
+                    ),
+                )
+            })
+            .await;
+            match stream {
+                Ok(s) => {
+                    if self.config.handshake_timeout > Duration::from_secs(0) {
+                        s.set_handshake_timeout(self.config.handshake_timeout);
+                    }
+                    Ok(ProxyTcpStream::Shadowsocks(
+                        s,
+                        Arc::new(chooser.track_connection(&ss_server, permit)),
+                    ))
+                }
+                Err(e) => {
+                    self.dns_client.server_failed(ss_server.addr(), server);
+                    chooser.connect_failed(&ss_server).await;
+                    Err(e)
+                }
+            }
+        })
+        .await
+    }
+
+    async fn connect_shadowsocks_udp(
+        &self,
+        chooser: &ShadowsocksServerChooser,
+        addr: &Address,
+    ) -> Result<ProxyUdpSocket> {
+        retry!(3, async {
+            let (ss_server, _) = chooser.candidate(addr).ok_or_else(no_candidate)?;
+            let server = self.dns_client.lookup_server(&ss_server.addr()).await?;
+            trace!(
+                name = ss_server.name(),
+                "choose_proxy_udp_socket: shadowsocks"
+            );
+            let udp = retry_with_backoff(ss_server.retry(), || {
+                timeout(
+                    self.config.connect_timeout,
+                    SSUdpSocket::new(server, ss_server.method(), ss_server.key()),
+                )
+            })
+            .await;
+            match udp {
+                Ok(s) => Ok(ProxyUdpSocket::Shadowsocks(Arc::new(s))),
+                Err(e) => {
+                    self.dns_client.server_failed(ss_server.addr(), server);
+                    chooser.connect_failed(&ss_server).await;
+                    Err(e)
+                }
+            }
+        })
+        .await
+    }
+
     async fn probe_connectivity(&self, addr: SocketAddr) -> bool {
         timeout(self.config.probe_timeout, TcpStream::connect(addr))
             .await
             .is_ok()
     }
 
-    async fn run_tcp_relay_server(&self) -> Result<()> {
-        let listener = TcpListener::bind((self.config.tun_ip, 1300)).await?;
+    async fn run_tcp_relay_server(&self, ip: IpAddr) -> Result<()> {
+        let listener = TcpListener::bind((ip, 1300)).await?;
         let mut incoming = listener.incoming();
         while let Some(Ok(conn)) = incoming.next().await {
             let peer_addr = conn.peer_addr()?;
@@ -305,54 +545,506 @@ impl ProxyClient {
                 Some(s) => s,
                 None => continue,
             };
+            if self.config.dns_hijack && real_dest.port() == 53 {
+                self.hijack_tcp_dns(conn, real_src, real_dest);
+                continue;
+            }
+            let host = self.host_of(real_dest);
+            let _ = self
+                .relay_tcp_connection(conn, real_src, host, b"", vec![], None)
+                .instrument(trace_span!(
+                    "tcp connection",
+                    ?peer_addr,
+                    ?real_src,
+                    ?real_dest
+                ))
+                .await;
+        }
+        Ok::<(), io::Error>(())
+    }
 
-            async {
-                let ip = real_dest.ip().to_string();
-                let host = self
-                    .resolver
-                    .lookup_host(&ip)
-                    .map(|s| Address::DomainNameAddress(s, real_dest.port()))
-                    .unwrap_or_else(|| Address::SocketAddress(real_dest));
+    /// Relay the dns queries of `conn` to the embedded dns server in a new task.
+    fn hijack_tcp_dns(&self, conn: TcpStream, real_src: SocketAddr, real_dest: SocketAddr) {
+        let read_timeout = self.config.read_timeout;
+        let dns_addr = match dns_hijack::local_dns_addr(&self.config.dns_listen) {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!(?e, "dns hijack");
+                return;
+            }
+        };
+        trace!(?real_src, ?real_dest, "hijack tcp dns");
+        spawn(async move {
+            if let Err(e) = dns_hijack::relay_tcp_queries(conn, dns_addr, read_timeout).await {
+                trace!(?e, ?real_src, ?real_dest, "hijacked tcp dns closed");
+            }
+        });
+    }
 
-                trace!(dest_host = ?host, "new relay connection");
+    /// The domain of a fake ip, or the address itself.
+    fn host_of(&self, addr: SocketAddr) -> Address {
+        self.resolver
+            .lookup_host(&addr.ip().to_string())
+            .map(|s| Address::DomainNameAddress(s, addr.port()))
+            .unwrap_or_else(|| Address::SocketAddress(addr))
+    }
 
-                let sock_addr = match self.dns_client.lookup_address(&host).await {
-                    Ok(a) => a,
-                    Err(e) => {
-                        error!(?e, ?host, "error resolve dns");
-                        return;
+    /// Connect to `host` by the rules, or by `via` if set, and relay `conn` to it in a new task.
+    /// Once connected, `reply` is written to `conn` and `first_data` to the host before relaying.
+    /// Returns an error if the host can't be connected, or the `rejected` error without resolving
+    /// and connecting it if it matches a `REJECT` rule.
+    #[allow(clippy::too_many_arguments)]
+    async fn relay_tcp_connection(
+        &self,
+        mut conn: TcpStream,
+        real_src: SocketAddr,
+        host: Address,
+        reply: &'static [u8],
+        first_data: Vec<u8>,
+        via: Option<&Action>,
+    ) -> Result<()> {
+        trace!(dest_host = ?host, "new relay connection");
+
+        let (action, rule) = match via {
+            Some(action) => (action.clone(), "forward".to_string()),
+            None => {
+                self.get_action_for_addr(real_src, &host)
+                    .instrument(trace_span!("rule match"))
+                    .await?
+            }
+        };
+        if action == Action::Reject {
+            trace!(?host, %rule, "rejected");
+            return Err(rejected());
+        }
+
+        let sock_addr = match self
+            .dns_client
+            .lookup_address(&host)
+            .instrument(trace_span!("dns lookup"))
+            .await
+        {
+            Ok(a) => a,
+            Err(e) => {
+                error!(?e, ?host, "error resolve dns");
+                return Err(e);
+            }
+        };
+
+        trace!(host = ?host, "lookup host");
+
+        let action = self.resolve_probe(action, sock_addr).await;
+        trace!(?action, %rule, "selected action");
+        let start = Instant::now();
+        // Includes the handshake with the proxy server.
+        let mut remote_conn = match self
+            .connect_tcp_stream(action.clone(), sock_addr, &host)
+            .instrument(trace_span!("connect", ?action))
+            .await
+        {
+            Ok(connected) => connected,
+            Err(e) => {
+                self.metrics.connect_failed();
+                error!(?e, "connect error");
+                return Err(e);
+            }
+        };
+        self.metrics.connected(start.elapsed());
+        let metrics = self.metrics.clone();
+        let registered = self.connections.clone().register(
+            real_src,
+            host.to_string(),
+            remote_conn.server_name().to_string(),
+            rule,
+            conn.clone(),
+        );
+        let id = registered.id();
+        trace!(id, "connect successfully");
+        let domain = match &host {
+            Address::DomainNameAddress(domain, _) => domain.clone(),
+            Address::SocketAddress(addr) => addr.ip().to_string(),
+        };
+        let traffic = vec![
+            metrics.traffic(remote_conn.server_name()),
+            metrics.domain_traffic(&domain),
+            registered.traffic(),
+        ];
+        let capture = self
+            .config
+            .capture
+            .as_ref()
+            .and_then(|config| CaptureFile::open(config, id, &domain, real_src, sock_addr));
+        let span = trace_span!("relay", id);
+        spawn(
+            async move {
+                let connection = remote_conn.active_connection();
+                metrics.connection_opened();
+                let ret = async {
+                    conn.write_all(reply).await?;
+                    remote_conn.write_all(&first_data).await?;
+                    for traffic in &traffic {
+                        traffic
+                            .up
+                            .fetch_add(first_data.len() as u64, Ordering::Relaxed);
                     }
-                };
+                    tunnel_tcp_stream(conn, remote_conn, &traffic, capture.as_ref()).await
+                }
+                .await;
+                metrics.connection_closed();
+                drop(registered);
+                trace!(id, "connection closed");
+                if let (Err(e), Some(connection)) = (ret, connection) {
+                    trace!(
+                        id,
+                        ?e,
+                        name = connection.server().name(),
+                        "shadowsocks connection broken"
+                    );
+                    connection.report_broken().await;
+                }
+            }
+            .instrument(span),
+        );
+        Ok(())
+    }
 
-                trace!(ip = ?ip, host = ?host, "lookup host");
+    async fn serve_http_proxy_client(&self, mut conn: TcpStream) {
+        let peer_addr = match conn.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+        let request = match timeout(self.config.read_timeout, read_request(&mut conn)).await {
+            Ok(request) => request,
+            Err(e) => {
+                trace!(?e, ?peer_addr, "invalid http proxy request");
+                let _ = conn.write_all(BAD_REQUEST).await;
+                return;
+            }
+        };
+        // Requests to the fake ips resolved by seeker are matched by their domains.
+        let host = match request.host {
+            Address::SocketAddress(addr) => self.host_of(addr),
+            host => host,
+        };
+        let ret = self
+            .relay_tcp_connection(
+                conn.clone(),
+                peer_addr,
+                host,
+                request.reply,
+                request.first_data,
+                None,
+            )
+            .instrument(trace_span!("http proxy connection", ?peer_addr))
+            .await;
+        match ret {
+            Err(e) if is_rejected(&e) => {
+                let _ = conn.write_all(FORBIDDEN).await;
+            }
+            Err(_) => {
+                let _ = conn.write_all(BAD_GATEWAY).await;
+            }
+            Ok(()) => {}
+        }
+    }
 
-                match self
-                    .choose_proxy_tcp_stream(real_src, sock_addr, &host)
-                    .await
-                {
-                    Ok(remote_conn) => {
-                        trace!("connect successfully");
-                        spawn(async move { tunnel_tcp_stream(conn, remote_conn).await });
+    async fn serve_socks5_client(&self, mut conn: TcpStream, config: &Socks5InboundConfig) {
+        let peer_addr = match conn.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+        let handshake = socks5_inbound::handshake(&mut conn, config);
+        let (host, user) = match timeout(self.config.read_timeout, handshake).await {
+            Ok(request) => request,
+            Err(e) => {
+                trace!(?e, ?peer_addr, "socks5 handshake error");
+                return;
+            }
+        };
+        let host = match host {
+            Address::SocketAddress(addr) => self.host_of(addr),
+            host => host,
+        };
+        if let Some(user) = user {
+            if !user.allows(&host) {
+                info!(username = %user.username, %host, "socks5 destination not allowed");
+                let _ = conn.write_all(socks5_inbound::NOT_ALLOWED).await;
+                return;
+            }
+        }
+        let ret = self
+            .relay_tcp_connection(
+                conn.clone(),
+                peer_addr,
+                host,
+                socks5_inbound::SUCCEEDED,
+                vec![],
+                None,
+            )
+            .instrument(trace_span!("socks5 connection", ?peer_addr))
+            .await;
+        match ret {
+            Err(e) if is_rejected(&e) => {
+                let _ = conn.write_all(socks5_inbound::NOT_ALLOWED).await;
+            }
+            Err(_) => {
+                let _ = conn.write_all(socks5_inbound::HOST_UNREACHABLE).await;
+            }
+            Ok(()) => {}
+        }
+    }
+
+    /// Serve socks5 and http clients on the same port, socks5 clients start with the version.
+    async fn serve_mixed_client(&self, conn: TcpStream, socks5: &Socks5InboundConfig) {
+        let mut first = [0u8; 1];
+        match timeout(self.config.read_timeout, conn.peek(&mut first)).await {
+            Ok(1) if first[0] == SOCKS5_VERSION => self.serve_socks5_client(conn, socks5).await,
+            Ok(1) => self.serve_http_proxy_client(conn).await,
+            _ => {}
+        }
+    }
+
+    async fn serve_forward_client(&self, conn: TcpStream, forward: &ForwardConfig) {
+        let peer_addr = match conn.peer_addr() {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+        let _ = self
+            .relay_tcp_connection(
+                conn,
+                peer_addr,
+                forward.target.clone(),
+                b"",
+                vec![],
+                forward.via.as_ref(),
+            )
+            .instrument(trace_span!("forward connection", ?peer_addr, target = %forward.target))
+            .await;
+    }
+
+    /// Relay tcp connections redirected by `REDIRECT`, and udp packets diverted by `TPROXY` if
+    /// `udp_listen` is set.
+    #[cfg(target_os = "linux")]
+    async fn run_redir(&self, config: &RedirConfig) -> Result<()> {
+        let tcp = run_inbound("redir", &config.tcp_listen, |conn| {
+            self.serve_redir_client(conn)
+        });
+        let udp = async {
+            match &config.udp_listen {
+                Some(addr) => self.run_tproxy_udp_server(addr).await,
+                None => pending().await,
+            }
+        };
+        tcp.race(udp).await
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn serve_redir_client(&self, conn: TcpStream) {
+        let (peer_addr, local_addr) = match (conn.peer_addr(), conn.local_addr()) {
+            (Ok(peer_addr), Ok(local_addr)) => (peer_addr, local_addr),
+            _ => return,
+        };
+        let real_dest = match redir::original_dst(&conn) {
+            Ok(addr) => addr,
+            Err(e) => {
+                error!(?e, ?peer_addr, "get original destination");
+                return;
+            }
+        };
+        // Connected to the listen address directly, relaying it would connect to itself.
+        if real_dest == local_addr {
+            trace!(?peer_addr, "connection not redirected");
+            return;
+        }
+        let host = self.host_of(real_dest);
+        let _ = self
+            .relay_tcp_connection(conn, peer_addr, host, b"", vec![], None)
+            .instrument(trace_span!("redir connection", ?peer_addr, ?real_dest))
+            .await;
+    }
+
+    /// Relay udp packets diverted by `TPROXY` to `addr`. Replies are sent from the original
+    /// destinations of the packets.
+    #[cfg(target_os = "linux")]
+    async fn run_tproxy_udp_server(&self, addr: &str) -> Result<()> {
+        let addr = addr
+            .parse::<SocketAddr>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let socket = TproxyUdpSocket::bind(addr)?;
+        info!(%addr, "tproxy udp listening");
+        // async-std can't receive the original destinations, packets are received by a thread.
+        let (sender, receiver) = channel(1024);
+        thread::Builder::new()
+            .name("tproxy-udp".to_string())
+            .spawn(move || {
+                let mut buf = vec![0; 2000];
+                loop {
+                    match socket.recv(&mut buf) {
+                        Ok((size, src, dest)) => {
+                            block_on(sender.send((buf[..size].to_vec(), src, dest)))
+                        }
+                        Err(e) => error!(?e, "receive tproxy udp packet"),
+                    }
+                }
+            })?;
+
+        let write_timeout = self.config.write_timeout;
+        let sessions: TproxyUdpSessions = Arc::new(RwLock::new(HashMap::new()));
+        while let Some((data, src, real_dest)) = receiver.recv().await {
+            let existing = sessions.read().get(&(src, real_dest)).cloned();
+            let (socket, dest_addr) = match existing {
+                Some(session) => session,
+                None => match self.new_tproxy_udp_session(src, real_dest).await {
+                    Ok(session) => {
+                        sessions.write().insert((src, real_dest), session.clone());
+                        self.relay_tproxy_udp_replies(
+                            session.0.clone(),
+                            src,
+                            real_dest,
+                            sessions.clone(),
+                        );
+                        session
                     }
                     Err(e) => {
-                        error!(?e, "connect error");
+                        error!(?e, ?src, ?real_dest, "new tproxy udp session");
+                        continue;
                     }
-                };
+                },
+            };
+            if let Err(e) = timeout(write_timeout, socket.send_to(&data, dest_addr)).await {
+                error!(?e, "send to {}", dest_addr);
             }
-            .instrument(trace_span!(
-                "tcp connection",
-                ?peer_addr,
-                ?real_src,
-                ?real_dest
-            ))
-            .await
         }
-        Ok::<(), io::Error>(())
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn new_tproxy_udp_session(
+        &self,
+        src: SocketAddr,
+        real_dest: SocketAddr,
+    ) -> Result<(ProxyUdpSocket, SocketAddr)> {
+        trace!(?src, ?real_dest, "new tproxy udp session");
+        let host = self.host_of(real_dest);
+        let sock_addr = self.dns_client.lookup_address(&host).await?;
+        let socket = self.choose_proxy_udp_socket(src, sock_addr, &host).await?;
+        Ok((socket, sock_addr))
+    }
+
+    /// Send the replies of `socket` to `src` from `real_dest`, until none is received for
+    /// `read_timeout`.
+    #[cfg(target_os = "linux")]
+    fn relay_tproxy_udp_replies(
+        &self,
+        socket: ProxyUdpSocket,
+        src: SocketAddr,
+        real_dest: SocketAddr,
+        sessions: TproxyUdpSessions,
+    ) {
+        let recv_timeout = self.config.read_timeout;
+        let write_timeout = self.config.write_timeout;
+        spawn(async move {
+            let ret: Result<()> = async {
+                let reply = UdpSocket::from(redir::bind_transparent_udp(real_dest)?);
+                let mut buf = vec![0; 2000];
+                loop {
+                    let (size, _) = timeout(recv_timeout, socket.recv_from(&mut buf)).await?;
+                    timeout(write_timeout, reply.send_to(&buf[..size], src)).await?;
+                }
+            }
+            .await;
+            trace!(?ret, ?src, ?real_dest, "tproxy udp session closed");
+            sessions.write().remove(&(src, real_dest));
+        });
     }
 
     pub async fn run(&self) {
-        self.run_tcp_relay_server()
-            .race(self.run_udp_relay_server())
+        let ipv6 = async {
+            match self.config.tun_ipv6 {
+                Some(tun_ipv6) => {
+                    self.run_tcp_relay_server(tun_ipv6.ip.into())
+                        .race(self.run_udp_relay_server(tun_ipv6.ip.into()))
+                        .await
+                }
+                None => pending().await,
+            }
+        };
+        let http_proxy = async {
+            match &self.config.http_listen {
+                Some(addr) => {
+                    run_inbound("http", addr, |conn| self.serve_http_proxy_client(conn)).await
+                }
+                None => pending().await,
+            }
+        };
+        let socks5 = async {
+            match &self.config.socks5_inbound {
+                Some(config) => {
+                    let public = config
+                        .listen
+                        .parse::<SocketAddr>()
+                        .map(|addr| !addr.ip().is_loopback())
+                        .unwrap_or(true);
+                    if config.users.is_empty() && public {
+                        warn!(
+                            addr = %config.listen,
+                            "socks5 proxy is reachable from the network without authentication"
+                        );
+                    }
+                    run_inbound("socks5", &config.listen, |conn| {
+                        self.serve_socks5_client(conn, config)
+                    })
+                    .await
+                }
+                None => pending().await,
+            }
+        };
+        let mixed = async {
+            match &self.config.mixed_listen {
+                Some(addr) => {
+                    // Without authentication, the same as the http proxy.
+                    let socks5 = Socks5InboundConfig {
+                        listen: addr.clone(),
+                        users: vec![],
+                    };
+                    run_inbound("mixed", addr, |conn| self.serve_mixed_client(conn, &socks5)).await
+                }
+                None => pending().await,
+            }
+        };
+        let forwards = async {
+            let mut forwards = self
+                .config
+                .forwards
+                .iter()
+                .map(|forward| {
+                    run_inbound("forward", &forward.listen, move |conn| {
+                        self.serve_forward_client(conn, forward)
+                    })
+                })
+                .collect::<FuturesUnordered<_>>();
+            match forwards.next().await {
+                Some(ret) => ret,
+                None => pending().await,
+            }
+        };
+        let transparent = async {
+            #[cfg(target_os = "linux")]
+            {
+                if let Some(redir) = &self.config.redir {
+                    return self.run_redir(redir).await;
+                }
+            }
+            self.run_tcp_relay_server(self.config.tun_ip.into())
+                .race(self.run_udp_relay_server(Ipv4Addr::UNSPECIFIED.into()))
+                .race(ipv6)
+                .await
+        };
+        transparent
+            .race(http_proxy)
+            .race(socks5)
+            .race(mixed)
+            .race(forwards)
             .await
             .unwrap();
     }
@@ -376,24 +1068,31 @@ impl ProxyClient {
             return Ok(r.clone());
         }
 
-        let ip = real_dest.ip().to_string();
-        let host = self
-            .resolver
-            .lookup_host(&ip)
-            .map(|s| Address::DomainNameAddress(s, real_dest.port()))
-            .unwrap_or_else(|| Address::SocketAddress(real_dest));
-        let sock_addr = self.dns_client.lookup_address(&host).await?;
-        let socket = self
-            .choose_proxy_udp_socket(real_src, sock_addr, &host)
+        let (socket, sock_addr) = if self.config.dns_hijack && real_dest.port() == 53 {
+            trace!(?real_src, ?real_dest, "hijack udp dns");
+            let dns_addr = dns_hijack::local_dns_addr(&self.config.dns_listen)?;
+            let socket = UdpSocket::bind(match dns_addr {
+                SocketAddr::V4(_) => "0.0.0.0:0",
+                SocketAddr::V6(_) => "[::]:0",
+            })
             .await?;
+            (ProxyUdpSocket::Direct(Arc::new(socket)), dns_addr)
+        } else {
+            let host = self.host_of(real_dest);
+            let sock_addr = self.dns_client.lookup_address(&host).await?;
+            let socket = self
+                .choose_proxy_udp_socket(real_src, sock_addr, &host)
+                .await?;
+            (socket, sock_addr)
+        };
         self.udp_manager
             .write()
             .insert(port, (socket.clone(), sock_addr));
         Ok((socket, sock_addr))
     }
 
-    async fn run_udp_relay_server(&self) -> Result<()> {
-        let udp_listener = Arc::new(UdpSocket::bind("0.0.0.0:1300").await?);
+    async fn run_udp_relay_server(&self, ip: IpAddr) -> Result<()> {
+        let udp_listener = Arc::new(UdpSocket::bind((ip, 1300)).await?);
         let recv_timeout = self.config.read_timeout;
         let write_timeout = self.config.write_timeout;
         let mut buf = vec![0; 2000];
@@ -445,12 +1144,55 @@ impl ProxyClient {
     }
 }
 
-async fn tunnel_tcp_stream<T1: Read + Write + Unpin + Clone, T2: Read + Write + Unpin + Clone>(
-    mut conn1: T1,
-    mut conn2: T2,
+/// Accept the clients of a local proxy on `addr`. Clients are served by `serve` concurrently,
+/// without holding up the accepting of others.
+async fn run_inbound<F, Fut>(name: &str, addr: &str, serve: F) -> Result<()>
+where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "{} proxy listening", name);
+    let mut incoming = listener.incoming();
+    let mut clients = FuturesUnordered::new();
+    loop {
+        let accepted = if clients.is_empty() {
+            incoming.next().await
+        } else {
+            let accept = async { Some(incoming.next().await) };
+            let serving = async {
+                clients.next().await;
+                None
+            };
+            match accept.race(serving).await {
+                Some(accepted) => accepted,
+                None => continue,
+            }
+        };
+        match accepted {
+            Some(Ok(conn)) => clients.push(serve(conn)),
+            Some(Err(e)) => error!(?e, "accept {} proxy client", name),
+            None => return Ok(()),
+        }
+    }
+}
+
+async fn tunnel_tcp_stream(
+    mut conn1: TcpStream,
+    conn2: ProxyTcpStream,
+    traffic: &[Arc<Traffic>],
+    capture: Option<&CaptureFile>,
 ) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    let conn2 = match (conn2, capture) {
+        (ProxyTcpStream::Direct(remote), None) => {
+            return splice_tcp_stream(&conn1, &remote, traffic).await;
+        }
+        (conn2, _) => conn2,
+    };
+    // async-std tcp streams are shared without locking, so cloning is enough for them.
     let mut conn1_clone = conn1.clone();
-    let mut conn2_clone = conn2.clone();
+    let (mut conn2_read, mut conn2_write) = conn2.into_split();
     let f1 = async {
         let mut buf = vec![0; 1500];
         loop {
@@ -458,29 +1200,172 @@ async fn tunnel_tcp_stream<T1: Read + Write + Unpin + Clone, T2: Read + Write +
             if size == 0 {
                 break Ok(());
             }
-            conn2.write_all(&buf[..size]).await?;
+            conn2_write.write_all(&buf[..size]).await?;
+            if let Some(capture) = capture {
+                capture.up(&buf[..size]);
+            }
+            for traffic in traffic {
+                traffic.up.fetch_add(size as u64, Ordering::Relaxed);
+            }
         }
     };
     let f2 = async {
         let mut buf = vec![0; 1500];
         loop {
-            let size = conn2_clone.read(&mut buf).await?;
+            let size = conn2_read.read(&mut buf).await?;
             if size == 0 {
                 break Ok(());
             }
             conn1_clone.write_all(&buf[..size]).await?;
+            if let Some(capture) = capture {
+                capture.down(&buf[..size]);
+            }
+            for traffic in traffic {
+                traffic.down.fetch_add(size as u64, Ordering::Relaxed);
+            }
         }
     };
     f1.race(f2).await
 }
 
-async fn run_dns_resolver(config: &Config, resolver: AsyncStdResolver) -> RuleBasedDnsResolver {
+/// Relay a direct connection without copying the data to userspace, or through io_uring with
+/// the `uring` feature.
+#[cfg(target_os = "linux")]
+async fn splice_tcp_stream(
+    conn1: &TcpStream,
+    conn2: &TcpStream,
+    traffic: &[Arc<Traffic>],
+) -> Result<()> {
+    let count_up = |size: usize| {
+        for traffic in traffic {
+            traffic.up.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    };
+    let count_down = |size: usize| {
+        for traffic in traffic {
+            traffic.down.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    };
+    #[cfg(feature = "uring")]
+    {
+        if let Some(driver) = crate::uring::driver() {
+            let up = driver.copy(conn1, conn2, count_up);
+            let down = driver.copy(conn2, conn1, count_down);
+            return up.race(down).await;
+        }
+    }
+    let up = splice_copy(conn1, conn2, count_up);
+    let down = splice_copy(conn2, conn1, count_down);
+    up.race(down).await
+}
+
+/// Servers which are always connected directly.
+fn extra_directly_servers(config: &Config) -> Vec<String> {
+    let mut servers = vec![];
+    // always pass proxy for socks5 server
+    if let Some(socks5_addr) = &config.socks5_server {
+        servers.push(socks5_addr.addr.to_string());
+    }
+    // always pass proxy for socks5 server
+    if let Some(c) = &config.http_proxy_server {
+        servers.push(c.addr.to_string());
+    }
+
+    if let Some(shadowsocks_servers) = &config.shadowsocks_servers {
+        for shadowsocks_server in shadowsocks_servers.iter() {
+            servers.push(shadowsocks_server.addr().to_string());
+        }
+    }
+    servers
+}
+
+/// A group is empty when none of its servers is provided by the config or the subscriptions.
+fn no_candidate() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no server available in the group")
+}
+
+/// Connections and packets matching a `REJECT` rule are dropped, closing the connection tells
+/// the application.
+fn rejected() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, Rejected)
+}
+
+/// Tells rejected connections from connections refused by the host.
+fn is_rejected(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |e| e.is::<Rejected>())
+}
+
+#[derive(Debug)]
+struct Rejected;
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("rejected by rule")
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Servers of a named group, in the order of the group.
+fn group_servers(
+    shadowsocks_servers: &[ShadowsocksServerConfig],
+    names: &[String],
+) -> Arc<Vec<ShadowsocksServerConfig>> {
+    let servers = names
+        .iter()
+        .filter_map(|name| {
+            shadowsocks_servers
+                .iter()
+                .find(|server| server.name() == name)
+        })
+        .cloned()
+        .collect();
+    Arc::new(servers)
+}
+
+async fn run_server_chooser(
+    servers: Arc<Vec<ShadowsocksServerConfig>>,
+    dns_client: DnsClient,
+    ping_timeout: Duration,
+    group: ServerGroupConfig,
+) -> Arc<ShadowsocksServerChooser> {
+    let ping_url = vec![
+        (
+            Address::DomainNameAddress("google.com".to_string(), 80),
+            "/".to_string(),
+        ),
+        (
+            Address::DomainNameAddress("twitter.com".to_string(), 80),
+            "/".to_string(),
+        ),
+        (
+            Address::DomainNameAddress("github.com".to_string(), 80),
+            "/".to_string(),
+        ),
+        (
+            Address::DomainNameAddress("youtube.com".to_string(), 80),
+            "/".to_string(),
+        ),
+    ];
+    let chooser = Arc::new(
+        ShadowsocksServerChooser::new(servers, dns_client, ping_url, ping_timeout, group).await,
+    );
+    let chooser_clone = chooser.clone();
+    let _ = spawn(async move { chooser_clone.ping_servers_forever().await.unwrap() });
+    chooser.ping_servers().await;
+    chooser
+}
+
+async fn run_dns_resolver(config: &Config, upstream: Upstream) -> RuleBasedDnsResolver {
     let (dns_server, resolver) = create_dns_server(
         "dns.db",
         config.dns_listen.clone(),
-        config.dns_start_ip,
+        config.fake_ips(),
+        config.dns_start_ipv6,
         config.rules.clone(),
-        resolver,
+        config.hosts.clone(),
+        config.dns_aaaa,
+        upstream.clone(),
     )
     .await;
     println!("Spawn DNS server");
@@ -489,6 +1374,22 @@ async fn run_dns_resolver(config: &Config, resolver: AsyncStdResolver) -> RuleBa
             .run_server()
             .instrument(trace_span!("dns_server.run_server")),
     );
+    let resolver_clone = resolver.clone();
+    let max_age = config.fake_ip_max_age;
+    spawn(async move {
+        loop {
+            let removed = resolver_clone.remove_expired(max_age);
+            info!(removed, "remove expired fake ips");
+            let stats = upstream.cache_stats();
+            info!(
+                hits = stats.hits,
+                misses = stats.misses,
+                size = stats.size,
+                "dns cache stats"
+            );
+            async_std::task::sleep(Duration::from_secs(3600)).await;
+        }
+    });
     resolver
 }
 
@@ -505,3 +1406,139 @@ fn socket_addr_belong_to_user(addr: SocketAddr, uid: u32) -> Result<bool> {
 fn socket_addr_belong_to_user(_addr: SocketAddr, _uid: u32) -> Result<bool> {
     Ok(true)
 }
+
+#[cfg(target_arch = "x86_64")]
+fn find_process_name(addr: SocketAddr) -> Option<String> {
+    match sysconfig::find_process_name_by_local_addr(addr) {
+        Ok(name) => name,
+        Err(e) => {
+            error!(?e, ?addr, "find process name error");
+            None
+        }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn find_process_name(_addr: SocketAddr) -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ConfigFormat;
+    use config::Hosts;
+
+    /// A client with `rules`, without the dns server, the tun device and the server choosers.
+    async fn new_client(rules: &str, db: &std::path::Path) -> ProxyClient {
+        let yaml = format!(
+            r#"
+dns_start_ip: 11.0.0.10
+dns_servers: ["127.0.0.1:53"]
+tun_name: utun4
+tun_ip: 11.0.0.1
+tun_cidr: 11.0.0.0/16
+dns_listen: 127.0.0.1:53
+max_connect_errors: 1
+rules: {}
+socks5_server:
+  addr: 127.0.0.1:1
+"#,
+            rules
+        );
+        let config = Config::from_reader_with_format(yaml.as_bytes(), ConfigFormat::Yaml).unwrap();
+        let upstream = Upstream::new(&config.dns_servers, Duration::from_secs(1));
+        let ips = config.fake_ips();
+        let resolver = RuleBasedDnsResolver::new(
+            db,
+            u32::from(ips.start)..u32::from(ips.end),
+            u128::from(config.dns_start_ipv6),
+            config.rules.clone(),
+            Hosts::default(),
+            config.dns_aaaa,
+            upstream.clone(),
+        )
+        .await;
+        ProxyClient {
+            uid: None,
+            session_manager: SessionManager::new(10),
+            udp_manager: Arc::new(RwLock::new(HashMap::new())),
+            resolver,
+            dns_client: DnsClient::new(upstream, Hosts::default()),
+            extra_directly_servers: RwLock::new(vec![]),
+            ss_server_chooser: None,
+            group_choosers: HashMap::new(),
+            metrics: Arc::new(Metrics::default()),
+            connections: Arc::new(Connections::default()),
+            mux_sessions: MuxSessions::default(),
+            connection_limit: None,
+            config,
+        }
+    }
+
+    /// A connected pair, the accepted end and the connecting end.
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        (accepted, client)
+    }
+
+    #[test]
+    fn test_reject_port() {
+        let dir = std::env::temp_dir().join(format!("seeker-reject-{}", std::process::id()));
+        async_std::task::block_on(async {
+            let client = new_client("['DST-PORT,25,REJECT', 'MATCH,DIRECT']", &dir).await;
+            let (conn, mut app) = tcp_pair().await;
+            let peer_addr = conn.peer_addr().unwrap();
+            let host = Address::SocketAddress("127.0.0.1:25".parse().unwrap());
+            let ret = client
+                .relay_tcp_connection(conn, peer_addr, host, b"", vec![], None)
+                .await;
+            assert_eq!(ret.unwrap_err().kind(), io::ErrorKind::ConnectionRefused);
+            // Closed without connecting.
+            let mut buf = [0; 1];
+            assert_eq!(app.read(&mut buf).await.unwrap(), 0);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_reject_inbound() {
+        let dir = std::env::temp_dir().join(format!("seeker-reject-in-{}", std::process::id()));
+        async_std::task::block_on(async {
+            // The domain is rejected before it's resolved, there is no dns server.
+            let rules = "['DOMAIN-SUFFIX,blocked.test,REJECT', 'MATCH,DIRECT']";
+            let client = new_client(rules, &dir).await;
+            let socks5 = Socks5InboundConfig {
+                listen: "127.0.0.1:0".to_string(),
+                users: vec![],
+            };
+
+            let (conn, mut app) = tcp_pair().await;
+            app.write_all(
+                b"CONNECT www.blocked.test:443 HTTP/1.1\r\nHost: www.blocked.test:443\r\n\r\n",
+            )
+            .await
+            .unwrap();
+            client.serve_mixed_client(conn, &socks5).await;
+            let mut reply = vec![];
+            app.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, FORBIDDEN);
+
+            let (conn, mut app) = tcp_pair().await;
+            let mut request = vec![5, 1, 0, 5, 1, 0, 3, 16];
+            request.extend_from_slice(b"www.blocked.test");
+            request.extend_from_slice(&443u16.to_be_bytes());
+            app.write_all(&request).await.unwrap();
+            client.serve_mixed_client(conn, &socks5).await;
+            let mut reply = vec![];
+            app.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(&reply[..2], &[5, 0]);
+            assert_eq!(&reply[2..], socks5_inbound::NOT_ALLOWED);
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,10 +1,13 @@
+use crate::mux::{MuxReadHalf, MuxStream, MuxWriteHalf};
+use crate::server_chooser::ActiveConnection;
 use async_std::io::{Read, Write};
 use async_std::net::TcpStream;
 use http_proxy_client::HttpProxyTcpStream;
 use socks5_client::Socks5TcpStream;
-use ssclient::SSTcpStream;
-use std::io::Result;
+use ssclient::{SSReadHalf, SSTcpStream, SSWriteHalf};
+use std::io::{IoSlice, Result};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 #[derive(Clone)]
@@ -12,7 +15,70 @@ pub enum ProxyTcpStream {
     Direct(TcpStream),
     Socks5(Socks5TcpStream),
     HttpProxy(HttpProxyTcpStream),
-    Shadowsocks(SSTcpStream),
+    Shadowsocks(SSTcpStream, Arc<ActiveConnection>),
+    /// A stream of a mux session with a shadowsocks server.
+    Mux(MuxStream, Arc<ActiveConnection>),
+}
+
+impl ProxyTcpStream {
+    /// The connection through a shadowsocks server of the stream.
+    pub fn active_connection(&self) -> Option<Arc<ActiveConnection>> {
+        match self {
+            ProxyTcpStream::Shadowsocks(_, connection) | ProxyTcpStream::Mux(_, connection) => {
+                Some(connection.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Split into halves for relaying in both directions. Shadowsocks streams are split so the
+    /// halves don't share a lock, other streams are cheap to clone and used as both halves.
+    pub fn into_split(self) -> (ProxyReadHalf, ProxyWriteHalf) {
+        match self {
+            ProxyTcpStream::Shadowsocks(stream, connection) => match stream.into_split() {
+                Ok((read_half, write_half)) => (
+                    ProxyReadHalf::Shadowsocks(read_half),
+                    ProxyWriteHalf::Shadowsocks(write_half),
+                ),
+                Err(stream) => {
+                    let stream = ProxyTcpStream::Shadowsocks(stream, connection);
+                    (
+                        ProxyReadHalf::Shared(stream.clone()),
+                        ProxyWriteHalf::Shared(stream),
+                    )
+                }
+            },
+            ProxyTcpStream::Mux(stream, connection) => match stream.into_split() {
+                Ok((read_half, write_half)) => (
+                    ProxyReadHalf::Mux(read_half),
+                    ProxyWriteHalf::Mux(write_half),
+                ),
+                Err(stream) => {
+                    let stream = ProxyTcpStream::Mux(stream, connection);
+                    (
+                        ProxyReadHalf::Shared(stream.clone()),
+                        ProxyWriteHalf::Shared(stream),
+                    )
+                }
+            },
+            stream => (
+                ProxyReadHalf::Shared(stream.clone()),
+                ProxyWriteHalf::Shared(stream),
+            ),
+        }
+    }
+
+    /// Name of the server in metrics.
+    pub fn server_name(&self) -> &str {
+        match self {
+            ProxyTcpStream::Direct(_) => "DIRECT",
+            ProxyTcpStream::Socks5(_) => "socks5",
+            ProxyTcpStream::HttpProxy(_) => "http_proxy",
+            ProxyTcpStream::Shadowsocks(_, connection) | ProxyTcpStream::Mux(_, connection) => {
+                connection.server().name()
+            }
+        }
+    }
 }
 
 impl Read for ProxyTcpStream {
@@ -24,8 +90,9 @@ impl Read for ProxyTcpStream {
         match &mut *self {
             ProxyTcpStream::Direct(conn) => Pin::new(conn).poll_read(cx, buf),
             ProxyTcpStream::Socks5(conn) => Pin::new(conn).poll_read(cx, buf),
-            ProxyTcpStream::Shadowsocks(conn) => Pin::new(conn).poll_read(cx, buf),
+            ProxyTcpStream::Shadowsocks(conn, _) => Pin::new(conn).poll_read(cx, buf),
             ProxyTcpStream::HttpProxy(conn) => Pin::new(conn).poll_read(cx, buf),
+            ProxyTcpStream::Mux(conn, _) => Pin::new(conn).poll_read(cx, buf),
         }
     }
 }
@@ -39,8 +106,23 @@ impl Write for ProxyTcpStream {
         match &mut *self {
             ProxyTcpStream::Direct(conn) => Pin::new(conn).poll_write(cx, buf),
             ProxyTcpStream::Socks5(conn) => Pin::new(conn).poll_write(cx, buf),
-            ProxyTcpStream::Shadowsocks(conn) => Pin::new(conn).poll_write(cx, buf),
+            ProxyTcpStream::Shadowsocks(conn, _) => Pin::new(conn).poll_write(cx, buf),
             ProxyTcpStream::HttpProxy(conn) => Pin::new(conn).poll_write(cx, buf),
+            ProxyTcpStream::Mux(conn, _) => Pin::new(conn).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        match &mut *self {
+            ProxyTcpStream::Direct(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyTcpStream::Socks5(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyTcpStream::Shadowsocks(conn, _) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyTcpStream::HttpProxy(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyTcpStream::Mux(conn, _) => Pin::new(conn).poll_write_vectored(cx, bufs),
         }
     }
 
@@ -48,8 +130,9 @@ impl Write for ProxyTcpStream {
         match &mut *self {
             ProxyTcpStream::Direct(conn) => Pin::new(conn).poll_flush(cx),
             ProxyTcpStream::Socks5(conn) => Pin::new(conn).poll_flush(cx),
-            ProxyTcpStream::Shadowsocks(conn) => Pin::new(conn).poll_flush(cx),
+            ProxyTcpStream::Shadowsocks(conn, _) => Pin::new(conn).poll_flush(cx),
             ProxyTcpStream::HttpProxy(conn) => Pin::new(conn).poll_flush(cx),
+            ProxyTcpStream::Mux(conn, _) => Pin::new(conn).poll_flush(cx),
         }
     }
 
@@ -57,8 +140,79 @@ impl Write for ProxyTcpStream {
         match &mut *self {
             ProxyTcpStream::Direct(conn) => Pin::new(conn).poll_close(cx),
             ProxyTcpStream::Socks5(conn) => Pin::new(conn).poll_close(cx),
-            ProxyTcpStream::Shadowsocks(conn) => Pin::new(conn).poll_close(cx),
+            ProxyTcpStream::Shadowsocks(conn, _) => Pin::new(conn).poll_close(cx),
             ProxyTcpStream::HttpProxy(conn) => Pin::new(conn).poll_close(cx),
+            ProxyTcpStream::Mux(conn, _) => Pin::new(conn).poll_close(cx),
+        }
+    }
+}
+
+pub enum ProxyReadHalf {
+    /// A clone of the whole stream.
+    Shared(ProxyTcpStream),
+    Shadowsocks(SSReadHalf),
+    Mux(MuxReadHalf),
+}
+
+pub enum ProxyWriteHalf {
+    /// A clone of the whole stream.
+    Shared(ProxyTcpStream),
+    Shadowsocks(SSWriteHalf),
+    Mux(MuxWriteHalf),
+}
+
+impl Read for ProxyReadHalf {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        match &mut *self {
+            ProxyReadHalf::Shared(conn) => Pin::new(conn).poll_read(cx, buf),
+            ProxyReadHalf::Shadowsocks(conn) => Pin::new(conn).poll_read(cx, buf),
+            ProxyReadHalf::Mux(conn) => Pin::new(conn).poll_read(cx, buf),
+        }
+    }
+}
+
+impl Write for ProxyWriteHalf {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        match &mut *self {
+            ProxyWriteHalf::Shared(conn) => Pin::new(conn).poll_write(cx, buf),
+            ProxyWriteHalf::Shadowsocks(conn) => Pin::new(conn).poll_write(cx, buf),
+            ProxyWriteHalf::Mux(conn) => Pin::new(conn).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        match &mut *self {
+            ProxyWriteHalf::Shared(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyWriteHalf::Shadowsocks(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+            ProxyWriteHalf::Mux(conn) => Pin::new(conn).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut *self {
+            ProxyWriteHalf::Shared(conn) => Pin::new(conn).poll_flush(cx),
+            ProxyWriteHalf::Shadowsocks(conn) => Pin::new(conn).poll_flush(cx),
+            ProxyWriteHalf::Mux(conn) => Pin::new(conn).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match &mut *self {
+            ProxyWriteHalf::Shared(conn) => Pin::new(conn).poll_close(cx),
+            ProxyWriteHalf::Shadowsocks(conn) => Pin::new(conn).poll_close(cx),
+            ProxyWriteHalf::Mux(conn) => Pin::new(conn).poll_close(cx),
         }
     }
 }
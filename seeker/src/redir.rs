@@ -0,0 +1,205 @@
+//! Transparent proxy for connections redirected by iptables/nftables, without the tun device. Tcp
+//! connections redirected by `REDIRECT` keep their original destinations in conntrack, udp packets
+//! diverted by `TPROXY` come with theirs as ancillary data.
+
+use async_std::net::TcpStream;
+use libc::{c_int, c_void, sockaddr_storage, socklen_t};
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+
+// Also `IP6T_SO_ORIGINAL_DST` at `SOL_IPV6`.
+const SO_ORIGINAL_DST: c_int = 80;
+const IPV6_TRANSPARENT: c_int = 75;
+const IPV6_RECVORIGDSTADDR: c_int = 74;
+
+/// The destination of a redirected tcp connection before it was redirected.
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let level = match stream.local_addr()? {
+        SocketAddr::V6(addr) if addr.ip().to_ipv4().is_none() => libc::SOL_IPV6,
+        _ => libc::SOL_IP,
+    };
+    let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<sockaddr_storage>() as socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            SO_ORIGINAL_DST,
+            &mut storage as *mut _ as *mut c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    to_socket_addr(&storage)
+}
+
+/// A udp socket receiving the packets diverted to it by `TPROXY`.
+pub struct TproxyUdpSocket {
+    socket: UdpSocket,
+}
+
+impl TproxyUdpSocket {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let socket = bind_transparent_udp(addr)?;
+        let (level, name) = match addr {
+            SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_RECVORIGDSTADDR),
+            SocketAddr::V6(_) => (libc::SOL_IPV6, IPV6_RECVORIGDSTADDR),
+        };
+        set_option(socket.as_raw_fd(), level, name)?;
+        Ok(TproxyUdpSocket { socket })
+    }
+
+    /// Receive a packet, blocking. Returns its size, source and original destination.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+        let mut src: sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+        // u64 for the alignment of cmsghdr.
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut src as *mut _ as *mut c_void;
+        msg.msg_namelen = mem::size_of::<sockaddr_storage>() as socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let size = unsafe { libc::recvmsg(self.socket.as_raw_fd(), &mut msg, 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut dst = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            if (header.cmsg_level == libc::SOL_IP && header.cmsg_type == libc::IP_ORIGDSTADDR)
+                || (header.cmsg_level == libc::SOL_IPV6 && header.cmsg_type == IPV6_RECVORIGDSTADDR)
+            {
+                let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+                let len = (header.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize)
+                    .min(mem::size_of::<sockaddr_storage>());
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        libc::CMSG_DATA(cmsg),
+                        &mut storage as *mut _ as *mut u8,
+                        len,
+                    )
+                };
+                dst = Some(to_socket_addr(&storage)?);
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        let dst = dst
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no original destination"))?;
+        Ok((size as usize, to_socket_addr(&src)?, dst))
+    }
+}
+
+/// A udp socket bound to `addr`, which needn't be a local address. Replies to diverted packets are
+/// sent from their original destinations through it, several sockets may share an address.
+pub fn bind_transparent_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let (family, level, name) = match addr {
+        SocketAddr::V4(_) => (libc::AF_INET, libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::AF_INET6, libc::SOL_IPV6, IPV6_TRANSPARENT),
+    };
+    let fd = unsafe { libc::socket(family, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Closes the fd on errors.
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+    set_option(fd, level, name)?;
+    let (storage, len) = to_sockaddr(addr);
+    let ret = unsafe { libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+fn set_option(fd: RawFd, level: c_int, name: c_int) -> io::Result<()> {
+    let value: c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const c_void,
+            mem::size_of::<c_int>() as socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn to_socket_addr(storage: &sockaddr_storage) -> io::Result<SocketAddr> {
+    match c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported address family {}", family),
+        )),
+    }
+}
+
+fn to_sockaddr(addr: SocketAddr) -> (sockaddr_storage, socklen_t) {
+    let mut storage: sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as socklen_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sockaddr() {
+        for addr in &["10.0.0.1:53", "[fd00::1]:443"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let (storage, _) = to_sockaddr(addr);
+            assert_eq!(to_socket_addr(&storage).unwrap(), addr);
+        }
+    }
+}
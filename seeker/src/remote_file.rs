@@ -0,0 +1,108 @@
+use anyhow::Context;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A file downloaded from `url`, cached at `path` and downloaded again every `interval`, eg. a
+/// subscription or a rule provider.
+pub struct RemoteFile {
+    /// Used in the logs and the name of the refresh thread, eg. `subscription`.
+    pub kind: &'static str,
+    pub name: String,
+    pub url: String,
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+impl RemoteFile {
+    /// Load the cache, or download the file if it's not cached or outdated, then refresh it in a
+    /// background thread.
+    ///
+    /// `load` is called with the content of the cache and of each download, a download is only
+    /// cached if `load` accepts it. `refreshed` is called after each refresh in the background.
+    pub fn setup<L, R>(self, load: L, refreshed: R)
+    where
+        L: Fn(&str) -> anyhow::Result<()> + Send + 'static,
+        R: Fn() + Send + 'static,
+    {
+        let mut next_update = match self.load_from_cache(&load) {
+            Ok(age) => self.interval.checked_sub(age).unwrap_or_default(),
+            Err(e) => {
+                info!(?e, kind = self.kind, name = %self.name, "no cached file");
+                Duration::from_secs(0)
+            }
+        };
+        // Download synchronously at startup so that the file is used before any traffic.
+        if next_update == Duration::from_secs(0) {
+            next_update = self.update(&load).1;
+        }
+
+        let _ = thread::Builder::new()
+            .name(format!("{}-{}", self.kind, self.name))
+            .spawn(move || loop {
+                thread::sleep(next_update);
+                let (ok, next) = self.update(&load);
+                next_update = next;
+                if ok {
+                    refreshed();
+                }
+            })
+            .expect("spawn remote file thread");
+    }
+
+    /// Returns whether the update succeeded and the duration to wait before the next update.
+    fn update(&self, load: impl Fn(&str) -> anyhow::Result<()>) -> (bool, Duration) {
+        match self.download(load) {
+            Ok(()) => (true, self.interval),
+            Err(e) => {
+                error!(?e, kind = self.kind, name = %self.name, url = %self.url, "update remote file error");
+                (false, self.interval.min(RETRY_INTERVAL))
+            }
+        }
+    }
+
+    /// Returns how long ago the cache was written.
+    fn load_from_cache(
+        &self,
+        load: impl Fn(&str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Duration> {
+        let modified = fs::metadata(&self.path)
+            .context("Open cache error")?
+            .modified()?;
+        let content = fs::read_to_string(&self.path).context("Read cache error")?;
+        load(&content)?;
+        info!(kind = self.kind, name = %self.name, path = ?self.path, "load from cache");
+        Ok(SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default())
+    }
+
+    fn download(&self, load: impl Fn(&str) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let resp = ureq::get(&self.url)
+            .timeout_read(10000)
+            .timeout_connect(5000)
+            .timeout_write(5000)
+            .call();
+        if !resp.ok() {
+            return Err(anyhow::anyhow!(
+                "Download {} error: {}",
+                self.kind,
+                resp.status_line()
+            ));
+        }
+        let content = resp.into_string()?;
+        load(&content)?;
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, content).context("Write cache error")?;
+
+        info!(kind = self.kind, name = %self.name, "remote file updated");
+        Ok(())
+    }
+}
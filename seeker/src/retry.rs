@@ -0,0 +1,52 @@
+use async_std::task::sleep;
+use config::RetryConfig;
+use rand::Rng;
+use std::future::Future;
+use std::io::Result;
+use std::time::Duration;
+use tracing::warn;
+
+/// Call `f` until it succeeds or `config.max_attempts` attempts fail, backing off between
+/// attempts.
+pub async fn retry_with_backoff<T, F, Fut>(config: RetryConfig, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= config.max_attempts => return Err(e),
+            Err(e) => {
+                let delay = retry_delay(&config, attempt, rand::thread_rng().gen());
+                warn!(?e, attempt, delay = %delay.as_millis(), "retry connect");
+                sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Delay before retry `attempt` (from 1), `random` is in `[0, 1)`.
+fn retry_delay(config: &RetryConfig, attempt: usize, random: f64) -> Duration {
+    let exponent = (attempt - 1).min(16) as u32;
+    config.base_delay * 2u32.pow(exponent) + config.jitter.mul_f64(random)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        let config = RetryConfig {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            jitter: Duration::from_millis(50),
+        };
+        assert_eq!(retry_delay(&config, 1, 0.0), Duration::from_millis(100));
+        assert_eq!(retry_delay(&config, 2, 0.0), Duration::from_millis(200));
+        assert_eq!(retry_delay(&config, 3, 0.5), Duration::from_millis(425));
+    }
+}
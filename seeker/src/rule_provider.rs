@@ -0,0 +1,32 @@
+use crate::remote_file::RemoteFile;
+use config::rule::ProxyRules;
+use config::RuleProviderConfig;
+use std::collections::HashMap;
+use tracing::info;
+
+/// Load every rule provider into `rules`, then refresh each of them in a background thread.
+///
+/// Cached rule-sets are used at startup if they exist, so seeker can start without network.
+pub fn setup_rule_providers(rules: &ProxyRules, providers: &HashMap<String, RuleProviderConfig>) {
+    for (name, provider) in providers {
+        let file = RemoteFile {
+            kind: "rule-provider",
+            name: name.clone(),
+            url: provider.url.clone(),
+            path: provider.cache_path(name),
+            interval: provider.interval,
+        };
+        let rules = rules.clone();
+        let name = name.clone();
+        let provider = provider.clone();
+        file.setup(
+            move |content| {
+                let rule_set = provider.load_rule_set(content.as_bytes())?;
+                info!(name = %name, len = rule_set.len(), "load rule provider");
+                rules.update_rule_set(&name, rule_set);
+                Ok(())
+            },
+            || {},
+        );
+    }
+}
@@ -1,26 +1,91 @@
+use crate::connection_limit::ConnectionPermit;
 use crate::dns_client::DnsClient;
 use async_std::io::timeout;
+use async_std::net::TcpStream;
 use async_std::prelude::*;
-use async_std::task::{sleep, spawn};
-use config::{Address, ShadowsocksServerConfig};
+use async_std::task::{sleep, spawn, spawn_blocking};
+use config::{
+    Address, BalanceStrategy, GroupMode, ProbeMethod, ServerGroupConfig, ShadowsocksServerConfig,
+};
 use futures_util::stream::FuturesUnordered;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 use ssclient::SSTcpStream;
-use std::collections::HashMap;
-use std::io::Result;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tracing::{error, info};
 
+/// An open connection through a server, counted until dropped.
+pub struct ActiveConnection {
+    server: ShadowsocksServerConfig,
+    count: Arc<AtomicUsize>,
+    chooser: ShadowsocksServerChooser,
+    /// Held against `server_connection_limit` until the connection is closed.
+    _permit: Option<ConnectionPermit>,
+}
+
+impl ActiveConnection {
+    pub fn server(&self) -> &ShadowsocksServerConfig {
+        &self.server
+    }
+
+    /// Tell the chooser of the server that the connection broke.
+    pub async fn report_broken(&self) {
+        self.chooser.connection_broken(&self.server).await;
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A server taken down after repeated connect errors is not pinged until `until`.
+struct Cooldown {
+    until: Instant,
+    backoff: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub name: String,
+    pub addr: String,
+    pub alive: bool,
+    /// Latency measured by the last ping, none if the ping failed.
+    pub latency_ms: Option<u64>,
+    pub connections: usize,
+    /// Seconds until the server is pinged again after it was taken down.
+    pub cooldown_secs: Option<u64>,
+    /// Whether new connections currently use this server.
+    pub active: bool,
+    pub selected: bool,
+}
+
 #[derive(Clone)]
 pub struct ShadowsocksServerChooser {
     ping_url: Vec<(Address, String)>,
     ping_timeout: Duration,
-    servers: Arc<Vec<ShadowsocksServerConfig>>,
+    group: ServerGroupConfig,
+    servers: Arc<RwLock<Arc<Vec<ShadowsocksServerConfig>>>>,
     candidates: Arc<Mutex<Vec<ShadowsocksServerConfig>>>,
     dns_client: DnsClient,
     server_aliveness: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    latencies: Arc<Mutex<HashMap<String, Duration>>>,
+    connections: Arc<Mutex<HashMap<String, Arc<AtomicUsize>>>>,
+    /// Current weights of the smooth weighted round robin.
+    round_robin: Arc<Mutex<HashMap<String, i64>>>,
+    rechecking: Arc<Mutex<HashSet<String>>>,
+    selected: Arc<Mutex<Option<ShadowsocksServerConfig>>>,
+    connect_errors: Arc<Mutex<HashMap<String, usize>>>,
+    cooldowns: Arc<Mutex<HashMap<String, Cooldown>>>,
 }
 
 impl ShadowsocksServerChooser {
@@ -29,19 +94,50 @@ impl ShadowsocksServerChooser {
         dns_client: DnsClient,
         ping_url: Vec<(Address, String)>,
         ping_timeout: Duration,
+        group: ServerGroupConfig,
     ) -> Self {
         let chooser = ShadowsocksServerChooser {
             ping_url,
             ping_timeout,
+            group,
             candidates: Arc::new(Mutex::new(vec![])),
-            servers,
+            servers: Arc::new(RwLock::new(servers)),
             dns_client,
             server_aliveness: Arc::new(Mutex::new(HashMap::new())),
+            latencies: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            round_robin: Arc::new(Mutex::new(HashMap::new())),
+            rechecking: Arc::new(Mutex::new(HashSet::new())),
+            selected: Arc::new(Mutex::new(None)),
+            connect_errors: Arc::new(Mutex::new(HashMap::new())),
+            cooldowns: Arc::new(Mutex::new(HashMap::new())),
         };
         chooser.ping_servers().await;
         chooser
     }
 
+    fn servers(&self) -> Arc<Vec<ShadowsocksServerConfig>> {
+        self.servers.read().clone()
+    }
+
+    /// Replace the servers of the group, eg. after the config is reloaded. Servers are
+    /// matched by name, open connections through removed servers are kept until closed.
+    pub async fn update_servers(&self, servers: Arc<Vec<ShadowsocksServerConfig>>) {
+        let find = |name: &str| servers.iter().find(|config| config.name() == name).cloned();
+        {
+            let mut selected = self.selected.lock();
+            *selected = selected.as_ref().and_then(|config| find(config.name()));
+            let mut candidates = self.candidates.lock();
+            *candidates = candidates
+                .iter()
+                .filter_map(|config| find(config.name()))
+                .collect();
+        }
+        info!(len = servers.len(), "Update shadowsocks servers");
+        *self.servers.write() = servers;
+        self.ping_servers().await;
+    }
+
     fn get_server_aliveness(&self, config: &ShadowsocksServerConfig) -> Arc<AtomicBool> {
         let mut server_aliveness = self.server_aliveness.lock();
         let entry = server_aliveness
@@ -66,18 +162,206 @@ impl ShadowsocksServerChooser {
         entry.store(true, Ordering::SeqCst);
     }
 
-    pub fn candidate(&self) -> Option<(ShadowsocksServerConfig, Arc<AtomicBool>)> {
-        let config = self.candidates.lock().first().cloned()?;
+    fn connection_count(&self, config: &ShadowsocksServerConfig) -> Arc<AtomicUsize> {
+        let mut connections = self.connections.lock();
+        let entry = connections
+            .entry(config.name().to_string())
+            .or_insert_with(|| Arc::new(AtomicUsize::new(0)));
+        entry.clone()
+    }
+
+    /// Count a new connection through `config` until the returned value is dropped.
+    pub fn track_connection(
+        &self,
+        config: &ShadowsocksServerConfig,
+        permit: Option<ConnectionPermit>,
+    ) -> ActiveConnection {
+        self.connect_errors.lock().remove(config.name());
+        let count = self.connection_count(config);
+        count.fetch_add(1, Ordering::SeqCst);
+        ActiveConnection {
+            server: config.clone(),
+            count,
+            chooser: self.clone(),
+            _permit: permit,
+        }
+    }
+
+    /// Close the open connections through `config`, new connections are not affected.
+    fn drain_connections(&self, config: &ShadowsocksServerConfig) {
+        let mut server_aliveness = self.server_aliveness.lock();
+        if let Some(alive) = server_aliveness.get_mut(config.name()) {
+            let is_alive = alive.swap(false, Ordering::SeqCst);
+            *alive = Arc::new(AtomicBool::new(is_alive));
+        }
+    }
+
+    fn current(&self) -> Option<ShadowsocksServerConfig> {
+        if let Some(selected) = self.selected.lock().clone() {
+            return Some(selected);
+        }
+        self.candidates.lock().first().cloned()
+    }
+
+    /// Use the server named `name` for all new connections until it goes down, overriding the
+    /// group mode. Returns false if there is no such server.
+    pub fn select(&self, name: &str) -> bool {
+        let servers = self.servers();
+        let config = match servers.iter().find(|config| config.name() == name) {
+            Some(config) => config.clone(),
+            None => return false,
+        };
+        info!(name, server = ?config.addr(), "Select shadowsocks server");
+        *self.selected.lock() = Some(config);
+        if self.group.drain_on_select {
+            for other in servers.iter().filter(|other| other.name() != name) {
+                self.drain_connections(other);
+            }
+        }
+        true
+    }
+
+    pub fn servers_status(&self) -> Vec<ServerStatus> {
+        let current = self.current();
+        let selected = self.selected.lock().clone();
+        let latencies = self.latencies.lock().clone();
+        let now = Instant::now();
+        let cooldowns = self.cooldowns.lock();
+        self.servers()
+            .iter()
+            .map(|config| {
+                let is = |other: &Option<ShadowsocksServerConfig>| {
+                    other.as_ref().map(|c| c.name()) == Some(config.name())
+                };
+                ServerStatus {
+                    name: config.name().to_string(),
+                    addr: config.addr().to_string(),
+                    alive: self.get_server_aliveness(config).load(Ordering::SeqCst),
+                    latency_ms: latencies
+                        .get(config.name())
+                        .map(|latency| latency.as_millis() as u64),
+                    connections: self.connection_count(config).load(Ordering::SeqCst),
+                    cooldown_secs: cooldowns
+                        .get(config.name())
+                        .filter(|cooldown| cooldown.until > now)
+                        .map(|cooldown| (cooldown.until - now).as_secs()),
+                    active: (self.group.mode == GroupMode::LoadBalance && selected.is_none())
+                        || is(&current),
+                    selected: is(&selected),
+                }
+            })
+            .collect()
+    }
+
+    /// Server for a new connection to `remote_addr`.
+    pub fn candidate(
+        &self,
+        remote_addr: &Address,
+    ) -> Option<(ShadowsocksServerConfig, Arc<AtomicBool>)> {
+        let selected = self.selected.lock().clone();
+        let config = match (selected, self.group.mode) {
+            (Some(selected), _) => selected,
+            (None, GroupMode::LoadBalance) => self.balance(remote_addr)?,
+            (None, GroupMode::Sticky) | (None, GroupMode::Auto) | (None, GroupMode::Fallback) => {
+                self.current()?
+            }
+        };
         let alive = self.get_server_aliveness(&config);
         Some((config, alive))
     }
 
-    pub async fn take_down_current_and_move_next(&self) {
+    fn balance(&self, remote_addr: &Address) -> Option<ShadowsocksServerConfig> {
+        let candidates = self.candidates.lock();
+        if candidates.is_empty() {
+            return None;
+        }
+        let config = match self.group.strategy {
+            BalanceStrategy::RoundRobin => {
+                weighted_round_robin(&candidates, &mut self.round_robin.lock())?
+            }
+            BalanceStrategy::LeastConnections => candidates.iter().min_by(|a, b| {
+                // compare connections / weight without division
+                let a_connections = self.connection_count(a).load(Ordering::SeqCst) as u64;
+                let b_connections = self.connection_count(b).load(Ordering::SeqCst) as u64;
+                (a_connections * b.weight() as u64).cmp(&(b_connections * a.weight() as u64))
+            })?,
+            BalanceStrategy::ConsistentHashing => {
+                rendezvous_hash(&candidates, &destination_host(remote_addr))?
+            }
+        };
+        Some(config.clone())
+    }
+
+    /// Count a failed connect through `config`. After `max_failures` consecutive errors the
+    /// server is taken down.
+    pub async fn connect_failed(&self, config: &ShadowsocksServerConfig) {
+        let errors = {
+            let mut connect_errors = self.connect_errors.lock();
+            let errors = connect_errors.entry(config.name().to_string()).or_insert(0);
+            *errors += 1;
+            *errors
+        };
+        if errors >= self.group.max_failures {
+            self.connect_errors.lock().remove(config.name());
+            self.take_down_and_move_next(config).await;
+        }
+    }
+
+    /// Start or double the cooldown of `config`.
+    fn cool_down(&self, config: &ShadowsocksServerConfig) {
+        let mut cooldowns = self.cooldowns.lock();
+        let backoff = next_backoff(
+            cooldowns
+                .get(config.name())
+                .map(|cooldown| cooldown.backoff),
+            self.group.cooldown,
+            self.group.max_cooldown,
+        );
+        info!(
+            name = config.name(),
+            server = ?config.addr(),
+            cooldown = backoff.as_secs(),
+            "Cool down shadowsocks server"
+        );
+        cooldowns.insert(
+            config.name().to_string(),
+            Cooldown {
+                until: Instant::now() + backoff,
+                backoff,
+            },
+        );
+    }
+
+    fn in_cooldown(&self, config: &ShadowsocksServerConfig) -> bool {
+        self.cooldowns
+            .lock()
+            .get(config.name())
+            .map_or(false, |cooldown| cooldown.until > Instant::now())
+    }
+
+    /// Mark `config` down and stop choosing it until its cooldown ends and a ping succeeds.
+    async fn take_down_and_move_next(&self, config: &ShadowsocksServerConfig) {
+        self.cool_down(config);
+        {
+            let mut selected = self.selected.lock();
+            if selected.as_ref().map(|c| c.name()) == Some(config.name()) {
+                info!(
+                    name = config.name(),
+                    "Selected shadowsocks server is down, choose by the group again"
+                );
+                *selected = None;
+            }
+        }
         // make sure `candidates` drop after block ends to avoid deadlock.
         {
             let mut candidates = self.candidates.lock();
+            let position = match candidates.iter().position(|c| c.name() == config.name()) {
+                Some(position) => position,
+                // already taken down by another connection
+                None => return,
+            };
             if candidates.len() > 1 {
-                let removed = candidates.remove(0);
+                let removed = candidates.remove(position);
                 self.set_server_down(&removed);
                 let new = &candidates[0];
                 info!(
@@ -95,24 +379,45 @@ impl ShadowsocksServerChooser {
         self.ping_servers().await;
     }
 
+    /// Called when a connection through `config` broke. Fallback groups check the server at
+    /// once instead of waiting for the next ping, so that a dead server is demoted quickly.
+    pub async fn connection_broken(&self, config: &ShadowsocksServerConfig) {
+        if self.group.mode != GroupMode::Fallback {
+            return;
+        }
+        // skip if a check of this server is already running
+        if !self.rechecking.lock().insert(config.name().to_string()) {
+            return;
+        }
+        if self.ping_server(config.clone()).await.is_err() {
+            self.take_down_and_move_next(config).await;
+        }
+        self.rechecking.lock().remove(config.name());
+    }
+
     pub async fn ping_servers_forever(&self) -> Result<()> {
         loop {
             self.ping_servers().await;
-            sleep(Duration::from_secs(30)).await;
+            sleep(self.group.interval).await;
         }
     }
 
     pub async fn ping_servers(&self) {
+        let current = self.current();
         let mut candidates = vec![];
-        if let Some((current_config, _)) = self.candidate() {
-            if self.ping_server(current_config.clone()).await.is_ok() {
-                candidates.push(current_config);
+        if self.group.mode == GroupMode::Sticky {
+            if let Some(current_config) = &current {
+                if self.ping_server(current_config.clone()).await.is_ok() {
+                    candidates.push(current_config.clone());
+                }
             }
         }
 
-        let mut fut: FuturesUnordered<_> = self
-            .servers
+        let mut measured = vec![];
+        let servers = self.servers();
+        let mut fut: FuturesUnordered<_> = servers
             .iter()
+            .filter(|config| !self.in_cooldown(config))
             .map(|config| {
                 let self_clone = self.clone();
                 let config_clone = config.clone();
@@ -134,9 +439,14 @@ impl ShadowsocksServerChooser {
                         latency = %duration.as_millis(),
                         "Ping shadowsocks server"
                     );
-                    candidates.push(config);
+                    self.cooldowns.lock().remove(config.name());
+                    measured.push((config, duration));
                 }
                 Err(config) => {
+                    // cooldown ended but the server is still down
+                    if self.cooldowns.lock().contains_key(config.name()) {
+                        self.cool_down(&config);
+                    }
                     info!(
                         name = config.name(),
                         server = ?config.addr(),
@@ -145,37 +455,411 @@ impl ShadowsocksServerChooser {
                 }
             }
         }
+
+        match self.group.mode {
+            GroupMode::Sticky | GroupMode::LoadBalance => {
+                candidates.extend(measured.into_iter().map(|(config, _)| config));
+            }
+            GroupMode::Auto => {
+                candidates = rank_by_latency(
+                    measured,
+                    current.as_ref().map(|config| config.name()),
+                    self.group.tolerance,
+                );
+            }
+            GroupMode::Fallback => {
+                candidates = rank_by_priority(measured, &servers);
+            }
+        }
+        if let (Some(old), Some(new)) = (&current, candidates.first()) {
+            if old.name() != new.name() {
+                info!(
+                    old_name = old.name(),
+                    old_server = ?old.addr(),
+                    new_name = new.name(),
+                    new_server = ?new.addr(),
+                    "Change shadowsocks server"
+                );
+            }
+        }
         if !candidates.is_empty() {
             *self.candidates.lock() = candidates;
         }
     }
 
     async fn ping_server(&self, config: ShadowsocksServerConfig) -> Result<Duration> {
+        let ret = match self.group.mode {
+            GroupMode::Sticky => self.ping_urls(&config).await,
+            GroupMode::Auto | GroupMode::LoadBalance | GroupMode::Fallback => {
+                self.probe(&config).await
+            }
+        };
+        match ret {
+            Ok(latency) => {
+                self.set_server_alive(&config);
+                self.latencies
+                    .lock()
+                    .insert(config.name().to_string(), latency);
+                Ok(latency)
+            }
+            Err(e) => {
+                self.set_server_down(&config);
+                self.latencies.lock().remove(config.name());
+                // The server may reject the password in use, eg. after rotating its password,
+                // and closes the connection without a response. Other errors, eg. of the network,
+                // say nothing about the password.
+                if e.kind() == ErrorKind::UnexpectedEof && config.next_password() {
+                    info!(name = config.name(), "Try the next password of the server");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn ping_urls(&self, config: &ShadowsocksServerConfig) -> Result<Duration> {
         let instant = Instant::now();
         for (host, path) in &self.ping_url {
-            let ret: Result<_> = timeout(self.ping_timeout, async {
-                let resolved_addr = self.dns_client.lookup_address(config.addr()).await?;
+            timeout(self.ping_timeout, async {
+                let resolved_addr = self.dns_client.lookup_server(config.addr()).await?;
                 let mut conn = SSTcpStream::connect(
                     host.clone(),
                     resolved_addr,
                     Arc::new(AtomicBool::new(true)),
                     config.method(),
                     config.key(),
+                    config.fast_open(),
                 )
                 .await?;
+                conn.set_handshake_timeout(self.ping_timeout);
                 conn.write_all(format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes())
                     .await?;
                 let mut buf = vec![0; 1024];
-                let _size = conn.read(&mut buf).await?;
+                // Closed without a response by servers failing to decrypt the request.
+                if conn.read(&mut buf).await? == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
                 Ok(())
             })
-            .await;
-            if let Err(e) = ret {
-                self.set_server_down(&config);
-                return Err(e);
-            }
+            .await?;
         }
-        self.set_server_alive(&config);
         Ok(instant.elapsed())
     }
+
+    async fn probe(&self, config: &ShadowsocksServerConfig) -> Result<Duration> {
+        let instant = Instant::now();
+        let latency = timeout(self.ping_timeout, async {
+            let resolved_addr = self.dns_client.lookup_server(config.addr()).await?;
+            match self.group.probe {
+                ProbeMethod::Http | ProbeMethod::Head => {
+                    self.probe_url(config, resolved_addr).await?;
+                    Ok(instant.elapsed())
+                }
+                ProbeMethod::Tcp => {
+                    TcpStream::connect(resolved_addr).await?;
+                    Ok(instant.elapsed())
+                }
+                ProbeMethod::Icmp => ping(resolved_addr.ip(), self.ping_timeout).await,
+            }
+        })
+        .await?;
+        Ok(latency)
+    }
+
+    /// Request `group.url` through the server.
+    async fn probe_url(
+        &self,
+        config: &ShadowsocksServerConfig,
+        resolved_addr: SocketAddr,
+    ) -> Result<()> {
+        let url = &self.group.url;
+        let method = if self.group.probe == ProbeMethod::Head {
+            "HEAD"
+        } else {
+            "GET"
+        };
+        let mut conn = SSTcpStream::connect(
+            url.address(),
+            resolved_addr,
+            Arc::new(AtomicBool::new(true)),
+            config.method(),
+            config.key(),
+            config.fast_open(),
+        )
+        .await?;
+        conn.set_handshake_timeout(self.ping_timeout);
+        conn.write_all(
+            format!(
+                "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                method, url.path, url.host
+            )
+            .as_bytes(),
+        )
+        .await?;
+        let mut buf = vec![0; 1024];
+        let size = conn.read(&mut buf).await?;
+        // Closed without a response by servers failing to decrypt the request.
+        if size == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let status = buf[..size].split(|b| *b == b' ').nth(1).unwrap_or_default();
+        let ok = match self.group.probe {
+            ProbeMethod::Head => status.starts_with(b"2") || status.starts_with(b"3"),
+            _ => status == b"204",
+        };
+        if !ok {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected response from {}", url),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Ping `ip` once with the `ping` command and return the round trip time it reports.
+async fn ping(ip: IpAddr, deadline: Duration) -> Result<Duration> {
+    let deadline = deadline.as_secs().max(1).to_string();
+    let ip = ip.to_string();
+    let output = spawn_blocking(move || {
+        // `-w` on linux and `-t` on macOS are the deadline in seconds
+        let deadline_flag = if cfg!(target_os = "macos") {
+            "-t"
+        } else {
+            "-w"
+        };
+        Command::new("ping")
+            .args(&["-c", "1", deadline_flag, &deadline, &ip])
+            .output()
+    })
+    .await?;
+    if !output.status.success() {
+        return Err(Error::new(ErrorKind::Other, "ping failed"));
+    }
+    parse_ping_time(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid ping output"))
+}
+
+/// Parse the round trip time from output like `64 bytes from 1.1.1.1: icmp_seq=0 time=5.2 ms`.
+fn parse_ping_time(output: &str) -> Option<Duration> {
+    let start = output.find("time=")? + "time=".len();
+    let millis: String = output[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    let millis: f64 = millis.parse().ok()?;
+    Some(Duration::from_micros((millis * 1000.0) as u64))
+}
+
+/// Orders servers from the fastest to the slowest. The current server stays first unless the
+/// fastest one beats it by more than `tolerance`, which avoids flapping between servers with
+/// similar latency.
+fn rank_by_latency(
+    mut measured: Vec<(ShadowsocksServerConfig, Duration)>,
+    current: Option<&str>,
+    tolerance: Duration,
+) -> Vec<ShadowsocksServerConfig> {
+    measured.sort_by_key(|(_, latency)| *latency);
+    let current_pos = current.and_then(|name| {
+        measured
+            .iter()
+            .position(|(config, _)| config.name() == name)
+    });
+    if let Some(pos) = current_pos {
+        if measured[pos].1 <= measured[0].1 + tolerance {
+            let current = measured.remove(pos);
+            measured.insert(0, current);
+        }
+    }
+    measured.into_iter().map(|(config, _)| config).collect()
+}
+
+/// Alive servers in the order of the config, so the first healthy one is preferred.
+fn rank_by_priority(
+    measured: Vec<(ShadowsocksServerConfig, Duration)>,
+    servers: &[ShadowsocksServerConfig],
+) -> Vec<ShadowsocksServerConfig> {
+    servers
+        .iter()
+        .filter(|server| {
+            measured
+                .iter()
+                .any(|(config, _)| config.name() == server.name())
+        })
+        .cloned()
+        .collect()
+}
+
+/// Cooldowns double from `base` on every failure, up to `max`.
+fn next_backoff(previous: Option<Duration>, base: Duration, max: Duration) -> Duration {
+    match previous {
+        Some(previous) => (previous * 2).min(max),
+        None => base.min(max),
+    }
+}
+
+fn destination_host(addr: &Address) -> String {
+    match addr {
+        Address::SocketAddress(addr) => addr.ip().to_string(),
+        Address::DomainNameAddress(domain, _) => domain.clone(),
+    }
+}
+
+/// Rendezvous hashing: only hosts mapped to a removed server move to another one.
+fn rendezvous_hash<'a>(
+    candidates: &'a [ShadowsocksServerConfig],
+    host: &str,
+) -> Option<&'a ShadowsocksServerConfig> {
+    // weighted rendezvous hashing: the score is `-weight / ln(hash)` with hash in (0, 1)
+    let score = |config: &ShadowsocksServerConfig| {
+        let mut hasher = DefaultHasher::new();
+        config.name().hash(&mut hasher);
+        host.hash(&mut hasher);
+        let hash = (hasher.finish() as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+        -(config.weight() as f64) / hash.ln()
+    };
+    candidates
+        .iter()
+        .map(|config| (config, score(config)))
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(config, _)| config)
+}
+
+/// Smooth weighted round robin as in nginx: every pick adds the weights to the current
+/// weights, picks the largest and subtracts the total weight from it. Servers are spread
+/// evenly, eg. weights 5, 1, 1 give `a a b a c a a`.
+fn weighted_round_robin<'a>(
+    candidates: &'a [ShadowsocksServerConfig],
+    current_weights: &mut HashMap<String, i64>,
+) -> Option<&'a ShadowsocksServerConfig> {
+    current_weights.retain(|name, _| candidates.iter().any(|config| config.name() == name));
+    let mut total = 0;
+    let mut best: Option<(&ShadowsocksServerConfig, i64)> = None;
+    for config in candidates {
+        let weight = config.weight() as i64;
+        let current = current_weights
+            .entry(config.name().to_string())
+            .or_insert(0);
+        *current += weight;
+        total += weight;
+        if best.map_or(true, |(_, best_weight)| *current > best_weight) {
+            best = Some((config, *current));
+        }
+    }
+    let (config, _) = best?;
+    *current_weights.get_mut(config.name())? -= total;
+    Some(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::CipherType;
+
+    fn server(name: &str) -> ShadowsocksServerConfig {
+        ShadowsocksServerConfig::new(
+            name.to_string(),
+            Address::DomainNameAddress(format!("{}.example.com", name), 8388),
+            "password".to_string(),
+            CipherType::Plain,
+        )
+    }
+
+    fn names(servers: Vec<ShadowsocksServerConfig>) -> Vec<String> {
+        servers.iter().map(|s| s.name().to_string()).collect()
+    }
+
+    #[test]
+    fn test_rank_by_latency() {
+        let measured = vec![
+            (server("a"), Duration::from_millis(300)),
+            (server("b"), Duration::from_millis(100)),
+            (server("c"), Duration::from_millis(130)),
+        ];
+        let tolerance = Duration::from_millis(50);
+        assert_eq!(
+            names(rank_by_latency(measured.clone(), None, tolerance)),
+            vec!["b", "c", "a"]
+        );
+        assert_eq!(
+            names(rank_by_latency(measured.clone(), Some("c"), tolerance)),
+            vec!["c", "b", "a"]
+        );
+        assert_eq!(
+            names(rank_by_latency(measured.clone(), Some("a"), tolerance)),
+            vec!["b", "c", "a"]
+        );
+        assert_eq!(
+            names(rank_by_latency(measured, Some("gone"), tolerance)),
+            vec!["b", "c", "a"]
+        );
+    }
+
+    #[test]
+    fn test_rank_by_priority() {
+        let servers = vec![server("a"), server("b"), server("c")];
+        let measured = vec![
+            (server("c"), Duration::from_millis(100)),
+            (server("b"), Duration::from_millis(300)),
+        ];
+        assert_eq!(names(rank_by_priority(measured, &servers)), vec!["b", "c"]);
+        assert!(rank_by_priority(vec![], &servers).is_empty());
+    }
+
+    #[test]
+    fn test_parse_ping_time() {
+        let output = "PING 1.1.1.1 (1.1.1.1): 56 data bytes\n\
+                      64 bytes from 1.1.1.1: icmp_seq=0 ttl=59 time=5.25 ms\n";
+        assert_eq!(parse_ping_time(output), Some(Duration::from_micros(5250)));
+        assert_eq!(parse_ping_time("1 packets transmitted, 0 received"), None);
+    }
+
+    #[test]
+    fn test_next_backoff() {
+        let base = Duration::from_secs(30);
+        let max = Duration::from_secs(100);
+        assert_eq!(next_backoff(None, base, max), base);
+        assert_eq!(next_backoff(Some(base), base, max), Duration::from_secs(60));
+        assert_eq!(next_backoff(Some(Duration::from_secs(60)), base, max), max);
+        assert_eq!(next_backoff(Some(max), base, max), max);
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let mut a = server("a");
+        a.set_weight(5);
+        let servers = vec![a, server("b"), server("c")];
+        let mut current_weights = HashMap::new();
+        let picks: Vec<&str> = (0..7)
+            .map(|_| {
+                weighted_round_robin(&servers, &mut current_weights)
+                    .unwrap()
+                    .name()
+            })
+            .collect();
+        assert_eq!(picks, vec!["a", "a", "b", "a", "c", "a", "a"]);
+        assert!(weighted_round_robin(&[], &mut current_weights).is_none());
+    }
+
+    #[test]
+    fn test_rendezvous_hash() {
+        let servers = vec![server("a"), server("b"), server("c")];
+        for i in 0..100 {
+            let host = format!("host{}.com", i);
+            let chosen = rendezvous_hash(&servers, &host).unwrap();
+            assert_eq!(
+                rendezvous_hash(&servers, &host).unwrap().name(),
+                chosen.name()
+            );
+            let remaining: Vec<_> = servers
+                .iter()
+                .filter(|s| s.name() == chosen.name() || s.name() == "a")
+                .cloned()
+                .collect();
+            assert_eq!(
+                rendezvous_hash(&remaining, &host).unwrap().name(),
+                chosen.name()
+            );
+        }
+        assert!(rendezvous_hash(&[], "example.com").is_none());
+    }
 }
@@ -0,0 +1,135 @@
+//! Local socks5 proxy. `CONNECT` requests are relayed by the same rules as connections through
+//! the tun device, users are authenticated by username and password (RFC 1929) if configured.
+
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use config::{Address, Socks5InboundConfig, Socks5User};
+use socks5_client::{
+    Command, HandshakeRequest, HandshakeResponse, TcpRequestHeader, SOCKS5_AUTH_METHOD_NONE,
+    SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE, SOCKS5_AUTH_METHOD_PASSWORD,
+};
+use std::io;
+
+const PASSWORD_AUTH_VERSION: u8 = 1;
+// Replies to the request, the bound address is left unspecified.
+pub const SUCCEEDED: &[u8] = &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+pub const NOT_ALLOWED: &[u8] = &[5, 2, 0, 1, 0, 0, 0, 0, 0, 0];
+pub const HOST_UNREACHABLE: &[u8] = &[5, 4, 0, 1, 0, 0, 0, 0, 0, 0];
+const COMMAND_NOT_SUPPORTED: &[u8] = &[5, 7, 0, 1, 0, 0, 0, 0, 0, 0];
+
+/// Negotiate the authentication and read the request of a client. Returns the requested address
+/// and the authenticated user, if users are configured.
+pub async fn handshake<'a>(
+    conn: &mut TcpStream,
+    config: &'a Socks5InboundConfig,
+) -> io::Result<(Address, Option<&'a Socks5User>)> {
+    let request = HandshakeRequest::read_from(conn).await?;
+    let method = if config.users.is_empty() {
+        SOCKS5_AUTH_METHOD_NONE
+    } else {
+        SOCKS5_AUTH_METHOD_PASSWORD
+    };
+    if !request.methods.contains(&method) {
+        HandshakeResponse::new(SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE)
+            .write_to(conn)
+            .await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "no acceptable authentication method",
+        ));
+    }
+    HandshakeResponse::new(method).write_to(conn).await?;
+    let user = if method == SOCKS5_AUTH_METHOD_PASSWORD {
+        Some(authenticate(conn, config).await?)
+    } else {
+        None
+    };
+
+    let header = TcpRequestHeader::read_from(conn).await?;
+    if header.command != Command::TcpConnect {
+        conn.write_all(COMMAND_NOT_SUPPORTED).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported command {:?}", header.command),
+        ));
+    }
+    Ok((header.address, user))
+}
+
+/// Username/password sub-negotiation.
+async fn authenticate<'a>(
+    conn: &mut TcpStream,
+    config: &'a Socks5InboundConfig,
+) -> io::Result<&'a Socks5User> {
+    let mut header = [0u8; 2];
+    conn.read_exact(&mut header).await?;
+    if header[0] != PASSWORD_AUTH_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported authentication version {:#x}", header[0]),
+        ));
+    }
+    let mut username = vec![0; usize::from(header[1])];
+    conn.read_exact(&mut username).await?;
+    let mut password_len = [0u8; 1];
+    conn.read_exact(&mut password_len).await?;
+    let mut password = vec![0; usize::from(password_len[0])];
+    conn.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username);
+    match config.authenticate(&username, &String::from_utf8_lossy(&password)) {
+        Some(user) => {
+            conn.write_all(&[PASSWORD_AUTH_VERSION, 0]).await?;
+            Ok(user)
+        }
+        None => {
+            conn.write_all(&[PASSWORD_AUTH_VERSION, 1]).await?;
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("authentication of {} failed", username),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task::{block_on, spawn};
+
+    #[test]
+    fn test_handshake() {
+        block_on(async {
+            let config: Socks5InboundConfig = serde_yaml::from_str(
+                "{listen: '127.0.0.1:0', users: [{username: guest, password: secret}]}",
+            )
+            .unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = spawn(async move {
+                let mut conn = TcpStream::connect(addr).await.unwrap();
+                conn.write_all(&[5, 1, 2]).await.unwrap();
+                conn.write_all(&[1, 5]).await.unwrap();
+                conn.write_all(b"guest").await.unwrap();
+                conn.write_all(&[6]).await.unwrap();
+                conn.write_all(b"secret").await.unwrap();
+                // CONNECT example.com:443
+                conn.write_all(&[5, 1, 0, 3, 11]).await.unwrap();
+                conn.write_all(b"example.com").await.unwrap();
+                conn.write_all(&443u16.to_be_bytes()).await.unwrap();
+                let mut replies = [0u8; 4];
+                conn.read_exact(&mut replies).await.unwrap();
+                replies
+            });
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let (address, user) = handshake(&mut conn, &config).await.unwrap();
+            assert_eq!(
+                address,
+                Address::DomainNameAddress("example.com".to_string(), 443)
+            );
+            assert_eq!(user.unwrap().username, "guest");
+            assert_eq!(client.await, [5, 2, 1, 0]);
+        });
+    }
+}
@@ -0,0 +1,145 @@
+//! Relay between two tcp sockets with `splice(2)` through a pipe, so the data isn't copied to
+//! userspace. Used for direct connections, which don't need encryption.
+
+use async_std::net::TcpStream;
+use async_std::prelude::*;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+
+/// Bytes moved by one splice, the default capacity of a pipe.
+const SPLICE_SIZE: usize = 64 * 1024;
+
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Pipe {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Move data from `from` to `to` until `from` is closed, `relayed` is called with the size of
+/// each moved block.
+pub async fn splice_copy(
+    from: &TcpStream,
+    to: &TcpStream,
+    mut relayed: impl FnMut(usize),
+) -> io::Result<()> {
+    let pipe = Pipe::new()?;
+    let mut peek_buf = [0; 1];
+    loop {
+        // Wait until there is data, or eof if nothing is peeked.
+        if from.peek(&mut peek_buf).await? == 0 {
+            return Ok(());
+        }
+        let size = match splice(from.as_raw_fd(), pipe.write, SPLICE_SIZE) {
+            Ok(0) => return Ok(()),
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut remaining = size;
+        while remaining > 0 {
+            match splice(pipe.read, to.as_raw_fd(), remaining) {
+                Ok(n) => remaining -= n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    // `to` is full. There is no way to wait for it to be writable, so the rest is
+                    // written through userspace.
+                    let mut buf = vec![0; remaining];
+                    let n = unsafe {
+                        libc::read(pipe.read, buf.as_mut_ptr() as *mut libc::c_void, remaining)
+                    };
+                    if n < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    if n == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    let mut to = to;
+                    to.write_all(&buf[..n as usize]).await?;
+                    remaining -= n as usize;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        relayed(size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::task::{block_on, spawn};
+
+    #[test]
+    fn test_splice_copy() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+            let expected = data.clone();
+
+            // client -> relay_in, relay_out -> server
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (relay_in, _) = listener.accept().await.unwrap();
+            let relay_out = TcpStream::connect(addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+
+            let h = spawn(async move {
+                let mut total = 0;
+                splice_copy(&relay_in, &relay_out, |n| total += n)
+                    .await
+                    .unwrap();
+                total
+            });
+            let reader = spawn(async move {
+                let mut received = vec![];
+                server.read_to_end(&mut received).await.unwrap();
+                received
+            });
+            client.write_all(&data).await.unwrap();
+            drop(client);
+            assert_eq!(h.await, expected.len());
+            assert_eq!(reader.await, expected);
+        });
+    }
+}
@@ -0,0 +1,422 @@
+//! Shadowsocks server run by `seeker server`, so the same binary can be used on both ends for
+//! testing and small deployments. Only tcp is relayed.
+
+use async_std::future;
+use async_std::io::{timeout, Read, Write};
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_std::prelude::*;
+use async_std::task::{sleep, spawn};
+use config::Address;
+use crypto::{CipherCategory, CipherType, SecretKey};
+use ssclient::{ReplayFilter, SSTcpStream};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, trace};
+
+#[derive(Clone)]
+pub struct SsServer {
+    method: CipherType,
+    /// Keys of the passwords accepted, eg. the old and new ones while rotating the password.
+    keys: Vec<SecretKey>,
+    /// Timeout of reading the target address and connecting to it.
+    connect_timeout: Duration,
+    /// Connections without data in either direction for this long are closed.
+    idle_timeout: Duration,
+    /// Salts seen recently, connections replayed by active probers are rejected.
+    replay_filter: Arc<ReplayFilter>,
+    probe_defense: ProbeDefense,
+}
+
+/// What to do with connections failing authentication or replaying a salt, likely made by active
+/// probers. A server closing them at once is easy to tell apart from other services. Only AEAD
+/// ciphers can be checked before reading the request, stream ciphers are always closed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeDefense {
+    Close,
+    /// Read and discard whatever is sent, until the client closes or the idle timeout.
+    Stall,
+    /// Like `Stall`, and answer a random byte every second.
+    Drip,
+    /// Relay the connection to the server at the address, eg. a web server on `127.0.0.1:80`.
+    Decoy(String),
+}
+
+impl SsServer {
+    pub fn new(
+        method: CipherType,
+        passwords: &[&str],
+        connect_timeout: Duration,
+        idle_timeout: Duration,
+        probe_defense: ProbeDefense,
+    ) -> Self {
+        SsServer {
+            method,
+            keys: passwords
+                .iter()
+                .map(|password| method.bytes_to_key(password.as_bytes()))
+                .collect(),
+            connect_timeout,
+            idle_timeout,
+            replay_filter: Arc::new(ReplayFilter::default()),
+            probe_defense,
+        }
+    }
+
+    pub async fn run(&self, addr: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(%addr, method = %self.method, "shadowsocks server listening");
+        self.serve_listener(listener).await
+    }
+
+    async fn serve_listener(&self, listener: TcpListener) -> io::Result<()> {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = stream?;
+            let server = self.clone();
+            spawn(async move {
+                let peer_addr = stream.peer_addr();
+                if let Err(e) = server.serve(stream).await {
+                    debug!(?e, ?peer_addr, "shadowsocks client error");
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let deadline = Instant::now() + self.connect_timeout;
+        let mut received = Vec::new();
+        let ret = timeout(
+            self.connect_timeout,
+            self.identify_key(&stream, &mut received),
+        )
+        .await;
+        let (key, subkey) = match ret {
+            Err(e) if is_probe(&e) => {
+                debug!(?e, defense = ?self.probe_defense, "suspected probe");
+                return self.defend(stream, &received).await;
+            }
+            ret => ret?,
+        };
+        let left = deadline.saturating_duration_since(Instant::now());
+        let (addr, client, remote) = timeout(left, async {
+            let mut client =
+                SSTcpStream::accept(stream, self.method, key, Some(self.replay_filter.clone()));
+            client.set_handshake_timeout(left);
+            if let Some(subkey) = subkey {
+                let (salt, chunk) = received.split_at(self.method.salt_size());
+                client.set_received(salt, chunk, subkey);
+            }
+            let addr = Address::read_from(&mut client).await?;
+            let remote = TcpStream::connect(resolve(&addr).await?).await?;
+            Ok::<_, io::Error>((addr, client, remote))
+        })
+        .await?;
+        trace!(%addr, "relay shadowsocks connection");
+
+        let (client_read, client_write) = client
+            .into_split()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "shadowsocks stream is shared"))?;
+        self.relay(client_read, client_write, remote).await
+    }
+
+    async fn relay(
+        &self,
+        mut client_read: impl Read + Unpin,
+        mut client_write: impl Write + Unpin,
+        remote: TcpStream,
+    ) -> io::Result<()> {
+        let start = Instant::now();
+        let last_active = AtomicU64::new(0);
+        let active = || {
+            last_active.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        };
+        let mut remote_read = remote.clone();
+        let mut remote_write = remote;
+        let up = async {
+            let mut buf = vec![0; 16 * 1024];
+            loop {
+                let size = client_read.read(&mut buf).await?;
+                if size == 0 {
+                    break Ok(());
+                }
+                remote_write.write_all(&buf[..size]).await?;
+                active();
+            }
+        };
+        let down = async {
+            let mut buf = vec![0; 16 * 1024];
+            loop {
+                let size = remote_read.read(&mut buf).await?;
+                if size == 0 {
+                    break Ok(());
+                }
+                client_write.write_all(&buf[..size]).await?;
+                active();
+            }
+        };
+        let idle = idle_timeout(start, &last_active, self.idle_timeout);
+        up.race(down).race(idle).await
+    }
+
+    /// The first of `keys` authenticating the first chunk of the client, the salt and the length
+    /// of the chunk read are kept in `received` so probes can still be relayed to the decoy.
+    /// Stream ciphers can't tell the keys apart, the first one is used for them and nothing is
+    /// read. For AEAD ciphers the session subkey of the key is returned too, so the subkey isn't
+    /// derived again.
+    async fn identify_key(
+        &self,
+        stream: &TcpStream,
+        received: &mut Vec<u8>,
+    ) -> io::Result<(SecretKey, Option<SecretKey>)> {
+        if self.method.category() != CipherCategory::Aead {
+            return Ok((self.keys[0].clone(), None));
+        }
+        let salt_len = self.method.salt_size();
+        let mut buf = vec![0; salt_len + 2 + self.method.tag_size()];
+        while received.len() < buf.len() {
+            let size = (&*stream).read(&mut buf[received.len()..]).await?;
+            if size == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            received.extend_from_slice(&buf[received.len()..received.len() + size]);
+        }
+        let (salt, chunk) = buf.split_at(salt_len);
+        if self.replay_filter.contains(salt) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "replayed salt",
+            ));
+        }
+        self.keys
+            .iter()
+            .find_map(|key| {
+                let mut len = [0; 2];
+                let subkey = crypto::make_skey(self.method, key, salt);
+                crypto::new_aead_decryptor_with_subkey(self.method, &subkey)
+                    .decrypt(chunk, &mut len)
+                    .ok()
+                    .map(|_| (key.clone(), Some(subkey)))
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "unknown password"))
+    }
+
+    /// `received` is what the client has sent so far, which is relayed to the decoy first.
+    async fn defend(&self, stream: TcpStream, received: &[u8]) -> io::Result<()> {
+        match &self.probe_defense {
+            ProbeDefense::Close => Ok(()),
+            ProbeDefense::Stall => stall(stream, None, self.idle_timeout).await,
+            ProbeDefense::Drip => {
+                stall(stream, Some(Duration::from_secs(1)), self.idle_timeout).await
+            }
+            ProbeDefense::Decoy(addr) => {
+                let mut decoy =
+                    timeout(self.connect_timeout, TcpStream::connect(addr.as_str())).await?;
+                decoy.write_all(received).await?;
+                self.relay(stream.clone(), stream, decoy).await
+            }
+        }
+    }
+}
+
+/// Whether the client failing to identify its key is likely a probe: authentication failed, a
+/// salt was replayed, or the first chunk wasn't completed in time or before closing.
+fn is_probe(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::PermissionDenied | io::ErrorKind::TimedOut | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Discard what `stream` sends until it's closed or idle for `idle`, writing a random byte every
+/// `drip` if set.
+async fn stall(stream: TcpStream, drip: Option<Duration>, idle: Duration) -> io::Result<()> {
+    let start = Instant::now();
+    let last_active = AtomicU64::new(0);
+    let drain = async {
+        let mut buf = vec![0; 1024];
+        loop {
+            if (&stream).read(&mut buf).await? == 0 {
+                break Ok(());
+            }
+            last_active.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    };
+    let drip = async {
+        let interval = match drip {
+            Some(interval) => interval,
+            None => return future::pending::<io::Result<()>>().await,
+        };
+        loop {
+            sleep(interval).await;
+            (&stream).write_all(&[rand::random::<u8>()]).await?;
+        }
+    };
+    drain
+        .race(drip)
+        .race(idle_timeout(start, &last_active, idle))
+        .await
+}
+
+/// Returns an error once `last_active`, in milliseconds since `start`, is `timeout` ago.
+async fn idle_timeout(
+    start: Instant,
+    last_active: &AtomicU64,
+    timeout: Duration,
+) -> io::Result<()> {
+    loop {
+        let last = Duration::from_millis(last_active.load(Ordering::Relaxed));
+        let idle = start.elapsed() - last;
+        if idle >= timeout {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        sleep(timeout - idle).await;
+    }
+}
+
+async fn resolve(addr: &Address) -> io::Result<SocketAddr> {
+    match addr {
+        Address::SocketAddress(addr) => Ok(*addr),
+        Address::DomainNameAddress(domain, port) => (domain.as_str(), *port)
+            .to_socket_addrs()
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, domain.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task::block_on;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_relay() {
+        block_on(async {
+            let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let echo_addr = echo.local_addr().unwrap();
+            spawn(async move {
+                let (stream, _) = echo.accept().await.unwrap();
+                let (mut reader, mut writer) = (&stream, &stream);
+                async_std::io::copy(&mut reader, &mut writer).await.unwrap();
+            });
+
+            let method = CipherType::ChaCha20IetfPoly1305;
+            let server = SsServer::new(
+                method,
+                &["new", "password"],
+                Duration::from_secs(1),
+                Duration::from_secs(1),
+                ProbeDefense::Close,
+            );
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            spawn(async move { server.serve_listener(listener).await });
+
+            let mut conn = SSTcpStream::connect(
+                Address::SocketAddress(echo_addr),
+                server_addr,
+                Arc::new(AtomicBool::new(true)),
+                method,
+                method.bytes_to_key(b"password"),
+                false,
+            )
+            .await
+            .unwrap();
+            conn.write_all(b"hello").await.unwrap();
+            let mut buf = [0; 5];
+            conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+    #[test]
+    fn test_probe_defense() {
+        block_on(async {
+            let decoy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let decoy_addr = decoy.local_addr().unwrap();
+            spawn(async move {
+                let mut incoming = decoy.incoming();
+                while let Some(stream) = incoming.next().await {
+                    let stream = stream.unwrap();
+                    spawn(async move {
+                        let (mut reader, mut writer) = (&stream, &stream);
+                        async_std::io::copy(&mut reader, &mut writer).await.unwrap();
+                    });
+                }
+            });
+            let method = CipherType::ChaCha20IetfPoly1305;
+            let probe = vec![1; method.salt_size() + 2 + method.tag_size()];
+            let decoy = ProbeDefense::Decoy(decoy_addr.to_string());
+
+            for defense in vec![
+                ProbeDefense::Close,
+                ProbeDefense::Stall,
+                ProbeDefense::Drip,
+                decoy.clone(),
+            ] {
+                let server = SsServer::new(
+                    method,
+                    &["password"],
+                    Duration::from_secs(1),
+                    Duration::from_secs(1),
+                    defense.clone(),
+                );
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let server_addr = listener.local_addr().unwrap();
+                spawn(async move { server.serve_listener(listener).await });
+
+                let mut conn = TcpStream::connect(server_addr).await.unwrap();
+                conn.write_all(&probe).await.unwrap();
+                let mut buf = vec![0; probe.len()];
+                let ret = timeout(Duration::from_millis(500), conn.read(&mut buf)).await;
+                match defense {
+                    ProbeDefense::Close => match ret {
+                        Ok(size) => assert_eq!(size, 0),
+                        Err(e) => assert_ne!(e.kind(), io::ErrorKind::TimedOut),
+                    },
+                    ProbeDefense::Stall => {
+                        assert_eq!(ret.unwrap_err().kind(), io::ErrorKind::TimedOut)
+                    }
+                    ProbeDefense::Drip => {
+                        // A byte every second.
+                        let size = match ret {
+                            Ok(size) => size,
+                            Err(_) => timeout(Duration::from_secs(1), conn.read(&mut buf))
+                                .await
+                                .unwrap(),
+                        };
+                        assert_eq!(size, 1);
+                    }
+                    ProbeDefense::Decoy(_) => {
+                        conn.read_exact(&mut buf[ret.unwrap()..]).await.unwrap();
+                        assert_eq!(buf, probe);
+                    }
+                }
+            }
+
+            // A probe shorter than the first chunk is defended once the connect timeout passes.
+            let server = SsServer::new(
+                method,
+                &["password"],
+                Duration::from_millis(200),
+                Duration::from_secs(1),
+                decoy,
+            );
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            spawn(async move { server.serve_listener(listener).await });
+            let mut conn = TcpStream::connect(server_addr).await.unwrap();
+            let probe = &probe[..10];
+            conn.write_all(probe).await.unwrap();
+            let mut buf = vec![0; probe.len()];
+            timeout(Duration::from_secs(1), conn.read_exact(&mut buf))
+                .await
+                .unwrap();
+            assert_eq!(buf, probe);
+        });
+    }
+}
@@ -0,0 +1,98 @@
+use crate::remote_file::RemoteFile;
+use anyhow::Context;
+use async_std::sync::Sender;
+use async_std::task::block_on;
+use config::{Config, ShadowsocksServerConfig, SubscriptionConfig};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Download the subscriptions which are not cached or outdated, then refresh each of them in a
+/// background thread. `updated` is notified after each successful refresh, the servers are
+/// merged into the config by `merge_subscriptions`.
+pub fn setup_subscriptions(
+    subscriptions: &HashMap<String, SubscriptionConfig>,
+    updated: Sender<()>,
+) {
+    for (name, subscription) in subscriptions {
+        let file = RemoteFile {
+            kind: "subscription",
+            name: name.clone(),
+            url: subscription.url.clone(),
+            path: subscription.cache_path(name),
+            interval: subscription.interval,
+        };
+        let name = name.clone();
+        let subscription = subscription.clone();
+        let updated = updated.clone();
+        file.setup(
+            move |content| {
+                let servers = subscription.parse_servers(content)?;
+                info!(name = %name, len = servers.len(), "load subscription");
+                Ok(())
+            },
+            move || block_on(updated.send(())),
+        );
+    }
+}
+
+/// Add the servers of the cached subscriptions to `shadowsocks_servers`, skipping servers with
+/// the name of an existing server.
+pub fn merge_subscriptions(config: &mut Config) {
+    if config.subscriptions.is_empty() {
+        return;
+    }
+    let mut servers: Vec<ShadowsocksServerConfig> = config
+        .shadowsocks_servers
+        .as_ref()
+        .map(|servers| servers.to_vec())
+        .unwrap_or_default();
+    let mut names: Vec<&String> = config.subscriptions.keys().collect();
+    names.sort();
+    for name in names {
+        let subscription = &config.subscriptions[name];
+        let subscription_servers = match load_from_cache(name, subscription) {
+            Ok(servers) => servers,
+            Err(e) => {
+                error!(?e, name = %name, "load subscription error");
+                continue;
+            }
+        };
+        for server in subscription_servers {
+            if servers.iter().any(|s| s.name() == server.name()) {
+                info!(name = server.name(), "skip duplicated subscription server");
+                continue;
+            }
+            if server.has_insecure_cipher() && !config.allow_insecure_ciphers {
+                warn!(
+                    name = server.name(),
+                    method = %server.method(),
+                    "skip subscription server with insecure cipher"
+                );
+                continue;
+            }
+            servers.push(server);
+        }
+    }
+    // Servers of groups are only checked against the subscriptions here, after downloading.
+    for group in &config.server_groups {
+        for name in &group.servers {
+            if !servers.iter().any(|server| server.name() == name) {
+                warn!(group = %group.name, server = %name, "unknown server in server group");
+            }
+        }
+    }
+    config.shadowsocks_servers = Some(Arc::new(servers));
+}
+
+fn load_from_cache(
+    name: &str,
+    subscription: &SubscriptionConfig,
+) -> anyhow::Result<Vec<ShadowsocksServerConfig>> {
+    let path = subscription.cache_path(name);
+    let content = fs::read_to_string(&path).context("Read subscription cache error")?;
+    let servers = subscription.parse_servers(&content)?;
+    info!(name, path = ?path, len = servers.len(), "load subscription from cache");
+    Ok(servers)
+}
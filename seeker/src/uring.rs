@@ -0,0 +1,341 @@
+//! Relay of direct connections through io_uring. Reads and writes are queued to a ring owned by
+//! a driver thread, which saves the epoll wakeup and the extra syscalls of the async-std reactor
+//! for every block relayed. Used instead of `splice` with the `uring` feature.
+
+use async_std::net::TcpStream;
+use io_uring::{opcode, squeue, types, IoUring};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use tracing::error;
+
+const RING_ENTRIES: u32 = 256;
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+/// User data of the read of the eventfd, which wakes the driver for new submissions.
+const WAKE_USER_DATA: u64 = 0;
+/// User data of cancellations. Ops use the address of their state, which is never 0 or 1.
+const CANCEL_USER_DATA: u64 = 1;
+
+static DRIVER: Lazy<Option<Driver>> = Lazy::new(|| match Driver::start() {
+    Ok(driver) => Some(driver),
+    Err(e) => {
+        error!(?e, "io_uring is unavailable, fall back to splice");
+        None
+    }
+});
+
+/// The driver, started by the first call. None if the kernel doesn't support io_uring.
+pub fn driver() -> Option<&'static Driver> {
+    DRIVER.as_ref()
+}
+
+pub struct Driver {
+    submissions: Arc<Mutex<Vec<squeue::Entry>>>,
+    eventfd: RawFd,
+}
+
+impl Driver {
+    fn start() -> io::Result<Self> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if eventfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let submissions = Arc::new(Mutex::new(vec![]));
+        let submissions_clone = submissions.clone();
+        thread::Builder::new()
+            .name("io_uring".to_string())
+            .spawn(move || {
+                if let Err(e) = run(ring, eventfd, &submissions_clone) {
+                    error!(?e, "io_uring driver exited");
+                }
+            })?;
+        Ok(Driver {
+            submissions,
+            eventfd,
+        })
+    }
+
+    fn push(&self, entry: squeue::Entry) {
+        self.submissions.lock().push(entry);
+        let one = 1u64;
+        unsafe {
+            libc::write(
+                self.eventfd,
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+    }
+
+    /// Queue the entry built for the fd of `stream` and the address of `buf`, the buffer is
+    /// returned on completion.
+    fn submit(
+        &'static self,
+        stream: &TcpStream,
+        mut buf: Vec<u8>,
+        build: impl FnOnce(types::Fd, *mut u8) -> squeue::Entry,
+    ) -> Completion {
+        // The heap buffer doesn't move with the vec.
+        let ptr = buf.as_mut_ptr();
+        let op = Arc::new(Op {
+            state: Mutex::new(OpState {
+                result: None,
+                waker: None,
+                buf,
+            }),
+            _stream: stream.clone(),
+        });
+        let entry =
+            build(types::Fd(stream.as_raw_fd()), ptr).user_data(Arc::into_raw(op.clone()) as u64);
+        self.push(entry);
+        Completion { driver: self, op }
+    }
+
+    /// Wait until `stream` is ready for `events`.
+    async fn poll(&'static self, stream: &TcpStream, events: i16) -> io::Result<()> {
+        let (ret, _) = self
+            .submit(stream, vec![], |fd, _| {
+                opcode::PollAdd::new(fd, events as u32).build()
+            })
+            .await;
+        ret.map(|_| ())
+    }
+
+    /// Move data from `from` to `to` until `from` is closed, `relayed` is called with the size of
+    /// each moved block.
+    pub async fn copy(
+        &'static self,
+        from: &TcpStream,
+        to: &TcpStream,
+        mut relayed: impl FnMut(usize),
+    ) -> io::Result<()> {
+        let mut buf = vec![0; RELAY_BUFFER_SIZE];
+        loop {
+            let (ret, returned) = self
+                .submit(from, buf, |fd, ptr| {
+                    opcode::Recv::new(fd, ptr, RELAY_BUFFER_SIZE as u32).build()
+                })
+                .await;
+            buf = returned;
+            let size = match ret {
+                Ok(0) => return Ok(()),
+                Ok(size) => size,
+                // Sockets of async-std are nonblocking, which some kernels respect.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.poll(from, libc::POLLIN).await?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let mut pos = 0;
+            while pos < size {
+                let (ret, returned) = self
+                    .submit(to, buf, |fd, ptr| {
+                        let ptr = unsafe { ptr.add(pos) };
+                        opcode::Send::new(fd, ptr, (size - pos) as u32).build()
+                    })
+                    .await;
+                buf = returned;
+                match ret {
+                    Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                    Ok(n) => pos += n,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        self.poll(to, libc::POLLOUT).await?;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            relayed(size);
+        }
+    }
+}
+
+/// An op in flight. The driver holds a reference until it completes, so the buffer stays alive
+/// even if the waiting future is dropped.
+struct Op {
+    state: Mutex<OpState>,
+    /// A clone of the socket keeps its fd open until the op completes, otherwise the fd could be
+    /// reused by a new socket while the op is still queued.
+    _stream: TcpStream,
+}
+
+struct OpState {
+    result: Option<i32>,
+    waker: Option<Waker>,
+    buf: Vec<u8>,
+}
+
+impl Op {
+    fn complete(&self, result: i32) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.result = Some(result);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+struct Completion {
+    driver: &'static Driver,
+    op: Arc<Op>,
+}
+
+impl Future for Completion {
+    type Output = (io::Result<usize>, Vec<u8>);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.op.state.lock();
+        match state.result {
+            Some(result) => {
+                let buf = mem::replace(&mut state.buf, vec![]);
+                let ret = if result < 0 {
+                    Err(io::Error::from_raw_os_error(-result))
+                } else {
+                    Ok(result as usize)
+                };
+                Poll::Ready((ret, buf))
+            }
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        // A pending recv keeps a reference to the socket, cancel it so the socket can be closed.
+        if self.op.state.lock().result.is_none() {
+            let user_data = Arc::as_ptr(&self.op) as u64;
+            self.driver.push(
+                opcode::AsyncCancel::new(user_data)
+                    .build()
+                    .user_data(CANCEL_USER_DATA),
+            );
+        }
+    }
+}
+
+fn run(
+    mut ring: IoUring,
+    eventfd: RawFd,
+    submissions: &Mutex<Vec<squeue::Entry>>,
+) -> io::Result<()> {
+    let mut counter = [0u8; 8];
+    let mut wake_queued = false;
+    loop {
+        if !wake_queued {
+            let entry = opcode::Read::new(types::Fd(eventfd), counter.as_mut_ptr(), 8)
+                .build()
+                .user_data(WAKE_USER_DATA);
+            push(&mut ring, &entry)?;
+            wake_queued = true;
+        }
+        let entries = mem::replace(&mut *submissions.lock(), vec![]);
+        for entry in &entries {
+            push(&mut ring, entry)?;
+        }
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        for cqe in ring.completion() {
+            match cqe.user_data() {
+                WAKE_USER_DATA => wake_queued = false,
+                CANCEL_USER_DATA => {}
+                ptr => unsafe { Arc::from_raw(ptr as *const Op) }.complete(cqe.result()),
+            }
+        }
+    }
+}
+
+fn push(ring: &mut IoUring, entry: &squeue::Entry) -> io::Result<()> {
+    // Buffers of the entries are kept alive by their ops until completion.
+    while unsafe { ring.submission().push(entry) }.is_err() {
+        ring.submit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::prelude::*;
+    use async_std::task::{block_on, spawn};
+
+    #[test]
+    fn test_copy() {
+        let driver = match driver() {
+            Some(driver) => driver,
+            None => return,
+        };
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+            let expected = data.clone();
+
+            // client -> relay_in, relay_out -> server
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (relay_in, _) = listener.accept().await.unwrap();
+            let relay_out = TcpStream::connect(addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+
+            let h = spawn(async move {
+                let mut total = 0;
+                driver
+                    .copy(&relay_in, &relay_out, |n| total += n)
+                    .await
+                    .unwrap();
+                total
+            });
+            let reader = spawn(async move {
+                let mut received = vec![];
+                server.read_to_end(&mut received).await.unwrap();
+                received
+            });
+            client.write_all(&data).await.unwrap();
+            drop(client);
+            assert_eq!(h.await, expected.len());
+            assert_eq!(reader.await, expected);
+        });
+    }
+
+    #[test]
+    fn test_op_keeps_socket_open() {
+        let driver = match driver() {
+            Some(driver) => driver,
+            None => return,
+        };
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let _server = listener.accept().await.unwrap();
+            let fd = client.as_raw_fd();
+            let completion = driver.submit(&client, vec![], |fd, _| {
+                opcode::PollAdd::new(fd, libc::POLLIN as u32).build()
+            });
+            drop(client);
+            // The queued poll still refers to the fd, it must not be closed and reused.
+            assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+            drop(completion);
+        });
+    }
+}
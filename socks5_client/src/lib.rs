@@ -3,5 +3,9 @@ mod types;
 mod udp;
 
 pub use tcp::Socks5TcpStream;
-pub use types::Address;
+pub use types::{
+    Address, Command, HandshakeRequest, HandshakeResponse, TcpRequestHeader,
+    SOCKS5_AUTH_METHOD_NONE, SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE, SOCKS5_AUTH_METHOD_PASSWORD,
+    SOCKS5_VERSION,
+};
 pub use udp::Socks5UdpSocket;
@@ -0,0 +1,112 @@
+//! Throughput of relaying through `SSTcpStream` over loopback, including the framing buffers, and
+//! the cost of setting up the AEAD ciphers of a connection.
+//!
+//! Compare changes against a baseline:
+//!
+//! ```plain
+//! cargo bench -p ssclient -- --save-baseline before
+//! # apply the change
+//! cargo bench -p ssclient -- --baseline before
+//! ```
+
+use async_std::net::TcpListener;
+use async_std::prelude::*;
+use async_std::task::block_on;
+use config::Address;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use crypto::CipherType;
+use futures_util::future::join;
+use ssclient::{SSReadHalf, SSTcpStream, SSWriteHalf};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+
+/// Size of the writes, as done by the relay.
+const CHUNK_SIZE: usize = 1500;
+const TOTAL_SIZE: usize = 4 * 1024 * 1024;
+
+/// A connected client and server, returns the writing half of the client and the reading half
+/// of the server.
+async fn connect(method: CipherType) -> (SSWriteHalf, SSReadHalf) {
+    let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let addr = Address::DomainNameAddress("example.com".to_string(), 443);
+    let client = SSTcpStream::connect(
+        addr,
+        server_addr,
+        Arc::new(AtomicBool::new(true)),
+        method,
+        key.clone(),
+        false,
+    );
+    let (client, accepted) = join(client, listener.accept()).await;
+    let mut server = SSTcpStream::accept(accepted.unwrap().0, method, key, None);
+    Address::read_from(&mut server).await.unwrap();
+    let (_, client_writer) = client.unwrap().into_split().ok().unwrap();
+    let (server_reader, _) = server.into_split().ok().unwrap();
+    (client_writer, server_reader)
+}
+
+fn relay(c: &mut Criterion) {
+    let mut group = c.benchmark_group("relay");
+    group.throughput(Throughput::Bytes(TOTAL_SIZE as u64));
+    for (name, method) in &[
+        ("chacha20-ietf-poly1305", CipherType::ChaCha20IetfPoly1305),
+        ("aes-256-gcm", CipherType::Aes256Gcm),
+        ("chacha20-ietf", CipherType::ChaCha20Ietf),
+        // The overhead of the relay without crypto.
+        ("plain", CipherType::Plain),
+    ] {
+        let (mut writer, mut reader) = block_on(connect(*method));
+        let data = vec![0u8; CHUNK_SIZE];
+        let mut received = vec![0u8; TOTAL_SIZE];
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                block_on(async {
+                    let write = async {
+                        for _ in 0..TOTAL_SIZE / CHUNK_SIZE {
+                            writer.write_all(&data).await.unwrap();
+                        }
+                    };
+                    let read =
+                        reader.read_exact(&mut received[..TOTAL_SIZE / CHUNK_SIZE * CHUNK_SIZE]);
+                    let (_, read) = join(write, read).await;
+                    read.unwrap();
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Decrypting the first chunk of a connection, with the session subkey derived by HKDF and with
+/// the subkey cached, as done by the server after finding the password of the connection.
+fn handshake(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+    let method = CipherType::ChaCha20IetfPoly1305;
+    let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+    let salt = method.gen_salt();
+    let mut chunk = vec![0u8; 2 + method.tag_size()];
+    crypto::new_aead_encryptor(method, &key, &salt).encrypt(&[0, 5], &mut chunk);
+    let subkey = crypto::make_skey(method, &key, &salt);
+    group.bench_function("derive_subkey", |b| {
+        b.iter(|| {
+            let mut len = [0; 2];
+            crypto::new_aead_decryptor(method, &key, &salt)
+                .decrypt(&chunk, &mut len)
+                .unwrap();
+        })
+    });
+    group.bench_function("cached_subkey", |b| {
+        b.iter(|| {
+            let mut len = [0; 2];
+            crypto::new_aead_decryptor_with_subkey(method, &subkey)
+                .decrypt(&chunk, &mut len)
+                .unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, relay, handshake);
+criterion_main!(benches);
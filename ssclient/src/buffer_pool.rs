@@ -0,0 +1,54 @@
+//! Per thread pool of the buffers encrypted data is written from, so relaying doesn't allocate a
+//! buffer for every write once the pool is warm.
+
+use bytes::BytesMut;
+use std::cell::RefCell;
+
+use crate::{BUFFER_SIZE, MAX_WRITE_SIZE};
+
+/// Buffers kept by each thread.
+const MAX_POOLED: usize = 16;
+/// Larger buffers are freed instead of being pooled.
+const MAX_POOLED_CAPACITY: usize = 2 * MAX_WRITE_SIZE;
+
+thread_local! {
+    static POOL: RefCell<Vec<BytesMut>> = RefCell::new(Vec::new());
+}
+
+/// An empty buffer, from the pool if there is one.
+pub fn take() -> BytesMut {
+    POOL.with(|pool| pool.borrow_mut().pop())
+        .unwrap_or_else(|| BytesMut::with_capacity(BUFFER_SIZE))
+}
+
+/// Return `buf` to the pool of the current thread.
+pub fn give_back(mut buf: BytesMut) {
+    if buf.capacity() > MAX_POOLED_CAPACITY {
+        return;
+    }
+    buf.clear();
+    POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < MAX_POOLED {
+            pool.push(buf);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut buf = take();
+        buf.extend_from_slice(b"hello");
+        let ptr = buf.as_ptr();
+        give_back(buf);
+        let buf = take();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        give_back(BytesMut::with_capacity(MAX_POOLED_CAPACITY + 1));
+        assert!(take().capacity() <= MAX_POOLED_CAPACITY);
+    }
+}
@@ -1,8 +1,13 @@
+mod buffer_pool;
+mod replay;
 mod tcp_io;
 mod udp_io;
 
 const BUFFER_SIZE: usize = 8 * 1024; // 8K buffer
+/// Plaintext encrypted and sent by one write of the tcp writers.
+const MAX_WRITE_SIZE: usize = 64 * 1024;
 
-pub use tcp_io::SSTcpStream;
+pub use replay::ReplayFilter;
+pub use tcp_io::{SSReadHalf, SSTcpStream, SSWriteHalf};
 pub use udp_io::crypto_io::{decrypt_payload, encrypt_payload};
 pub use udp_io::SSUdpSocket;
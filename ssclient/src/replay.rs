@@ -0,0 +1,140 @@
+//! Salts and IVs seen recently by a server. Active probers replay recorded connections to find
+//! out whether a host runs shadowsocks, connections reusing a salt are rejected before anything is
+//! decrypted.
+//!
+//! Two bloom filters are used in turn, when the current one is full the older one is cleared and
+//! takes its place, so the latest `capacity` salts at least are always remembered.
+
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+
+/// Salts remembered by `ReplayFilter::default`.
+const DEFAULT_CAPACITY: usize = 100_000;
+/// Chance of a fresh salt being taken as replayed.
+const DEFAULT_FALSE_POSITIVE_RATE: f64 = 1e-6;
+
+pub struct ReplayFilter {
+    capacity: usize,
+    hash_keys: (RandomState, RandomState),
+    filters: Mutex<(BloomFilter, BloomFilter)>,
+}
+
+impl ReplayFilter {
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((bits as f64 / capacity as f64) * ln2).ceil().max(1.0) as u64;
+        ReplayFilter {
+            capacity,
+            hash_keys: (RandomState::new(), RandomState::new()),
+            filters: Mutex::new((
+                BloomFilter::new(bits, hashes),
+                BloomFilter::new(bits, hashes),
+            )),
+        }
+    }
+
+    /// Whether `salt` has been seen, without recording it.
+    pub fn contains(&self, salt: &[u8]) -> bool {
+        let hashes = (hash(&self.hash_keys.0, salt), hash(&self.hash_keys.1, salt));
+        let filters = self.filters.lock();
+        filters.0.contains(hashes) || filters.1.contains(hashes)
+    }
+
+    /// Record `salt`, returns false if it has been seen.
+    pub fn check_and_insert(&self, salt: &[u8]) -> bool {
+        let hashes = (hash(&self.hash_keys.0, salt), hash(&self.hash_keys.1, salt));
+        let mut filters = self.filters.lock();
+        let (current, previous) = &mut *filters;
+        if current.contains(hashes) || previous.contains(hashes) {
+            return false;
+        }
+        if current.len >= self.capacity {
+            std::mem::swap(current, previous);
+            current.clear();
+        }
+        current.insert(hashes);
+        true
+    }
+}
+
+impl Default for ReplayFilter {
+    fn default() -> Self {
+        ReplayFilter::new(DEFAULT_CAPACITY, DEFAULT_FALSE_POSITIVE_RATE)
+    }
+}
+
+fn hash(key: &RandomState, salt: &[u8]) -> u64 {
+    let mut hasher = key.build_hasher();
+    salt.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u64,
+    len: usize,
+}
+
+impl BloomFilter {
+    fn new(bits: usize, hashes: u64) -> Self {
+        BloomFilter {
+            bits: vec![0; (bits + 63) / 64],
+            hashes,
+            len: 0,
+        }
+    }
+
+    /// Positions of a salt by double hashing.
+    fn positions(&self, (h1, h2): (u64, u64)) -> impl Iterator<Item = usize> {
+        let bits = self.bits.len() as u64 * 64;
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    fn contains(&self, hashes: (u64, u64)) -> bool {
+        self.positions(hashes)
+            .all(|pos| self.bits[pos / 64] & (1 << (pos % 64)) != 0)
+    }
+
+    fn insert(&mut self, hashes: (u64, u64)) {
+        for pos in self.positions(hashes) {
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+        self.len += 1;
+    }
+
+    fn clear(&mut self) {
+        for word in &mut self.bits {
+            *word = 0;
+        }
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_and_insert() {
+        let filter = ReplayFilter::new(100, 1e-6);
+        assert!(!filter.contains(b"salt"));
+        assert!(filter.check_and_insert(b"salt"));
+        assert!(filter.contains(b"salt"));
+        assert!(!filter.check_and_insert(b"salt"));
+
+        for i in 0..150u32 {
+            assert!(filter.check_and_insert(&i.to_be_bytes()));
+        }
+        // Still in the previous filter.
+        assert!(!filter.check_and_insert(b"salt"));
+        for i in 150..250u32 {
+            filter.check_and_insert(&i.to_be_bytes());
+        }
+        // Forgotten once both filters have been filled since.
+        assert!(filter.check_and_insert(b"salt"));
+        assert!(!filter.check_and_insert(&249u32.to_be_bytes()));
+    }
+}
@@ -1,26 +1,31 @@
 mod aead;
+mod fast_open;
 mod stream;
 
-use async_std::io::{Read, Write};
+use async_std::io::{BufRead, Read, Write};
 use async_std::prelude::*;
-use std::io::{ErrorKind, Result};
+use async_std::task::sleep;
+use std::io::{ErrorKind, IoSlice, Result};
 
 use std::{
+    future::Future,
     io,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use bytes::{Bytes, BytesMut};
+use bytes::BytesMut;
 use futures_util::ready;
 use tracing::trace;
 
-use crypto::{CipherCategory, CipherType};
+use crypto::{CipherCategory, CipherType, SecretKey};
 
 use self::{
     aead::{DecryptedReader as AeadDecryptedReader, EncryptedWriter as AeadEncryptedWriter},
     stream::{DecryptedReader as StreamDecryptedReader, EncryptedWriter as StreamEncryptedWriter},
 };
+use crate::replay::ReplayFilter;
 use async_std::net::TcpStream;
 use config::Address;
 use parking_lot::Mutex;
@@ -43,74 +48,62 @@ enum ReadStatus {
     /// Waiting for initializing vector (or nonce for AEAD ciphers)
     ///
     /// (context, Buffer, already_read_bytes, method, key)
-    WaitIv(Vec<u8>, usize, CipherType, Bytes),
+    WaitIv(Vec<u8>, usize, CipherType, SecretKey),
 
     /// Connection is established, DecryptedReader is initialized
-    Established,
+    Established(DecryptedReader<TcpStream>),
+}
+
+/// The decrypting half of a `SSTcpStream`, owned by one task so reads take no lock.
+pub struct SSReadHalf {
+    stream: TcpStream,
+    status: ReadStatus,
+    server_alive: Arc<AtomicBool>,
+    /// Salts and IVs seen by the server, set for accepted connections.
+    replay_filter: Option<Arc<ReplayFilter>>,
+    /// Fails the handshake if the iv hasn't been received when it's done.
+    handshake_deadline: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    /// A salt and its session subkey derived before the handshake.
+    session_subkey: Option<(Vec<u8>, SecretKey)>,
+    /// Bytes of the first AEAD chunk read before the handshake.
+    received: Vec<u8>,
+}
+
+/// The encrypting half of a `SSTcpStream`, owned by one task so writes take no lock.
+pub struct SSWriteHalf {
+    stream: TcpStream,
+    enc: EncryptedWriter<TcpStream>,
+    server_alive: Arc<AtomicBool>,
 }
 
 /// A bidirectional stream for communicating with ShadowSocks' server
+///
+/// Clones share the halves behind locks, use `into_split` to relay in both directions
+/// without locking.
 #[derive(Clone)]
 pub struct SSTcpStream {
     stream: TcpStream,
-    dec: Option<Arc<Mutex<DecryptedReader<TcpStream>>>>,
-    enc: Arc<Mutex<EncryptedWriter<TcpStream>>>,
-    read_status: Arc<Mutex<ReadStatus>>,
-    server_alive: Arc<AtomicBool>,
+    read_half: Arc<Mutex<SSReadHalf>>,
+    write_half: Arc<Mutex<SSWriteHalf>>,
 }
 
 impl SSTcpStream {
-    /// Create a new CryptoStream with the underlying stream connection
+    /// Create a new CryptoStream with the underlying stream connection. With `fast_open` the
+    /// salt and `addr` are sent in the SYN where the network allows it.
     pub async fn connect(
         addr: Address,
         server_addr: SocketAddr,
         server_alive: Arc<AtomicBool>,
         method: CipherType,
-        key: Bytes,
+        key: SecretKey,
+        fast_open: bool,
     ) -> Result<SSTcpStream> {
-        let stream = TcpStream::connect(server_addr).await?;
-        let prev_len = match method.category() {
-            CipherCategory::Stream => method.iv_size(),
-            CipherCategory::Aead => method.salt_size(),
-        };
-
-        let iv = match method.category() {
-            CipherCategory::Stream => {
-                let local_iv = method.gen_init_vec();
-                trace!("generated Stream cipher IV {:?}", local_iv);
-                local_iv
-            }
-            CipherCategory::Aead => {
-                let local_salt = method.gen_salt();
-                trace!("generated AEAD cipher salt {:?}", local_salt);
-                local_salt
-            }
-        };
-
-        let enc = match method.category() {
-            CipherCategory::Stream => EncryptedWriter::Stream(StreamEncryptedWriter::new(
-                stream.clone(),
-                method,
-                &key,
-                iv,
-            )),
-            CipherCategory::Aead => {
-                EncryptedWriter::Aead(AeadEncryptedWriter::new(stream.clone(), method, &key, iv))
-            }
-        };
-
-        let mut ss_stream = SSTcpStream {
-            stream,
-            dec: None,
-            enc: Arc::new(Mutex::new(enc)),
-            read_status: Arc::new(Mutex::new(ReadStatus::WaitIv(
-                vec![0u8; prev_len],
-                0usize,
-                method,
-                key,
-            ))),
-            server_alive,
+        let stream = if fast_open {
+            fast_open::connect(server_addr).await?
+        } else {
+            TcpStream::connect(server_addr).await?
         };
+        let mut ss_stream = SSTcpStream::new(stream, server_alive, method, key);
 
         let mut addr_buf = BytesMut::with_capacity(addr.serialized_len());
         addr.write_to_buf(&mut addr_buf);
@@ -118,7 +111,25 @@ impl SSTcpStream {
         Ok(ss_stream)
     }
 
-    pub fn accept(stream: TcpStream, method: CipherType, key: Bytes) -> SSTcpStream {
+    /// Accept a client connection. Connections reusing a salt or IV in `replay_filter` fail on the
+    /// first read.
+    pub fn accept(
+        stream: TcpStream,
+        method: CipherType,
+        key: SecretKey,
+        replay_filter: Option<Arc<ReplayFilter>>,
+    ) -> SSTcpStream {
+        let mut ss_stream = SSTcpStream::new(stream, Arc::new(AtomicBool::new(true)), method, key);
+        ss_stream.read_half.lock().replay_filter = replay_filter;
+        ss_stream
+    }
+
+    fn new(
+        stream: TcpStream,
+        server_alive: Arc<AtomicBool>,
+        method: CipherType,
+        key: SecretKey,
+    ) -> SSTcpStream {
         let prev_len = match method.category() {
             CipherCategory::Stream => method.iv_size(),
             CipherCategory::Aead => method.salt_size(),
@@ -127,11 +138,13 @@ impl SSTcpStream {
         let iv = match method.category() {
             CipherCategory::Stream => {
                 let local_iv = method.gen_init_vec();
+                #[cfg(feature = "trace-iv")]
                 trace!("generated Stream cipher IV {:?}", local_iv);
                 local_iv
             }
             CipherCategory::Aead => {
                 let local_salt = method.gen_salt();
+                #[cfg(feature = "trace-iv")]
                 trace!("generated AEAD cipher salt {:?}", local_salt);
                 local_salt
             }
@@ -149,31 +162,97 @@ impl SSTcpStream {
             }
         };
 
+        let read_half = SSReadHalf {
+            stream: stream.clone(),
+            status: ReadStatus::WaitIv(vec![0u8; prev_len], 0usize, method, key),
+            server_alive: server_alive.clone(),
+            replay_filter: None,
+            handshake_deadline: None,
+            session_subkey: None,
+            received: Vec::new(),
+        };
+        let write_half = SSWriteHalf {
+            stream: stream.clone(),
+            enc,
+            server_alive,
+        };
         SSTcpStream {
             stream,
-            dec: None,
-            enc: Arc::new(Mutex::new(enc)),
-            read_status: Arc::new(Mutex::new(ReadStatus::WaitIv(
-                vec![0u8; prev_len],
-                0usize,
-                method,
-                key,
-            ))),
-            server_alive: Arc::new(AtomicBool::new(true)),
+            read_half: Arc::new(Mutex::new(read_half)),
+            write_half: Arc::new(Mutex::new(write_half)),
         }
     }
 
+    /// Fail reading if the iv or salt of the peer isn't received within `timeout` from the first
+    /// read, instead of waiting for a broken or malicious peer forever.
+    pub fn set_handshake_timeout(&self, timeout: Duration) {
+        self.read_half.lock().handshake_deadline = Some(Box::pin(sleep(timeout)));
+    }
+
+    /// Use `subkey`, derived from the AEAD `salt` beforehand, if the peer sends this salt,
+    /// instead of running the HKDF again.
+    pub fn set_session_subkey(&self, salt: &[u8], subkey: SecretKey) {
+        self.read_half.lock().session_subkey = Some((salt.to_vec(), subkey));
+    }
+
+    /// Continue the AEAD handshake of a stream whose `salt` and the beginning of the first chunk,
+    /// `received`, have been read already, eg. to check the password. `subkey` is derived from
+    /// the salt.
+    pub fn set_received(&self, salt: &[u8], received: &[u8], subkey: SecretKey) {
+        let mut read_half = self.read_half.lock();
+        if let ReadStatus::WaitIv(buf, pos, ..) = &mut read_half.status {
+            buf.copy_from_slice(salt);
+            *pos = buf.len();
+        }
+        read_half.session_subkey = Some((salt.to_vec(), subkey));
+        read_half.received = received.to_vec();
+    }
+
     /// Return a reference to the underlying stream
     pub fn get_ref(&self) -> &TcpStream {
         &self.stream
     }
 
+    /// Split into halves which read and write without locking. The stream is returned back if
+    /// it has been cloned.
+    pub fn into_split(self) -> std::result::Result<(SSReadHalf, SSWriteHalf), SSTcpStream> {
+        let SSTcpStream {
+            stream,
+            read_half,
+            write_half,
+        } = self;
+        match (Arc::try_unwrap(read_half), Arc::try_unwrap(write_half)) {
+            (Ok(read_half), Ok(write_half)) => {
+                Ok((read_half.into_inner(), write_half.into_inner()))
+            }
+            (read_half, write_half) => Err(SSTcpStream {
+                stream,
+                read_half: read_half.map(Arc::new).unwrap_or_else(|r| r),
+                write_half: write_half.map(Arc::new).unwrap_or_else(|w| w),
+            }),
+        }
+    }
+}
+
+impl SSReadHalf {
     fn poll_read_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        if let ReadStatus::WaitIv(ref mut buf, ref mut pos, method, ref key) =
-            *self.read_status.lock()
-        {
+        if let ReadStatus::WaitIv(ref mut buf, ref mut pos, method, ref key) = self.status {
             while *pos < buf.len() {
-                let n = ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buf[*pos..]))?;
+                let n = match Pin::new(&mut self.stream).poll_read(cx, &mut buf[*pos..]) {
+                    Poll::Ready(ret) => ret?,
+                    Poll::Pending => {
+                        if let Some(deadline) = &mut self.handshake_deadline {
+                            if deadline.as_mut().poll(cx).is_ready() {
+                                trace!("wait iv timeout");
+                                return Poll::Ready(Err(io::Error::new(
+                                    ErrorKind::TimedOut,
+                                    "iv not received in time",
+                                )));
+                            }
+                        }
+                        return Poll::Pending;
+                    }
+                };
                 if n == 0 {
                     trace!("wait iv error");
                     return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
@@ -181,8 +260,20 @@ impl SSTcpStream {
                 *pos += n;
             }
 
+            // `plain` and `table` have no iv to tell connections apart.
+            if let Some(filter) = self.replay_filter.as_ref().filter(|_| !buf.is_empty()) {
+                if !filter.check_and_insert(buf) {
+                    trace!("replayed iv");
+                    return Poll::Ready(Err(io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        "replayed iv",
+                    )));
+                }
+            }
+
             let dec = match method.category() {
                 CipherCategory::Stream => {
+                    #[cfg(feature = "trace-iv")]
                     trace!("got Stream cipher IV {:?}", &buf);
                     DecryptedReader::Stream(StreamDecryptedReader::new(
                         self.stream.clone(),
@@ -192,56 +283,124 @@ impl SSTcpStream {
                     ))
                 }
                 CipherCategory::Aead => {
+                    #[cfg(feature = "trace-iv")]
                     trace!("got AEAD cipher salt {:?}", &buf);
-                    DecryptedReader::Aead(AeadDecryptedReader::new(
-                        self.stream.clone(),
-                        method,
-                        key,
-                        &buf,
-                    ))
+                    let subkey = match self.session_subkey.take() {
+                        Some((salt, subkey)) if salt == *buf => subkey,
+                        _ => crypto::make_skey(method, key, buf),
+                    };
+                    DecryptedReader::Aead(
+                        AeadDecryptedReader::with_subkey(self.stream.clone(), method, &subkey)
+                            .with_received(&self.received),
+                    )
                 }
             };
 
-            self.dec = Some(Arc::new(Mutex::new(dec)));
-        } else {
-            return Poll::Ready(Ok(()));
-        };
-
-        *self.read_status.lock() = ReadStatus::Established;
+            self.status = ReadStatus::Established(dec);
+            self.handshake_deadline = None;
+        }
         Poll::Ready(Ok(()))
     }
+}
 
-    fn priv_poll_read(
+impl Read for SSReadHalf {
+    fn poll_read(
         self: Pin<&mut Self>,
         ctx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
+        if !this.server_alive.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        ready!(this.poll_read_handshake(ctx))?;
+
+        match this.status {
+            ReadStatus::Established(DecryptedReader::Aead(ref mut r)) => {
+                Pin::new(r).poll_read(ctx, buf)
+            }
+            ReadStatus::Established(DecryptedReader::Stream(ref mut r)) => {
+                Pin::new(r).poll_read(ctx, buf)
+            }
+            ReadStatus::WaitIv(..) => unreachable!("iv is read by the handshake"),
+        }
+    }
+}
+
+impl BufRead for SSReadHalf {
+    fn poll_fill_buf(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if !this.server_alive.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
         ready!(this.poll_read_handshake(ctx))?;
 
-        match *this.dec.as_ref().unwrap().lock() {
-            DecryptedReader::Aead(ref mut r) => Pin::new(r).poll_read(ctx, buf),
-            DecryptedReader::Stream(ref mut r) => Pin::new(r).poll_read(ctx, buf),
+        match this.status {
+            ReadStatus::Established(DecryptedReader::Aead(ref mut r)) => {
+                Pin::new(r).poll_fill_buf(ctx)
+            }
+            ReadStatus::Established(DecryptedReader::Stream(ref mut r)) => {
+                Pin::new(r).poll_fill_buf(ctx)
+            }
+            ReadStatus::WaitIv(..) => unreachable!("iv is read by the handshake"),
         }
     }
 
-    fn priv_poll_write(
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        match self.get_mut().status {
+            ReadStatus::Established(DecryptedReader::Aead(ref mut r)) => Pin::new(r).consume(amt),
+            ReadStatus::Established(DecryptedReader::Stream(ref mut r)) => Pin::new(r).consume(amt),
+            ReadStatus::WaitIv(..) => {}
+        }
+    }
+}
+
+impl Write for SSWriteHalf {
+    fn poll_write(
         self: Pin<&mut Self>,
         ctx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         let this = self.get_mut();
-        match *this.enc.lock() {
+        if !this.server_alive.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        match this.enc {
             EncryptedWriter::Aead(ref mut w) => Pin::new(w).poll_write(ctx, buf),
             EncryptedWriter::Stream(ref mut w) => Pin::new(w).poll_write(ctx, buf),
         }
     }
 
-    fn priv_poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        ctx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.server_alive.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        match this.enc {
+            EncryptedWriter::Aead(ref mut w) => Pin::new(w).poll_write_vectored(ctx, bufs),
+            EncryptedWriter::Stream(ref mut w) => Pin::new(w).poll_write_vectored(ctx, bufs),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.server_alive.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
         Write::poll_flush(Pin::new(&mut self.stream), ctx)
     }
 
-    fn priv_poll_close(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    fn poll_close(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.server_alive.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
         Write::poll_close(Pin::new(&mut self.stream), ctx)
     }
 }
@@ -252,11 +411,7 @@ impl Read for SSTcpStream {
         ctx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        if !self.server_alive.load(Ordering::SeqCst) {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
-        }
-
-        self.priv_poll_read(ctx, buf)
+        Pin::new(&mut *self.read_half.lock()).poll_read(ctx, buf)
     }
 }
 
@@ -266,27 +421,23 @@ impl Write for SSTcpStream {
         ctx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        if !self.server_alive.load(Ordering::SeqCst) {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
-        }
+        Pin::new(&mut *self.write_half.lock()).poll_write(ctx, buf)
+    }
 
-        self.priv_poll_write(ctx, buf)
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        ctx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.write_half.lock()).poll_write_vectored(ctx, bufs)
     }
 
     fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        if !self.server_alive.load(Ordering::SeqCst) {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
-        }
-
-        self.priv_poll_flush(ctx)
+        Pin::new(&mut *self.write_half.lock()).poll_flush(ctx)
     }
 
     fn poll_close(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        if !self.server_alive.load(Ordering::SeqCst) {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
-        }
-
-        self.priv_poll_close(ctx)
+        Pin::new(&mut *self.write_half.lock()).poll_close(ctx)
     }
 }
 
@@ -295,8 +446,8 @@ mod tests {
     use super::*;
     use async_std::net::TcpListener;
     use async_std::task::{block_on, sleep, spawn};
+    use futures_util::future::join;
     use std::net::ToSocketAddrs;
-    use std::time::Duration;
     use tracing::trace;
 
     #[allow(dead_code)]
@@ -324,7 +475,7 @@ mod tests {
             let h = spawn(async move {
                 let (stream, _) = listener.accept().await.unwrap();
                 trace!("accept conn");
-                let mut ss_server = SSTcpStream::accept(stream, method, key);
+                let mut ss_server = SSTcpStream::accept(stream, method, key, None);
                 let addr = Address::read_from(&mut ss_server).await.unwrap();
                 trace!("read address");
                 assert_eq!(addr, addr_clone);
@@ -343,6 +494,7 @@ mod tests {
                 Arc::new(AtomicBool::new(true)),
                 method,
                 key_clone,
+                false,
             )
             .await
             .unwrap();
@@ -353,4 +505,242 @@ mod tests {
             h.await;
         })
     }
+
+    #[test]
+    fn test_into_split() {
+        let method = CipherType::ChaCha20IetfPoly1305;
+        let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+        let addr = Address::DomainNameAddress("twitter.com".to_string(), 443);
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            let key_clone = key.clone();
+            let h = spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ss_server = SSTcpStream::accept(stream, method, key_clone, None);
+                Address::read_from(&mut ss_server).await.unwrap();
+                let mut buf = vec![0; 5];
+                ss_server.read_exact(&mut buf).await.unwrap();
+                ss_server.write_all(&buf).await.unwrap();
+            });
+
+            let conn = SSTcpStream::connect(
+                addr,
+                server,
+                Arc::new(AtomicBool::new(true)),
+                method,
+                key,
+                false,
+            )
+            .await
+            .unwrap();
+            // Halves can't be taken while a clone shares them.
+            assert!(conn.clone().into_split().is_err());
+            let (mut reader, mut writer) = conn.into_split().ok().unwrap();
+            writer.write_all(b"hello").await.unwrap();
+            let mut buf = vec![0; 5];
+            reader.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            h.await;
+        })
+    }
+
+    #[test]
+    fn test_replay_filter() {
+        let method = CipherType::ChaCha20IetfPoly1305;
+        let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+        let addr = Address::DomainNameAddress("twitter.com".to_string(), 443);
+        block_on(async {
+            // Record a connection as a prober would.
+            let recorder = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let conn = SSTcpStream::connect(
+                addr.clone(),
+                recorder.local_addr().unwrap(),
+                Arc::new(AtomicBool::new(true)),
+                method,
+                key.clone(),
+                false,
+            )
+            .await
+            .unwrap();
+            drop(conn);
+            let (mut recorded, _) = recorder.accept().await.unwrap();
+            let mut data = vec![];
+            recorded.read_to_end(&mut data).await.unwrap();
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            let filter = Arc::new(ReplayFilter::default());
+            for replayed in &[false, true] {
+                let mut client = TcpStream::connect(server).await.unwrap();
+                client.write_all(&data).await.unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ss_server =
+                    SSTcpStream::accept(stream, method, key.clone(), Some(filter.clone()));
+                let ret = Address::read_from(&mut ss_server).await;
+                if *replayed {
+                    assert!(ret.is_err());
+                } else {
+                    assert_eq!(ret.unwrap(), addr);
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_session_subkey() {
+        let method = CipherType::ChaCha20IetfPoly1305;
+        let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+        let addr = Address::DomainNameAddress("twitter.com".to_string(), 443);
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            // A subkey set for the salt is used instead of the derived one.
+            for (right_subkey, other_salt) in &[(true, false), (false, false), (false, true)] {
+                let client = SSTcpStream::connect(
+                    addr.clone(),
+                    server,
+                    Arc::new(AtomicBool::new(true)),
+                    method,
+                    key.clone(),
+                    false,
+                );
+                let (client, accepted) = join(client, listener.accept()).await;
+                let _client = client.unwrap();
+                let (stream, _) = accepted.unwrap();
+                let mut salt = vec![0; method.salt_size()];
+                stream.peek(&mut salt).await.unwrap();
+                let subkey = if *right_subkey {
+                    crypto::make_skey(method, &key, &salt)
+                } else {
+                    SecretKey::from(vec![0; method.key_size()])
+                };
+                if *other_salt {
+                    salt[0] ^= 1;
+                }
+                let mut ss_server = SSTcpStream::accept(stream, method, key.clone(), None);
+                ss_server.set_session_subkey(&salt, subkey);
+                let ret = Address::read_from(&mut ss_server).await;
+                if *right_subkey || *other_salt {
+                    assert_eq!(ret.unwrap(), addr);
+                } else {
+                    assert!(ret.is_err());
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_received() {
+        let method = CipherType::ChaCha20IetfPoly1305;
+        let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+        let addr = Address::DomainNameAddress("twitter.com".to_string(), 443);
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            let client = SSTcpStream::connect(
+                addr.clone(),
+                server,
+                Arc::new(AtomicBool::new(true)),
+                method,
+                key.clone(),
+                false,
+            );
+            let (client, accepted) = join(client, listener.accept()).await;
+            let _client = client.unwrap();
+            let (mut stream, _) = accepted.unwrap();
+            // The salt and the length of the first chunk are read before the handshake.
+            let mut received = vec![0; method.salt_size() + 2 + method.tag_size()];
+            stream.read_exact(&mut received).await.unwrap();
+            let (salt, chunk) = received.split_at(method.salt_size());
+            let subkey = crypto::make_skey(method, &key, salt);
+            let mut ss_server = SSTcpStream::accept(stream, method, key.clone(), None);
+            ss_server.set_received(salt, chunk, subkey);
+            assert_eq!(Address::read_from(&mut ss_server).await.unwrap(), addr);
+        })
+    }
+
+    #[test]
+    fn test_plain() {
+        let method = CipherType::Plain;
+        let key = method.bytes_to_key(b"");
+        let addr = Address::DomainNameAddress("twitter.com".to_string(), 443);
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            let filter = Arc::new(ReplayFilter::default());
+            // Without an iv, connections aren't taken as replayed.
+            for _ in 0..2 {
+                let client = SSTcpStream::connect(
+                    addr.clone(),
+                    server,
+                    Arc::new(AtomicBool::new(true)),
+                    method,
+                    key.clone(),
+                    false,
+                );
+                let (client, accepted) = join(client, listener.accept()).await;
+                client.unwrap().write_all(b"hello").await.unwrap();
+                let (stream, _) = accepted.unwrap();
+                let mut ss_server =
+                    SSTcpStream::accept(stream, method, key.clone(), Some(filter.clone()));
+                assert_eq!(Address::read_from(&mut ss_server).await.unwrap(), addr);
+                let mut buf = [0; 5];
+                ss_server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            }
+
+            // The address and the payload are sent as they are.
+            let client = SSTcpStream::connect(
+                addr.clone(),
+                server,
+                Arc::new(AtomicBool::new(true)),
+                method,
+                key,
+                false,
+            );
+            let (client, accepted) = join(client, listener.accept()).await;
+            client.unwrap().write_all(b"hello").await.unwrap();
+            let mut expected = BytesMut::new();
+            addr.write_to_buf(&mut expected);
+            expected.extend_from_slice(b"hello");
+            let mut sent = vec![0; expected.len()];
+            accepted.unwrap().0.read_exact(&mut sent).await.unwrap();
+            assert_eq!(sent, expected);
+        })
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let method = CipherType::ChaCha20IetfPoly1305;
+        let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            for handshake_timeout in &[None, Some(Duration::from_millis(100))] {
+                // The peer sends half of the salt and goes silent.
+                let mut client = TcpStream::connect(server).await.unwrap();
+                client.write_all(&[0; 16]).await.unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut ss_server = SSTcpStream::accept(stream, method, key.clone(), None);
+                let mut buf = [0; 1];
+                match handshake_timeout {
+                    None => {
+                        // Stuck waiting for the rest.
+                        let ret = async_std::future::timeout(
+                            Duration::from_millis(300),
+                            ss_server.read(&mut buf),
+                        )
+                        .await;
+                        assert!(ret.is_err());
+                    }
+                    Some(timeout) => {
+                        ss_server.set_handshake_timeout(*timeout);
+                        let e = ss_server.read(&mut buf).await.unwrap_err();
+                        assert_eq!(e.kind(), ErrorKind::TimedOut);
+                    }
+                }
+            }
+        })
+    }
 }
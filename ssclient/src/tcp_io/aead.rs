@@ -32,9 +32,9 @@
 //! +--------------+---------------+--------------+------------+
 //! ```
 
-use std::io::Result;
+use std::io::{IoSlice, Result};
 use std::{
-    cmp, io,
+    cmp, io, mem,
     pin::Pin,
     slice,
     task::{Context, Poll},
@@ -45,8 +45,8 @@ use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, Bytes, BytesMut};
 use futures_util::ready;
 
-use crate::BUFFER_SIZE;
-use async_std::io::{Read, Write};
+use crate::{buffer_pool, BUFFER_SIZE, MAX_WRITE_SIZE};
+use async_std::io::{BufRead, Read, Write};
 use crypto::{self, BoxAeadDecryptor, BoxAeadEncryptor, CipherType};
 
 /// AEAD packet payload must be smaller than 0x3FFF
@@ -72,11 +72,16 @@ pub struct DecryptedReader<T> {
 
 impl<T: Read + Write + Unpin> DecryptedReader<T> {
     pub fn new(conn: T, t: CipherType, key: &[u8], nonce: &[u8]) -> DecryptedReader<T> {
+        DecryptedReader::with_subkey(conn, t, &crypto::make_skey(t, key, nonce))
+    }
+
+    /// Creates a reader with the session subkey derived from the salt already.
+    pub fn with_subkey(conn: T, t: CipherType, skey: &[u8]) -> DecryptedReader<T> {
         DecryptedReader {
             conn,
             buffer: BytesMut::with_capacity(BUFFER_SIZE),
             data: BytesMut::with_capacity(BUFFER_SIZE),
-            cipher: crypto::new_aead_decryptor(t, key, nonce),
+            cipher: crypto::new_aead_decryptor_with_subkey(t, skey),
             pos: 0,
             tag_size: t.tag_size(),
             steps: DecryptReadStep::Length,
@@ -84,6 +89,14 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
         }
     }
 
+    /// Start with `received`, the beginning of the first chunk read from `conn` already.
+    pub fn with_received(mut self, received: &[u8]) -> DecryptedReader<T> {
+        self.buffer.extend_from_slice(received);
+        self
+    }
+
+    /// Decrypt the next chunk straight into `dst` when it fits, otherwise into `data` and copy
+    /// from there.
     fn poll_read_decrypted(
         &mut self,
         ctx: &mut Context<'_>,
@@ -95,9 +108,15 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
                 return Poll::Ready(Ok(0));
             }
 
-            // Refill buffer
             match self.steps {
                 DecryptReadStep::Length => ready!(self.poll_read_decrypted_length(ctx))?,
+                DecryptReadStep::Data(len) if len > 0 && dst.len() >= len => {
+                    ready!(self.poll_read_exact(ctx, len + self.tag_size, false))?;
+                    self.cipher
+                        .decrypt_mut(&mut self.buffer[..], &mut dst[..len])?;
+                    self.data_decrypted();
+                    return Poll::Ready(Ok(len));
+                }
                 DecryptReadStep::Data(len) => ready!(self.poll_read_decrypted_data(ctx, len))?,
             }
         }
@@ -109,6 +128,17 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
         Poll::Ready(Ok(n))
     }
 
+    /// Decrypt the next chunk into `data`, if everything decrypted before has been consumed.
+    fn poll_fill_data(&mut self, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos >= self.data.len() && !self.got_final {
+            match self.steps {
+                DecryptReadStep::Length => ready!(self.poll_read_decrypted_length(ctx))?,
+                DecryptReadStep::Data(len) => ready!(self.poll_read_decrypted_data(ctx, len))?,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
     fn poll_read_decrypted_length(&mut self, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
         let buf_len = 2 + self.tag_size;
         ready!(self.poll_read_exact(ctx, buf_len, true))?;
@@ -119,19 +149,17 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
         // Done reading, decrypt it
         let len = {
             let mut len_buf = [0u8; 2];
-            self.cipher.decrypt(&self.buffer[..], &mut len_buf)?;
+            self.cipher
+                .decrypt_mut(&mut self.buffer[..], &mut len_buf)?;
             BigEndian::read_u16(&len_buf) as usize
         };
 
         // Clear buffer before overwriting it
         self.buffer.clear();
-        self.data.clear();
-        self.pos = 0;
 
         // Next step, read data
         self.steps = DecryptReadStep::Data(len);
         self.buffer.reserve(len + self.tag_size);
-        self.data.reserve(len);
 
         Poll::Ready(Ok(()))
     }
@@ -145,27 +173,33 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
         ready!(self.poll_read_exact(ctx, buf_len, false))?;
 
         // Done reading data, decrypt it
+        self.data.clear();
+        self.data.reserve(size);
         unsafe {
             // It has enough space, I am sure about that
             let buffer =
                 slice::from_raw_parts_mut(self.data.bytes_mut().as_mut_ptr() as *mut u8, size);
-            self.cipher.decrypt(&self.buffer[..], buffer)?;
+            self.cipher.decrypt_mut(&mut self.buffer[..], buffer)?;
 
             // Move forward the pointer
             self.data.advance_mut(size);
         }
 
-        // Clear buffer before overwriting it
-        self.buffer.clear();
-
         // Reset read position
         self.pos = 0;
+        self.data_decrypted();
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Get ready for the length of the next chunk.
+    fn data_decrypted(&mut self) {
+        // Clear buffer before overwriting it
+        self.buffer.clear();
 
         // Next step, read length
         self.steps = DecryptReadStep::Length;
         self.buffer.reserve(2 + self.tag_size);
-
-        Poll::Ready(Ok(()))
     }
 
     fn poll_read_exact(
@@ -211,9 +245,23 @@ impl<T: Read + Write + Unpin> Read for DecryptedReader<T> {
     }
 }
 
+/// Decrypted bytes left by a short read are handed out without copying them again.
+impl<T: Read + Write + Unpin> BufRead for DecryptedReader<T> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<&[u8]>> {
+        let this = self.get_mut();
+        ready!(this.poll_fill_data(cx))?;
+        Poll::Ready(Ok(&this.data[this.pos..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.data.len());
+    }
+}
+
 enum EncryptWriteStep {
     Nothing,
-    Writing(BytesMut, usize),
+    /// (Encrypted chunks, written bytes, length of the plaintext)
+    Writing(BytesMut, usize, usize),
 }
 
 /// Writer wrapper that will encrypt data automatically
@@ -237,70 +285,43 @@ impl<T: Read + Write + Unpin> EncryptedWriter<T> {
         }
     }
 
+    /// Encrypt `bufs` into chunks of at most `MAX_PACKET_SIZE` bytes, which are sent by a single
+    /// write so the length, tags and payload don't go out as separate small segments. At most
+    /// `MAX_WRITE_SIZE` bytes are taken.
     fn poll_write_encrypted(
         &mut self,
         ctx: &mut Context<'_>,
-        mut data: &[u8],
+        bufs: &[IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
-        // Data.Len is a 16-bit big-endian integer indicating the length of Data. It must be smaller than 0x3FFF.
-        if data.len() > MAX_PACKET_SIZE {
-            data = &data[..MAX_PACKET_SIZE];
-        }
-
-        ready!(self.poll_write_all_encrypted(ctx, data))?;
-        Poll::Ready(Ok(data.len()))
-    }
-
-    fn poll_write_all_encrypted(
-        &mut self,
-        ctx: &mut Context<'_>,
-        data: &[u8],
-    ) -> Poll<io::Result<()>> {
-        assert!(
-            data.len() <= MAX_PACKET_SIZE,
-            "buffer size too large, AEAD encryption protocol requires buffer to be smaller than 0x3FFF"
-        );
-
         loop {
             match self.steps {
                 EncryptWriteStep::Nothing => {
-                    let output_length = self.buffer_size(data);
-                    let data_length = data.len() as u16;
-
-                    // Send the first packet with nonce
-                    let nonce_length = match self.nonce {
-                        Some(ref n) => n.len(),
-                        None => 0,
-                    };
+                    if bufs.iter().all(|data| data.is_empty()) {
+                        return Poll::Ready(Ok(0));
+                    }
 
-                    let mut buf = BytesMut::with_capacity(nonce_length + output_length);
+                    let mut buf = buffer_pool::take();
 
-                    // Put nonce first
+                    // Send the first packet with nonce
                     if let Some(n) = self.nonce.take() {
                         buf.extend(n);
                     }
 
-                    let mut data_len_buf = [0u8; 2];
-                    BigEndian::write_u16(&mut data_len_buf, data_length);
-
-                    unsafe {
-                        let b = slice::from_raw_parts_mut(
-                            buf.bytes_mut().as_mut_ptr() as *mut u8,
-                            output_length,
-                        );
-
-                        let output_length_size = 2 + self.tag_size;
-                        self.cipher
-                            .encrypt(&data_len_buf, &mut b[..output_length_size]);
-                        self.cipher
-                            .encrypt(data, &mut b[output_length_size..output_length]);
-
-                        buf.advance_mut(output_length);
+                    let mut len = 0;
+                    'bufs: for data in bufs {
+                        for chunk in data.chunks(MAX_PACKET_SIZE) {
+                            let chunk = &chunk[..cmp::min(chunk.len(), MAX_WRITE_SIZE - len)];
+                            self.encrypt_chunk(chunk, &mut buf);
+                            len += chunk.len();
+                            if len == MAX_WRITE_SIZE {
+                                break 'bufs;
+                            }
+                        }
                     }
 
-                    self.steps = EncryptWriteStep::Writing(buf, 0);
+                    self.steps = EncryptWriteStep::Writing(buf, 0, len);
                 }
-                EncryptWriteStep::Writing(ref mut buf, ref mut pos) => {
+                EncryptWriteStep::Writing(ref mut buf, ref mut pos, len) => {
                     while *pos < buf.len() {
                         let n = ready!(Pin::new(&mut self.conn).poll_write(ctx, &buf[*pos..]))?;
                         if n == 0 {
@@ -310,13 +331,43 @@ impl<T: Read + Write + Unpin> EncryptedWriter<T> {
                         *pos += n;
                     }
 
-                    self.steps = EncryptWriteStep::Nothing;
-                    return Poll::Ready(Ok(()));
+                    if let EncryptWriteStep::Writing(buf, ..) =
+                        mem::replace(&mut self.steps, EncryptWriteStep::Nothing)
+                    {
+                        buffer_pool::give_back(buf);
+                    }
+                    return Poll::Ready(Ok(len));
                 }
             }
         }
     }
 
+    /// Append the encrypted length and `data` with their tags to `buf`.
+    fn encrypt_chunk(&mut self, data: &[u8], buf: &mut BytesMut) {
+        // Data.Len is a 16-bit big-endian integer indicating the length of Data. It must be smaller than 0x3FFF.
+        assert!(
+            data.len() <= MAX_PACKET_SIZE,
+            "buffer size too large, AEAD encryption protocol requires buffer to be smaller than 0x3FFF"
+        );
+        let output_length = self.buffer_size(data);
+        let mut data_len_buf = [0u8; 2];
+        BigEndian::write_u16(&mut data_len_buf, data.len() as u16);
+
+        buf.reserve(output_length);
+        unsafe {
+            let b =
+                slice::from_raw_parts_mut(buf.bytes_mut().as_mut_ptr() as *mut u8, output_length);
+
+            let output_length_size = 2 + self.tag_size;
+            self.cipher
+                .encrypt(&data_len_buf, &mut b[..output_length_size]);
+            self.cipher
+                .encrypt(data, &mut b[output_length_size..output_length]);
+
+            buf.advance_mut(output_length);
+        }
+    }
+
     fn buffer_size(&self, data: &[u8]) -> usize {
         2 + self.tag_size // len and len_tag
             + data.len() + self.tag_size // data and data_tag
@@ -329,7 +380,15 @@ impl<T: Read + Write + Unpin> Write for EncryptedWriter<T> {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize>> {
-        (&mut *self).poll_write_encrypted(cx, buf)
+        (&mut *self).poll_write_encrypted(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        (&mut *self).poll_write_encrypted(cx, bufs)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
@@ -344,11 +403,14 @@ impl<T: Read + Write + Unpin> Write for EncryptedWriter<T> {
 #[cfg(test)]
 mod tests {
     use super::{DecryptedReader, EncryptedWriter};
-    use async_std::io::Cursor;
+    use async_std::io::{BufRead, Cursor};
     use async_std::prelude::*;
     use async_std::task::block_on;
     use bytes::Bytes;
-    use crypto::CipherType;
+    use crypto::{CipherType, SecretKey};
+    use futures_util::future::poll_fn;
+    use std::io::IoSlice;
+    use std::pin::Pin;
 
     #[test]
     fn test_write() {
@@ -367,6 +429,48 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_write_vectored() {
+        block_on(async move {
+            let method = CipherType::ChaCha20IetfPoly1305;
+            let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+            let nonce = method.gen_salt();
+            let mut buf = Cursor::new(Vec::new());
+            let mut writer = EncryptedWriter::new(&mut buf, method, &key, nonce.clone());
+            let bufs = [
+                IoSlice::new(b"hello"),
+                IoSlice::new(b""),
+                IoSlice::new(b" world"),
+            ];
+            assert_eq!(writer.write_vectored(&bufs).await.unwrap(), 11);
+            let output = buf.get_ref()[nonce.len()..].to_vec();
+            let mut reader = DecryptedReader::new(Cursor::new(output), method, &key, &nonce);
+            let mut data = vec![];
+            reader.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data.as_slice(), b"hello world");
+        });
+    }
+
+    #[test]
+    fn test_xchacha20_ietf_poly1305() {
+        block_on(async move {
+            // 24 bytes nonces, incremented per chunk like the 12 bytes ones.
+            let method = CipherType::XChaCha20IetfPoly1305;
+            assert_eq!(method.iv_size(), 24);
+            let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+            let nonce = method.gen_salt();
+            let mut output = Cursor::new(Vec::new());
+            let mut writer = EncryptedWriter::new(&mut output, method, &key, nonce.clone());
+            writer.write_all(b"hello").await.unwrap();
+            writer.write_all(b" world").await.unwrap();
+            let output = output.get_ref()[nonce.len()..].to_vec();
+            let mut reader = DecryptedReader::new(Cursor::new(output), method, &key, &nonce);
+            let mut data = vec![];
+            reader.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data.as_slice(), b"hello world");
+        });
+    }
+
     #[test]
     fn test_read() {
         block_on(async move {
@@ -383,6 +487,42 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_read_into_small_and_large_buffers() {
+        block_on(async move {
+            let method = CipherType::ChaCha20IetfPoly1305;
+            let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+            let nonce = method.gen_salt();
+            let mut output = Cursor::new(Vec::new());
+            let mut writer = EncryptedWriter::new(&mut output, method, &key, nonce.clone());
+            writer.write_all(b"hello world").await.unwrap();
+            writer.write_all(b"again").await.unwrap();
+            let output = output.get_ref()[nonce.len()..].to_vec();
+            let mut reader = DecryptedReader::new(Cursor::new(output), method, &key, &nonce);
+
+            // A short read leaves the rest of the chunk buffered.
+            let mut buf = [0u8; 5];
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 5);
+            assert_eq!(&buf, b"hello");
+            let buffered = poll_fn(|cx| {
+                Pin::new(&mut reader)
+                    .poll_fill_buf(cx)
+                    .map_ok(|buffered| buffered.to_vec())
+            })
+            .await
+            .unwrap();
+            assert_eq!(buffered, b" world");
+            Pin::new(&mut reader).consume(1);
+            let mut buf = [0u8; 64];
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 5);
+            assert_eq!(&buf[..5], b"world");
+            // The next chunk is decrypted straight into the large buffer.
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 5);
+            assert_eq!(&buf[..5], b"again");
+            assert_eq!(reader.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
     #[test]
     fn test_encrypt_decrypt() {
         let method = CipherType::ChaCha20IetfPoly1305;
@@ -394,7 +534,7 @@ mod tests {
         assert_eq!(decrypt(method, key, nonce, &output).as_slice(), data);
     }
 
-    fn encrypt(method: CipherType, key: Bytes, nonce: Bytes, data: &[u8]) -> Vec<u8> {
+    fn encrypt(method: CipherType, key: SecretKey, nonce: Bytes, data: &[u8]) -> Vec<u8> {
         let data_len = data.len();
         let tag_size = method.tag_size();
         let buf_size = 2 + tag_size // len and len_tag
@@ -416,7 +556,7 @@ mod tests {
         right_buf
     }
 
-    fn decrypt(method: CipherType, key: Bytes, nonce: Bytes, data: &[u8]) -> Vec<u8> {
+    fn decrypt(method: CipherType, key: SecretKey, nonce: Bytes, data: &[u8]) -> Vec<u8> {
         let tag_size = method.tag_size();
 
         let mut right_buf = vec![0; 1024];
@@ -0,0 +1,129 @@
+//! Tcp Fast Open for connections to the server. With `TCP_FASTOPEN_CONNECT` the connect is
+//! deferred until the first write, so the salt and the address written right after connecting go
+//! out in the SYN once the kernel has a cookie of the server.
+
+use async_std::net::TcpStream;
+use std::io::Result;
+use std::net::SocketAddr;
+
+#[cfg(target_os = "linux")]
+pub async fn connect(addr: SocketAddr) -> Result<TcpStream> {
+    use std::io::Error;
+    use std::mem;
+    use std::os::unix::io::FromRawFd;
+    use tracing::trace;
+
+    /// Not defined by libc yet, available since linux 4.11.
+    const TCP_FASTOPEN_CONNECT: libc::c_int = 30;
+
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe {
+        libc::socket(
+            domain,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // Owned from here, so the socket is closed on errors.
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::IPPROTO_TCP,
+            TCP_FASTOPEN_CONNECT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        // Older kernels, connect as usual.
+        trace!(e = ?Error::last_os_error(), "enable tcp fast open");
+    }
+
+    let (storage, len) = sockaddr(addr);
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &storage as *const libc::sockaddr_storage as *const libc::sockaddr,
+            len,
+        )
+    };
+    if ret < 0 {
+        let e = Error::last_os_error();
+        // Without a cookie the SYN is sent now, the first write waits for the handshake.
+        if e.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(e);
+        }
+    }
+    Ok(TcpStream::from(stream))
+}
+
+#[cfg(target_os = "linux")]
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    use std::mem;
+
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(addr.ip().octets()),
+            };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Tcp Fast Open is only supported on linux, other systems connect as usual.
+#[cfg(not(target_os = "linux"))]
+pub async fn connect(addr: SocketAddr) -> Result<TcpStream> {
+    TcpStream::connect(addr).await
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use async_std::prelude::*;
+    use async_std::task::{block_on, spawn};
+
+    #[test]
+    fn test_connect() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let h = spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                buf
+            });
+            let mut stream = connect(addr).await.unwrap();
+            stream.write_all(b"hello").await.unwrap();
+            assert_eq!(&h.await, b"hello");
+        });
+    }
+}
@@ -1,18 +1,18 @@
 //! Stream protocol implementation
 
 use std::{
-    cmp, io,
+    cmp, io, mem,
     pin::Pin,
     task::{Context, Poll},
 };
 
-use async_std::io::{Read, Write};
+use async_std::io::{BufRead, Read, Write};
 use bytes::{BufMut, Bytes, BytesMut};
 use crypto::{new_stream, BoxStreamCipher, CipherType, CryptoMode};
 use futures_util::ready;
-use std::io::Result;
+use std::io::{IoSlice, Result};
 
-use crate::BUFFER_SIZE;
+use crate::{buffer_pool, BUFFER_SIZE, MAX_WRITE_SIZE};
 
 const DUMMY_BUFFER: [u8; BUFFER_SIZE] = [0u8; BUFFER_SIZE];
 
@@ -45,11 +45,18 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
         ctx: &mut Context<'_>,
         dst: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        while self.pos >= self.buffer.len() {
-            if self.got_final {
-                return Poll::Ready(Ok(0));
-            }
+        ready!(self.poll_fill_buffer(ctx))?;
 
+        let remaining_len = self.buffer.len() - self.pos;
+        let n = cmp::min(dst.len(), remaining_len);
+        (&mut dst[..n]).copy_from_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+
+    /// Decrypt more data into `buffer`, if everything decrypted before has been consumed.
+    fn poll_fill_buffer(&mut self, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos >= self.buffer.len() && !self.got_final {
             let n = ready!(Pin::new(&mut self.conn).poll_read(ctx, &mut self.incoming_buffer))?;
 
             // Reset pointers
@@ -69,12 +76,7 @@ impl<T: Read + Write + Unpin> DecryptedReader<T> {
                 self.cipher.update(data, &mut self.buffer)?;
             }
         }
-
-        let remaining_len = self.buffer.len() - self.pos;
-        let n = cmp::min(dst.len(), remaining_len);
-        (&mut dst[..n]).copy_from_slice(&self.buffer[self.pos..self.pos + n]);
-        self.pos += n;
-        Poll::Ready(Ok(n))
+        Poll::Ready(Ok(()))
     }
 
     fn buffer_size(&self, data: &[u8]) -> usize {
@@ -92,9 +94,22 @@ impl<T: Read + Write + Unpin> Read for DecryptedReader<T> {
     }
 }
 
+impl<T: Read + Write + Unpin> BufRead for DecryptedReader<T> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<&[u8]>> {
+        let this = self.get_mut();
+        ready!(this.poll_fill_buffer(cx))?;
+        Poll::Ready(Ok(&this.buffer[this.pos..]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.buffer.len());
+    }
+}
+
 enum EncryptWriteStep {
     Nothing,
-    Writing(BytesMut, usize),
+    /// (Encrypted data, written bytes, length of the plaintext)
+    Writing(BytesMut, usize, usize),
 }
 
 /// Writer wrapper that will encrypt data automatically
@@ -116,43 +131,42 @@ impl<T: Read + Write + Unpin> EncryptedWriter<T> {
         }
     }
 
+    /// Encrypt `bufs` and send them by a single write, at most `MAX_WRITE_SIZE` bytes are taken.
     fn poll_write_encrypted(
         &mut self,
         ctx: &mut Context<'_>,
-        data: &[u8],
+        bufs: &[IoSlice<'_>],
     ) -> Poll<io::Result<usize>> {
-        ready!(self.poll_write_all_encrypted(ctx, data))?;
-        Poll::Ready(Ok(data.len()))
-    }
-
-    fn poll_write_all_encrypted(
-        &mut self,
-        ctx: &mut Context<'_>,
-        data: &[u8],
-    ) -> Poll<io::Result<()>> {
         // FIXME: How about finalize?
 
         loop {
             match self.steps {
                 EncryptWriteStep::Nothing => {
-                    // Send the first packet with iv
-                    let iv_length = match self.iv {
-                        Some(ref i) => i.len(),
-                        None => 0,
-                    };
+                    if bufs.iter().all(|data| data.is_empty()) {
+                        return Poll::Ready(Ok(0));
+                    }
 
-                    let mut buf = BytesMut::with_capacity(iv_length + self.buffer_size(data));
+                    let mut buf = buffer_pool::take();
 
                     // Put iv first
                     if let Some(i) = self.iv.take() {
                         buf.extend(i);
                     }
 
-                    self.cipher_update(data, &mut buf)?;
+                    let mut len = 0;
+                    for data in bufs {
+                        let data = &data[..cmp::min(data.len(), MAX_WRITE_SIZE - len)];
+                        buf.reserve(self.buffer_size(data));
+                        self.cipher_update(data, &mut buf)?;
+                        len += data.len();
+                        if len == MAX_WRITE_SIZE {
+                            break;
+                        }
+                    }
 
-                    self.steps = EncryptWriteStep::Writing(buf, 0);
+                    self.steps = EncryptWriteStep::Writing(buf, 0, len);
                 }
-                EncryptWriteStep::Writing(ref mut buf, ref mut pos) => {
+                EncryptWriteStep::Writing(ref mut buf, ref mut pos, len) => {
                     while *pos < buf.len() {
                         let n = ready!(Pin::new(&mut self.conn).poll_write(ctx, &buf[*pos..]))?;
                         if n == 0 {
@@ -162,8 +176,12 @@ impl<T: Read + Write + Unpin> EncryptedWriter<T> {
                         *pos += n;
                     }
 
-                    self.steps = EncryptWriteStep::Nothing;
-                    return Poll::Ready(Ok(()));
+                    if let EncryptWriteStep::Writing(buf, ..) =
+                        mem::replace(&mut self.steps, EncryptWriteStep::Nothing)
+                    {
+                        buffer_pool::give_back(buf);
+                    }
+                    return Poll::Ready(Ok(len));
                 }
             }
         }
@@ -189,7 +207,15 @@ impl<T: Read + Write + Unpin> Write for EncryptedWriter<T> {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize>> {
-        (&mut *self).poll_write_encrypted(cx, buf)
+        (&mut *self).poll_write_encrypted(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        (&mut *self).poll_write_encrypted(cx, bufs)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
@@ -208,7 +234,8 @@ mod tests {
     use async_std::prelude::*;
     use async_std::task::block_on;
     use bytes::Bytes;
-    use crypto::{CipherType, CryptoMode};
+    use crypto::{CipherType, CryptoMode, SecretKey};
+    use std::io::IoSlice;
 
     #[test]
     fn test_write() {
@@ -227,6 +254,28 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_write_vectored() {
+        block_on(async move {
+            let method = CipherType::ChaCha20Ietf;
+            let key = method.bytes_to_key(b"GwEU01uXWm0Pp6t08");
+            let nonce = method.gen_init_vec();
+            let mut buf = Cursor::new(Vec::new());
+            let mut writer = EncryptedWriter::new(&mut buf, method, &key, nonce.clone());
+            let bufs = [
+                IoSlice::new(b"hello"),
+                IoSlice::new(b""),
+                IoSlice::new(b" world"),
+            ];
+            assert_eq!(writer.write_vectored(&bufs).await.unwrap(), 11);
+            let output = buf.get_ref()[nonce.len()..].to_vec();
+            let mut reader = DecryptedReader::new(Cursor::new(output), method, &key, &nonce);
+            let mut data = vec![];
+            reader.read_to_end(&mut data).await.unwrap();
+            assert_eq!(data.as_slice(), b"hello world");
+        });
+    }
+
     #[test]
     fn test_read() {
         block_on(async move {
@@ -254,14 +303,14 @@ mod tests {
         assert_eq!(decrypt(method, key, nonce, &output).as_slice(), data);
     }
 
-    fn encrypt(method: CipherType, key: Bytes, nonce: Bytes, data: &[u8]) -> Vec<u8> {
+    fn encrypt(method: CipherType, key: SecretKey, nonce: Bytes, data: &[u8]) -> Vec<u8> {
         let mut encryptor = crypto::new_stream(method, &key, &nonce, CryptoMode::Encrypt);
         let mut right_buf = Vec::new();
         encryptor.update(data, &mut right_buf).unwrap();
         right_buf
     }
 
-    fn decrypt(method: CipherType, key: Bytes, nonce: Bytes, data: &[u8]) -> Vec<u8> {
+    fn decrypt(method: CipherType, key: SecretKey, nonce: Bytes, data: &[u8]) -> Vec<u8> {
         let mut decryptor = crypto::new_stream(method, &key, &nonce, CryptoMode::Decrypt);
         let buf_size = decryptor.buffer_size(data);
         let mut buf = Vec::with_capacity(buf_size);
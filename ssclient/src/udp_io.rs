@@ -6,14 +6,14 @@ use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
 };
 
-use bytes::{Bytes, BytesMut};
+use bytes::BytesMut;
 use tracing::debug;
 
 use self::crypto_io::{decrypt_payload, encrypt_payload};
 
 use async_std::net::UdpSocket;
 use config::Address;
-use crypto::CipherType;
+use crypto::{CipherType, SecretKey};
 
 pub const MAXIMUM_UDP_PAYLOAD_SIZE: usize = 1500;
 
@@ -21,7 +21,7 @@ pub const MAXIMUM_UDP_PAYLOAD_SIZE: usize = 1500;
 pub struct SSUdpSocket {
     socket: UdpSocket,
     method: CipherType,
-    key: Bytes,
+    key: SecretKey,
 }
 
 impl SSUdpSocket {
@@ -29,7 +29,7 @@ impl SSUdpSocket {
     pub async fn new(
         server_addr: SocketAddr,
         method: CipherType,
-        key: Bytes,
+        key: SecretKey,
     ) -> io::Result<SSUdpSocket> {
         let local_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
         let socket = UdpSocket::bind(local_addr).await?;
@@ -41,7 +41,7 @@ impl SSUdpSocket {
             key,
         })
     }
-    pub fn bind(socket: UdpSocket, method: CipherType, key: Bytes) -> SSUdpSocket {
+    pub fn bind(socket: UdpSocket, method: CipherType, key: SecretKey) -> SSUdpSocket {
         SSUdpSocket {
             socket,
             method,
@@ -1,23 +1,40 @@
+use std::io;
 use std::process::Command;
 use tracing::debug;
 
 pub fn run_cmd(cmd: &str, args: &[&str]) -> String {
-    let output = Command::new(cmd)
-        .args(args)
-        .output()
-        .expect("run cmd failed");
+    check_cmd(cmd, args).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Run a command which has to succeed, the error has the output of a failed command.
+pub fn check_cmd(cmd: &str, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(cmd).args(args).output()?;
     debug!("{} {:?}", cmd, args);
 
     if !output.status.success() {
-        panic!(
-            "{} {}\nstdout: {}\nstderr: {}",
-            cmd,
-            args.join(" "),
-            std::str::from_utf8(&output.stdout).expect("utf8"),
-            std::str::from_utf8(&output.stderr).expect("utf8")
-        );
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "{} {}\nstdout: {}\nstderr: {}",
+                cmd,
+                args.join(" "),
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
     }
-    std::str::from_utf8(&output.stdout)
+    Ok(std::str::from_utf8(&output.stdout)
         .expect("utf8")
-        .to_string()
+        .to_string())
+}
+
+/// Run a command which may fail, eg. to probe for a tool or undo a setting which may be gone.
+/// Returns whether it succeeded.
+pub fn try_run_cmd(cmd: &str, args: &[&str]) -> bool {
+    debug!("{} {:?}", cmd, args);
+    Command::new(cmd)
+        .args(args)
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
 }
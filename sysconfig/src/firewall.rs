@@ -0,0 +1,324 @@
+//! Firewall rules diverting the connections to fake ips to the redir listeners, by nftables or
+//! iptables. The rules are kept in a table or chains of seeker's own, which are deleted as a whole
+//! on exit, or on the next start if seeker crashed.
+
+use crate::command::{check_cmd, try_run_cmd};
+use crate::state::StateFile;
+use std::io;
+use tracing::info;
+
+const FIREWALL_STATE: &str = "firewall";
+/// nftables table, and iptables chains in the `nat` and `mangle` tables.
+const TABLE: &str = "seeker";
+const CHAIN: &str = "SEEKER";
+const MARK_CHAIN: &str = "SEEKER_MARK";
+/// Mark of udp packets to fake ips, routed to the local host by `ROUTE_TABLE` for `TPROXY`.
+const MARK: &str = "0x5ee";
+const ROUTE_TABLE: &str = "1518";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Firewall {
+    Nftables,
+    Iptables,
+}
+
+impl Firewall {
+    /// nftables if `nft` works, otherwise iptables. Where both are installed, iptables is usually
+    /// the nftables compatible one, nftables is used directly then.
+    pub fn detect() -> Option<Firewall> {
+        if try_run_cmd("nft", &["list", "tables"]) {
+            Some(Firewall::Nftables)
+        } else if try_run_cmd("iptables", &["-w", "-t", "nat", "-S"]) {
+            Some(Firewall::Iptables)
+        } else {
+            None
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Firewall::Nftables => "nftables",
+            Firewall::Iptables => "iptables",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Firewall> {
+        match name {
+            "nftables" => Some(Firewall::Nftables),
+            "iptables" => Some(Firewall::Iptables),
+            _ => None,
+        }
+    }
+}
+
+/// Tcp connections to `fake_cidr` are redirected to `tcp_port`, udp packets are diverted to
+/// `udp_port` if set. Both from the LAN and the host itself. The rules are removed on drop.
+pub struct RedirRules {
+    firewall: Firewall,
+    state: StateFile,
+}
+
+impl RedirRules {
+    /// Rules set up before a failed command are removed before returning the error.
+    pub fn new(
+        firewall: Firewall,
+        fake_cidr: &str,
+        tcp_port: u16,
+        udp_port: Option<u16>,
+    ) -> io::Result<Self> {
+        info!(?firewall, "setup redir rules");
+        let mut state = StateFile::new(FIREWALL_STATE);
+        state.save(&[firewall.name().to_string()]);
+        for (cmd, args) in setup_commands(firewall, fake_cidr, tcp_port, udp_port) {
+            let args: Vec<&str> = args.iter().map(|s| s.as_str()).collect();
+            if let Err(e) = check_cmd(cmd, &args) {
+                remove_rules(firewall);
+                state.remove();
+                return Err(e);
+            }
+        }
+        Ok(RedirRules { firewall, state })
+    }
+}
+
+impl Drop for RedirRules {
+    fn drop(&mut self) {
+        info!(firewall = ?self.firewall, "remove redir rules");
+        remove_rules(self.firewall);
+        self.state.remove();
+    }
+}
+
+/// Remove the redir rules left by a seeker which didn't exit cleanly.
+pub fn restore_crashed_rules() {
+    let state = StateFile::new(FIREWALL_STATE);
+    if let Some(values) = state.load_crashed() {
+        if let Some(firewall) = values.first().and_then(|v| Firewall::from_name(v)) {
+            info!(?firewall, "Remove redir rules left by a crashed seeker");
+            remove_rules(firewall);
+        }
+        state.remove();
+    }
+}
+
+fn remove_rules(firewall: Firewall) {
+    // Some of them may be gone or never set up.
+    for (cmd, args) in remove_commands(firewall) {
+        let _ = try_run_cmd(cmd, &args);
+    }
+}
+
+fn setup_commands(
+    firewall: Firewall,
+    fake_cidr: &str,
+    tcp_port: u16,
+    udp_port: Option<u16>,
+) -> Vec<(&'static str, Vec<String>)> {
+    let mut commands = vec![];
+    match firewall {
+        Firewall::Nftables => {
+            let mut nft = |command: String| commands.push(("nft", vec![command]));
+            nft(format!("add table ip {}", TABLE));
+            nft(format!(
+                "add chain ip {} prerouting {{ type nat hook prerouting priority -100 ; }}",
+                TABLE
+            ));
+            nft(format!(
+                "add chain ip {} output {{ type nat hook output priority -100 ; }}",
+                TABLE
+            ));
+            for chain in &["prerouting", "output"] {
+                nft(format!(
+                    "add rule ip {} {} ip daddr {} meta l4proto tcp redirect to :{}",
+                    TABLE, chain, fake_cidr, tcp_port
+                ));
+            }
+            if let Some(udp_port) = udp_port {
+                nft(format!(
+                    "add chain ip {} divert {{ type filter hook prerouting priority -150 ; }}",
+                    TABLE
+                ));
+                nft(format!(
+                    "add rule ip {} divert ip daddr {} meta l4proto udp tproxy to :{} meta mark set {} accept",
+                    TABLE, fake_cidr, udp_port, MARK
+                ));
+                nft(format!(
+                    "add chain ip {} mark {{ type route hook output priority -150 ; }}",
+                    TABLE
+                ));
+                nft(format!(
+                    "add rule ip {} mark ip daddr {} meta l4proto udp meta mark set {}",
+                    TABLE, fake_cidr, MARK
+                ));
+            }
+        }
+        Firewall::Iptables => {
+            let mut iptables = |args: &[&str]| {
+                let mut command = vec!["-w".to_string()];
+                command.extend(args.iter().map(|s| s.to_string()));
+                commands.push(("iptables", command));
+            };
+            let tcp_port = tcp_port.to_string();
+            iptables(&["-t", "nat", "-N", CHAIN]);
+            iptables(&[
+                "-t",
+                "nat",
+                "-A",
+                CHAIN,
+                "-d",
+                fake_cidr,
+                "-p",
+                "tcp",
+                "-j",
+                "REDIRECT",
+                "--to-ports",
+                &tcp_port,
+            ]);
+            iptables(&["-t", "nat", "-A", "PREROUTING", "-j", CHAIN]);
+            iptables(&["-t", "nat", "-A", "OUTPUT", "-j", CHAIN]);
+            if let Some(udp_port) = udp_port {
+                let udp_port = udp_port.to_string();
+                iptables(&["-t", "mangle", "-N", CHAIN]);
+                iptables(&[
+                    "-t",
+                    "mangle",
+                    "-A",
+                    CHAIN,
+                    "-d",
+                    fake_cidr,
+                    "-p",
+                    "udp",
+                    "-j",
+                    "TPROXY",
+                    "--on-port",
+                    &udp_port,
+                    "--tproxy-mark",
+                    MARK,
+                ]);
+                iptables(&["-t", "mangle", "-A", "PREROUTING", "-j", CHAIN]);
+                iptables(&["-t", "mangle", "-N", MARK_CHAIN]);
+                iptables(&[
+                    "-t",
+                    "mangle",
+                    "-A",
+                    MARK_CHAIN,
+                    "-d",
+                    fake_cidr,
+                    "-p",
+                    "udp",
+                    "-j",
+                    "MARK",
+                    "--set-mark",
+                    MARK,
+                ]);
+                iptables(&["-t", "mangle", "-A", "OUTPUT", "-j", MARK_CHAIN]);
+            }
+        }
+    }
+    // Marked packets are delivered locally, to the transparent udp socket.
+    if udp_port.is_some() {
+        let ip = |args: &[&str]| ("ip", args.iter().map(|s| s.to_string()).collect());
+        commands.push(ip(&["rule", "add", "fwmark", MARK, "lookup", ROUTE_TABLE]));
+        commands.push(ip(&[
+            "route",
+            "add",
+            "local",
+            "default",
+            "dev",
+            "lo",
+            "table",
+            ROUTE_TABLE,
+        ]));
+    }
+    commands
+}
+
+fn remove_commands(firewall: Firewall) -> Vec<(&'static str, Vec<&'static str>)> {
+    let mut commands = match firewall {
+        Firewall::Nftables => vec![("nft", vec!["delete", "table", "ip", TABLE])],
+        Firewall::Iptables => vec![
+            (
+                "iptables",
+                vec!["-w", "-t", "nat", "-D", "PREROUTING", "-j", CHAIN],
+            ),
+            (
+                "iptables",
+                vec!["-w", "-t", "nat", "-D", "OUTPUT", "-j", CHAIN],
+            ),
+            ("iptables", vec!["-w", "-t", "nat", "-F", CHAIN]),
+            ("iptables", vec!["-w", "-t", "nat", "-X", CHAIN]),
+            (
+                "iptables",
+                vec!["-w", "-t", "mangle", "-D", "PREROUTING", "-j", CHAIN],
+            ),
+            ("iptables", vec!["-w", "-t", "mangle", "-F", CHAIN]),
+            ("iptables", vec!["-w", "-t", "mangle", "-X", CHAIN]),
+            (
+                "iptables",
+                vec!["-w", "-t", "mangle", "-D", "OUTPUT", "-j", MARK_CHAIN],
+            ),
+            ("iptables", vec!["-w", "-t", "mangle", "-F", MARK_CHAIN]),
+            ("iptables", vec!["-w", "-t", "mangle", "-X", MARK_CHAIN]),
+        ],
+    };
+    commands.push((
+        "ip",
+        vec!["rule", "del", "fwmark", MARK, "lookup", ROUTE_TABLE],
+    ));
+    commands.push(("ip", vec!["route", "flush", "table", ROUTE_TABLE]));
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joined(commands: Vec<(&str, Vec<String>)>) -> Vec<String> {
+        commands
+            .into_iter()
+            .map(|(cmd, args)| format!("{} {}", cmd, args.join(" ")))
+            .collect()
+    }
+
+    #[test]
+    fn test_setup_commands() {
+        let nft = joined(setup_commands(
+            Firewall::Nftables,
+            "11.0.0.0/16",
+            1300,
+            None,
+        ));
+        assert_eq!(
+            nft,
+            vec![
+                "nft add table ip seeker",
+                "nft add chain ip seeker prerouting { type nat hook prerouting priority -100 ; }",
+                "nft add chain ip seeker output { type nat hook output priority -100 ; }",
+                "nft add rule ip seeker prerouting ip daddr 11.0.0.0/16 meta l4proto tcp redirect to :1300",
+                "nft add rule ip seeker output ip daddr 11.0.0.0/16 meta l4proto tcp redirect to :1300",
+            ]
+        );
+
+        let iptables = joined(setup_commands(
+            Firewall::Iptables,
+            "11.0.0.0/16",
+            1300,
+            Some(1301),
+        ));
+        assert_eq!(
+            &iptables[1],
+            "iptables -w -t nat -A SEEKER -d 11.0.0.0/16 -p tcp -j REDIRECT --to-ports 1300"
+        );
+        assert_eq!(
+            &iptables[5],
+            "iptables -w -t mangle -A SEEKER -d 11.0.0.0/16 -p udp -j TPROXY --on-port 1301 --tproxy-mark 0x5ee"
+        );
+        assert_eq!(
+            &iptables[iptables.len() - 2..],
+            &[
+                "ip rule add fwmark 0x5ee lookup 1518",
+                "ip route add local default dev lo table 1518",
+            ]
+        );
+    }
+}
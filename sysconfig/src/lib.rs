@@ -1,12 +1,23 @@
 mod command;
+#[cfg(target_os = "linux")]
+mod firewall;
 mod net;
+#[cfg(target_os = "linux")]
+mod privilege;
 #[cfg(target_arch = "x86_64")]
 mod proc;
+mod state;
 mod ulimit;
 
-pub use net::{setup_ip, DNSSetup, IpForward};
+#[cfg(target_os = "linux")]
+pub use firewall::{Firewall, RedirRules};
+pub use net::{restore_crashed, set_mtu, setup_ip, setup_ipv6, DNSSetup, IpForward};
+#[cfg(target_os = "linux")]
+pub use privilege::drop_privileges;
 #[cfg(target_arch = "x86_64")]
-pub use proc::sys::{list_system_proc_socks, list_user_proc_socks};
+pub use proc::sys::{
+    find_process_name_by_local_addr, list_system_proc_socks, list_user_proc_socks,
+};
 #[cfg(target_arch = "x86_64")]
 pub use proc::SocketInfo;
 pub use ulimit::{get_rlimit_no_file, set_rlimit_no_file};
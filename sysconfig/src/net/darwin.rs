@@ -1,7 +1,10 @@
 use crate::command::run_cmd;
+use crate::state::StateFile;
 use std::net::IpAddr;
 use tracing::info;
 
+const DNS_STATE: &str = "dns";
+
 pub struct DNSSetup {
     primary_network: String,
     original_dns: Vec<String>,
@@ -20,6 +23,11 @@ impl DNSSetup {
             .collect::<Vec<_>>();
 
         info!("Original DNS is {:?}", &original_dns);
+        // The network service goes first.
+        let mut state = vec![network.clone()];
+        state.extend(original_dns.iter().cloned());
+        StateFile::new(DNS_STATE).save(&state);
+
         if !original_dns.is_empty() {
             let mut args = vec!["-setdnsservers", &network, "127.0.0.1"];
             for dns in &original_dns {
@@ -43,25 +51,53 @@ impl DNSSetup {
 
 impl Drop for DNSSetup {
     fn drop(&mut self) {
-        let mut args = vec!["-setdnsservers", &self.primary_network];
-        if self.original_dns.is_empty() {
-            args.push("empty");
-        } else {
-            for dns in &self.original_dns {
-                args.push(dns);
-            }
-        };
         info!("Restore original DNS: {:?}", self.original_dns);
+        restore_dns(&self.primary_network, &self.original_dns);
+        StateFile::new(DNS_STATE).remove();
+    }
+}
 
-        let _ = run_cmd("networksetup", &args);
+/// Restore the dns changed by a seeker which didn't exit cleanly.
+pub fn restore_crashed_dns() {
+    let state = StateFile::new(DNS_STATE);
+    if let Some(values) = state.load_crashed() {
+        if let Some((network, original_dns)) = values.split_first() {
+            info!(
+                "Restore original DNS of {} left by a crashed seeker: {:?}",
+                network, original_dns
+            );
+            restore_dns(network, original_dns);
+        }
+        state.remove();
     }
 }
 
+fn restore_dns(network: &str, original_dns: &[String]) {
+    let mut args = vec!["-setdnsservers", network];
+    if original_dns.is_empty() {
+        args.push("empty");
+    } else {
+        for dns in original_dns {
+            args.push(dns);
+        }
+    };
+    let _ = run_cmd("networksetup", &args);
+}
+
 pub fn setup_ip(tun_name: &str, ip: &str, cidr: &str) {
     let _ = run_cmd("ifconfig", &[tun_name, ip, ip]);
     let _ = run_cmd("route", &["add", cidr, ip]);
 }
 
+pub fn set_mtu(tun_name: &str, mtu: u16) {
+    let _ = run_cmd("ifconfig", &[tun_name, "mtu", &mtu.to_string()]);
+}
+
+pub fn setup_ipv6(tun_name: &str, ip: &str, cidr: &str) {
+    let _ = run_cmd("ifconfig", &[tun_name, "inet6", ip, "prefixlen", "128"]);
+    let _ = run_cmd("route", &["add", "-inet6", cidr, "-interface", tun_name]);
+}
+
 fn get_primary_network() -> String {
     let route_ret = run_cmd("route", &["-n", "get", "0.0.0.0"]);
     let device = route_ret
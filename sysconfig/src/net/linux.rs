@@ -1,14 +1,20 @@
 use crate::command::run_cmd;
-use std::fs::OpenOptions;
+use crate::state::StateFile;
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::IpAddr;
 use tracing::info;
 
 pub struct DNSSetup {
     original_dns: Vec<String>,
+    /// Kept open to restore the file after dropping privileges.
+    resolv: File,
+    state: StateFile,
 }
 
 const RESOLV_PATH: &str = "/etc/resolv.conf";
+const DNS_STATE: &str = "dns";
+
 impl DNSSetup {
     pub fn new(dns: String) -> Self {
         info!("setup dns");
@@ -23,6 +29,8 @@ impl DNSSetup {
         let content = std::str::from_utf8(&buf).unwrap();
         let original_dns = get_original_dns(content, &dns);
         info!("original dns: {:?}", &original_dns);
+        let mut state = StateFile::new(DNS_STATE);
+        state.save(&original_dns);
 
         resolv.set_len(0).unwrap();
         resolv.seek(SeekFrom::Start(0)).unwrap();
@@ -30,38 +38,78 @@ impl DNSSetup {
             .write_all(generate_resolve_file(&["127.0.0.1", &dns]).as_slice())
             .unwrap();
 
-        DNSSetup { original_dns }
+        DNSSetup {
+            original_dns,
+            resolv,
+            state,
+        }
     }
 }
 
 impl Drop for DNSSetup {
     fn drop(&mut self) {
         info!("Restore original DNS: {:?}", self.original_dns);
-        let mut resolv = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(RESOLV_PATH)
-            .unwrap();
-        resolv
-            .write_all(
-                generate_resolve_file(
-                    self.original_dns
-                        .iter()
-                        .map(|s| s.as_str())
-                        .collect::<Vec<_>>()
-                        .as_slice(),
-                )
-                .as_slice(),
-            )
-            .unwrap();
+        let _ = self.resolv.set_len(0);
+        let _ = self.resolv.seek(SeekFrom::Start(0));
+        write_dns(&mut self.resolv, &self.original_dns);
+        self.state.remove();
     }
 }
 
+/// Restore the dns changed by a seeker which didn't exit cleanly.
+pub fn restore_crashed_dns() {
+    let state = StateFile::new(DNS_STATE);
+    if let Some(original_dns) = state.load_crashed() {
+        info!(
+            "Restore original DNS left by a crashed seeker: {:?}",
+            original_dns
+        );
+        restore_dns(&original_dns);
+        state.remove();
+    }
+}
+
+fn restore_dns(original_dns: &[String]) {
+    let mut resolv = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(RESOLV_PATH)
+        .unwrap();
+    write_dns(&mut resolv, original_dns);
+}
+
+fn write_dns(resolv: &mut File, original_dns: &[String]) {
+    resolv
+        .write_all(
+            generate_resolve_file(
+                original_dns
+                    .iter()
+                    .map(|s| s.as_str())
+                    .collect::<Vec<_>>()
+                    .as_slice(),
+            )
+            .as_slice(),
+        )
+        .unwrap();
+}
+
 pub fn setup_ip(tun_name: &str, ip: &str, _cidr: &str) {
     let _ = run_cmd("ip", &["addr", "add", ip, "dev", tun_name]);
     let _ = run_cmd("ip", &["link", "set", tun_name, "up"]);
 }
 
+pub fn set_mtu(tun_name: &str, mtu: u16) {
+    let _ = run_cmd(
+        "ip",
+        &["link", "set", "dev", tun_name, "mtu", &mtu.to_string()],
+    );
+}
+
+pub fn setup_ipv6(tun_name: &str, ip: &str, cidr: &str) {
+    let _ = run_cmd("ip", &["-6", "addr", "add", ip, "dev", tun_name, "nodad"]);
+    let _ = run_cmd("ip", &["-6", "route", "add", cidr, "dev", tun_name]);
+}
+
 fn get_original_dns(content: &str, dns: &str) -> Vec<String> {
     let mut dns_list: Vec<_> = content
         .lines()
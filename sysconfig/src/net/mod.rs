@@ -1,4 +1,6 @@
 use crate::command::run_cmd;
+use crate::state::StateFile;
+use tracing::info;
 
 #[cfg(any(
     target_os = "macos",
@@ -10,8 +12,11 @@ const IP_FORWARDING_KEY: &str = "net.inet.ip.forwarding";
 #[cfg(target_os = "linux")]
 const IP_FORWARDING_KEY: &str = "net.ipv4.ip_forward";
 
+const IP_FORWARD_STATE: &str = "ip_forward";
+
 pub struct IpForward {
     original_option: usize,
+    state: StateFile,
 }
 
 impl IpForward {
@@ -19,22 +24,43 @@ impl IpForward {
     pub fn new() -> Self {
         let output = run_cmd("sysctl", &["-n", IP_FORWARDING_KEY]);
         let option = output.trim().parse::<usize>().unwrap();
+        let mut state = StateFile::new(IP_FORWARD_STATE);
+        state.save(&[option.to_string()]);
         let _ = run_cmd("sysctl", &["-w", &format!("{}={}", IP_FORWARDING_KEY, 1)]);
         IpForward {
             original_option: option,
+            state,
         }
     }
 }
 
 impl Drop for IpForward {
     fn drop(&mut self) {
-        let _ = run_cmd(
-            "sysctl",
-            &[
-                "-w",
-                &format!("{}={}", IP_FORWARDING_KEY, self.original_option),
-            ],
-        );
+        restore_ip_forward(self.original_option);
+        self.state.remove();
+    }
+}
+
+fn restore_ip_forward(option: usize) {
+    let _ = run_cmd(
+        "sysctl",
+        &["-w", &format!("{}={}", IP_FORWARDING_KEY, option)],
+    );
+}
+
+/// Restore the system settings changed by a seeker which didn't exit cleanly, before they are
+/// changed again.
+pub fn restore_crashed() {
+    sys::restore_crashed_dns();
+    #[cfg(target_os = "linux")]
+    crate::firewall::restore_crashed_rules();
+    let state = StateFile::new(IP_FORWARD_STATE);
+    if let Some(values) = state.load_crashed() {
+        if let Some(option) = values.first().and_then(|v| v.parse::<usize>().ok()) {
+            info!("Restore ip forwarding left by a crashed seeker: {}", option);
+            restore_ip_forward(option);
+        }
+        state.remove();
     }
 }
 
@@ -46,4 +72,4 @@ pub mod sys;
 #[path = "linux.rs"]
 pub mod sys;
 
-pub use sys::{setup_ip, DNSSetup};
+pub use sys::{set_mtu, setup_ip, setup_ipv6, DNSSetup};
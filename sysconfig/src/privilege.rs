@@ -0,0 +1,136 @@
+//! Switch to an unprivileged user once the tun device and routes are set up, keeping only the
+//! capabilities needed by the relay: `CAP_NET_ADMIN` for routes and firewall rules,
+//! `CAP_NET_RAW` for binding sockets to interfaces and `CAP_NET_BIND_SERVICE` for the dns server.
+//! They are also raised as ambient capabilities, so `ip`, `nft` and `sysctl` run by seeker keep
+//! them.
+//!
+//! Capabilities belong to threads, only the calling thread and the threads it starts afterwards
+//! keep them. Threads started before lose all of them.
+
+use std::ffi::CString;
+use std::io;
+use tracing::info;
+
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+const KEPT_CAPS: [u32; 3] = [CAP_NET_BIND_SERVICE, CAP_NET_ADMIN, CAP_NET_RAW];
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Switch to `user`, and `group` or the primary group of `user`. Both can be names or ids.
+pub fn drop_privileges(user: &str, group: Option<&str>) -> io::Result<()> {
+    let (uid, gid) = resolve_ids(user, group)?;
+    info!(uid, gid, "drop privileges");
+
+    // Arguments of prctl are read as unsigned longs.
+    let (one, zero): (libc::c_ulong, libc::c_ulong) = (1, 0);
+    check(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, one, zero, zero, zero) })?;
+    check(unsafe { libc::setgroups(1, &gid) })?;
+    check(unsafe { libc::setgid(gid) })?;
+    check(unsafe { libc::setuid(uid) })?;
+
+    let mask = KEPT_CAPS.iter().fold(0, |mask, cap| mask | 1 << cap);
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [
+        CapUserData {
+            effective: mask,
+            permitted: mask,
+            inheritable: mask,
+        },
+        CapUserData::default(),
+    ];
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapUserHeader,
+            data.as_ptr(),
+        )
+    } as i32)?;
+    for cap in &KEPT_CAPS {
+        check(unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                libc::c_ulong::from(*cap),
+                zero,
+                zero,
+            )
+        })?;
+    }
+    Ok(())
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn resolve_ids(user: &str, group: Option<&str>) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let not_found = |kind: &str, name: &str| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} not found: {}", kind, name),
+        )
+    };
+    let c_user = CString::new(user).map_err(|_| not_found("user", user))?;
+    let passwd = unsafe { libc::getpwnam(c_user.as_ptr()) };
+    let (uid, primary_gid) = if !passwd.is_null() {
+        unsafe { ((*passwd).pw_uid, Some((*passwd).pw_gid)) }
+    } else {
+        let uid = user.parse().map_err(|_| not_found("user", user))?;
+        let passwd = unsafe { libc::getpwuid(uid) };
+        let gid = if passwd.is_null() {
+            None
+        } else {
+            unsafe { Some((*passwd).pw_gid) }
+        };
+        (uid, gid)
+    };
+    let gid = match group {
+        Some(group) => {
+            let c_group = CString::new(group).map_err(|_| not_found("group", group))?;
+            let entry = unsafe { libc::getgrnam(c_group.as_ptr()) };
+            if !entry.is_null() {
+                unsafe { (*entry).gr_gid }
+            } else {
+                group.parse().map_err(|_| not_found("group", group))?
+            }
+        }
+        None => primary_gid.ok_or_else(|| not_found("group of user", user))?,
+    };
+    Ok((uid, gid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_ids() {
+        assert_eq!(resolve_ids("root", None).unwrap(), (0, 0));
+        assert_eq!(resolve_ids("0", Some("0")).unwrap(), (0, 0));
+        assert_eq!(resolve_ids("root", Some("1234")).unwrap(), (0, 1234));
+        assert!(resolve_ids("no-such-user-of-seeker", None).is_err());
+        assert!(resolve_ids("root", Some("no-such-group-of-seeker")).is_err());
+    }
+}
@@ -1,8 +1,8 @@
 #![allow(dead_code)]
 use super::SocketInfo;
 use libproc::libproc::proc_pid::{
-    listpidinfo, listpids, pidfdinfo, InSockInfo, ListFDs, ProcFDType, ProcType, SocketFDInfo,
-    SocketInfoKind,
+    listpidinfo, listpids, name, pidfdinfo, InSockInfo, ListFDs, ProcFDType, ProcType,
+    SocketFDInfo, SocketInfoKind,
 };
 use std::collections::HashMap;
 use std::io::Result;
@@ -33,6 +33,22 @@ pub fn list_user_proc_socks(uid: u32) -> Result<HashMap<i32, Vec<SocketInfo>>> {
     Ok(pid_sockaddr_map)
 }
 
+/// Find the name of the process owning the tcp socket bound to `addr`.
+pub fn find_process_name_by_local_addr(addr: SocketAddr) -> Result<Option<String>> {
+    let pids = listpids(ProcType::ProcAllPIDS, 0)?;
+    for pid in pids {
+        let pid = pid as i32;
+        let found = match list_sockaddr(pid) {
+            Ok(socks) => socks.iter().any(|s| s.local == addr),
+            Err(_) => false,
+        };
+        if found {
+            return Ok(name(pid).ok());
+        }
+    }
+    Ok(None)
+}
+
 fn list_sockaddr(pid: i32) -> Result<Vec<SocketInfo>> {
     let mut addrs = vec![];
     for fd in listpidinfo::<ListFDs>(pid, 4000)? {
@@ -2,6 +2,7 @@ use crate::SocketInfo;
 use procfs::process::FDTarget;
 use std::collections::HashMap;
 use std::io::Result;
+use std::net::SocketAddr;
 
 pub fn list_system_proc_socks() -> Result<HashMap<i32, Vec<SocketInfo>>> {
     let all_procs = procfs::process::all_processes().expect("list all processes");
@@ -68,6 +69,42 @@ pub fn list_user_proc_socks(uid: u32) -> Result<HashMap<i32, Vec<SocketInfo>>> {
     Ok(socks_map)
 }
 
+/// Find the name of the process owning the tcp or udp socket bound to `addr`.
+pub fn find_process_name_by_local_addr(addr: SocketAddr) -> Result<Option<String>> {
+    let tcp = procfs::net::tcp().unwrap_or_default();
+    let tcp6 = procfs::net::tcp6().unwrap_or_default();
+    let udp = procfs::net::udp().unwrap_or_default();
+    let udp6 = procfs::net::udp6().unwrap_or_default();
+    let inode = tcp
+        .into_iter()
+        .chain(tcp6)
+        .map(|entry| (entry.local_address, entry.inode))
+        .chain(
+            udp.into_iter()
+                .chain(udp6)
+                .map(|entry| (entry.local_address, entry.inode)),
+        )
+        .find(|(local, _)| *local == addr)
+        .map(|(_, inode)| inode);
+    let inode = match inode {
+        Some(inode) => inode,
+        None => return Ok(None),
+    };
+
+    let all_procs = procfs::process::all_processes().expect("list all processes");
+    for process in &all_procs {
+        if let Ok(fds) = process.fd() {
+            if fds
+                .iter()
+                .any(|fd| matches!(fd.target, FDTarget::Socket(i) if i == inode))
+            {
+                return Ok(Some(process.stat.comm.clone()));
+            }
+        }
+    }
+    Ok(None)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -82,4 +119,12 @@ mod test {
             .values()
             .any(|sockets| sockets.iter().any(|s| s.local.port() == 65532)));
     }
+
+    #[test]
+    fn test_find_process_name_by_local_addr() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:65531").unwrap();
+        let name = find_process_name_by_local_addr(socket.local_addr().unwrap()).unwrap();
+        let comm = procfs::process::Process::myself().unwrap().stat.comm;
+        assert_eq!(name, Some(comm));
+    }
 }
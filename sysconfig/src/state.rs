@@ -0,0 +1,110 @@
+//! Original values of system settings are recorded to a state file before seeker changes them, and
+//! the file is removed once they are restored on exit. A file left behind by a process which is
+//! gone means seeker crashed or was killed, the settings are restored from it on the next start.
+//!
+//! The directory stays owned by root. A saved file is kept open, so it can still be cleared after
+//! dropping privileges, when it can't be removed.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::error;
+
+const STATE_DIR: &str = "/var/run/seeker";
+
+pub struct StateFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl StateFile {
+    pub fn new(name: &str) -> Self {
+        Self::in_dir(Path::new(STATE_DIR), name)
+    }
+
+    fn in_dir(dir: &Path, name: &str) -> Self {
+        StateFile {
+            path: dir.join(name),
+            file: None,
+        }
+    }
+
+    /// Record `values` for the current process, one per line after its pid.
+    pub fn save(&mut self, values: &[String]) {
+        let mut content = format!("{}\n", std::process::id());
+        for value in values {
+            content.push_str(value);
+            content.push('\n');
+        }
+        let ret = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| File::create(&self.path))
+            .and_then(|mut file| {
+                file.write_all(content.as_bytes())?;
+                Ok(file)
+            });
+        match ret {
+            Ok(file) => self.file = Some(file),
+            Err(e) => error!(?e, path = ?self.path, "save state"),
+        }
+    }
+
+    /// The values recorded by a process which is no longer running.
+    pub fn load_crashed(&self) -> Option<Vec<String>> {
+        let content = fs::read_to_string(&self.path).ok()?;
+        let mut lines = content.lines();
+        let pid = lines.next()?.parse::<i32>().ok()?;
+        // Another seeker is running with the settings changed.
+        if pid != std::process::id() as i32 && is_running(pid) {
+            return None;
+        }
+        Some(lines.map(|l| l.to_string()).collect())
+    }
+
+    /// An empty file left behind records nothing.
+    pub fn remove(&self) {
+        if fs::remove_file(&self.path).is_err() {
+            if let Some(file) = &self.file {
+                let _ = file.set_len(0);
+            }
+        }
+    }
+}
+
+fn is_running(pid: i32) -> bool {
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_file() {
+        let dir = std::env::temp_dir().join(format!("seeker-state-{}", std::process::id()));
+        let mut state = StateFile::in_dir(&dir, "dns");
+        assert_eq!(state.load_crashed(), None);
+
+        let values = vec!["1.1.1.1".to_string(), "8.8.8.8".to_string()];
+        state.save(&values);
+        // Recorded by this process, which is taken as crashed when starting again.
+        assert_eq!(state.load_crashed(), Some(values.clone()));
+
+        // A running process keeps its settings.
+        fs::write(&state.path, "1\n1.1.1.1\n").unwrap();
+        assert_eq!(state.load_crashed(), None);
+
+        state.remove();
+        assert_eq!(state.load_crashed(), None);
+
+        // Cleared but not removed, eg. after dropping privileges.
+        state.save(&values);
+        fs::write(&state.path, "").unwrap();
+        assert_eq!(state.load_crashed(), None);
+        state.remove();
+        let _ = fs::remove_dir(&dir);
+    }
+}
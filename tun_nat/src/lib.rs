@@ -1,79 +1,131 @@
+#[cfg(target_os = "linux")]
+mod offload;
 mod tun_socket;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+mod uring;
 
 use crate::tun_socket::TunSocket;
 use bitvec::vec::BitVec;
 use parking_lot::RwLock;
-use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Cidr, Ipv4Packet, TcpPacket, UdpPacket};
-use std::collections::HashMap;
+use smoltcp::wire::{
+    Icmpv4Message, Icmpv4Packet, Icmpv6Message, Icmpv6Packet, IpAddress, IpProtocol, Ipv4Cidr,
+    Ipv4Packet, Ipv6Cidr, Ipv6Packet, TcpPacket, UdpPacket,
+};
+use std::collections::{BTreeSet, HashMap};
 use std::io::Result;
 use std::io::{Read, Write};
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::SystemTime;
-use sysconfig::setup_ip;
+use std::time::{Duration, SystemTime};
+use sysconfig::{set_mtu, setup_ip, setup_ipv6};
 
 const BEGIN_PORT: u16 = 50000;
 const END_PORT: u16 = 60000;
 const EXPIRE_SECONDS: u64 = 60 * 1000;
 
+/// Rewrite the ports and addresses of a tcp or udp packet in place, `$std_addr` and `$wire_addr`
+/// are the address variants of its ip version. The checksum is left to the caller with
+/// `$partial_checksum`. Evaluates to false if the packet should be dropped.
 macro_rules! route_packet {
-    ($packet_ty: tt, $ipv4_packet: expr, $session_manager: expr, $relay_addr: expr, $relay_port: expr) => {{
-        let src_addr = $ipv4_packet.src_addr().into();
-        let dest_addr = $ipv4_packet.dst_addr().into();
-        let mut packet = $packet_ty::new_checked($ipv4_packet.payload_mut()).unwrap();
-        let src_port = packet.src_port();
-        let dest_port = packet.dst_port();
-
-        if let Some((new_src_addr, new_src_port, new_dst_addr, new_dest_port)) =
-            if src_addr == $relay_addr && src_port == $relay_port {
-                let session_manager = $session_manager.read();
-                if let Some(assoc) = session_manager.get_by_port(dest_port) {
-                    Some((
-                        assoc.dest_addr.into(),
-                        assoc.dest_port,
-                        assoc.src_addr.into(),
-                        assoc.src_port,
-                    ))
-                } else {
-                    None
+    ($packet_ty: tt, $ip_packet: expr, $std_addr: path, $wire_addr: path, $session_manager: expr, $relay: expr, $partial_checksum: expr) => {{
+        let src_addr = $std_addr($ip_packet.src_addr().into());
+        let dest_addr = $std_addr($ip_packet.dst_addr().into());
+        let mut packet = $packet_ty::new_checked($ip_packet.payload_mut()).unwrap();
+        let src = SocketAddr::new(src_addr, packet.src_port());
+        let dest = SocketAddr::new(dest_addr, packet.dst_port());
+        let size = packet.payload_mut().len();
+
+        match session_route($session_manager, src, dest, $relay, size) {
+            Some((new_src, new_dest)) => match (new_src.ip(), new_dest.ip()) {
+                ($std_addr(new_src_addr), $std_addr(new_dest_addr)) => {
+                    packet.set_src_port(new_src.port());
+                    packet.set_dst_port(new_dest.port());
+                    if !$partial_checksum {
+                        packet.fill_checksum(
+                            &$wire_addr(new_src_addr.into()),
+                            &$wire_addr(new_dest_addr.into()),
+                        );
+                    }
+                    $ip_packet.set_src_addr(new_src_addr.into());
+                    $ip_packet.set_dst_addr(new_dest_addr.into());
+                    true
                 }
-            } else {
-                let mut session_manager = $session_manager.write();
-                let port =
-                    session_manager.get_or_create_session(src_addr, src_port, dest_addr, dest_port);
-                session_manager.update_activity_for_port(port);
-                Some((dest_addr.into(), port, $relay_addr.into(), $relay_port))
-            }
-        {
-            packet.set_src_port(new_src_port);
-            packet.set_dst_port(new_dest_port);
-            packet.fill_checksum(
-                &IpAddress::Ipv4(new_src_addr),
-                &IpAddress::Ipv4(new_dst_addr),
-            );
-            $ipv4_packet.set_src_addr(new_src_addr);
-            $ipv4_packet.set_dst_addr(new_dst_addr);
-
-            $ipv4_packet.fill_checksum();
-            Some($ipv4_packet)
-        } else {
-            None
+                // Sessions are created by packets of the same ip version.
+                _ => false,
+            },
+            None => false,
         }
     }};
 }
 
+/// How the tun device is opened.
+#[derive(Debug, Clone, Copy)]
+pub enum TunDevice<'a> {
+    /// Create the device, which is gone when seeker exits, and set up its addresses and routes.
+    Create(&'a str),
+    /// Attach to a persistent device created and set up beforehand, eg. by
+    /// `ip tuntap add mode tun user <user>`, which needs no privilege afterwards.
+    Persistent(&'a str),
+    /// Take over an opened device, eg. inherited from a parent which has set it up. It's read as a
+    /// single queue without offload.
+    Fd(RawFd),
+}
+
+/// Start translating the packets of the tun device. Connections are redirected to `relay_port` of
+/// `tun_ip`, or of the first address of `ipv6` for IPv6 packets, whose cidr is routed to the tun
+/// device too. IPv6 packets are dropped if `ipv6` is None. With `mtu` set, the MTU of the tun
+/// device is changed and the MSS of tcp SYNs is clamped to fit in it. Beyond `max_sessions`, the
+/// least recently used sessions are evicted. Packets are read from `queues` queues of the tun
+/// device in parallel, by a thread each, on linux. With `offload`, also linux only, the kernel
+/// passes coalesced tcp segments with partial checksums.
+#[allow(clippy::too_many_arguments)]
 pub fn run_nat(
-    tun_name: &str,
+    device: TunDevice,
     tun_ip: Ipv4Addr,
     tun_cidr: Ipv4Cidr,
+    ipv6: Option<(Ipv6Addr, Ipv6Cidr)>,
+    mtu: Option<u16>,
+    max_sessions: usize,
+    queues: usize,
+    offload: bool,
     relay_port: u16,
 ) -> Result<SessionManager> {
-    let mut tun = TunSocket::new(tun_name)?;
-    let tun_name = tun.name()?;
+    let offload = match device {
+        TunDevice::Fd(_) => false,
+        _ => offload,
+    };
+    let tuns = open_queues(device, queues, offload)?;
+    if let TunDevice::Create(_) = device {
+        setup_device(&tuns[0].name()?, tun_ip, tun_cidr, ipv6, mtu);
+    }
+
+    let relay = SocketAddr::new(tun_ip.into(), relay_port);
+    let relay6 = ipv6.map(|(tun_ipv6, _)| SocketAddr::new(tun_ipv6.into(), relay_port));
+
+    let session_manager = SessionManager::new(max_sessions);
+    for (i, tun) in tuns.into_iter().enumerate() {
+        let session_manager = session_manager.inner.clone();
+        thread::Builder::new()
+            .name(format!("tun-{}", i))
+            .spawn(move || run_queue(tun, &session_manager, relay, relay6, mtu, offload))?;
+    }
+    Ok(session_manager)
+}
+
+/// Set up the addresses, routes and MTU of a device created by seeker.
+fn setup_device(
+    tun_name: &str,
+    tun_ip: Ipv4Addr,
+    tun_cidr: Ipv4Cidr,
+    ipv6: Option<(Ipv6Addr, Ipv6Cidr)>,
+    mtu: Option<u16>,
+) {
     if cfg!(target_os = "macos") {
         setup_ip(
-            &tun_name,
+            tun_name,
             tun_ip.to_string().as_str(),
             tun_cidr.to_string().as_str(),
         );
@@ -81,62 +133,351 @@ pub fn run_nat(
         let new_ip =
             Ipv4Cidr::from_netmask(tun_ip.into(), tun_cidr.netmask()).expect("convert netmask");
         setup_ip(
-            &tun_name,
+            tun_name,
             new_ip.to_string().as_str(),
             tun_cidr.to_string().as_str(),
         );
     }
 
-    let relay_addr = tun_ip;
+    if let Some(mtu) = mtu {
+        set_mtu(tun_name, mtu);
+    }
 
-    let session_manager = Arc::new(RwLock::new(InnerSessionManager::new(BEGIN_PORT, END_PORT)));
-    let sesion_mamager_clone = session_manager.clone();
-    let _handle = thread::spawn(move || {
-        let mut buf = vec![0; 2000];
+    if let Some((tun_ipv6, tun_cidr6)) = ipv6 {
+        setup_ipv6(
+            tun_name,
+            tun_ipv6.to_string().as_str(),
+            tun_cidr6.to_string().as_str(),
+        );
+    }
+}
 
-        loop {
-            let size = tun.read(&mut buf).unwrap();
-            if size == 0 {
-                eprintln!("tun read return 0, exit now");
-                break;
+#[cfg(target_os = "linux")]
+fn open_queues(device: TunDevice, queues: usize, offload: bool) -> Result<Vec<TunSocket>> {
+    match device {
+        TunDevice::Fd(fd) => Ok(vec![TunSocket::from_fd(fd)?]),
+        TunDevice::Create(tun_name) | TunDevice::Persistent(tun_name) => (0..queues.max(1))
+            .map(|_| TunSocket::new_queue(tun_name, queues > 1, offload))
+            .collect(),
+    }
+}
+
+/// Only linux supports multiple queues and offload.
+#[cfg(not(target_os = "linux"))]
+fn open_queues(device: TunDevice, _queues: usize, _offload: bool) -> Result<Vec<TunSocket>> {
+    match device {
+        TunDevice::Fd(fd) => Ok(vec![TunSocket::from_fd(fd)?]),
+        TunDevice::Create(tun_name) | TunDevice::Persistent(tun_name) => {
+            Ok(vec![TunSocket::new(tun_name)?])
+        }
+    }
+}
+
+/// Translate the packets of a queue until the tun device is closed.
+fn run_queue(
+    mut tun: TunSocket,
+    session_manager: &RwLock<InnerSessionManager>,
+    relay: SocketAddr,
+    relay6: Option<SocketAddr>,
+    mtu: Option<u16>,
+    offload: bool,
+) {
+    #[cfg(target_os = "linux")]
+    {
+        if offload {
+            run_offload_queue(tun, session_manager, relay, relay6, mtu);
+            return;
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = offload;
+
+    let route = |buf: &mut [u8]| rewrite_packet(buf, session_manager, relay, relay6, mtu, false);
+
+    #[cfg(all(target_os = "linux", feature = "uring"))]
+    match uring::TunRing::new() {
+        Ok(ring) => {
+            match ring.run(&tun, route) {
+                Ok(()) => eprintln!("tun read return 0, exit now"),
+                Err(e) => eprintln!("tun io_uring error: {}, exit now", e),
             }
-            let mut ipv4_packet = match Ipv4Packet::new_checked(&mut buf[..size]) {
-                Err(_) => continue,
-                Ok(p) => p,
-            };
-
-            if let Some(packet) = match ipv4_packet.protocol() {
-                IpProtocol::Udp => route_packet!(
-                    UdpPacket,
-                    ipv4_packet,
-                    session_manager,
-                    relay_addr,
-                    relay_port
-                ),
-                IpProtocol::Tcp => route_packet!(
-                    TcpPacket,
-                    ipv4_packet,
-                    session_manager,
-                    relay_addr,
-                    relay_port
-                ),
-                _ => continue,
-            } {
-                let _ = tun.write(packet.as_ref()).unwrap();
+            return;
+        }
+        Err(e) => eprintln!("io_uring is unavailable, read tun blocking: {}", e),
+    }
+
+    let mut buf = vec![0; 2000];
+    loop {
+        let size = tun.read(&mut buf).unwrap();
+        if size == 0 {
+            eprintln!("tun read return 0, exit now");
+            break;
+        }
+        if route(&mut buf[..size]) {
+            let _ = tun.write(&buf[..size]).unwrap();
+        }
+    }
+}
+
+/// Like `run_queue`, for packets prefixed by a virtio-net header. A coalesced packet is translated
+/// once and written back whole with its header, the kernel segments it again on its way out.
+#[cfg(target_os = "linux")]
+fn run_offload_queue(
+    mut tun: TunSocket,
+    session_manager: &RwLock<InnerSessionManager>,
+    relay: SocketAddr,
+    relay6: Option<SocketAddr>,
+    mtu: Option<u16>,
+) {
+    use crate::offload::{VnetHdr, OFFLOAD_BUFFER_SIZE, VNET_HDR_LEN};
+
+    let mut buf = vec![0; OFFLOAD_BUFFER_SIZE];
+    loop {
+        let size = tun.read(&mut buf).unwrap();
+        if size == 0 {
+            eprintln!("tun read return 0, exit now");
+            break;
+        }
+        let hdr = match VnetHdr::parse(&buf[..size]) {
+            Some(hdr) => hdr,
+            None => continue,
+        };
+        let packet = &mut buf[VNET_HDR_LEN..size];
+        let partial_checksum = hdr.needs_checksum();
+        if rewrite_packet(
+            packet,
+            session_manager,
+            relay,
+            relay6,
+            mtu,
+            partial_checksum,
+        ) {
+            if partial_checksum {
+                hdr.fill_partial_checksum(packet);
+            }
+            let _ = tun.write(&buf[..size]).unwrap();
+        }
+    }
+}
+
+/// Rewrite the addresses of a packet read from the tun device in place. Returns false if the
+/// packet should be dropped.
+fn rewrite_packet(
+    buf: &mut [u8],
+    session_manager: &RwLock<InnerSessionManager>,
+    relay: SocketAddr,
+    relay6: Option<SocketAddr>,
+    mtu: Option<u16>,
+    partial_checksum: bool,
+) -> bool {
+    match buf.first().map(|b| b >> 4) {
+        Some(4) => rewrite_ipv4_packet(buf, session_manager, relay, mtu, partial_checksum),
+        Some(6) => match relay6 {
+            Some(relay6) => {
+                rewrite_ipv6_packet(buf, session_manager, relay6, mtu, partial_checksum)
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+fn rewrite_ipv4_packet(
+    buf: &mut [u8],
+    session_manager: &RwLock<InnerSessionManager>,
+    relay: SocketAddr,
+    mtu: Option<u16>,
+    partial_checksum: bool,
+) -> bool {
+    let mut ipv4_packet = match Ipv4Packet::new_checked(buf) {
+        Err(_) => return false,
+        Ok(p) => p,
+    };
+    let routed = match ipv4_packet.protocol() {
+        IpProtocol::Udp => route_packet!(
+            UdpPacket,
+            ipv4_packet,
+            IpAddr::V4,
+            IpAddress::Ipv4,
+            session_manager,
+            relay,
+            partial_checksum
+        ),
+        IpProtocol::Tcp => {
+            if let Some(mtu) = mtu {
+                clamp_mss(ipv4_packet.payload_mut(), mtu.saturating_sub(40));
+            }
+            route_packet!(
+                TcpPacket,
+                ipv4_packet,
+                IpAddr::V4,
+                IpAddress::Ipv4,
+                session_manager,
+                relay,
+                partial_checksum
+            )
+        }
+        IpProtocol::Icmp => reply_echo_v4(&mut ipv4_packet),
+        _ => false,
+    };
+    if routed {
+        ipv4_packet.fill_checksum();
+    }
+    routed
+}
+
+/// Turn an echo request into the reply in place, so pinging addresses of the tun device works.
+fn reply_echo_v4(ipv4_packet: &mut Ipv4Packet<&mut [u8]>) -> bool {
+    let mut icmp = match Icmpv4Packet::new_checked(ipv4_packet.payload_mut()) {
+        Ok(icmp) if icmp.msg_type() == Icmpv4Message::EchoRequest => icmp,
+        _ => return false,
+    };
+    icmp.set_msg_type(Icmpv4Message::EchoReply);
+    icmp.fill_checksum();
+    let src_addr = ipv4_packet.src_addr();
+    ipv4_packet.set_src_addr(ipv4_packet.dst_addr());
+    ipv4_packet.set_dst_addr(src_addr);
+    true
+}
+
+fn reply_echo_v6(ipv6_packet: &mut Ipv6Packet<&mut [u8]>) -> bool {
+    let src_addr = ipv6_packet.src_addr();
+    let dst_addr = ipv6_packet.dst_addr();
+    let mut icmp = match Icmpv6Packet::new_checked(ipv6_packet.payload_mut()) {
+        Ok(icmp) if icmp.msg_type() == Icmpv6Message::EchoRequest => icmp,
+        _ => return false,
+    };
+    icmp.set_msg_type(Icmpv6Message::EchoReply);
+    icmp.fill_checksum(&IpAddress::Ipv6(dst_addr), &IpAddress::Ipv6(src_addr));
+    ipv6_packet.set_src_addr(dst_addr);
+    ipv6_packet.set_dst_addr(src_addr);
+    true
+}
+
+fn rewrite_ipv6_packet(
+    buf: &mut [u8],
+    session_manager: &RwLock<InnerSessionManager>,
+    relay: SocketAddr,
+    mtu: Option<u16>,
+    partial_checksum: bool,
+) -> bool {
+    let mut ipv6_packet = match Ipv6Packet::new_checked(buf) {
+        Err(_) => return false,
+        Ok(p) => p,
+    };
+    // Packets with extension headers are dropped, the tcp or udp header must follow directly.
+    match ipv6_packet.next_header() {
+        IpProtocol::Udp => route_packet!(
+            UdpPacket,
+            ipv6_packet,
+            IpAddr::V6,
+            IpAddress::Ipv6,
+            session_manager,
+            relay,
+            partial_checksum
+        ),
+        IpProtocol::Tcp => {
+            if let Some(mtu) = mtu {
+                clamp_mss(ipv6_packet.payload_mut(), mtu.saturating_sub(60));
+            }
+            route_packet!(
+                TcpPacket,
+                ipv6_packet,
+                IpAddr::V6,
+                IpAddress::Ipv6,
+                session_manager,
+                relay,
+                partial_checksum
+            )
+        }
+        IpProtocol::Icmpv6 => reply_echo_v6(&mut ipv6_packet),
+        _ => false,
+    }
+}
+
+/// Lower the MSS option of a tcp SYN to `max_mss`. The checksum is filled after the ports are
+/// rewritten.
+fn clamp_mss(segment: &mut [u8], max_mss: u16) {
+    const SYN: u8 = 0x02;
+    const OPTION_END: u8 = 0;
+    const OPTION_NOP: u8 = 1;
+    const OPTION_MSS: u8 = 2;
+
+    if segment.len() < 20 || segment[13] & SYN == 0 {
+        return;
+    }
+    let header_len = (usize::from(segment[12] >> 4) * 4).min(segment.len());
+    let mut i = 20;
+    while i + 1 < header_len {
+        match segment[i] {
+            OPTION_END => break,
+            OPTION_NOP => i += 1,
+            kind => {
+                let len = usize::from(segment[i + 1]);
+                if len < 2 || i + len > header_len {
+                    break;
+                }
+                if kind == OPTION_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([segment[i + 2], segment[i + 3]]);
+                    if mss > max_mss {
+                        segment[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+                    }
+                }
+                i += len;
             }
         }
-    });
-    Ok(SessionManager {
-        inner: sesion_mamager_clone,
-    })
+    }
+}
+
+/// New source and destination of a packet from `src` to `dest`, with `size` bytes of payload.
+/// Packets of applications go to `relay` from the session port, replies of `relay` go back to the
+/// application. None if the session of a reply is gone.
+fn session_route(
+    session_manager: &RwLock<InnerSessionManager>,
+    src: SocketAddr,
+    dest: SocketAddr,
+    relay: SocketAddr,
+    size: usize,
+) -> Option<(SocketAddr, SocketAddr)> {
+    if src == relay {
+        let session_manager = session_manager.read();
+        let assoc = session_manager.get_by_port(dest.port())?;
+        assoc.bytes.fetch_add(size as u64, Ordering::Relaxed);
+        Some((
+            SocketAddr::new(assoc.dest_addr, assoc.dest_port),
+            SocketAddr::new(assoc.src_addr, assoc.src_port),
+        ))
+    } else {
+        let mut session_manager = session_manager.write();
+        let port =
+            session_manager.get_or_create_session(src.ip(), src.port(), dest.ip(), dest.port());
+        session_manager.update_activity_for_port(port);
+        if let Some(assoc) = session_manager.get_by_port(port) {
+            assoc.bytes.fetch_add(size as u64, Ordering::Relaxed);
+        }
+        Some((SocketAddr::new(dest.ip(), port), relay))
+    }
 }
 
 pub struct Association {
-    pub src_addr: Ipv4Addr,
+    pub src_addr: IpAddr,
     pub src_port: u16,
-    pub dest_addr: Ipv4Addr,
+    pub dest_addr: IpAddr,
     pub dest_port: u16,
     last_activity_ts: u64,
+    /// Payload of the tcp or udp packets in both directions.
+    bytes: AtomicU64,
+}
+
+/// A session of the nat table, for inspection.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// Port the relay sees the session from.
+    pub port: u16,
+    pub src: SocketAddr,
+    pub dest: SocketAddr,
+    pub idle: Duration,
+    pub bytes: u64,
 }
 
 #[derive(Clone)]
@@ -144,13 +485,58 @@ pub struct SessionManager {
     inner: Arc<RwLock<InnerSessionManager>>,
 }
 
+/// Counters of the session table.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NatStats {
+    /// Sessions in the table.
+    pub sessions: usize,
+    /// Sessions evicted to make room for new ones since the start.
+    pub evicted: u64,
+}
+
 impl SessionManager {
+    /// An empty table, which stays empty without a tun device translating packets into it.
+    pub fn new(max_sessions: usize) -> Self {
+        SessionManager {
+            inner: Arc::new(RwLock::new(InnerSessionManager::new(
+                BEGIN_PORT,
+                END_PORT,
+                max_sessions,
+            ))),
+        }
+    }
+
+    pub fn stats(&self) -> NatStats {
+        let inner = self.inner.read();
+        NatStats {
+            sessions: inner.map.len(),
+            evicted: inner.evicted,
+        }
+    }
+
+    /// Sessions in the table, in no particular order.
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let inner = self.inner.read();
+        let now = now();
+        inner
+            .map
+            .iter()
+            .map(|(port, assoc)| SessionInfo {
+                port: *port,
+                src: SocketAddr::new(assoc.src_addr, assoc.src_port),
+                dest: SocketAddr::new(assoc.dest_addr, assoc.dest_port),
+                idle: Duration::from_millis(now.saturating_sub(assoc.last_activity_ts)),
+                bytes: assoc.bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
     pub fn get_by_port(&self, port: u16) -> Option<(SocketAddr, SocketAddr)> {
         let inner = self.inner.read();
         if let Some(assoc) = inner.map.get(&port) {
             Some((
-                SocketAddr::new(assoc.src_addr.into(), assoc.src_port),
-                SocketAddr::new(assoc.dest_addr.into(), assoc.dest_port),
+                SocketAddr::new(assoc.src_addr, assoc.src_port),
+                SocketAddr::new(assoc.dest_addr, assoc.dest_port),
             ))
         } else {
             None
@@ -160,14 +546,19 @@ impl SessionManager {
 
 struct InnerSessionManager {
     map: HashMap<u16, Association>,
-    reverse_map: HashMap<(Ipv4Addr, u16, Ipv4Addr, u16), u16>,
+    reverse_map: HashMap<(IpAddr, u16, IpAddr, u16), u16>,
+    /// Ports of the sessions ordered by the last activity, the least recently used first.
+    lru: BTreeSet<(u64, u16)>,
     begin_port: u16,
     next_index: u16,
     available_ports: BitVec,
+    /// At most the number of ports.
+    max_sessions: usize,
+    evicted: u64,
 }
 
 impl InnerSessionManager {
-    pub fn new(begin_port: u16, end_port: u16) -> Self {
+    pub fn new(begin_port: u16, end_port: u16, max_sessions: usize) -> Self {
         let range = (end_port - begin_port) as usize;
         let mut ports = BitVec::with_capacity(range);
         ports.resize(range, true);
@@ -175,9 +566,12 @@ impl InnerSessionManager {
         InnerSessionManager {
             map: HashMap::new(),
             reverse_map: HashMap::new(),
+            lru: BTreeSet::new(),
             available_ports: ports,
             next_index: 0,
             begin_port,
+            max_sessions: max_sessions.min(range).max(1),
+            evicted: 0,
         }
     }
 
@@ -209,16 +603,22 @@ impl InnerSessionManager {
     }
 
     pub fn update_activity_for_port(&mut self, port: u16) {
+        self.set_activity(port, now());
+    }
+
+    fn set_activity(&mut self, port: u16, ts: u64) {
         if let Some(assoc) = self.map.get_mut(&port) {
-            assoc.last_activity_ts = now();
+            self.lru.remove(&(assoc.last_activity_ts, port));
+            assoc.last_activity_ts = ts;
+            self.lru.insert((ts, port));
         }
     }
 
     pub fn get_or_create_session(
         &mut self,
-        src_addr: Ipv4Addr,
+        src_addr: IpAddr,
         src_port: u16,
-        dest_addr: Ipv4Addr,
+        dest_addr: IpAddr,
         dest_port: u16,
     ) -> u16 {
         if let Some(port) = self
@@ -228,9 +628,13 @@ impl InnerSessionManager {
             return *port;
         }
 
-        let port = self.fetch_next_available_port();
-
         let now = now();
+        self.remove_expired(now);
+        while self.map.len() >= self.max_sessions {
+            self.evict_least_recently_used();
+        }
+
+        let port = self.fetch_next_available_port();
         self.map.insert(
             port,
             Association {
@@ -239,30 +643,43 @@ impl InnerSessionManager {
                 dest_addr,
                 dest_port,
                 last_activity_ts: now,
+                bytes: AtomicU64::new(0),
             },
         );
         self.reverse_map
             .insert((src_addr, src_port, dest_addr, dest_port), port);
+        self.lru.insert((now, port));
+        port
+    }
 
-        let map = &mut self.map;
-        let reverse_map = &mut self.reverse_map;
-        let available_ports = &mut self.available_ports;
-        let begin_port = self.begin_port;
-        map.retain(|port, assoc| {
-            let retain = now - assoc.last_activity_ts < EXPIRE_SECONDS;
-            if !retain {
-                reverse_map.remove(&(
-                    assoc.src_addr,
-                    assoc.src_port,
-                    assoc.dest_addr,
-                    assoc.dest_port,
-                ));
-                let idx = *port - begin_port;
-                available_ports.set(idx as usize, true);
+    fn remove_expired(&mut self, now: u64) {
+        while let Some(&(ts, port)) = self.lru.iter().next() {
+            if now.saturating_sub(ts) < EXPIRE_SECONDS {
+                break;
             }
-            retain
-        });
-        port
+            self.remove_session(port);
+        }
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        if let Some(&(_, port)) = self.lru.iter().next() {
+            self.remove_session(port);
+            self.evicted += 1;
+        }
+    }
+
+    fn remove_session(&mut self, port: u16) {
+        if let Some(assoc) = self.map.remove(&port) {
+            self.lru.remove(&(assoc.last_activity_ts, port));
+            self.reverse_map.remove(&(
+                assoc.src_addr,
+                assoc.src_port,
+                assoc.dest_addr,
+                assoc.dest_port,
+            ));
+            self.available_ports
+                .set((port - self.begin_port) as usize, true);
+        }
     }
 }
 
@@ -272,3 +689,115 @@ fn now() -> u64 {
         .unwrap()
         .as_millis() as u64
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::Ipv4Address;
+
+    #[test]
+    fn test_session_route() {
+        let session_manager = RwLock::new(InnerSessionManager::new(BEGIN_PORT, END_PORT, 100));
+        let relay: SocketAddr = "[fd00:5ee:1::1]:1300".parse().unwrap();
+        let app: SocketAddr = "[fd00:5ee:1::1]:40000".parse().unwrap();
+        let fake: SocketAddr = "[fd00:5ee::10]:443".parse().unwrap();
+
+        let (src, dest) = session_route(&session_manager, app, fake, relay, 100).unwrap();
+        assert_eq!(src.ip(), fake.ip());
+        assert_eq!(dest, relay);
+        let (real_src, real_dest) = SessionManager {
+            inner: Arc::new(session_manager),
+        }
+        .get_by_port(src.port())
+        .unwrap();
+        assert_eq!((real_src, real_dest), (app, fake));
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let mut session_manager = InnerSessionManager::new(BEGIN_PORT, END_PORT, 2);
+        let app: IpAddr = "10.0.0.1".parse().unwrap();
+        let fake: IpAddr = "10.0.0.10".parse().unwrap();
+        let p1 = session_manager.get_or_create_session(app, 1, fake, 80);
+        let p2 = session_manager.get_or_create_session(app, 2, fake, 80);
+        let ts = session_manager.map[&p1].last_activity_ts;
+        session_manager.set_activity(p1, ts + 1000);
+
+        let p3 = session_manager.get_or_create_session(app, 3, fake, 80);
+        assert!(session_manager.get_by_port(p1).is_some());
+        assert!(session_manager.get_by_port(p2).is_none());
+        assert!(session_manager.get_by_port(p3).is_some());
+        assert_eq!(session_manager.map.len(), 2);
+        assert_eq!(session_manager.lru.len(), 2);
+        assert_eq!(session_manager.evicted, 1);
+        // The evicted session is created again on its next packet.
+        assert_ne!(session_manager.get_or_create_session(app, 2, fake, 80), p2);
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        // A SYN with a nop and a mss of 1460.
+        let mut segment = [0u8; 28];
+        segment[12] = 7 << 4;
+        segment[13] = 0x02;
+        segment[20..28].copy_from_slice(&[1, 2, 4, 0x05, 0xb4, 0, 0, 0]);
+        clamp_mss(&mut segment, 1360);
+        assert_eq!(&segment[22..25], &[4, 0x05, 0x50]);
+        clamp_mss(&mut segment, 1400);
+        assert_eq!(&segment[22..25], &[4, 0x05, 0x50]);
+
+        // Only SYNs are clamped.
+        segment[13] = 0x10;
+        segment[23..25].copy_from_slice(&1460u16.to_be_bytes());
+        clamp_mss(&mut segment, 1360);
+        assert_eq!(&segment[23..25], &1460u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_reply_echo() {
+        let mut buf = vec![0u8; 28];
+        let mut packet = Ipv4Packet::new_unchecked(&mut buf[..]);
+        packet.set_version(4);
+        packet.set_header_len(20);
+        packet.set_total_len(28);
+        packet.set_hop_limit(64);
+        packet.set_protocol(IpProtocol::Icmp);
+        packet.set_src_addr(Ipv4Address::new(10, 0, 0, 1));
+        packet.set_dst_addr(Ipv4Address::new(10, 0, 0, 10));
+        // Echo request
+        packet.payload_mut()[0] = 8;
+
+        assert!(reply_echo_v4(&mut packet));
+        assert_eq!(packet.src_addr(), Ipv4Address::new(10, 0, 0, 10));
+        assert_eq!(packet.dst_addr(), Ipv4Address::new(10, 0, 0, 1));
+        let icmp = Icmpv4Packet::new_checked(packet.payload()).unwrap();
+        assert_eq!(icmp.msg_type(), Icmpv4Message::EchoReply);
+        assert!(icmp.verify_checksum());
+        // Replies are not answered.
+        assert!(!reply_echo_v4(&mut packet));
+    }
+
+    #[test]
+    fn test_session_route_reply() {
+        let session_manager = RwLock::new(InnerSessionManager::new(BEGIN_PORT, END_PORT, 100));
+        let relay: SocketAddr = "10.0.0.1:1300".parse().unwrap();
+        let app: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let fake: SocketAddr = "10.0.0.10:80".parse().unwrap();
+
+        let (src, _) = session_route(&session_manager, app, fake, relay, 100).unwrap();
+        let (reply_src, reply_dest) =
+            session_route(&session_manager, relay, src, relay, 50).unwrap();
+        assert_eq!((reply_src, reply_dest), (fake, app));
+        // The session of an unknown port is gone.
+        let unknown = SocketAddr::new(fake.ip(), END_PORT);
+        assert!(session_route(&session_manager, relay, unknown, relay, 50).is_none());
+
+        let sessions = SessionManager {
+            inner: Arc::new(session_manager),
+        }
+        .sessions();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].port, src.port());
+        assert_eq!(sessions[0].bytes, 150);
+    }
+}
@@ -0,0 +1,116 @@
+//! Packets of a tun device opened with offload are prefixed by a virtio-net header. The kernel
+//! coalesces tcp segments into packets up to 64KB described by the header, and leaves checksums of
+//! local packets to be completed, which saves translating and checksumming every segment.
+
+pub const VNET_HDR_LEN: usize = 10;
+/// Room for the header and the largest coalesced packet.
+pub const OFFLOAD_BUFFER_SIZE: usize = VNET_HDR_LEN + 65535;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+/// The fields of `struct virtio_net_hdr` used here, in native byte order. The header is written
+/// back unchanged with the translated packet, so the kernel segments it again if needed.
+#[derive(Debug, Clone, Copy)]
+pub struct VnetHdr {
+    flags: u8,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VnetHdr {
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < VNET_HDR_LEN {
+            return None;
+        }
+        Some(VnetHdr {
+            flags: buf[0],
+            csum_start: u16::from_ne_bytes([buf[6], buf[7]]),
+            csum_offset: u16::from_ne_bytes([buf[8], buf[9]]),
+        })
+    }
+
+    /// The transport checksum holds only the sum of the pseudo header, the rest is summed by the
+    /// kernel.
+    pub fn needs_checksum(&self) -> bool {
+        self.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0
+    }
+
+    /// Set the partial checksum of a translated tcp or udp packet to the pseudo header sum of its
+    /// new addresses.
+    pub fn fill_partial_checksum(&self, packet: &mut [u8]) {
+        let (src, dst, protocol) = match packet.first().map(|b| b >> 4) {
+            Some(4) if packet.len() >= 20 => (&packet[12..16], &packet[16..20], packet[9]),
+            Some(6) if packet.len() >= 40 => (&packet[8..24], &packet[24..40], packet[6]),
+            _ => return,
+        };
+        let start = usize::from(self.csum_start);
+        let field = start + usize::from(self.csum_offset);
+        if (protocol != PROTOCOL_TCP && protocol != PROTOCOL_UDP) || field + 2 > packet.len() {
+            return;
+        }
+        let sum = pseudo_header_sum(src, dst, protocol, (packet.len() - start) as u32);
+        packet[field..field + 2].copy_from_slice(&sum.to_be_bytes());
+    }
+}
+
+fn pseudo_header_sum(src: &[u8], dst: &[u8], protocol: u8, len: u32) -> u16 {
+    let mut sum: u32 = src
+        .chunks(2)
+        .chain(dst.chunks(2))
+        .map(|c| u32::from(u16::from_be_bytes([c[0], c[1]])))
+        .sum();
+    sum += u32::from(protocol) + (len >> 16) + (len & 0xffff);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Address, Ipv4Packet, TcpPacket};
+
+    #[test]
+    fn test_fill_partial_checksum() {
+        let mut buf = vec![0u8; 20 + 20 + 100];
+        let mut ipv4 = Ipv4Packet::new_unchecked(&mut buf[..]);
+        ipv4.set_version(4);
+        ipv4.set_header_len(20);
+        ipv4.set_total_len(140);
+        ipv4.set_protocol(IpProtocol::Tcp);
+        ipv4.set_src_addr(Ipv4Address::new(10, 0, 0, 1));
+        ipv4.set_dst_addr(Ipv4Address::new(10, 0, 0, 2));
+        let mut tcp = TcpPacket::new_unchecked(ipv4.payload_mut());
+        tcp.set_src_port(1234);
+        tcp.set_dst_port(80);
+        tcp.set_header_len(20);
+        for (i, b) in tcp.payload_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        tcp.fill_checksum(
+            &IpAddress::Ipv4(Ipv4Address::new(10, 0, 0, 1)),
+            &IpAddress::Ipv4(Ipv4Address::new(10, 0, 0, 2)),
+        );
+        let full = tcp.checksum();
+
+        let hdr = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            csum_start: 20,
+            csum_offset: 16,
+        };
+        assert!(hdr.needs_checksum());
+        hdr.fill_partial_checksum(&mut buf);
+        // Complete it the way the kernel does, summing from `csum_start`.
+        let mut sum: u32 = buf[20..]
+            .chunks(2)
+            .map(|c| u32::from(u16::from_be_bytes([c[0], c[1]])))
+            .sum();
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        assert_eq!(!(sum as u16), full);
+    }
+}
@@ -128,6 +128,15 @@ impl TunSocket {
         Ok(TunSocket { fd })
     }
 
+    /// Take over `fd` of a utun device opened by another process, eg. inherited from the parent.
+    pub fn from_fd(fd: RawFd) -> Result<TunSocket> {
+        // Owned from here, so it's closed on errors.
+        let tun = TunSocket { fd };
+        // Fails if it's not a utun control socket.
+        tun.name()?;
+        Ok(tun)
+    }
+
     pub fn name(&self) -> Result<String> {
         let mut tunnel_name = [0u8; 256];
         let mut tunnel_name_len: socklen_t = tunnel_name.len() as u32;
@@ -211,11 +220,6 @@ impl TunSocket {
             n => Ok((n - 4) as usize),
         }
     }
-
-    #[allow(dead_code)]
-    pub fn write6(&self, src: &[u8]) -> Result<usize> {
-        self.af_write(src, AF_INET6 as u8)
-    }
 }
 
 impl Read for TunSocket {
@@ -273,7 +277,13 @@ impl Read for &TunSocket {
 
 impl Write for &TunSocket {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.af_write(buf, AF_INET as u8)
+        // utun needs the address family of the packet, taken from the ip version.
+        let af = if buf.first().map(|b| b >> 4) == Some(6) {
+            AF_INET6
+        } else {
+            AF_INET
+        };
+        self.af_write(buf, af as u8)
     }
 
     fn flush(&mut self) -> Result<()> {
@@ -6,6 +6,13 @@ use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::os::unix::io::{AsRawFd, RawFd};
 
 const TUNSETIFF: u64 = 0x4004_54ca;
+const TUNSETOFFLOAD: u64 = 0x4004_54d0;
+const TUNGETIFF: u64 = 0x8004_54d2;
+const IFF_MULTI_QUEUE: c_int = 0x0100;
+const IFF_VNET_HDR: c_int = 0x4000;
+const TUN_F_CSUM: c_uint = 0x01;
+const TUN_F_TSO4: c_uint = 0x02;
+const TUN_F_TSO6: c_uint = 0x04;
 
 #[repr(C)]
 union IfrIfru {
@@ -55,6 +62,31 @@ impl AsRawFd for TunSocket {
 
 impl TunSocket {
     pub fn new(name: &str) -> Result<TunSocket> {
+        Self::open_with_flags(name, IFF_TUN | IFF_NO_PI)
+    }
+
+    /// Open a queue of the multi-queue device `name`, which is created by the first queue. With
+    /// `offload`, packets are prefixed by a virtio-net header and the kernel may pass tcp segments
+    /// coalesced up to 64KB, with their checksums left to be completed.
+    pub fn new_queue(name: &str, multi_queue: bool, offload: bool) -> Result<TunSocket> {
+        let mut flags = IFF_TUN | IFF_NO_PI;
+        if multi_queue {
+            flags |= IFF_MULTI_QUEUE;
+        }
+        if offload {
+            flags |= IFF_VNET_HDR;
+        }
+        let tun = Self::open_with_flags(name, flags)?;
+        if offload {
+            let offloads = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+            if unsafe { ioctl(tun.fd, TUNSETOFFLOAD as _, offloads as c_ulong) } < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(tun)
+    }
+
+    fn open_with_flags(name: &str, flags: c_int) -> Result<TunSocket> {
         let fd = match unsafe { open(b"/dev/net/tun\0".as_ptr() as _, O_RDWR) } {
             -1 => return Err(Error::last_os_error()),
             fd => fd,
@@ -64,7 +96,7 @@ impl TunSocket {
         let mut ifr = ifreq {
             ifr_name: [0; IFNAMSIZ],
             ifr_ifru: IfrIfru {
-                ifru_flags: (IFF_TUN | IFF_NO_PI) as _,
+                ifru_flags: flags as _,
             },
         };
 
@@ -83,6 +115,29 @@ impl TunSocket {
         Ok(TunSocket { fd, name })
     }
 
+    /// Take over `fd` of a tun device opened by another process, eg. inherited from the parent.
+    pub fn from_fd(fd: RawFd) -> Result<TunSocket> {
+        // Owned from here, so it's closed on errors.
+        let mut tun = TunSocket {
+            fd,
+            name: String::new(),
+        };
+        let mut ifr = ifreq {
+            ifr_name: [0; IFNAMSIZ],
+            ifr_ifru: IfrIfru { ifru_flags: 0 },
+        };
+        if unsafe { ioctl(fd, TUNGETIFF as _, &mut ifr) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        let len = ifr
+            .ifr_name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(IFNAMSIZ);
+        tun.name = String::from_utf8_lossy(&ifr.ifr_name[..len]).to_string();
+        Ok(tun)
+    }
+
     pub fn name(&self) -> Result<String> {
         Ok(self.name.clone())
     }
@@ -0,0 +1,89 @@
+//! Reading and writing the tun device through io_uring. Reads of `QUEUE_DEPTH` packets are kept
+//! in flight, so a batch of packets costs one syscall instead of a read and a write per packet.
+
+use crate::tun_socket::TunSocket;
+use io_uring::{opcode, types, IoUring};
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::AsRawFd;
+
+const QUEUE_DEPTH: usize = 64;
+const BUFFER_SIZE: usize = 2000;
+/// Set in the user data of writes, the rest is the index of the buffer.
+const WRITE_FLAG: u64 = 1 << 63;
+
+pub struct TunRing {
+    ring: IoUring,
+    bufs: Vec<[u8; BUFFER_SIZE]>,
+}
+
+impl TunRing {
+    pub fn new() -> Result<Self> {
+        Ok(TunRing {
+            // Every buffer is either read into or written from, never both.
+            ring: IoUring::new(QUEUE_DEPTH as u32)?,
+            bufs: vec![[0; BUFFER_SIZE]; QUEUE_DEPTH],
+        })
+    }
+
+    /// Read packets until the tun device is closed. `route` rewrites a packet in place and
+    /// returns whether it should be written back.
+    pub fn run(mut self, tun: &TunSocket, mut route: impl FnMut(&mut [u8]) -> bool) -> Result<()> {
+        let fd = types::Fd(tun.as_raw_fd());
+        for index in 0..QUEUE_DEPTH {
+            self.read(fd, index)?;
+        }
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            let completed: Vec<_> = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            for (user_data, result) in completed {
+                if result < 0 {
+                    return Err(Error::from_raw_os_error(-result));
+                }
+                let index = (user_data & !WRITE_FLAG) as usize;
+                if user_data & WRITE_FLAG == 0 {
+                    if result == 0 {
+                        return Ok(());
+                    }
+                    let size = result as usize;
+                    if route(&mut self.bufs[index][..size]) {
+                        self.write(fd, index, size)?;
+                        continue;
+                    }
+                }
+                self.read(fd, index)?;
+            }
+        }
+    }
+
+    fn read(&mut self, fd: types::Fd, index: usize) -> Result<()> {
+        let buf = &mut self.bufs[index];
+        let entry = opcode::Read::new(fd, buf.as_mut_ptr(), BUFFER_SIZE as u32)
+            .build()
+            .user_data(index as u64);
+        self.push(entry)
+    }
+
+    fn write(&mut self, fd: types::Fd, index: usize, size: usize) -> Result<()> {
+        let buf = &self.bufs[index];
+        let entry = opcode::Write::new(fd, buf.as_ptr(), size as u32)
+            .build()
+            .user_data(index as u64 | WRITE_FLAG);
+        self.push(entry)
+    }
+
+    fn push(&mut self, entry: io_uring::squeue::Entry) -> Result<()> {
+        // The buffers outlive the ring and aren't touched until their entry completes.
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.ring.submit()?;
+        }
+        Ok(())
+    }
+}